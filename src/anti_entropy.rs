@@ -0,0 +1,171 @@
+//! Periodic anti-entropy: besides merging when told about new heads, every
+//! member's announcement is checked on a fixed interval and anything missed
+//! (a lost pubsub message, a publish that raced a merge) is repaired, so
+//! replicas converge even when notifications go missing.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+
+use crate::crdt::identity::ReplicaId;
+use crate::workspace::Workspace;
+
+/// Time between rounds by default.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+/// Longest a failing member is skipped for by default.
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// Outcome of one anti-entropy round.
+#[derive(Debug, Default)]
+pub struct AntiEntropyReport {
+    /// Members whose announcement carried operations this replica lacked,
+    /// with how many were applied.
+    pub repaired: Vec<(ReplicaId, usize)>,
+    /// Members this replica had already caught up with.
+    pub in_sync: Vec<ReplicaId>,
+    /// Members that couldn't be resolved, fetched or merged; they back off.
+    pub failed: Vec<(ReplicaId, anyhow::Error)>,
+    /// Members skipped because they are still backing off.
+    pub skipped: Vec<ReplicaId>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+/// When rounds run, and which members sit them out after failing. A member
+/// that fails waits `min_backoff`, doubling with every further failure up
+/// to `max_backoff`; one success clears it.
+#[derive(Debug, Clone)]
+pub struct AntiEntropy {
+    interval: Duration,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    next_round: Instant,
+    backoff: HashMap<ReplicaId, Backoff>,
+}
+
+impl Default for AntiEntropy {
+    fn default() -> Self {
+        AntiEntropy::new(DEFAULT_INTERVAL)
+    }
+}
+
+impl AntiEntropy {
+    /// Runs a round every `interval`, the first one right away. Failing
+    /// members back off from one interval up to `DEFAULT_MAX_BACKOFF`.
+    pub fn new(interval: Duration) -> Self {
+        AntiEntropy {
+            interval,
+            min_backoff: interval,
+            max_backoff: DEFAULT_MAX_BACKOFF.max(interval),
+            next_round: Instant::now(),
+            backoff: HashMap::new(),
+        }
+    }
+
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Waits until the next round is due. A round that overran the interval
+    /// is followed by the next one right away, not by a burst of them.
+    pub async fn tick(&mut self) {
+        sleep_until(self.next_round).await;
+        self.next_round = (self.next_round + self.interval).max(Instant::now());
+    }
+
+    /// Whether `member` takes part in a round starting at `now`.
+    pub fn is_due(&self, member: &ReplicaId, now: Instant) -> bool {
+        self.backoff.get(member).is_none_or(|backoff| backoff.retry_at <= now)
+    }
+
+    /// When a backing-off `member` is tried again.
+    pub fn retry_at(&self, member: &ReplicaId) -> Option<Instant> {
+        self.backoff.get(member).map(|backoff| backoff.retry_at)
+    }
+
+    pub fn record_success(&mut self, member: &ReplicaId) {
+        self.backoff.remove(member);
+    }
+
+    pub fn record_failure(&mut self, member: &ReplicaId, now: Instant) {
+        let failures = self.backoff.get(member).map_or(0, |backoff| backoff.failures) + 1;
+        let delay = self
+            .min_backoff
+            .checked_mul(1 << (failures - 1).min(31))
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff));
+        self.backoff.insert(member.clone(), Backoff { failures, retry_at: now + delay });
+    }
+}
+
+impl Workspace {
+    /// Checks every other member's current announcement against this
+    /// replica's version vector and merges whatever it is missing. Members
+    /// backing off in `schedule` are skipped; the outcome for the rest
+    /// updates it. Doesn't publish.
+    pub async fn anti_entropy_round(&mut self, schedule: &mut AntiEntropy) -> AntiEntropyReport {
+        let mut report = AntiEntropyReport::default();
+        let now = Instant::now();
+        let others: Vec<ReplicaId> = self.members().filter(|id| *id != self.replica().author()).cloned().collect();
+
+        for member in others {
+            if !schedule.is_due(&member, now) {
+                report.skipped.push(member);
+                continue;
+            }
+            match self.merge_member(&member).await {
+                Ok(0) => {
+                    schedule.record_success(&member);
+                    report.in_sync.push(member);
+                }
+                Ok(applied) => {
+                    schedule.record_success(&member);
+                    report.repaired.push((member, applied));
+                }
+                Err(e) => {
+                    schedule.record_failure(&member, now);
+                    report.failed.push((member, e));
+                }
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod anti_entropy_test {
+    use super::*;
+    use crate::test_util::alice;
+
+    #[test]
+    fn test_failures_back_off_exponentially_until_success() {
+        let member = alice();
+        let mut schedule = AntiEntropy::new(Duration::from_secs(10))
+            .with_backoff(Duration::from_secs(5), Duration::from_secs(30));
+        let now = Instant::now();
+        assert!(schedule.is_due(&member, now));
+
+        let mut delays = Vec::new();
+        for _ in 0..5 {
+            schedule.record_failure(&member, now);
+            delays.push(schedule.retry_at(&member).unwrap() - now);
+        }
+        let secs: Vec<u64> = delays.iter().map(Duration::as_secs).collect();
+        assert_eq!(secs, vec![5, 10, 20, 30, 30]);
+        assert!(!schedule.is_due(&member, now + Duration::from_secs(29)));
+        assert!(schedule.is_due(&member, now + Duration::from_secs(30)));
+
+        schedule.record_success(&member);
+        assert!(schedule.is_due(&member, now));
+        assert_eq!(schedule.retry_at(&member), None);
+    }
+}
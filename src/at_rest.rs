@@ -0,0 +1,125 @@
+//! Encryption of what is kept on this machine: the local store's DAG, the
+//! scan index with its filenames, and the block cache with the decrypted
+//! content it holds. Network encryption keeps these from the daemon but not
+//! from someone reading the disk. Files are sealed whole with
+//! XChaCha20-Poly1305 under a key derived from the workspace key with
+//! `WorkspaceKey::at_rest`, or one kept in the OS keychain. A sealed file
+//! starts with `SEALED_MAGIC`; a file without it was written before
+//! encryption was turned on, is read as it is and sealed when next written.
+//! The journal is left as it is: it holds blocks as the daemon gets them,
+//! sealed already under network encryption.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use crate::crypto::WorkspaceKey;
+
+/// Start of a file sealed at rest.
+pub const SEALED_MAGIC: &[u8; 8] = b"crdtseal";
+
+/// `data` to write to a file, sealed under `key` if there is one.
+pub fn seal_file(key: Option<&WorkspaceKey>, data: &[u8]) -> Vec<u8> {
+    match key {
+        Some(key) => [SEALED_MAGIC.as_slice(), &key.seal(data)].concat(),
+        None => data.to_vec(),
+    }
+}
+
+/// The content of a file written by `seal_file`. Fails for a sealed file
+/// if `key` is None or isn't the one it was sealed under.
+pub fn open_file(key: Option<&WorkspaceKey>, bytes: Vec<u8>) -> Result<Vec<u8>> {
+    let Some(sealed) = bytes.strip_prefix(SEALED_MAGIC.as_slice()) else {
+        return Ok(bytes);
+    };
+    match key {
+        Some(key) => key.open(sealed),
+        None => bail!("File is encrypted at rest and no key was given"),
+    }
+}
+
+/// The key stored in the OS keychain under `service` and `account`, if
+/// any: through `security` on macOS and the Secret Service's `secret-tool`
+/// elsewhere on unix.
+pub fn keychain_key(service: &str, account: &str) -> Result<Option<WorkspaceKey>> {
+    let output = if cfg!(target_os = "macos") {
+        Command::new("security").args(["find-generic-password", "-s", service, "-a", account, "-w"]).output()
+    } else if cfg!(unix) {
+        Command::new("secret-tool").args(["lookup", "service", service, "account", account]).output()
+    } else {
+        bail!("No OS keychain is supported on this platform");
+    }
+    .context("Failed to run the OS keychain tool")?;
+    let secret = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() || secret.trim().is_empty() {
+        return Ok(None);
+    }
+    WorkspaceKey::from_str(&secret).map(Some).map_err(|e| anyhow!("Invalid key in OS keychain: {}", e))
+}
+
+/// Stores `key` in the OS keychain under `service` and `account`,
+/// replacing what was there; see `keychain_key`.
+pub fn store_keychain_key(service: &str, account: &str, key: &WorkspaceKey) -> Result<()> {
+    let secret = key.to_string();
+    let status = if cfg!(target_os = "macos") {
+        Command::new("security").args(["add-generic-password", "-U", "-s", service, "-a", account, "-w", &secret]).status()
+    } else if cfg!(unix) {
+        // the secret goes through stdin, out of sight of other processes
+        Command::new("secret-tool")
+            .args(["store", "--label", service, "service", service, "account", account])
+            .stdin(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                child.stdin.take().expect("stdin is piped").write_all(secret.as_bytes())?;
+                child.wait()
+            })
+    } else {
+        bail!("No OS keychain is supported on this platform");
+    }
+    .context("Failed to run the OS keychain tool")?;
+    if !status.success() {
+        bail!("OS keychain refused to store the key for {}", service);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod at_rest_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+    use crate::sync::cache::BlockCache;
+
+    #[test]
+    fn test_files_open_only_with_their_key() {
+        let key = WorkspaceKey::generate().unwrap().at_rest();
+        let sealed = seal_file(Some(&key), b"a.txt");
+        assert!(sealed.starts_with(SEALED_MAGIC));
+        assert!(!sealed.windows(5).any(|window| window == b"a.txt"));
+        assert_eq!(open_file(Some(&key), sealed.clone()).unwrap(), b"a.txt");
+        assert!(open_file(None, sealed.clone()).is_err());
+        assert!(open_file(Some(&WorkspaceKey::generate().unwrap()), sealed).is_err());
+        // written before encryption was turned on
+        assert_eq!(open_file(Some(&key), b"a.txt".to_vec()).unwrap(), b"a.txt");
+    }
+
+    #[test]
+    fn test_cache_seals_blocks_on_disk() {
+        let dir = std::env::temp_dir().join(format!("crdt-at-rest-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let key = WorkspaceKey::generate().unwrap().at_rest();
+        let cid = IpfsCid::compute(RAW_CODE, b"secret content");
+        let cache = BlockCache::open(&dir, 1 << 20).unwrap().with_encryption(key.clone());
+        cache.insert(&cid, b"secret content").unwrap();
+        let on_disk = std::fs::read(dir.join(cid.to_string())).unwrap();
+        assert!(!on_disk.windows(6).any(|window| window == b"secret"));
+
+        let reopened = BlockCache::open(&dir, 1 << 20).unwrap().with_encryption(key);
+        assert_eq!(reopened.get(&cid).unwrap(), b"secret content");
+        // sealed under another key, so dropped
+        let other = BlockCache::open(&dir, 1 << 20).unwrap().with_encryption(WorkspaceKey::generate().unwrap());
+        assert_eq!(other.get(&cid), None);
+        assert!(!dir.join(cid.to_string()).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
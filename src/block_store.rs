@@ -0,0 +1,304 @@
+//! Where blocks are kept, behind one trait: the IPFS daemon over its RPC
+//! API, a directory, or memory. The replica and the scanner fetch and store
+//! blocks through closures; the `_with` methods here pass them a store,
+//! as the `_from` and `_to` methods pass them the daemon, so they run
+//! against any backend, and in tests without a daemon. Stores hold blocks
+//! as they are written, sealed if the workspace has a key. A `BlockCache`
+//! is a store too, and `TieredStore` layers stores on each other.
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::crdt::replica::Replica;
+use crate::kubo_rpc::ipfs::{get_block, has_block, put_block_with_codec, IpfsCid};
+use crate::sync::cache::{fetch_cached, BlockCache};
+use crate::sync::checkout::CheckoutReport;
+use crate::sync::scan::{ScanReport, Scanner};
+
+/// Gets, puts and looks up blocks by CID.
+pub trait BlockStore {
+    /// The block `cid`, checked against it.
+    fn get(&self, cid: &IpfsCid) -> impl Future<Output = Result<Vec<u8>>> + Send;
+    /// Stores `bytes` as the block `cid`.
+    fn put(&self, cid: &IpfsCid, bytes: Bytes) -> impl Future<Output = Result<()>> + Send;
+    fn has(&self, cid: &IpfsCid) -> impl Future<Output = Result<bool>> + Send;
+}
+
+/// The blockstore of the IPFS daemon at a base URL.
+#[derive(Debug, Clone)]
+pub struct KuboStore {
+    base_url: String,
+}
+
+impl KuboStore {
+    pub fn new(base_url: &str) -> Self {
+        KuboStore { base_url: base_url.to_string() }
+    }
+}
+
+impl BlockStore for KuboStore {
+    async fn get(&self, cid: &IpfsCid) -> Result<Vec<u8>> {
+        let bytes = get_block(&self.base_url, cid).await?;
+        if !cid.verify(&bytes) {
+            bail!("Block does not match CID {}", cid);
+        }
+        Ok(bytes)
+    }
+
+    async fn put(&self, cid: &IpfsCid, bytes: Bytes) -> Result<()> {
+        let stored = put_block_with_codec(&self.base_url, bytes, cid.codec_name()).await?;
+        if stored != *cid {
+            bail!("Daemon stored block as {} but expected {}", stored, cid);
+        }
+        Ok(())
+    }
+
+    async fn has(&self, cid: &IpfsCid) -> Result<bool> {
+        has_block(&self.base_url, cid).await
+    }
+}
+
+/// Blocks as files named by their CIDs in a directory.
+#[derive(Debug, Clone)]
+pub struct FsStore {
+    dir: PathBuf,
+}
+
+impl FsStore {
+    /// Opens the store in `dir`, creating the directory if needed.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        Ok(FsStore { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl BlockStore for FsStore {
+    async fn get(&self, cid: &IpfsCid) -> Result<Vec<u8>> {
+        let bytes = tokio::fs::read(self.dir.join(cid.to_string()))
+            .await
+            .with_context(|| format!("Failed to read block {}", cid))?;
+        if !cid.verify(&bytes) {
+            bail!("Block does not match CID {}", cid);
+        }
+        Ok(bytes)
+    }
+
+    async fn put(&self, cid: &IpfsCid, bytes: Bytes) -> Result<()> {
+        if !cid.verify(&bytes) {
+            bail!("Block does not match CID {}", cid);
+        }
+        let path = self.dir.join(cid.to_string());
+        let temp = path.with_extension("tmp");
+        tokio::fs::write(&temp, &bytes).await.with_context(|| format!("Failed to write block {}", cid))?;
+        tokio::fs::rename(&temp, &path).await.with_context(|| format!("Failed to write block {}", cid))
+    }
+
+    async fn has(&self, cid: &IpfsCid) -> Result<bool> {
+        Ok(tokio::fs::try_exists(self.dir.join(cid.to_string())).await?)
+    }
+}
+
+/// Blocks in memory. Clones share them.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    blocks: Arc<Mutex<HashMap<IpfsCid, Bytes>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl BlockStore for MemoryStore {
+    async fn get(&self, cid: &IpfsCid) -> Result<Vec<u8>> {
+        match self.blocks.lock().unwrap().get(cid) {
+            Some(bytes) => Ok(bytes.to_vec()),
+            None => bail!("Block {} is not in the store", cid),
+        }
+    }
+
+    async fn put(&self, cid: &IpfsCid, bytes: Bytes) -> Result<()> {
+        if !cid.verify(&bytes) {
+            bail!("Block does not match CID {}", cid);
+        }
+        self.blocks.lock().unwrap().insert(cid.clone(), bytes);
+        Ok(())
+    }
+
+    async fn has(&self, cid: &IpfsCid) -> Result<bool> {
+        Ok(self.blocks.lock().unwrap().contains_key(cid))
+    }
+}
+
+/// A read-only HTTP gateway, asked for raw blocks as the trustless
+/// gateway spec has it.
+#[derive(Debug, Clone)]
+pub struct GatewayStore {
+    url: String,
+}
+
+impl GatewayStore {
+    /// The gateway at `url`, such as `https://ipfs.io`.
+    pub fn new(url: &str) -> Self {
+        GatewayStore { url: url.trim_end_matches('/').to_string() }
+    }
+}
+
+impl BlockStore for GatewayStore {
+    async fn get(&self, cid: &IpfsCid) -> Result<Vec<u8>> {
+        let response = reqwest::Client::new()
+            .get(format!("{}/ipfs/{}?format=raw", self.url, cid))
+            .header(reqwest::header::ACCEPT, "application/vnd.ipld.raw")
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("Gateway {} didn't serve block {}", self.url, cid))?;
+        let bytes = response.bytes().await?;
+        if !cid.verify(&bytes) {
+            bail!("Block does not match CID {}", cid);
+        }
+        Ok(bytes.to_vec())
+    }
+
+    async fn put(&self, cid: &IpfsCid, _bytes: Bytes) -> Result<()> {
+        bail!("Gateway {} is read-only, can't store block {}", self.url, cid)
+    }
+
+    async fn has(&self, cid: &IpfsCid) -> Result<bool> {
+        let response = reqwest::Client::new()
+            .head(format!("{}/ipfs/{}?format=raw", self.url, cid))
+            .header(reqwest::header::ACCEPT, "application/vnd.ipld.raw")
+            .send()
+            .await?;
+        Ok(response.status().is_success())
+    }
+}
+
+impl BlockStore for BlockCache {
+    async fn get(&self, cid: &IpfsCid) -> Result<Vec<u8>> {
+        match BlockCache::get(self, cid) {
+            Some(bytes) => Ok(bytes),
+            None => bail!("Block {} is not cached", cid),
+        }
+    }
+
+    async fn put(&self, cid: &IpfsCid, bytes: Bytes) -> Result<()> {
+        self.insert(cid, &bytes)
+    }
+
+    async fn has(&self, cid: &IpfsCid) -> Result<bool> {
+        Ok(self.contains(cid))
+    }
+}
+
+impl Replica {
+    /// Merges `head` fetching nodes from `store`.
+    pub async fn pull_with(&mut self, store: &impl BlockStore, head: &IpfsCid) -> Result<usize> {
+        self.merge_head(head, async |cid| store.get(&cid).await).await
+    }
+
+    /// Stores unpublished nodes in `store`.
+    pub async fn push_with(&mut self, store: &impl BlockStore) -> Result<()> {
+        self.push(async |cid, bytes| store.put(&cid, bytes.into()).await).await
+    }
+
+    /// Reads the file content `cid` from `store`, decrypting it if the
+    /// replica has a workspace key.
+    pub async fn content_with(&self, store: &impl BlockStore, cid: &IpfsCid) -> Result<Vec<u8>> {
+        let bytes = store.get(cid).await?;
+        match self.workspace_key() {
+            Some(key) => key.open(&bytes),
+            None => Ok(bytes),
+        }
+    }
+}
+
+impl Scanner {
+    /// `scan` storing chunks in `store`.
+    pub async fn scan_with(&mut self, store: &(impl BlockStore + Sync), replica: &mut Replica) -> Result<ScanReport> {
+        let bandwidth = self.bandwidth.clone();
+        self.scan(replica, async |cid, bytes| {
+            bandwidth.upload(bytes.len()).await;
+            store.put(&cid, bytes).await
+        })
+        .await
+    }
+
+    /// `checkout` fetching content from `store`, unless the block cache
+    /// holds it.
+    pub async fn checkout_with(&mut self, store: &impl BlockStore, replica: &Replica) -> Result<CheckoutReport> {
+        let (bandwidth, cache) = (self.bandwidth.clone(), self.cache.clone());
+        self.checkout(replica, async |cid| {
+            fetch_cached(cache.as_ref(), cid, async |cid| {
+                let data = replica.content_with(store, &cid).await?;
+                bandwidth.download(data.len()).await;
+                Ok(data)
+            })
+            .await
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod block_store_test {
+    use super::*;
+    use crate::crdt::op::Entry;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use crate::test_util::{alice, bob};
+
+    async fn keeps_blocks(store: &impl BlockStore) {
+        let cid = IpfsCid::compute(RAW_CODE, b"block");
+        let other = IpfsCid::compute(RAW_CODE, b"other");
+        assert!(store.put(&cid, Bytes::from_static(b"wrong")).await.is_err());
+        store.put(&cid, Bytes::from_static(b"block")).await.unwrap();
+        assert_eq!(store.get(&cid).await.unwrap(), b"block");
+        assert!(store.has(&cid).await.unwrap());
+        assert!(!store.has(&other).await.unwrap());
+        assert!(store.get(&other).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stores_keep_blocks() {
+        let dir = std::env::temp_dir().join(format!("crdt-block-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        keeps_blocks(&FsStore::open(&dir).unwrap()).await;
+        keeps_blocks(&MemoryStore::new()).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replicas_sync_through_a_store() {
+        let alice = alice();
+        let bob = bob();
+        let store = MemoryStore::new();
+        let content = IpfsCid::compute(RAW_CODE, b"data");
+        store.put(&content, Bytes::from_static(b"data")).await.unwrap();
+
+        let mut a = Replica::new(alice);
+        a.put("a.txt", Entry { content: content.clone(), size: 4, mode: 0o644, mtime: 0, chunks: Vec::new(), symlink: None }).unwrap();
+        a.push_with(&store).await.unwrap();
+        let mut b = Replica::new(bob);
+        b.pull_with(&store, &a.heads()[0]).await.unwrap();
+        assert_eq!(b.state().get("a.txt"), a.state().get("a.txt"));
+        assert_eq!(b.content_with(&store, &content).await.unwrap(), b"data");
+    }
+}
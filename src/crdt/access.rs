@@ -0,0 +1,149 @@
+//! Write permissions: an access-control document in the CRDT saying who may
+//! write what. It is empty, and every admitted writer may write anything,
+//! until someone is listed. From then on unlisted authors are read-only,
+//! and every replica's validator rejects the ops they weren't allowed.
+
+use anyhow::{bail, Result};
+
+use super::history::path_matches;
+use super::identity::ReplicaId;
+use super::op::{Access, OpKind};
+use super::replica::Replica;
+use super::state::State;
+use super::validate::validate_path;
+use super::wire::Node;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+impl Access {
+    /// Whether this allows changing the file at `path`.
+    pub fn allows(&self, path: &str) -> bool {
+        match self {
+            Access::Write => true,
+            Access::WriteUnder { prefixes } => prefixes.iter().any(|prefix| path_matches(prefix, path)),
+            Access::ReadOnly => false,
+        }
+    }
+
+    /// Whether this allows `kind`: workspace settings and the access list
+    /// itself need `Write`.
+    pub fn allows_op(&self, kind: &OpKind) -> bool {
+        let paths = kind.paths();
+        match self {
+            Access::Write => true,
+            _ if paths.is_empty() => false,
+            _ => paths.iter().all(|path| self.allows(path)),
+        }
+    }
+}
+
+impl State {
+    /// What `author` may write: `Write` while nobody is listed, `ReadOnly`
+    /// for unlisted authors after that.
+    pub fn write_access(&self, author: &ReplicaId) -> Access {
+        if !self.has_access_list() {
+            return Access::Write;
+        }
+        self.access(author).cloned().unwrap_or(Access::ReadOnly)
+    }
+}
+
+impl Replica {
+    /// Sets what `member` may write. The first change to an empty list also
+    /// grants this replica `Write`, so it can keep managing the list.
+    pub fn set_access(&mut self, member: ReplicaId, access: Access) -> Result<IpfsCid> {
+        if let Access::WriteUnder { prefixes } = &access {
+            if prefixes.is_empty() {
+                bail!("Access under no prefix; use ReadOnly instead");
+            }
+            for prefix in prefixes {
+                validate_path(prefix, self.limits())?;
+            }
+        }
+        if !self.state().has_access_list() && member != *self.author() {
+            self.commit(OpKind::SetAccess { member: self.author().clone(), access: Access::Write })?;
+        }
+        self.commit(OpKind::SetAccess { member, access })
+    }
+
+    /// Whether this replica may change the file at `path`.
+    pub fn can_write(&self, path: &str) -> bool {
+        self.state().write_access(self.author()).allows(path)
+    }
+
+    /// Rejects a local op the access-control document doesn't allow, which
+    /// every other replica would reject.
+    pub(crate) fn check_access(&self, kind: &OpKind) -> Result<()> {
+        if !self.state().write_access(self.author()).allows_op(kind) {
+            bail!("{} has no write access for this change", self.author());
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `node`'s author was allowed to write it according to the
+/// access-control document in `state`. Snapshots restate the whole
+/// workspace, so they need `Write`.
+pub(crate) fn authorize(state: &State, node: &Node) -> Result<()> {
+    let author = node.author();
+    let access = state.write_access(author);
+    match node {
+        Node::Op(op_node) if !access.allows_op(&op_node.op.kind) => match access {
+            Access::ReadOnly => bail!("{} has read-only access to the workspace", author),
+            _ => bail!("{} may not write {:?}", author, op_node.op.kind.paths()),
+        },
+        Node::Snapshot(_) if access != Access::Write => bail!("{} may not write snapshots", author),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod access_test {
+    use super::*;
+    use crate::crdt::clock::VersionVector;
+    use crate::test_util::{alice, bob, carol, entry};
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+
+    async fn merge(into: &mut Replica, from: &mut Replica) -> Result<usize> {
+        let mut store = HashMap::new();
+        from.push(async |cid, bytes| {
+            store.insert(cid, bytes);
+            Ok(())
+        })
+        .await?;
+        let head = from.heads()[0].clone();
+        into.merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid)))
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_validator_rejects_ops_outside_grant() {
+        let mut a = Replica::new(alice());
+        a.set_access(bob(), Access::WriteUnder { prefixes: vec!["docs".to_string()] }).unwrap();
+        assert_eq!(a.state().write_access(&alice()), Access::Write);
+        assert_eq!(a.state().write_access(&carol()), Access::ReadOnly);
+
+        let mut b = Replica::new(bob());
+        b.apply_delta(&a.delta_since(&VersionVector::new())).unwrap();
+        // checked locally first
+        assert!(b.put("src/main.rs", entry(b"x")).is_err());
+        assert!(b.can_write("docs/a.md") && !b.can_write("src/main.rs"));
+        b.put("docs/a.md", entry(b"a")).unwrap();
+        assert_eq!(merge(&mut a, &mut b).await.unwrap(), 1);
+
+        // a replica that hasn't seen the list is still rejected everywhere
+        let mut c = Replica::new(carol());
+        c.put("docs/b.md", entry(b"b")).unwrap();
+        assert_eq!(merge(&mut a, &mut c).await.unwrap(), 0);
+        let (_, quarantined) = a.quarantined().next().unwrap();
+        assert!(quarantined.reason.contains("read-only"), "{}", quarantined.reason);
+        assert_eq!(a.state().get("docs/b.md"), None);
+
+        // only full writers change the list
+        assert!(b.set_access(bob(), Access::Write).is_err());
+        a.set_access(bob(), Access::Write).unwrap();
+        b.apply_delta(&a.delta_since(b.state().version_vector())).unwrap();
+        b.put("src/main.rs", entry(b"b")).unwrap();
+        assert_eq!(merge(&mut a, &mut b).await.unwrap(), 1);
+    }
+}
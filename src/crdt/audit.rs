@@ -0,0 +1,144 @@
+//! Audit log: a record of every op a replica applies, its own and merged
+//! ones alike, for compliance and monitoring tools outside the workspace.
+//! Recording is off until `with_audit_log`; records queue up in the
+//! replica until drained.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+use super::clock::Hlc;
+use super::identity::ReplicaId;
+use super::op::{Op, OpKind};
+use super::replica::Replica;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// Where an applied op was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    Local,
+    Remote,
+}
+
+/// One applied op, as a line of the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// The op node.
+    pub node: IpfsCid,
+    pub author: ReplicaId,
+    pub seq: u64,
+    pub timestamp: Hlc,
+    pub origin: Origin,
+    /// `put`, `remove`, `patch`, `set_member`, `set_conflict_policy`,
+    /// `set_access`, `set_chunker`, `set_file_policy` or `transaction`.
+    pub kind: String,
+    pub paths: Vec<String>,
+    /// Content the op wrote, in order.
+    pub content: Vec<IpfsCid>,
+}
+
+impl AuditRecord {
+    pub fn new(node: IpfsCid, op: &Op, origin: Origin) -> Self {
+        let content = op
+            .kind
+            .parts()
+            .iter()
+            .filter_map(|part| match part {
+                OpKind::Put { entry, .. } => Some(entry.content.clone()),
+                OpKind::Patch { patch, .. } => patch.content.as_ref().map(|content| content.content.clone()),
+                _ => None,
+            })
+            .collect();
+        AuditRecord {
+            node,
+            author: op.author.clone(),
+            seq: op.seq,
+            timestamp: op.timestamp,
+            origin,
+            kind: kind_name(&op.kind).to_string(),
+            paths: op.kind.paths().into_iter().map(str::to_string).collect(),
+            content,
+        }
+    }
+}
+
+fn kind_name(kind: &OpKind) -> &'static str {
+    match kind {
+        OpKind::Put { .. } => "put",
+        OpKind::Remove { .. } => "remove",
+        OpKind::Patch { .. } => "patch",
+        OpKind::SetMember { .. } => "set_member",
+        OpKind::SetConflictPolicy { .. } => "set_conflict_policy",
+        OpKind::SetAccess { .. } => "set_access",
+        OpKind::SetChunker { .. } => "set_chunker",
+        OpKind::SetFilePolicy { .. } => "set_file_policy",
+        OpKind::Transaction { .. } => "transaction",
+    }
+}
+
+impl Replica {
+    /// Records every op applied from now on, see `drain_audit`. Ops a
+    /// snapshot covers are merged as a whole and not recorded one by one.
+    pub fn with_audit_log(mut self) -> Self {
+        self.audit = Some(Vec::new());
+        self
+    }
+
+    /// Takes the records queued since the last drain, in the order the ops
+    /// were applied.
+    pub fn drain_audit(&mut self) -> Vec<AuditRecord> {
+        self.audit.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Drains the queued records into `out` as JSON lines and returns how
+    /// many were written.
+    pub fn write_audit_log(&mut self, out: &mut impl Write) -> Result<usize> {
+        let records = self.drain_audit();
+        for record in &records {
+            serde_json::to_writer(&mut *out, record).context("Failed to encode audit record")?;
+            out.write_all(b"\n").context("Failed to write audit log")?;
+        }
+        Ok(records.len())
+    }
+
+    pub(crate) fn record_applied(&mut self, node: &IpfsCid, op: &Op, origin: Origin) {
+        if let Some(audit) = &mut self.audit {
+            audit.push(AuditRecord::new(node.clone(), op, origin));
+        }
+    }
+}
+
+#[cfg(test)]
+mod audit_test {
+    use super::*;
+    use crate::crdt::clock::VersionVector;
+    use crate::test_util::{alice, bob, entry};
+
+    #[test]
+    fn test_audit_log_records_local_and_remote_ops() {
+        let alice = alice();
+        let bob = bob();
+        let mut a = Replica::new(alice.clone()).with_audit_log();
+        let mut b = Replica::new(bob.clone());
+        let put = a.put("a.txt", entry(b"a")).unwrap();
+        b.put("b.txt", entry(b"b")).unwrap();
+        b.remove("b.txt").unwrap();
+        a.apply_delta(&b.delta_since(&VersionVector::new())).unwrap();
+        assert!(b.drain_audit().is_empty());
+
+        let mut out = Vec::new();
+        assert_eq!(a.write_audit_log(&mut out).unwrap(), 3);
+        assert_eq!(a.write_audit_log(&mut out).unwrap(), 0);
+        let lines: Vec<AuditRecord> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0].node, put);
+        assert_eq!((lines[0].origin, lines[0].kind.as_str()), (Origin::Local, "put"));
+        assert_eq!(lines[0].content, vec![entry(b"a").content]);
+        assert_eq!((&lines[2].author, lines[2].seq, lines[2].origin), (&bob, 2, Origin::Remote));
+        assert_eq!(lines[2].paths, vec!["b.txt"]);
+    }
+}
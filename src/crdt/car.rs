@@ -0,0 +1,231 @@
+//! CARv1 archives: a DAG-CBOR header naming the roots, then every block as
+//! a varint length, its CID and its bytes. `ipfs dag import` loads them
+//! into any IPFS node.
+
+use anyhow::{anyhow, bail, Context, Result};
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::Path;
+
+use super::replica::Replica;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+#[derive(Serialize, Deserialize)]
+struct CarHeader {
+    roots: Vec<IpfsCid>,
+    version: u64,
+}
+
+/// The contents of a CAR file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Car {
+    pub roots: Vec<IpfsCid>,
+    pub blocks: Vec<(IpfsCid, Vec<u8>)>,
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*pos).ok_or_else(|| anyhow!("CAR ends inside a varint"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("CAR varint longer than 64 bits")
+}
+
+/// Reads the length-prefixed section at `pos`.
+fn read_section<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a [u8]> {
+    let len = read_varint(bytes, pos)? as usize;
+    let section = bytes
+        .get(*pos..pos.saturating_add(len))
+        .ok_or_else(|| anyhow!("CAR section of {} bytes runs past the end", len))?;
+    *pos += len;
+    Ok(section)
+}
+
+impl Car {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let header = CarHeader { roots: self.roots.clone(), version: 1 };
+        let header = serde_ipld_dagcbor::to_vec(&header).map_err(|e| anyhow!("Failed to encode CAR header: {}", e))?;
+        let mut out = Vec::new();
+        write_varint(&mut out, header.len() as u64);
+        out.extend_from_slice(&header);
+        for (cid, bytes) in &self.blocks {
+            let cid = cid.0.to_bytes();
+            write_varint(&mut out, (cid.len() + bytes.len()) as u64);
+            out.extend_from_slice(&cid);
+            out.extend_from_slice(bytes);
+        }
+        Ok(out)
+    }
+
+    pub async fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        tokio::fs::write(path.as_ref(), self.encode()?)
+            .await
+            .with_context(|| format!("Failed to write {}", path.as_ref().display()))
+    }
+
+    pub async fn read(path: impl AsRef<Path>) -> Result<Car> {
+        let bytes = tokio::fs::read(path.as_ref())
+            .await
+            .with_context(|| format!("Failed to read {}", path.as_ref().display()))?;
+        Car::decode(&bytes)
+    }
+
+    /// Parses a CARv1 file, checking every block against its CID.
+    pub fn decode(bytes: &[u8]) -> Result<Car> {
+        let mut pos = 0;
+        let header: CarHeader = serde_ipld_dagcbor::from_slice(read_section(bytes, &mut pos)?)
+            .map_err(|e| anyhow!("Malformed CAR header: {}", e))?;
+        if header.version != 1 {
+            bail!("Unsupported CAR version {}", header.version);
+        }
+        let mut blocks = Vec::new();
+        while pos < bytes.len() {
+            let section = read_section(bytes, &mut pos)?;
+            let mut reader = Cursor::new(section);
+            let cid = Cid::read_bytes(&mut reader).map_err(|e| anyhow!("Malformed CID in CAR: {}", e))?;
+            let cid = IpfsCid::try_from(cid).map_err(|e| anyhow!(e))?;
+            let data = &section[reader.position() as usize..];
+            if !cid.verify(data) {
+                bail!("CAR block doesn't match its CID {}", cid);
+            }
+            blocks.push((cid, data.to_vec()));
+        }
+        Ok(Car { roots: header.roots, blocks })
+    }
+}
+
+impl Replica {
+    /// A CAR with a fresh head announcement as its only root, followed by
+    /// every held block the heads reach: all history back to the first
+    /// node held, snapshots' state blocks and shards. File contents live
+    /// on the daemon and aren't included.
+    pub fn to_car(&self) -> Result<Car> {
+        let (root, announcement) = self.encode_block(&self.announcement())?;
+        let mut cids: Vec<IpfsCid> = self.history_blocks().into_iter().collect();
+        cids.sort();
+        let mut blocks = vec![(root.clone(), announcement)];
+        for cid in cids {
+            let bytes = self.block_bytes(&cid)?.expect("reachable blocks are held");
+            blocks.push((cid, bytes));
+        }
+        Ok(Car { roots: vec![root], blocks })
+    }
+
+    /// Writes `to_car` to `path` as an offline backup and returns the root
+    /// announcement. Another replica restores it with `merge_announcement`
+    /// over the file's blocks, or after `ipfs dag import`.
+    pub async fn export_car(&self, path: impl AsRef<Path>) -> Result<IpfsCid> {
+        let car = self.to_car()?;
+        car.write(path).await?;
+        Ok(car.roots[0].clone())
+    }
+
+    /// Merges the head announcement `car` is rooted at, as written by
+    /// `export_car`, reading every block from the archive. A new replica
+    /// starts from the newest snapshot in it, so a collaborator can be
+    /// onboarded without network access. Returns the number of operations
+    /// applied.
+    pub async fn merge_car(&mut self, car: &Car) -> Result<usize> {
+        let [root] = car.roots.as_slice() else {
+            bail!("CAR has {} roots, expected a single head announcement", car.roots.len());
+        };
+        let blocks: HashMap<&IpfsCid, &Vec<u8>> = car.blocks.iter().map(|(cid, bytes)| (cid, bytes)).collect();
+        if !blocks.contains_key(root) {
+            bail!("CAR root {} is not among its blocks", root);
+        }
+        self.merge_announcement(root, async |cid| {
+            blocks.get(&cid).map(|bytes| bytes.to_vec()).ok_or_else(|| anyhow!("Block {} is missing from the CAR", cid))
+        })
+        .await
+    }
+
+    /// `merge_car` with the CAR file at `path`.
+    pub async fn import_car(&mut self, path: impl AsRef<Path>) -> Result<usize> {
+        let car = Car::read(path).await?;
+        self.merge_car(&car).await
+    }
+}
+
+#[cfg(test)]
+mod car_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use crate::test_util::{alice, bob, entry};
+
+    #[tokio::test]
+    async fn test_exported_car_restores_replica() {
+        let alice = alice();
+        let bob = bob();
+        let mut a = Replica::new(alice).with_snapshot_interval(2);
+        for i in 0..5 {
+            a.put(&format!("file{}", i), entry(&[i])).unwrap();
+        }
+        a.remove("file0").unwrap();
+
+        let path = std::env::temp_dir().join(format!("crdt-dir-ipfs-{}.car", std::process::id()));
+        let root = a.export_car(&path).await.unwrap();
+        let car = Car::decode(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(car.roots, vec![root.clone()]);
+        // every node back to the first op, behind the snapshots too
+        assert_eq!(car.blocks.len(), 1 + a.history_blocks().len());
+        assert!(a.history_blocks().len() > a.reachable_blocks().len());
+
+        let store: HashMap<IpfsCid, Vec<u8>> = car.blocks.into_iter().collect();
+        let mut b = Replica::new(bob);
+        b.merge_announcement(&root, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(b.state(), a.state());
+        assert_eq!(b.heads(), a.heads());
+    }
+
+    #[tokio::test]
+    async fn test_import_car_bootstraps_new_replica() {
+        let alice = alice();
+        let bob = bob();
+        let mut a = Replica::new(alice).with_snapshot_interval(3);
+        for i in 0..4 {
+            a.put(&format!("file{}", i), entry(&[i])).unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("crdt-dir-ipfs-import-{}.car", std::process::id()));
+        a.export_car(&path).await.unwrap();
+
+        let mut b = Replica::new(bob.clone());
+        let result = b.import_car(&path).await;
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        assert_eq!(b.state(), a.state());
+        b.put("file9", entry(b"9")).unwrap();
+
+        // the root must be present
+        let mut car = a.to_car().unwrap();
+        car.blocks.remove(0);
+        assert!(Replica::new(bob).merge_car(&car).await.is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_tampered_block() {
+        let block = b"hello".to_vec();
+        let car = Car { roots: Vec::new(), blocks: vec![(IpfsCid::compute(RAW_CODE, &block), block)] };
+        let mut bytes = car.encode().unwrap();
+        assert_eq!(Car::decode(&bytes).unwrap(), car);
+        *bytes.last_mut().unwrap() = b'!';
+        assert!(Car::decode(&bytes).is_err());
+    }
+}
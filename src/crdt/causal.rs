@@ -0,0 +1,245 @@
+//! Causal delivery: nodes and delta bundles that arrive before what they
+//! depend on (from pubsub, or a partial DAG walk) wait in a bounded buffer
+//! until their dependencies are applied, instead of being rejected.
+
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+
+use super::replica::Replica;
+use super::validate::validate_parents;
+use super::wire::{DeltaBundle, Node};
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// Items held by default before the oldest is evicted.
+pub const DEFAULT_MAX_PENDING: usize = 1024;
+
+/// Counters describing what the pending buffer did so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeliveryMetrics {
+    /// Nodes and bundles held back because a dependency was missing.
+    pub buffered: u64,
+    /// Held items applied once their dependencies arrived.
+    pub released: u64,
+    /// Held items dropped, oldest first, to stay within the limit. A later
+    /// `merge_head` fetches what they carried.
+    pub evicted: u64,
+    /// Held items that failed to apply once released.
+    pub rejected: u64,
+    /// Nodes received again while already applied or held.
+    pub duplicates: u64,
+}
+
+#[derive(Debug, Clone)]
+enum Pending {
+    Node(IpfsCid, Box<Node>),
+    Delta(DeltaBundle),
+}
+
+/// Items waiting for their dependencies, oldest first.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingBuffer {
+    items: VecDeque<Pending>,
+    max: usize,
+    metrics: DeliveryMetrics,
+}
+
+impl PendingBuffer {
+    pub(crate) fn new(max: usize) -> Self {
+        PendingBuffer { items: VecDeque::new(), max, metrics: DeliveryMetrics::default() }
+    }
+
+    pub(crate) fn hold_delta(&mut self, bundle: DeltaBundle) {
+        self.hold(Pending::Delta(bundle));
+    }
+
+    fn hold(&mut self, item: Pending) {
+        self.items.push_back(item);
+        self.metrics.buffered += 1;
+        while self.items.len() > self.max {
+            self.items.pop_front();
+            self.metrics.evicted += 1;
+        }
+    }
+
+    fn holds(&self, cid: &IpfsCid) -> bool {
+        self.items.iter().any(|item| matches!(item, Pending::Node(held, _) if held == cid))
+    }
+}
+
+impl Replica {
+    /// Applies a single node block `cid`, e.g. one announced over pubsub,
+    /// as its author encoded it. A node whose
+    /// parents aren't all held yet waits in the pending buffer and is
+    /// applied once they are. Snapshot nodes must link a state this replica
+    /// already holds. Returns the number of operations applied, counting
+    /// any held items this released.
+    pub fn receive_node(&mut self, cid: &IpfsCid, bytes: &[u8]) -> Result<usize> {
+        let node: Node = self.decode_block(cid, bytes)?;
+        let cid = cid.clone();
+        if self.node(&cid).is_some() || self.is_quarantined(&cid) || self.pending.holds(&cid) {
+            self.pending.metrics.duplicates += 1;
+            return Ok(0);
+        }
+        validate_parents(node.parents(), self.limits()).map_err(|e| anyhow!("Rejecting node {}: {}", cid, e))?;
+        if !self.has_parents(&node) {
+            self.pending.hold(Pending::Node(cid, Box::new(node)));
+            return Ok(0);
+        }
+        let applied = self.apply_nodes(vec![(cid.clone(), node)], &[cid])?;
+        Ok(applied + self.release_pending())
+    }
+
+    /// Number of nodes and bundles waiting for their dependencies.
+    pub fn pending_len(&self) -> usize {
+        self.pending.items.len()
+    }
+
+    pub fn delivery_metrics(&self) -> DeliveryMetrics {
+        self.pending.metrics
+    }
+
+    /// Applies every held item whose dependencies are now satisfied, until
+    /// none is left ready. Items that fail to apply are dropped.
+    pub(crate) fn release_pending(&mut self) -> usize {
+        let mut applied = 0;
+        while let Some(index) = self.pending.items.iter().position(|item| self.is_ready(item)) {
+            let item = self.pending.items.remove(index).expect("index is in bounds");
+            let result = match item {
+                Pending::Node(cid, _) if self.node(&cid).is_some() => {
+                    self.pending.metrics.duplicates += 1;
+                    continue;
+                }
+                Pending::Node(cid, node) => self.apply_nodes(vec![(cid.clone(), *node)], &[cid]),
+                Pending::Delta(bundle) => self.apply_delta(&bundle),
+            };
+            match result {
+                Ok(n) => {
+                    self.pending.metrics.released += 1;
+                    applied += n;
+                }
+                Err(_) => self.pending.metrics.rejected += 1,
+            }
+        }
+        applied
+    }
+
+    fn is_ready(&self, item: &Pending) -> bool {
+        match item {
+            Pending::Node(cid, node) => self.node(cid).is_some() || self.has_parents(node),
+            Pending::Delta(bundle) => self.state().version_vector().dominates(&bundle.since),
+        }
+    }
+
+    /// Quarantined parents count, so the node gets quarantined too. So do
+    /// parents no longer held, pruned by GC or merged as part of a
+    /// snapshot, once the version vector covers all the node builds on.
+    fn has_parents(&self, node: &Node) -> bool {
+        let seen = self.state().version_vector();
+        let covered = match node {
+            Node::Op(op_node) => seen.dominates(&op_node.op.context),
+            Node::Snapshot(snapshot) => seen.dominates(snapshot.version_vector()),
+        };
+        covered || node.parents().iter().all(|parent| self.node(parent).is_some() || self.is_quarantined(parent))
+    }
+}
+
+#[cfg(test)]
+mod causal_test {
+    use super::*;
+    use crate::crdt::clock::VersionVector;
+    use crate::test_util::{alice, bob, entry};
+
+    /// `a`'s op node blocks, oldest first.
+    fn written(a: &mut Replica, paths: &[&str]) -> Vec<(IpfsCid, Vec<u8>)> {
+        paths
+            .iter()
+            .map(|path| {
+                let cid = a.put(path, entry(path.as_bytes())).unwrap();
+                let (encoded, bytes) = a.encode_block(a.node(&cid).unwrap()).unwrap();
+                assert_eq!(encoded, cid);
+                (cid, bytes)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_nodes_wait_for_their_parents() {
+        let mut a = Replica::new(alice()).with_snapshot_interval(0);
+        let mut b = Replica::new(bob());
+        let nodes = written(&mut a, &["one", "two", "three"]);
+
+        assert_eq!(b.receive_node(&nodes[2].0, &nodes[2].1).unwrap(), 0);
+        assert_eq!(b.receive_node(&nodes[1].0, &nodes[1].1).unwrap(), 0);
+        assert_eq!(b.receive_node(&nodes[1].0, &nodes[1].1).unwrap(), 0);
+        assert_eq!(b.pending_len(), 2);
+        assert!(b.state().get("three").is_none());
+
+        assert_eq!(b.receive_node(&nodes[0].0, &nodes[0].1).unwrap(), 3);
+        assert_eq!(b.pending_len(), 0);
+        assert_eq!(b.state(), a.state());
+        assert_eq!(b.heads(), a.heads());
+        let metrics = b.delivery_metrics();
+        assert_eq!((metrics.buffered, metrics.released, metrics.duplicates), (2, 2, 1));
+    }
+
+    #[test]
+    fn test_early_delta_waits_and_buffer_evicts_oldest() {
+        let mut a = Replica::new(alice()).with_snapshot_interval(0);
+        let mut b = Replica::new(bob()).with_max_pending(2);
+        a.put("one", entry(b"1")).unwrap();
+        let first = a.delta_since(&VersionVector::new());
+        let after_first = a.state().version_vector().clone();
+        a.put("two", entry(b"2")).unwrap();
+        let second = a.delta_since(&after_first);
+
+        assert_eq!(b.apply_delta(&second).unwrap(), 0);
+        assert_eq!(b.apply_delta(&first).unwrap(), 2);
+        assert_eq!(b.state(), a.state());
+
+        let nodes = written(&mut a, &["x", "y", "z", "w"]);
+        for (cid, bytes) in &nodes[1..] {
+            b.receive_node(cid, bytes).unwrap();
+        }
+        assert_eq!(b.pending_len(), 2);
+        assert_eq!(b.delivery_metrics().evicted, 1);
+        // "y" was evicted, so "z" and "w" stay stuck until a DAG walk
+        assert_eq!(b.receive_node(&nodes[0].0, &nodes[0].1).unwrap(), 1);
+        assert_eq!(b.pending_len(), 2);
+    }
+
+    #[test]
+    fn test_node_on_pruned_parent_is_released() {
+        let mut a = Replica::new(alice()).with_snapshot_interval(0);
+        let mut b = Replica::new(bob()).with_snapshot_interval(1);
+        let first = written(&mut a, &["one"]);
+        b.receive_node(&first[0].0, &first[0].1).unwrap();
+        b.put("two", entry(b"2")).unwrap();
+        b.prune_unreachable();
+        assert!(b.node(&first[0].0).is_none());
+
+        // built on "one", which b only has through its snapshot now
+        let next = written(&mut a, &["three"]);
+        assert_eq!(b.receive_node(&next[0].0, &next[0].1).unwrap(), 1);
+        assert_eq!(b.pending_len(), 0);
+        assert!(b.state().get("three").is_some());
+    }
+
+    #[test]
+    fn test_compressed_and_plain_replicas_exchange_deltas() {
+        let mut a = Replica::new(alice()).with_compression(0).with_snapshot_interval(2);
+        let mut b = Replica::new(bob());
+        for path in ["one", "two", "three"] {
+            a.put(path, entry(path.as_bytes())).unwrap();
+        }
+        b.apply_delta(&a.delta_since(&VersionVector::new())).unwrap();
+        assert_eq!((b.state(), b.heads(), b.pending_len()), (a.state(), a.heads(), 0));
+
+        b.put("four", entry(b"4")).unwrap();
+        a.apply_delta(&b.delta_since(a.state().version_vector())).unwrap();
+        assert_eq!((a.state(), a.heads()), (b.state(), b.heads()));
+
+        let nodes = written(&mut a, &["five"]);
+        assert_eq!(b.receive_node(&nodes[0].0, &nodes[0].1).unwrap(), 1);
+        assert_eq!(b.state(), a.state());
+    }
+}
@@ -0,0 +1,518 @@
+//! Chunked file content: a file is split where a rolling hash of its bytes
+//! hits a boundary pattern (FastCDC), so an edit only moves the boundaries
+//! around it. Versions of a file then share every chunk outside the edited
+//! region, and only the changed ones have to be stored.
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use globset::{GlobBuilder, GlobMatcher};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use super::op::{Chunk, Content, Entry, OpKind};
+use super::replica::Replica;
+use super::wire::Node;
+use crate::kubo_rpc::ipfs::{put_block, IpfsCid, DAG_CBOR_CODE, RAW_CODE};
+
+/// Where a `Chunker` may cut a file. A workspace can record one for every
+/// replica to use, see `Replica::set_chunker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunker {
+    pub min_size: usize,
+    /// Chunks cluster around this size, rounded down to a power of two.
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for Chunker {
+    /// 256 KiB to 4 MiB, about 1 MiB on average: a 2 GB file takes some
+    /// 2000 chunks, whose list still fits in an op node.
+    fn default() -> Self {
+        Chunker { min_size: 256 * 1024, avg_size: 1024 * 1024, max_size: 4 * 1024 * 1024 }
+    }
+}
+
+/// Random per-byte values for the gear hash, fixed so every replica cuts
+/// the same bytes the same way.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// A mask of the `bits` high bits: those of the gear hash depend on the
+/// last 64 bytes.
+fn high_bits(bits: u32) -> u64 {
+    (u64::MAX >> (64 - bits)) << (64 - bits)
+}
+
+impl Chunker {
+    /// Splits `data` into content-defined chunks. Empty data has none.
+    /// Normalized chunking: a cut is harder to find before the average size
+    /// and easier after it, so sizes cluster around it.
+    pub fn split<'a>(&self, data: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < data.len() {
+            let rest = &data[start..];
+            let cut = self.find_cut(rest).unwrap_or(rest.len().min(self.max_size));
+            chunks.push(&rest[..cut]);
+            start += cut;
+        }
+        chunks
+    }
+
+    /// Where the first chunk of `data` ends, if the boundary is among its
+    /// first `max_size` bytes. More data wouldn't move it.
+    pub fn find_cut(&self, data: &[u8]) -> Option<usize> {
+        let bits = self.avg_size.max(2).ilog2();
+        let (strict, loose) = (high_bits((bits + 2).min(63)), high_bits(bits.saturating_sub(2).max(1)));
+        let mut hash = 0u64;
+        for (i, byte) in data.iter().enumerate().take(self.max_size).skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            let mask = if i < self.avg_size { strict } else { loose };
+            if hash & mask == 0 {
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+}
+
+/// How a file is cut into chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChunkProfile {
+    ContentDefined(Chunker),
+    /// Every `size` bytes. Cheaper, and as good for append-only files,
+    /// whose old chunks never move, and compressed media, which hardly
+    /// dedups anyway.
+    Fixed { size: usize },
+}
+
+impl ChunkProfile {
+    /// Fails on a profile whose chunks could be empty: cutting with it
+    /// would never end.
+    pub fn split<'a>(&self, data: &'a [u8]) -> Result<Vec<&'a [u8]>> {
+        self.check()?;
+        Ok(match self {
+            ChunkProfile::ContentDefined(chunker) => chunker.split(data),
+            ChunkProfile::Fixed { size } => data.chunks(*size).collect(),
+        })
+    }
+
+    /// Where the first chunk of `data` ends, if `data` is long enough to
+    /// tell without reading on.
+    pub fn find_cut(&self, data: &[u8]) -> Result<Option<usize>> {
+        self.check()?;
+        Ok(match self {
+            ChunkProfile::ContentDefined(chunker) => chunker.find_cut(data),
+            ChunkProfile::Fixed { size } => (data.len() >= *size).then_some(*size),
+        })
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.max_chunk_size() == 0 {
+            bail!("Chunk size must be positive");
+        }
+        Ok(())
+    }
+
+    pub fn max_chunk_size(&self) -> usize {
+        match self {
+            ChunkProfile::ContentDefined(chunker) => chunker.max_size,
+            ChunkProfile::Fixed { size } => *size,
+        }
+    }
+}
+
+/// Cuts the files whose path matches `glob` with `profile`. In the glob
+/// `*` stops at `/` and `**` doesn't.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRule {
+    pub glob: String,
+    pub profile: ChunkProfile,
+    /// `glob` compiled on first use, `None` if it's invalid.
+    #[serde(skip)]
+    matcher: OnceLock<Option<GlobMatcher>>,
+}
+
+impl ChunkRule {
+    pub fn new(glob: impl Into<String>, profile: ChunkProfile) -> Self {
+        ChunkRule { glob: glob.into(), profile, matcher: OnceLock::new() }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        self.matcher
+            .get_or_init(|| compile_glob(&self.glob).ok())
+            .as_ref()
+            .is_some_and(|glob| glob.is_match(path))
+    }
+}
+
+impl PartialEq for ChunkRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.glob == other.glob && self.profile == other.profile
+    }
+}
+
+impl Eq for ChunkRule {}
+
+pub(crate) fn compile_glob(glob: &str) -> Result<GlobMatcher> {
+    let glob = GlobBuilder::new(glob)
+        .literal_separator(true)
+        .build()
+        .with_context(|| format!("Invalid glob {:?}", glob))?;
+    Ok(glob.compile_matcher())
+}
+
+/// The chunking a workspace asks every replica to use: `rules`, the first
+/// matching one deciding, and `chunker` for the other files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSettings {
+    pub chunker: Chunker,
+    /// Left out of the encoding when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<ChunkRule>,
+}
+
+/// The `content` of a chunked entry: the CID its chunk list has as a
+/// DAG-CBOR block. Equal chunk lists get equal ids, so lineage and merge
+/// bases work as for single blobs.
+pub fn chunk_list_id(chunks: &[Chunk]) -> IpfsCid {
+    let bytes = serde_ipld_dagcbor::to_vec(&chunks).expect("chunk lists always encode");
+    IpfsCid::compute(DAG_CBOR_CODE, &bytes)
+}
+
+/// The blocks a version of a file is stored as: its chunks, or the blob
+/// itself for one stored whole. None for a symlink.
+pub(crate) fn blocks_of(content: &Content) -> Vec<IpfsCid> {
+    match content.chunks.as_slice() {
+        [] if content.symlink.is_none() => vec![content.content.clone()],
+        chunks => chunks.iter().map(|chunk| chunk.content.clone()).collect(),
+    }
+}
+
+/// Reads a file stored as `content` or, if `chunks` is set, as those
+/// chunks in order, fetching each with `fetch`.
+pub async fn read_file<F>(content: &IpfsCid, chunks: &[Chunk], fetch: &mut F) -> Result<Vec<u8>>
+where
+    F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+{
+    if chunks.is_empty() {
+        return fetch(content.clone()).await;
+    }
+    let mut data = Vec::new();
+    for chunk in chunks {
+        let bytes = fetch(chunk.content.clone()).await?;
+        if bytes.len() as u64 != chunk.size {
+            bail!("Chunk {} is {} bytes, expected {}", chunk.content, bytes.len(), chunk.size);
+        }
+        data.extend(bytes);
+    }
+    Ok(data)
+}
+
+pub async fn read_entry<F>(entry: &Entry, fetch: &mut F) -> Result<Vec<u8>>
+where
+    F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+{
+    read_file(&entry.content, &entry.chunks, fetch).await
+}
+
+/// Chunks stored and skipped by `put_chunked`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkedPut {
+    /// The op node written.
+    pub node: IpfsCid,
+    pub stored: usize,
+    /// Chunks the previous versions of the file already had.
+    pub reused: usize,
+}
+
+/// Stores `bytes` as a raw block on the IPFS daemon at `base_url`,
+/// checking it gets the CID `cid`.
+pub(crate) async fn put_raw_block(base_url: &str, cid: &IpfsCid, bytes: Bytes) -> Result<()> {
+    let stored = put_block(base_url, bytes).await?;
+    if stored != *cid {
+        bail!("Daemon stored chunk as {} but expected {}", stored, cid);
+    }
+    Ok(())
+}
+
+impl Replica {
+    /// Creates or overwrites the file at `path` with `data`, split by the
+    /// replica's `Chunker`. Chunks the current versions of the file share
+    /// aren't stored again; every other chunk is passed to `store` with its
+    /// CID, sealed if the replica has a workspace key. A file over the
+    /// replica's quota is rejected before anything is stored.
+    pub async fn put_chunked<F>(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        mode: u32,
+        mtime: i64,
+        mut store: F,
+    ) -> Result<ChunkedPut>
+    where
+        F: AsyncFnMut(IpfsCid, Bytes) -> Result<()>,
+    {
+        // before storing any chunk
+        self.check_file_size(path, data.len() as u64)?;
+        self.check_total([(path, data.len() as u64)])?;
+        let (entry, stored, reused) = self.store_chunked(path, data, mode, mtime, &mut store).await?;
+        let node = self.put(path, entry)?;
+        Ok(ChunkedPut { node, stored, reused })
+    }
+
+    /// `put_chunked` storing chunks on the IPFS daemon at `base_url`.
+    pub async fn put_chunked_to(
+        &mut self,
+        base_url: &str,
+        path: &str,
+        data: &[u8],
+        mode: u32,
+        mtime: i64,
+    ) -> Result<ChunkedPut> {
+        self.put_chunked(path, data, mode, mtime, async |cid, bytes| put_raw_block(base_url, &cid, bytes).await)
+            .await
+    }
+
+    /// `data` split as the file at `path` is, each chunk with its block:
+    /// sealed if the replica has a workspace key.
+    pub(crate) fn chunk_blocks(&self, path: &str, data: &[u8]) -> Result<Vec<(Chunk, Bytes)>> {
+        Ok(self
+            .chunk_profile(path)
+            .split(data)?
+            .into_iter()
+            .map(|piece| {
+                let bytes = match self.workspace_key() {
+                    Some(key) => Bytes::from(key.seal(piece)),
+                    None => Bytes::copy_from_slice(piece),
+                };
+                let chunk = Chunk { content: IpfsCid::compute(RAW_CODE, &bytes), size: piece.len() as u64 };
+                (chunk, bytes)
+            })
+            .collect())
+    }
+
+    /// Stores the chunks of `data` the current versions at `path` don't
+    /// share and returns the entry to put, with how many chunks were
+    /// stored and reused. Empty data is a single empty blob.
+    pub(crate) async fn store_chunked<F>(
+        &self,
+        path: &str,
+        data: &[u8],
+        mode: u32,
+        mtime: i64,
+        store: &mut F,
+    ) -> Result<(Entry, usize, usize)>
+    where
+        F: AsyncFnMut(IpfsCid, Bytes) -> Result<()>,
+    {
+        let mut known = self.known_chunks(path);
+        if data.is_empty() {
+            let (content, bytes) = self.empty_blob();
+            store(content.clone(), bytes).await?;
+            let entry = Entry { content, size: 0, mode, mtime, chunks: Vec::new(), symlink: None };
+            return Ok((entry, 1, 0));
+        }
+        let (mut stored, mut reused) = (0, 0);
+        let mut chunks = Vec::new();
+        for (chunk, bytes) in self.chunk_blocks(path, data)? {
+            if known.insert(chunk.content.clone()) {
+                store(chunk.content.clone(), bytes).await?;
+                stored += 1;
+            } else {
+                reused += 1;
+            }
+            chunks.push(chunk);
+        }
+        let entry = Entry { content: chunk_list_id(&chunks), size: data.len() as u64, mode, mtime, chunks, symlink: None };
+        Ok((entry, stored, reused))
+    }
+
+    /// The blocks the current versions at `path` are made of: their chunks,
+    /// or the blob itself for one stored whole.
+    pub(crate) fn known_chunks(&self, path: &str) -> HashSet<IpfsCid> {
+        self.state()
+            .register(path)
+            .into_iter()
+            .flat_map(|register| register.content.versions())
+            .flat_map(|version| blocks_of(&version.value))
+            .collect()
+    }
+
+    /// The block an empty file is stored as, with its CID.
+    pub(crate) fn empty_blob(&self) -> (IpfsCid, Bytes) {
+        let bytes = match self.workspace_key() {
+            Some(key) => Bytes::from(key.seal(&[])),
+            None => Bytes::new(),
+        };
+        (IpfsCid::compute(RAW_CODE, &bytes), bytes)
+    }
+
+    /// The chunks of the chunked content `content`, looked up among the
+    /// current versions and the writes in held history. `None` for a single
+    /// blob or one no longer known.
+    pub fn chunks_of(&self, content: &IpfsCid) -> Option<Vec<Chunk>> {
+        if content.0.codec() != DAG_CBOR_CODE {
+            return None;
+        }
+        let found = |value: &Content| (value.content == *content).then(|| value.chunks.clone());
+        let current = self
+            .state()
+            .registers()
+            .flat_map(|(_, register)| register.content.versions())
+            .find_map(|version| found(&version.value));
+        current.or_else(|| {
+            self.nodes().find_map(|(_, node)| match node {
+                Node::Op(op_node) => op_node.op.kind.parts().iter().find_map(|kind| match kind {
+                    OpKind::Put { entry, .. } if entry.content == *content => Some(entry.chunks.clone()),
+                    OpKind::Patch { patch, .. } => patch.content.as_ref().and_then(found),
+                    _ => None,
+                }),
+                Node::Snapshot(_) => None,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod chunk_test {
+    use super::*;
+    use crate::test_util::{alice, bob};
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+
+    fn small() -> Chunker {
+        Chunker { min_size: 64, avg_size: 256, max_size: 1024 }
+    }
+
+    /// Deterministic bytes that don't repeat.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_edit_only_changes_nearby_chunks() {
+        let data = noise(64 * 1024, 1);
+        let chunks = small().split(&data);
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks.iter().all(|c| c.len() <= 1024));
+        assert!(chunks.len() > 50);
+
+        // insert bytes in the middle: boundaries after it line up again
+        let mut edited = data[..30_000].to_vec();
+        edited.extend_from_slice(b"inserted");
+        edited.extend_from_slice(&data[30_000..]);
+        let after = small().split(&edited);
+        let before: HashSet<&[u8]> = chunks.iter().copied().collect();
+        let changed = after.iter().filter(|c| !before.contains(*c)).count();
+        assert!(changed <= 2, "{} chunks changed", changed);
+    }
+
+    #[tokio::test]
+    async fn test_put_chunked_stores_only_new_chunks() {
+        let alice = alice();
+        let bob = bob();
+        let mut store = HashMap::new();
+        let mut a = Replica::new(alice).with_chunker(small());
+        let data = noise(32 * 1024, 2);
+        let put = a
+            .put_chunked("big.bin", &data, 0o644, 0, async |cid, bytes| {
+                store.insert(cid, bytes.to_vec());
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(put.reused, 0);
+        let first = a.state().get("big.bin").unwrap();
+
+        let mut edited = data.clone();
+        edited[20_000..20_010].copy_from_slice(b"0123456789");
+        let put = a
+            .put_chunked("big.bin", &edited, 0o644, 0, async |cid, bytes| {
+                store.insert(cid, bytes.to_vec());
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert!(put.stored <= 2 && put.reused > 10);
+
+        let mut b = Replica::new(bob);
+        b.apply_delta(&a.delta_since(b.state().version_vector())).unwrap();
+        let entry = b.state().get("big.bin").unwrap();
+        let mut fetch = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        assert_eq!(read_entry(&entry, &mut fetch).await.unwrap(), edited);
+        assert_eq!(b.chunks_of(&first.content), Some(first.chunks));
+    }
+
+    #[test]
+    fn test_workspace_chunker_applies_to_every_replica() {
+        let alice = alice();
+        let bob = bob();
+        let mut a = Replica::new(alice);
+        let mut b = Replica::new(bob).with_chunker(Chunker { min_size: 128, avg_size: 512, max_size: 2048 });
+        assert!(a.set_chunker(Chunker { min_size: 1024, avg_size: 512, max_size: 2048 }).is_err());
+        a.set_chunker(small()).unwrap();
+        b.apply_delta(&a.delta_since(b.state().version_vector())).unwrap();
+        assert_eq!(b.chunker(), small());
+
+        // sizes cluster around the average
+        let data = noise(256 * 1024, 3);
+        let mean = data.len() / small().split(&data).len();
+        assert!((192..=384).contains(&mean), "mean chunk size {}", mean);
+    }
+
+    #[tokio::test]
+    async fn test_fixed_size_rule_for_append_only_files() {
+        let alice = alice();
+        let mut a = Replica::new(alice).with_chunker(small());
+        let fixed = ChunkProfile::Fixed { size: 1000 };
+        assert!(ChunkProfile::Fixed { size: 0 }.split(b"log").is_err());
+        assert!(ChunkProfile::Fixed { size: 0 }.find_cut(b"log").is_err());
+        assert!(a.set_chunk_rules(vec![ChunkRule::new("[", fixed)]).is_err());
+        a.set_chunk_rules(vec![ChunkRule::new("logs/**/*.log", fixed)]).unwrap();
+        assert_eq!(a.chunk_profile("logs/2026/app.log"), fixed);
+        assert_eq!(a.chunk_profile("app.log"), ChunkProfile::ContentDefined(small()));
+
+        let mut store = HashMap::new();
+        let mut log = noise(4500, 4);
+        a.put_chunked("logs/2026/app.log", &log, 0o644, 0, async |cid, bytes| {
+            store.insert(cid, bytes.to_vec());
+            Ok(())
+        })
+        .await
+        .unwrap();
+        let sizes: Vec<u64> = a.state().get("logs/2026/app.log").unwrap().chunks.iter().map(|c| c.size).collect();
+        assert_eq!(sizes, vec![1000, 1000, 1000, 1000, 500]);
+
+        // appending only rewrites the last chunk
+        log.extend(noise(1200, 5));
+        let put = a
+            .put_chunked("logs/2026/app.log", &log, 0o644, 0, async |cid, bytes| {
+                store.insert(cid, bytes.to_vec());
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!((put.reused, put.stored), (4, 2));
+    }
+}
@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::identity::ReplicaId;
+
+/// Hybrid logical clock timestamp: wall clock milliseconds plus a logical
+/// counter to order events that happen within the same millisecond.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Hlc {
+    pub millis: u64,
+    pub counter: u32,
+}
+
+/// Generates monotonically increasing `Hlc` timestamps for one replica.
+#[derive(Debug, Clone, Default)]
+pub struct HlcClock {
+    last: Hlc,
+}
+
+impl HlcClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a timestamp strictly greater than any previously issued or
+    /// observed one.
+    pub fn tick(&mut self) -> Hlc {
+        let wall = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        self.tick_at(wall)
+    }
+
+    fn tick_at(&mut self, wall: u64) -> Hlc {
+        self.last = if wall > self.last.millis {
+            Hlc { millis: wall, counter: 0 }
+        } else {
+            Hlc { millis: self.last.millis, counter: self.last.counter + 1 }
+        };
+        self.last
+    }
+
+    /// Folds in a timestamp seen on a remote operation so that later local
+    /// ticks sort after it.
+    pub fn observe(&mut self, remote: Hlc) {
+        if remote > self.last {
+            self.last = remote;
+        }
+    }
+
+    pub fn last(&self) -> Hlc {
+        self.last
+    }
+}
+
+/// Identifies a single operation: the `seq`-th operation written by `author`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Dot {
+    pub author: ReplicaId,
+    pub seq: u64,
+}
+
+/// Highest operation sequence number seen from each author.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionVector(BTreeMap<ReplicaId, u64>);
+
+impl VersionVector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, author: &ReplicaId) -> u64 {
+        self.0.get(author).copied().unwrap_or(0)
+    }
+
+    /// Returns true if the operation identified by `dot` is covered.
+    pub fn contains(&self, dot: &Dot) -> bool {
+        self.get(&dot.author) >= dot.seq
+    }
+
+    /// Records `dot` as seen.
+    pub fn observe(&mut self, dot: &Dot) {
+        let seq = self.0.entry(dot.author.clone()).or_insert(0);
+        if dot.seq > *seq {
+            *seq = dot.seq;
+        }
+    }
+
+    /// Pointwise maximum of both vectors.
+    pub fn join(&mut self, other: &VersionVector) {
+        for (author, seq) in &other.0 {
+            self.observe(&Dot { author: author.clone(), seq: *seq });
+        }
+    }
+
+    /// Returns true if every operation covered by `other` is covered by self.
+    pub fn dominates(&self, other: &VersionVector) -> bool {
+        other.0.iter().all(|(author, seq)| self.get(author) >= *seq)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ReplicaId, u64)> {
+        self.0.iter().map(|(author, seq)| (author, *seq))
+    }
+}
+
+#[cfg(test)]
+mod clock_test {
+    use super::*;
+    use crate::test_util::{alice, bob};
+
+    #[test]
+    fn test_hlc_monotonic_when_wall_clock_stalls() {
+        let mut clock = HlcClock::new();
+        let a = clock.tick_at(100);
+        let b = clock.tick_at(100);
+        let c = clock.tick_at(50);
+        assert!(a < b && b < c);
+        assert_eq!(c, Hlc { millis: 100, counter: 2 });
+    }
+
+    #[test]
+    fn test_hlc_observe_remote() {
+        let mut clock = HlcClock::new();
+        clock.tick_at(10);
+        clock.observe(Hlc { millis: 500, counter: 3 });
+        assert!(clock.tick_at(20) > Hlc { millis: 500, counter: 3 });
+    }
+
+    #[test]
+    fn test_version_vector_join_and_dominates() {
+        let a = alice();
+        let b = bob();
+
+        let mut left = VersionVector::new();
+        left.observe(&Dot { author: a.clone(), seq: 3 });
+        let mut right = VersionVector::new();
+        right.observe(&Dot { author: b.clone(), seq: 1 });
+
+        assert!(!left.dominates(&right));
+        left.join(&right);
+        assert!(left.dominates(&right));
+        assert!(left.contains(&Dot { author: a, seq: 2 }));
+        assert!(!left.contains(&Dot { author: b, seq: 2 }));
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::clock::{Dot, VersionVector};
+use super::identity::ReplicaId;
+use super::replica::Replica;
+use super::validate::validate_parents;
+use super::wire::{HeadAnnouncement, Node};
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// An author's announcement that doesn't descend from the one adopted
+/// before it: the IPNS key was rolled back, e.g. by a restored backup or
+/// someone else holding the key. Both branches are kept for the user to
+/// reconcile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fork {
+    pub author: ReplicaId,
+    /// The announcement adopted last, already merged.
+    pub previous: IpfsCid,
+    pub previous_heads: Vec<IpfsCid>,
+    /// The announcement that rolled back, not merged.
+    pub current: IpfsCid,
+    pub current_heads: Vec<IpfsCid>,
+}
+
+/// Returns true if `node` or its history covers `target`.
+fn has_seen(node: &Node, target: &Node) -> bool {
+    let seen = |dot: &Dot| match node {
+        Node::Op(op_node) => op_node.op.context.contains(dot) || op_node.op.dot() == *dot,
+        Node::Snapshot(snapshot) => snapshot.version_vector().contains(dot),
+    };
+    match target {
+        Node::Op(op_node) => seen(&op_node.op.dot()),
+        Node::Snapshot(snapshot) => {
+            snapshot.version_vector().iter().all(|(author, seq)| seen(&Dot { author: author.clone(), seq }))
+        }
+    }
+}
+
+/// How to settle a `Fork`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForkResolution {
+    /// Merge the current branch too; the CRDT keeps both. Where an
+    /// operation on it reuses a sequence number of the previous branch, the
+    /// two resolve as `State::join` would: a register both wrote keeps the
+    /// write with the higher op hash, and the rest of either write is
+    /// dropped, whichever branch arrived first. Later announcements only
+    /// need to descend from the current one.
+    Merge,
+    /// Leave the current branch out and keep expecting announcements that
+    /// descend from the previous one.
+    KeepPrevious,
+}
+
+#[derive(Debug, Clone)]
+struct Adopted {
+    cid: IpfsCid,
+    heads: Vec<IpfsCid>,
+    version_vector: VersionVector,
+}
+
+/// Per-author announcement bookkeeping; local, never replicated.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ForkTracker {
+    adopted: HashMap<ReplicaId, Adopted>,
+    forks: BTreeMap<ReplicaId, Fork>,
+    /// Announcements left out with `KeepPrevious`.
+    dismissed: HashSet<IpfsCid>,
+}
+
+impl ForkTracker {
+    pub(crate) fn adopt(&mut self, cid: &IpfsCid, announcement: &HeadAnnouncement) {
+        let adopted = Adopted {
+            cid: cid.clone(),
+            heads: announcement.heads.clone(),
+            version_vector: announcement.version_vector.clone(),
+        };
+        self.adopted.insert(announcement.author.clone(), adopted);
+    }
+}
+
+impl Replica {
+    /// Checks that `announcement` descends from the one adopted last from
+    /// its author: its version vector covers the old one and its heads
+    /// reach the old heads. Returns false for an announcement dismissed
+    /// with `KeepPrevious`; fails, recording a `Fork`, for any other that
+    /// doesn't descend.
+    pub(crate) async fn check_announcement<F>(
+        &mut self,
+        cid: &IpfsCid,
+        announcement: &HeadAnnouncement,
+        fetch: &mut F,
+    ) -> Result<bool>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let Some(previous) = self.forks.adopted.get(&announcement.author).cloned() else {
+            return Ok(true);
+        };
+        if previous.cid == *cid {
+            return Ok(true);
+        }
+        if announcement.version_vector.dominates(&previous.version_vector) {
+            let mut fetched = HashMap::new();
+            let mut descends = true;
+            for target in &previous.heads {
+                descends &= self.reaches(&announcement.heads, target, &mut fetched, fetch).await?;
+            }
+            if descends {
+                return Ok(true);
+            }
+        }
+        if self.forks.dismissed.contains(cid) {
+            return Ok(false);
+        }
+        let fork = Fork {
+            author: announcement.author.clone(),
+            previous: previous.cid.clone(),
+            previous_heads: previous.heads,
+            current: cid.clone(),
+            current_heads: announcement.heads.clone(),
+        };
+        self.forks.forks.insert(announcement.author.clone(), fork);
+        bail!(
+            "{} announced {}, which doesn't descend from {}; resolve the fork before merging it",
+            announcement.author,
+            cid,
+            previous.cid
+        )
+    }
+
+    /// Walks back from `heads` looking for `target`, only through nodes
+    /// that have seen it. A rolled-back author reuses sequence numbers, so
+    /// version vectors alone can't tell the branches apart.
+    async fn reaches<F>(
+        &self,
+        heads: &[IpfsCid],
+        target: &IpfsCid,
+        fetched: &mut HashMap<IpfsCid, Node>,
+        fetch: &mut F,
+    ) -> Result<bool>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        // pruned, nothing to compare with
+        let Some(target_node) = self.node(target).cloned() else {
+            return Ok(true);
+        };
+        let mut stack = heads.to_vec();
+        let mut visited = HashSet::new();
+        while let Some(cid) = stack.pop() {
+            if cid == *target {
+                return Ok(true);
+            }
+            if !visited.insert(cid.clone()) {
+                continue;
+            }
+            let node = match self.node(&cid).or_else(|| fetched.get(&cid)) {
+                Some(node) => node.clone(),
+                None => {
+                    let node: Node = self.fetch_block(&cid, fetch).await?;
+                    validate_parents(node.parents(), self.limits()).map_err(|e| anyhow!("Rejecting node {}: {}", cid, e))?;
+                    fetched.insert(cid, node.clone());
+                    node
+                }
+            };
+            if has_seen(&node, &target_node) {
+                stack.extend(node.parents().iter().cloned());
+            }
+        }
+        Ok(false)
+    }
+
+    /// The unresolved fork of `author`'s announcements, if any.
+    pub fn fork(&self, author: &ReplicaId) -> Option<&Fork> {
+        self.forks.forks.get(author)
+    }
+
+    pub fn forks(&self) -> impl Iterator<Item = &Fork> {
+        self.forks.forks.values()
+    }
+
+    /// Settles the fork of `author`, fetching the current branch with
+    /// `fetch` if it is merged. Returns the number of operations applied.
+    pub async fn resolve_fork<F>(&mut self, author: &ReplicaId, resolution: ForkResolution, mut fetch: F) -> Result<usize>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let Some(fork) = self.forks.forks.get(author).cloned() else {
+            bail!("No unresolved fork of {}", author);
+        };
+        match resolution {
+            ForkResolution::Merge => {
+                let announcement: HeadAnnouncement = self.fetch_block(&fork.current, &mut fetch).await?;
+                let mut applied = 0;
+                for head in &announcement.heads {
+                    applied += self.merge_head(head, async |cid| fetch(cid).await).await?;
+                }
+                self.forks.adopt(&fork.current, &announcement);
+                self.forks.forks.remove(author);
+                Ok(applied)
+            }
+            ForkResolution::KeepPrevious => {
+                self.forks.dismissed.insert(fork.current);
+                self.forks.forks.remove(author);
+                Ok(0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod fork_test {
+    use super::*;
+    use crate::test_util::{alice, bob, carol, entry};
+    use anyhow::anyhow;
+
+    async fn announce(replica: &mut Replica, store: &mut HashMap<IpfsCid, Vec<u8>>) -> IpfsCid {
+        replica
+            .push(async |cid, bytes| {
+                store.insert(cid, bytes);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        let (cid, bytes) = replica.encode_block(&replica.announcement()).unwrap();
+        store.insert(cid.clone(), bytes);
+        cid
+    }
+
+    #[tokio::test]
+    async fn test_rollback_is_held_as_fork_until_resolved() {
+        let mut store = HashMap::new();
+        let mut a = Replica::new(alice());
+        let mut b = Replica::new(bob());
+        a.put("one", entry(b"1")).unwrap();
+        let backup = a.clone();
+        a.put("two", entry(b"2")).unwrap();
+        let first = announce(&mut a, &mut store).await;
+        let get = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        b.merge_announcement(&first, get).await.unwrap();
+
+        // alice's key gets pointed at a branch restored from the backup
+        let mut restored = backup;
+        restored.put("three", entry(b"3")).unwrap();
+        restored.put("four", entry(b"4")).unwrap();
+        let rolled_back = announce(&mut restored, &mut store).await;
+        let get = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        assert!(b.merge_announcement(&rolled_back, get).await.is_err());
+        let fork = b.fork(&alice()).unwrap();
+        assert_eq!((&fork.previous, &fork.current), (&first, &rolled_back));
+        assert_eq!(fork.current_heads, restored.heads());
+        assert!(b.state().get("four").is_none());
+
+        let get = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        assert_eq!(b.resolve_fork(&alice(), ForkResolution::Merge, get).await.unwrap(), 2);
+        assert!(b.fork(&alice()).is_none());
+        assert!(b.state().get("one").is_some() && b.state().get("four").is_some());
+        // "three" reused the sequence number of "two" for another path
+        assert!(b.state().get("two").is_none() && b.state().get("three").is_none());
+
+        // the branches arriving the other way round end the same
+        let mut c = Replica::new(carol());
+        let get = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        c.merge_announcement(&rolled_back, get).await.unwrap();
+        let get = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        assert!(c.merge_announcement(&first, get).await.is_err());
+        let get = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        c.resolve_fork(&alice(), ForkResolution::Merge, get).await.unwrap();
+        assert_eq!(c.state(), b.state());
+
+        // later announcements on the restored branch merge normally
+        restored.put("five", entry(b"5")).unwrap();
+        let next = announce(&mut restored, &mut store).await;
+        let get = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        assert_eq!(b.merge_announcement(&next, get).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dismissed_rollback_is_not_merged() {
+        let mut store = HashMap::new();
+        let mut a = Replica::new(alice());
+        let mut b = Replica::new(bob());
+        let backup = a.clone();
+        a.put("one", entry(b"1")).unwrap();
+        let first = announce(&mut a, &mut store).await;
+        let get = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        b.merge_announcement(&first, get).await.unwrap();
+
+        let mut restored = backup;
+        restored.put("other", entry(b"x")).unwrap();
+        let rolled_back = announce(&mut restored, &mut store).await;
+        let get = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        assert!(b.merge_announcement(&rolled_back, get).await.is_err());
+        let get = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        b.resolve_fork(&alice(), ForkResolution::KeepPrevious, get).await.unwrap();
+
+        let get = async |cid: IpfsCid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+        assert_eq!(b.merge_announcement(&rolled_back, get).await.unwrap(), 0);
+        assert!(b.forks().next().is_none());
+        assert!(b.state().get("other").is_none());
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashSet;
+
+use super::hamt::Child;
+use super::replica::Replica;
+use super::wire::Node;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+impl Replica {
+    /// Every held block the current heads still need: the nodes back to the
+    /// first snapshot on each path, plus those snapshots' state blocks and
+    /// shards. History behind a snapshot is covered by its state.
+    pub fn reachable_blocks(&self) -> HashSet<IpfsCid> {
+        self.reach(self.heads(), false)
+    }
+
+    /// Every held block the current heads reach, including the history
+    /// behind snapshots.
+    pub fn history_blocks(&self) -> HashSet<IpfsCid> {
+        self.reach(self.heads(), true)
+    }
+
+    /// `reachable_blocks` plus what the nodes not pushed yet reach the
+    /// same way, which merging them again from the journal needs.
+    pub(crate) fn retained_blocks(&self) -> HashSet<IpfsCid> {
+        let from: Vec<IpfsCid> = self.heads().iter().chain(&self.unpublished).cloned().collect();
+        self.reach(&from, false)
+    }
+
+    fn reach(&self, from: &[IpfsCid], past_snapshots: bool) -> HashSet<IpfsCid> {
+        let mut reachable = HashSet::new();
+        let mut stack = from.to_vec();
+        while let Some(cid) = stack.pop() {
+            match self.node(&cid) {
+                Some(node) if reachable.insert(cid) => match node {
+                    Node::Op(op_node) => stack.extend(op_node.parents.iter().cloned()),
+                    Node::Snapshot(snapshot) => {
+                        self.reach_state(&snapshot.state, &mut reachable);
+                        if past_snapshots {
+                            stack.extend(snapshot.parents.iter().cloned());
+                        }
+                    }
+                },
+                _ => {}
+            }
+        }
+        reachable
+    }
+
+    fn reach_state(&self, cid: &IpfsCid, reachable: &mut HashSet<IpfsCid>) {
+        let Some(block) = self.state_block(cid) else {
+            return;
+        };
+        reachable.insert(cid.clone());
+        // scoped replicas hold only some shards; keep the ones they do
+        let mut stack = vec![block.entries.clone()];
+        while let Some(cid) = stack.pop() {
+            if let Some(shard) = self.shard(&cid)
+                && reachable.insert(cid)
+            {
+                stack.extend(shard.children.iter().filter_map(|child| match child {
+                    Child::Link(next) => Some(next.clone()),
+                    Child::Bucket(_) => None,
+                }));
+            }
+        }
+    }
+
+    /// Held blocks `reachable_blocks` leaves out, which `prune_unreachable`
+    /// would drop. Blocks not pushed yet are never garbage.
+    pub fn unreachable_blocks(&self) -> Vec<IpfsCid> {
+        let reachable = self.reachable_blocks();
+        let mut garbage: Vec<IpfsCid> = self
+            .held_blocks()
+            .filter(|cid| !reachable.contains(*cid) && !self.is_unpublished(cid))
+            .cloned()
+            .collect();
+        garbage.sort();
+        garbage
+    }
+
+    /// Forgets every block `unreachable_blocks` lists and returns their
+    /// CIDs. The state is unaffected, but `history`, `undo_last` and
+    /// `state_at` can no longer look behind the retained snapshots.
+    pub fn prune_unreachable(&mut self) -> Vec<IpfsCid> {
+        let garbage = self.unreachable_blocks();
+        self.forget_blocks(&garbage);
+        garbage
+    }
+}
+
+#[cfg(test)]
+mod gc_test {
+    use super::*;
+    use crate::crdt::clock::VersionVector;
+    use crate::test_util::{alice, bob, entry};
+
+    #[tokio::test]
+    async fn test_prune_keeps_what_heads_need() {
+        let mut a = Replica::new(alice()).with_snapshot_interval(2);
+        let first = a.put("one", entry(b"1")).unwrap();
+        a.put("two", entry(b"2")).unwrap();
+        a.put("three", entry(b"3")).unwrap();
+        a.put("four", entry(b"4")).unwrap();
+        let last = a.put("five", entry(b"5")).unwrap();
+        // unpublished blocks are kept even when unreachable
+        assert!(a.unreachable_blocks().is_empty());
+        a.push(async |_, _| Ok(())).await.unwrap();
+
+        let garbage = a.unreachable_blocks();
+        assert!(garbage.contains(&first));
+        assert!(!garbage.contains(&last));
+        let before = a.state().clone();
+        assert_eq!(a.prune_unreachable(), garbage);
+        assert!(a.unreachable_blocks().is_empty());
+        assert_eq!(a.state(), &before);
+        assert_eq!(a.nodes().count(), 2);
+
+        let mut b = Replica::new(bob());
+        b.apply_delta(&a.delta_since(&VersionVector::new())).unwrap();
+        assert_eq!(b.state(), a.state());
+        assert_eq!(b.heads(), a.heads());
+    }
+}
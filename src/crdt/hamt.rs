@@ -0,0 +1,556 @@
+//! Sharded storage for the path registers of a snapshot state: a hash
+//! array mapped trie keyed by hashed path components (see `path_key`). A
+//! huge directory is spread over many small shard blocks, a snapshot only
+//! produces new blocks for the shards that changed, and lookups, diffs and
+//! subtree reads only load the shards on the paths they touch.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use super::history::path_matches;
+use super::state::PathRegister;
+use super::wire::{Versioned, WIRE_VERSION};
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// Slots per shard node unless configured otherwise.
+pub const DEFAULT_FANOUT: u32 = 256;
+
+/// Entries a slot holds inline before they move to a child shard.
+pub const MAX_BUCKET_SIZE: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketEntry {
+    pub path: String,
+    pub register: PathRegister,
+}
+
+/// An occupied slot: a few entries inline, or a link to a deeper shard.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Child {
+    Bucket(Vec<BucketEntry>),
+    Link(IpfsCid),
+}
+
+/// One node of the trie. Bit `i` of `bitmap`, most significant bit of the
+/// first byte first, is set when slot `i` is occupied; `children` holds the
+/// occupied slots in slot order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardNode {
+    pub version: u32,
+    #[serde(with = "serde_bytes")]
+    pub bitmap: Vec<u8>,
+    pub children: Vec<Child>,
+}
+
+impl ShardNode {
+    /// Pairs every child with its slot number.
+    fn slots(&self) -> impl Iterator<Item = (usize, &Child)> {
+        let occupied = (0..self.bitmap.len() * 8).filter(|&slot| self.bitmap[slot / 8] & (0x80 >> (slot % 8)) != 0);
+        occupied.zip(&self.children)
+    }
+
+    fn child(&self, slot: usize) -> Option<&Child> {
+        self.slots().find(|(s, _)| *s == slot).map(|(_, child)| child)
+    }
+
+    /// Checks the bitmap agrees with the children.
+    fn check(&self, fanout: u32) -> Result<()> {
+        if self.bitmap.len() != bitmap_len(fanout) {
+            bail!("shard bitmap is {} bytes, expected {}", self.bitmap.len(), bitmap_len(fanout));
+        }
+        let occupied: u32 = self.bitmap.iter().map(|byte| byte.count_ones()).sum();
+        if occupied as usize != self.children.len() {
+            bail!("shard bitmap marks {} slots but holds {} children", occupied, self.children.len());
+        }
+        for child in &self.children {
+            if let Child::Bucket(entries) = child {
+                if entries.is_empty() {
+                    bail!("shard has an empty bucket");
+                }
+                if entries.windows(2).any(|pair| pair[0].path >= pair[1].path) {
+                    bail!("shard bucket is not sorted by path");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Versioned for ShardNode {
+    fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+/// Outcome of walking a trie whose shards may not all be at hand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Walk<T> {
+    Done(T),
+    /// The walk needs these shards first.
+    Missing(Vec<IpfsCid>),
+}
+
+/// Fanouts must be powers of two so each level consumes whole hash bits,
+/// and fit a byte so a level consumes at most one.
+pub fn check_fanout(fanout: u32) -> Result<()> {
+    if !fanout.is_power_of_two() || !(2..=256).contains(&fanout) {
+        bail!("shard fanout {} is not a power of two between 2 and 256", fanout);
+    }
+    Ok(())
+}
+
+fn bitmap_len(fanout: u32) -> usize {
+    (fanout as usize).div_ceil(8)
+}
+
+/// Longest path key a trie is walked for: a byte per directory and 32 for
+/// the name, far past any path the default limits accept.
+const MAX_KEY_LEN: usize = 4096;
+
+/// How deep a shard may sit: deeper ones would need a longer key than
+/// `MAX_KEY_LEN` to reach.
+fn max_depth(fanout: u32) -> usize {
+    MAX_KEY_LEN * 8 / (fanout.trailing_zeros() as usize).max(1)
+}
+
+/// Marks `cid` as walked. Every shard of a trie hangs in one place, so a
+/// shard reached twice is a hostile trie blowing up the walk.
+fn visit(visited: &mut HashSet<IpfsCid>, cid: &IpfsCid) -> Result<()> {
+    if !visited.insert(cid.clone()) {
+        bail!("shard {} is reached twice", cid);
+    }
+    Ok(())
+}
+
+/// Trie key of a path: the first byte of the SHA-256 of each directory
+/// component, then the full SHA-256 of the file name. Everything beneath a
+/// directory shares a key prefix and so lives under one shard, which is
+/// what lets a subtree be read without the rest; the file name hash keeps
+/// a huge single directory balanced.
+fn path_key(path: &str) -> Vec<u8> {
+    let (dirs, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let mut key = prefix_key(dirs);
+    key.extend_from_slice(&Sha256::digest(name.as_bytes()));
+    key
+}
+
+/// The key prefix shared by every path beneath the directory `prefix`.
+fn prefix_key(prefix: &str) -> Vec<u8> {
+    prefix
+        .split('/')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Sha256::digest(dir.as_bytes())[0])
+        .collect()
+}
+
+/// The slot a key falls in at `depth`, or `None` once the key is used up
+/// and a bucket can no longer be split.
+fn slot_at(key: &[u8], depth: usize, fanout: u32) -> Option<usize> {
+    let bits = fanout.trailing_zeros() as usize;
+    let start = depth * bits;
+    if start + bits > key.len() * 8 {
+        return None;
+    }
+    Some((start..start + bits).fold(0, |slot, i| slot << 1 | ((key[i / 8] >> (7 - i % 8)) & 1) as usize))
+}
+
+/// Shards `registers` into a trie with `fanout` slots per node. Returns the
+/// root's CID and every shard node, children before their parents. The
+/// layout depends only on the registers, so equal maps give equal CIDs;
+/// `encode` gives the CID a shard is stored under.
+pub fn build<'a, E>(
+    registers: impl IntoIterator<Item = (&'a str, &'a PathRegister)>,
+    fanout: u32,
+    encode: &E,
+) -> Result<(IpfsCid, Vec<(IpfsCid, ShardNode)>)>
+where
+    E: Fn(&ShardNode) -> Result<IpfsCid>,
+{
+    check_fanout(fanout)?;
+    let keyed = registers.into_iter().map(|(path, register)| (path_key(path), path, register)).collect();
+    let mut shards = Vec::new();
+    let root = build_level(keyed, 0, fanout, encode, &mut shards)?;
+    Ok((root, shards))
+}
+
+fn build_level<E>(
+    entries: Vec<(Vec<u8>, &str, &PathRegister)>,
+    depth: usize,
+    fanout: u32,
+    encode: &E,
+    shards: &mut Vec<(IpfsCid, ShardNode)>,
+) -> Result<IpfsCid>
+where
+    E: Fn(&ShardNode) -> Result<IpfsCid>,
+{
+    let mut slots: BTreeMap<usize, Vec<_>> = BTreeMap::new();
+    for entry in entries {
+        let slot = slot_at(&entry.0, depth, fanout).expect("buckets stop splitting before the hash runs out");
+        slots.entry(slot).or_default().push(entry);
+    }
+
+    let mut bitmap = vec![0u8; bitmap_len(fanout)];
+    let mut children = Vec::with_capacity(slots.len());
+    for (slot, mut group) in slots {
+        bitmap[slot / 8] |= 0x80 >> (slot % 8);
+        if group.len() > MAX_BUCKET_SIZE && group.iter().all(|(key, _, _)| slot_at(key, depth + 1, fanout).is_some()) {
+            children.push(Child::Link(build_level(group, depth + 1, fanout, encode, shards)?));
+        } else {
+            group.sort_by_key(|(_, path, _)| *path);
+            let bucket = group
+                .into_iter()
+                .map(|(_, path, register)| BucketEntry { path: path.to_string(), register: register.clone() })
+                .collect();
+            children.push(Child::Bucket(bucket));
+        }
+    }
+
+    let shard = ShardNode { version: WIRE_VERSION, bitmap, children };
+    let cid = encode(&shard)?;
+    shards.push((cid.clone(), shard));
+    Ok(cid)
+}
+
+/// Reads the register of `path`, loading only the shards on its way.
+pub fn lookup<'a, G>(root: &IpfsCid, path: &str, fanout: u32, get: &G) -> Result<Walk<Option<PathRegister>>>
+where
+    G: Fn(&IpfsCid) -> Option<&'a ShardNode>,
+{
+    let key = path_key(path);
+    let mut cid = root;
+    for depth in 0.. {
+        let Some(shard) = get(cid) else {
+            return Ok(Walk::Missing(vec![cid.clone()]));
+        };
+        shard.check(fanout)?;
+        let Some(slot) = slot_at(&key, depth, fanout) else { break };
+        match shard.child(slot) {
+            None => break,
+            Some(Child::Bucket(entries)) => {
+                let found = entries.iter().find(|entry| entry.path == path);
+                return Ok(Walk::Done(found.map(|entry| entry.register.clone())));
+            }
+            Some(Child::Link(next)) => cid = next,
+        }
+    }
+    Ok(Walk::Done(None))
+}
+
+/// Reads every register at or beneath `prefix` (everything for `""`) in
+/// the trie at `root`, checking each path sits in the slot its key selects.
+/// Only shards beneath the prefix are loaded. Reports all shards missing
+/// at the deepest level reached, so a fetcher needs one round per level.
+pub fn collect<'a, G>(
+    root: &IpfsCid,
+    fanout: u32,
+    prefix: &str,
+    get: &G,
+) -> Result<Walk<BTreeMap<String, PathRegister>>>
+where
+    G: Fn(&IpfsCid) -> Option<&'a ShardNode>,
+{
+    let mut registers = BTreeMap::new();
+    let mut missing = Vec::new();
+
+    // Walk down to the shard holding everything beneath `prefix`.
+    let key = prefix_key(prefix);
+    let levels = key.len() * 8 / fanout.trailing_zeros() as usize;
+    let mut cid = root.clone();
+    let mut trail = Vec::new();
+    while trail.len() < levels {
+        let Some(shard) = get(&cid) else {
+            return Ok(Walk::Missing(vec![cid]));
+        };
+        shard.check(fanout)?;
+        let slot = slot_at(&key, trail.len(), fanout).expect("levels are within the key");
+        trail.push(slot);
+        match shard.child(slot) {
+            None => return Ok(Walk::Done(registers)),
+            Some(Child::Bucket(entries)) => {
+                take_bucket(entries, &trail, fanout, prefix, &mut registers)?;
+                return Ok(Walk::Done(registers));
+            }
+            Some(Child::Link(next)) => cid = next.clone(),
+        }
+    }
+
+    collect_below((cid, trail), fanout, prefix, get, &mut HashSet::new(), &mut registers, &mut missing)?;
+    if missing.is_empty() {
+        Ok(Walk::Done(registers))
+    } else {
+        Ok(Walk::Missing(missing))
+    }
+}
+
+/// Collects the subtrie at `start`, a shard and its slot path, failing on
+/// a shard in `visited` or too deep for any key.
+fn collect_below<'a, G>(
+    start: (IpfsCid, Vec<usize>),
+    fanout: u32,
+    prefix: &str,
+    get: &G,
+    visited: &mut HashSet<IpfsCid>,
+    registers: &mut BTreeMap<String, PathRegister>,
+    missing: &mut Vec<IpfsCid>,
+) -> Result<()>
+where
+    G: Fn(&IpfsCid) -> Option<&'a ShardNode>,
+{
+    let mut stack = vec![start];
+    while let Some((cid, trail)) = stack.pop() {
+        if trail.len() >= max_depth(fanout) {
+            bail!("shard trie is deeper than any path key reaches");
+        }
+        visit(visited, &cid)?;
+        let Some(shard) = get(&cid) else {
+            missing.push(cid);
+            continue;
+        };
+        shard.check(fanout)?;
+        for (slot, child) in shard.slots() {
+            let mut here: Vec<usize> = trail.clone();
+            here.push(slot);
+            match child {
+                Child::Link(next) => stack.push((next.clone(), here)),
+                Child::Bucket(entries) => take_bucket(entries, &here, fanout, prefix, registers)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds the entries of the bucket at slot path `trail` that lie beneath
+/// `prefix` to `registers`.
+fn take_bucket(
+    entries: &[BucketEntry],
+    trail: &[usize],
+    fanout: u32,
+    prefix: &str,
+    registers: &mut BTreeMap<String, PathRegister>,
+) -> Result<()> {
+    for entry in entries {
+        let key = path_key(&entry.path);
+        if trail.iter().enumerate().any(|(depth, &s)| slot_at(&key, depth, fanout) != Some(s)) {
+            bail!("path {:?} is stored in the wrong shard slot", entry.path);
+        }
+        if path_matches(prefix, &entry.path) && registers.insert(entry.path.clone(), entry.register.clone()).is_some() {
+            bail!("path {:?} is stored twice", entry.path);
+        }
+    }
+    Ok(())
+}
+
+/// Lists the CIDs of every shard of the trie at `root`.
+pub fn shard_cids<'a, G>(root: &IpfsCid, get: &G) -> Result<Walk<Vec<IpfsCid>>>
+where
+    G: Fn(&IpfsCid) -> Option<&'a ShardNode>,
+{
+    let mut cids = Vec::new();
+    let mut missing = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![root.clone()];
+    while let Some(cid) = stack.pop() {
+        visit(&mut visited, &cid)?;
+        let Some(shard) = get(&cid) else {
+            missing.push(cid);
+            continue;
+        };
+        for child in &shard.children {
+            if let Child::Link(next) = child {
+                stack.push(next.clone());
+            }
+        }
+        cids.push(cid);
+    }
+    Ok(if missing.is_empty() { Walk::Done(cids) } else { Walk::Missing(missing) })
+}
+
+/// Paths whose registers differ between the tries at `a` and `b`, which
+/// must share `fanout`. Subtrees with the same CID are skipped unloaded.
+pub fn diff<'a, G>(a: &IpfsCid, b: &IpfsCid, fanout: u32, get: &G) -> Result<Walk<BTreeSet<String>>>
+where
+    G: Fn(&IpfsCid) -> Option<&'a ShardNode>,
+{
+    let mut changed = BTreeSet::new();
+    let mut missing = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![(a.clone(), b.clone(), Vec::new())];
+    while let Some((a, b, trail)) = stack.pop() {
+        if a == b {
+            continue;
+        }
+        if trail.len() >= max_depth(fanout) {
+            bail!("shard trie is deeper than any path key reaches");
+        }
+        visit(&mut visited, &a)?;
+        visit(&mut visited, &b)?;
+        let (Some(left), Some(right)) = (get(&a), get(&b)) else {
+            missing.extend([a, b].into_iter().filter(|cid| get(cid).is_none()));
+            continue;
+        };
+        left.check(fanout)?;
+        right.check(fanout)?;
+        for slot in 0..fanout as usize {
+            let here = || [trail.as_slice(), &[slot]].concat();
+            match (left.child(slot), right.child(slot)) {
+                (Some(Child::Link(x)), Some(Child::Link(y))) => stack.push((x.clone(), y.clone(), here())),
+                (x, y) if x == y => {}
+                (x, y) => {
+                    let x = slot_registers(x, &here(), fanout, get, &mut visited, &mut missing)?;
+                    let y = slot_registers(y, &here(), fanout, get, &mut visited, &mut missing)?;
+                    if let (Some(x), Some(y)) = (x, y) {
+                        let paths: BTreeSet<&String> = x.keys().chain(y.keys()).collect();
+                        changed.extend(paths.into_iter().filter(|path| x.get(*path) != y.get(*path)).cloned());
+                    }
+                }
+            }
+        }
+    }
+    if missing.is_empty() {
+        Ok(Walk::Done(changed))
+    } else {
+        Ok(Walk::Missing(missing))
+    }
+}
+
+/// Every register under one slot, or `None` if shards below it are missing.
+fn slot_registers<'a, G>(
+    child: Option<&Child>,
+    trail: &[usize],
+    fanout: u32,
+    get: &G,
+    visited: &mut HashSet<IpfsCid>,
+    missing: &mut Vec<IpfsCid>,
+) -> Result<Option<BTreeMap<String, PathRegister>>>
+where
+    G: Fn(&IpfsCid) -> Option<&'a ShardNode>,
+{
+    match child {
+        None => Ok(Some(BTreeMap::new())),
+        Some(Child::Bucket(entries)) => {
+            Ok(Some(entries.iter().map(|entry| (entry.path.clone(), entry.register.clone())).collect()))
+        }
+        Some(Child::Link(cid)) => {
+            let mut registers = BTreeMap::new();
+            let before = missing.len();
+            collect_below((cid.clone(), trail.to_vec()), fanout, "", get, visited, &mut registers, missing)?;
+            Ok((missing.len() == before).then_some(registers))
+        }
+    }
+}
+
+#[cfg(test)]
+mod hamt_test {
+    use super::*;
+    use crate::crdt::clock::{Dot, Hlc, VersionVector};
+    use crate::crdt::op::Content;
+    use crate::crdt::state::Version;
+    use crate::crdt::wire::encode_block;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use crate::test_util::alice;
+    use std::cell::RefCell;
+    use std::collections::{HashMap, HashSet};
+
+    fn register(seq: u64) -> PathRegister {
+        let content = Content { content: IpfsCid::compute(RAW_CODE, &seq.to_le_bytes()), size: 8, lineage: Vec::new(), chunks: Vec::new(), symlink: None };
+        let version = Version {
+            dot: Dot { author: alice(), seq },
+            timestamp: Hlc { millis: seq, counter: 0 },
+            hash: vec![0; 32],
+            value: content,
+        };
+        let mut register = PathRegister::default();
+        register.content.write(version, &VersionVector::new());
+        register
+    }
+
+    fn registers(n: u64) -> BTreeMap<String, PathRegister> {
+        (0..n).map(|i| (format!("dir{}/file{}", i % 7, i), register(i + 1))).collect()
+    }
+
+    fn store(registers: &BTreeMap<String, PathRegister>, fanout: u32) -> (IpfsCid, HashMap<IpfsCid, ShardNode>) {
+        let encode = |shard: &ShardNode| Ok(encode_block(shard)?.0);
+        let (root, shards) = build(registers.iter().map(|(p, r)| (p.as_str(), r)), fanout, &encode).unwrap();
+        (root, shards.into_iter().collect())
+    }
+
+    #[test]
+    fn test_build_collect_roundtrip() {
+        let registers = registers(500);
+        let (root, shards) = store(&registers, 4);
+        assert!(shards.len() > 10);
+        assert_eq!(collect(&root, 4, "", &|cid| shards.get(cid)).unwrap(), Walk::Done(registers.clone()));
+        assert_eq!(store(&registers, 4).0, root);
+        assert!(build(registers.iter().map(|(p, r)| (p.as_str(), r)), 3, &|_| unreachable!()).is_err());
+    }
+
+    #[test]
+    fn test_lookup_and_diff_touch_only_affected_shards() {
+        let mut registers = registers(500);
+        let (before, mut shards) = store(&registers, 4);
+        registers.insert("dir3/file10".into(), register(9999));
+        let (after, changed) = store(&registers, 4);
+        let new_shards: Vec<_> = changed.into_iter().filter(|(cid, _)| !shards.contains_key(cid)).collect();
+        // one shard per level on the way to the file
+        assert!(new_shards.len() <= 10, "{} shards rewritten", new_shards.len());
+        shards.extend(new_shards);
+
+        let touched = RefCell::new(HashSet::new());
+        let get = |cid: &IpfsCid| {
+            touched.borrow_mut().insert(cid.clone());
+            shards.get(cid)
+        };
+        assert_eq!(lookup(&after, "dir3/file10", 4, &get).unwrap(), Walk::Done(Some(register(9999))));
+        assert_eq!(lookup(&after, "nowhere", 4, &get).unwrap(), Walk::Done(None));
+        assert!(touched.borrow().len() < 10);
+
+        touched.borrow_mut().clear();
+        let changed = diff(&before, &after, 4, &get).unwrap();
+        assert_eq!(changed, Walk::Done(BTreeSet::from(["dir3/file10".to_string()])));
+        assert!(touched.borrow().len() < 24);
+
+        touched.borrow_mut().clear();
+        let Walk::Done(subtree) = collect(&after, 4, "dir3/", &get).unwrap() else { panic!("missing shards") };
+        assert_eq!(subtree.len(), registers.keys().filter(|path| path.starts_with("dir3/")).count());
+        assert!(touched.borrow().len() * 3 < shards.len());
+    }
+
+    #[test]
+    fn test_collect_reports_missing_and_misplaced() {
+        let registers = registers(100);
+        let (root, mut shards) = store(&registers, 4);
+        let Walk::Missing(missing) = collect(&root, 4, "", &|cid| (cid == &root).then(|| &shards[&root])).unwrap() else {
+            panic!("expected missing shards");
+        };
+        assert!(!missing.is_empty() && missing.iter().all(|cid| shards.contains_key(cid)));
+
+        let Some(Child::Bucket(entries)) =
+            shards.values_mut().flat_map(|shard| &mut shard.children).find(|c| matches!(c, Child::Bucket(_)))
+        else {
+            panic!("expected a bucket");
+        };
+        entries[0].path = "moved".into();
+        assert!(collect(&root, 4, "", &|cid| shards.get(cid)).is_err());
+    }
+
+    #[test]
+    fn test_rejects_shared_and_endless_shards() {
+        let (root, mut shards) = store(&registers(100), 4);
+        let shared = ShardNode { version: WIRE_VERSION, bitmap: vec![0xf0], children: vec![Child::Link(root.clone()); 4] };
+        let (hostile, _) = encode_block(&shared).unwrap();
+        shards.insert(hostile.clone(), shared);
+        assert!(collect(&hostile, 4, "", &|cid| shards.get(cid)).is_err());
+        assert!(shard_cids(&hostile, &|cid| shards.get(cid)).is_err());
+
+        let mut deep = IpfsCid::compute(RAW_CODE, b"bottom");
+        for _ in 0..=max_depth(4) {
+            let link = ShardNode { version: WIRE_VERSION, bitmap: vec![0x80], children: vec![Child::Link(deep)] };
+            deep = encode_block(&link).unwrap().0;
+            shards.insert(deep.clone(), link);
+        }
+        assert!(collect(&deep, 4, "", &|cid| shards.get(cid)).is_err());
+        assert!(diff(&deep, &root, 4, &|cid| shards.get(cid)).is_err());
+    }
+}
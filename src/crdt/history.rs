@@ -0,0 +1,149 @@
+use futures_util::Stream;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+
+use super::clock::Hlc;
+use super::identity::ReplicaId;
+use super::op::OpKind;
+use super::replica::Replica;
+use super::wire::Node;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// One change to a path, as recorded in the op DAG. A transaction yields
+/// one record per change it made, all with the same node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeRecord {
+    /// CID of the op node that made the change.
+    pub node: IpfsCid,
+    pub author: ReplicaId,
+    pub timestamp: Hlc,
+    pub operation: OpKind,
+    /// Content the change pointed the path to; `None` for deletions and
+    /// patches that leave the content alone.
+    pub content: Option<IpfsCid>,
+}
+
+/// Returns true if `path` is `prefix` itself or lies underneath it.
+/// An empty prefix matches every path.
+pub(crate) fn path_matches(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    prefix.is_empty()
+        || path == prefix
+        || (path.starts_with(prefix) && path.as_bytes().get(prefix.len()) == Some(&b'/'))
+}
+
+/// Newest-first walk over the op nodes reachable from a set of heads.
+pub struct HistoryIter<'a> {
+    replica: &'a Replica,
+    path: String,
+    frontier: BinaryHeap<(Hlc, IpfsCid)>,
+    visited: HashSet<IpfsCid>,
+    /// Further matching changes of the transaction last visited.
+    queued: VecDeque<ChangeRecord>,
+}
+
+impl<'a> HistoryIter<'a> {
+    pub(crate) fn new(replica: &'a Replica, path: &str) -> Self {
+        let mut iter = HistoryIter {
+            replica,
+            path: path.to_string(),
+            frontier: BinaryHeap::new(),
+            visited: HashSet::new(),
+            queued: VecDeque::new(),
+        };
+        for head in replica.heads() {
+            iter.enqueue(head);
+        }
+        iter
+    }
+
+    fn enqueue(&mut self, cid: &IpfsCid) {
+        if let Some(node) = self.replica.node(cid)
+            && self.visited.insert(cid.clone())
+        {
+            self.frontier.push((node.timestamp(), cid.clone()));
+        }
+    }
+}
+
+impl Iterator for HistoryIter<'_> {
+    type Item = ChangeRecord;
+
+    fn next(&mut self) -> Option<ChangeRecord> {
+        if let Some(record) = self.queued.pop_front() {
+            return Some(record);
+        }
+        while let Some((_, cid)) = self.frontier.pop() {
+            let node = self.replica.node(&cid)?;
+            for parent in node.parents() {
+                self.enqueue(parent);
+            }
+
+            let Node::Op(op_node) = node else { continue };
+            let op = &op_node.op;
+            self.queued.extend(
+                op.kind
+                    .parts()
+                    .iter()
+                    .filter(|kind| kind.path().is_some_and(|path| path_matches(&self.path, path)))
+                    .map(|kind| ChangeRecord {
+                        node: cid.clone(),
+                        author: op.author.clone(),
+                        timestamp: op.timestamp,
+                        operation: kind.clone(),
+                        content: match kind {
+                            OpKind::Put { entry, .. } => Some(entry.content.clone()),
+                            OpKind::Patch { patch, .. } => patch.content.as_ref().map(|c| c.content.clone()),
+                            _ => None,
+                        },
+                    }),
+            );
+            if let Some(record) = self.queued.pop_front() {
+                return Some(record);
+            }
+        }
+        None
+    }
+}
+
+impl Replica {
+    /// Streams the changes to `path` (or anything beneath it), newest first.
+    ///
+    /// Only nodes this replica holds are visited: history older than the
+    /// snapshot a replica was bootstrapped from is not included.
+    pub fn history(&self, path: &str) -> impl Stream<Item = ChangeRecord> + '_ {
+        futures_util::stream::iter(HistoryIter::new(self, path))
+    }
+}
+
+#[cfg(test)]
+mod history_test {
+    use super::*;
+    use crate::test_util::{alice, entry};
+    use futures_util::StreamExt;
+
+    #[test]
+    fn test_path_matches() {
+        assert!(path_matches("docs", "docs"));
+        assert!(path_matches("docs/", "docs/a.txt"));
+        assert!(path_matches("", "anything"));
+        assert!(!path_matches("docs", "docs2/a.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_history_newest_first() {
+        let author = alice();
+        let mut replica = Replica::new(author.clone()).with_snapshot_interval(2);
+        replica.put("docs/a.txt", entry(b"v1")).unwrap();
+        replica.put("other.txt", entry(b"x")).unwrap();
+        replica.put("docs/a.txt", entry(b"v2")).unwrap();
+        replica.remove("docs/a.txt").unwrap();
+
+        let records: Vec<ChangeRecord> = replica.history("docs").collect().await;
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|r| r.author == author));
+        assert!(records.windows(2).all(|w| w[0].timestamp > w[1].timestamp));
+        assert_eq!(records[0].content, None);
+        assert_eq!(records[1].content, Some(entry(b"v2").content));
+        assert_eq!(records[2].content, Some(entry(b"v1").content));
+    }
+}
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+use cid::multihash::Multihash;
+use cid::Cid;
+
+use super::sign::PUBLIC_KEY_LENGTH;
+use crate::kubo_rpc::keys::IpnsKey;
+
+const LIBP2P_KEY_CODE: u64 = 0x72;
+const IDENTITY_HASH_CODE: u64 = 0x00;
+
+/// Identifies a replica. Derived from the IPNS (libp2p) key the replica
+/// publishes its heads under, so knowing a replica's id is enough to
+/// resolve its heads.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ReplicaId(IpnsKey);
+
+impl ReplicaId {
+    /// The libp2p peer id of an Ed25519 public key, i.e. the IPNS name the
+    /// daemon would publish under for that key.
+    pub fn from_ed25519(public_key: &[u8; PUBLIC_KEY_LENGTH]) -> Self {
+        // protobuf PublicKey { Type: Ed25519, Data: public_key }, inlined
+        // with the identity hash since it's short enough
+        let mut encoded = vec![0x08, 0x01, 0x12, PUBLIC_KEY_LENGTH as u8];
+        encoded.extend_from_slice(public_key);
+        let hash = Multihash::wrap(IDENTITY_HASH_CODE, &encoded).expect("encoded key fits in a multihash");
+        ReplicaId(IpnsKey(Cid::new_v1(LIBP2P_KEY_CODE, hash)))
+    }
+
+    pub fn ipns_key(&self) -> &IpnsKey {
+        &self.0
+    }
+}
+
+impl From<IpnsKey> for ReplicaId {
+    fn from(key: IpnsKey) -> Self {
+        ReplicaId(key)
+    }
+}
+
+impl From<&IpnsKey> for ReplicaId {
+    fn from(key: &IpnsKey) -> Self {
+        ReplicaId(key.clone())
+    }
+}
+
+impl FromStr for ReplicaId {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        IpnsKey::from_str(s).map(ReplicaId)
+    }
+}
+
+impl fmt::Display for ReplicaId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod identity_test {
+    use super::*;
+
+    #[test]
+    fn test_replica_id_matches_ipns_key() {
+        let s = "k51qzi5uqu5dgndmfpeorlwuar7u66p9g9l0dolwy2v7sm6dt5sorjityev4ib";
+        let id = ReplicaId::from_str(s).unwrap();
+        assert_eq!(id.to_string(), s);
+        assert_eq!(id.ipns_key(), &IpnsKey::from_str(s).unwrap());
+        assert!(ReplicaId::from_str("QmdbWa3wBGwQ4suXjEpPkrigP3UmBMECdJNmkHfz6btqaJ").is_err());
+    }
+
+    #[test]
+    fn test_replica_id_from_ed25519_is_a_valid_ipns_key() {
+        let public_key = crate::crdt::sign::ReplicaKeypair::from_seed([1; 32]).public_key();
+        let id = ReplicaId::from_ed25519(&public_key);
+        assert!(id.to_string().starts_with("k51qzi5uqu5d"));
+        assert_eq!(ReplicaId::from_str(&id.to_string()).unwrap(), id);
+    }
+
+    #[test]
+    fn test_replica_id_serializes_like_ipns_key() {
+        let key = IpnsKey::from_str("k51qzi5uqu5dgndmfpeorlwuar7u66p9g9l0dolwy2v7sm6dt5sorjityev4ib").unwrap();
+        let id = ReplicaId::from(&key);
+        assert_eq!(serde_json::to_string(&id).unwrap(), serde_json::to_string(&key).unwrap());
+    }
+}
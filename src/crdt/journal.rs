@@ -0,0 +1,260 @@
+//! A write-ahead journal of the blocks a replica writes, so local commits
+//! survive a crash before they are published. Every node, and the state
+//! and shards of a snapshot, is appended before the commit returns, and
+//! synced to disk before a scan records the files it committed as
+//! synced. On startup the journal is replayed into the replica, which
+//! then pushes those blocks again, and a publish the daemon confirmed
+//! empties it. A record the crash cut short ends the journal. How soon records
+//! reach the disk is the replica's `Durability`: as each is appended, or
+//! only before a scan records what it committed.
+
+use anyhow::{bail, Context, Result};
+use cid::Cid;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::replica::Replica;
+use crate::durability::{write_durably, Durability};
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// Kinds of record: a DAG node, or a block one links to.
+const NODE: u8 = 0;
+const BLOCK: u8 = 1;
+
+/// The journal file at a path. Clones append to the same file.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    path: PathBuf,
+    durability: Durability,
+}
+
+/// A block as journaled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub node: bool,
+    pub cid: IpfsCid,
+    pub bytes: Vec<u8>,
+}
+
+impl Journal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Journal { path: path.into(), durability: Durability::default() }
+    }
+
+    /// When records are synced to disk; at checkpoints by default.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The records written since the journal was last emptied, in order,
+    /// up to the first one that is cut short or doesn't match its CID.
+    pub fn records(&self) -> Result<Vec<Record>> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err).with_context(|| format!("Failed to read journal {}", self.path.display())),
+        };
+        let mut records = Vec::new();
+        let mut rest = data.as_slice();
+        while let Some(record) = read_record(&mut rest) {
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// Appends a record of the block `bytes` with CID `cid`, syncing it to
+    /// disk if the journal syncs each write.
+    pub(crate) fn append(&self, node: bool, cid: &IpfsCid, bytes: &[u8]) -> Result<()> {
+        let record = encode_record(node, cid, bytes);
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let written = std::fs::File::options().create(true).append(true).open(&self.path).and_then(|mut file| {
+            file.write_all(&record)?;
+            if self.durability.syncs_writes() {
+                file.sync_data()?;
+            }
+            Ok(())
+        });
+        written.with_context(|| format!("Failed to write journal {}", self.path.display()))
+    }
+
+    /// Syncs the records appended so far to disk, if the journal syncs at
+    /// checkpoints.
+    pub(crate) fn checkpoint(&self) -> Result<()> {
+        if self.durability != Durability::Checkpoint {
+            return Ok(());
+        }
+        match std::fs::File::options().append(true).open(&self.path) {
+            Ok(file) => file.sync_data(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+        .with_context(|| format!("Failed to sync journal {}", self.path.display()))
+    }
+
+    /// Replaces the journal with the records `keep` picks, each once, in
+    /// order, dropping any cut short. A crash leaves the old journal or the
+    /// new. Returns the number of records dropped.
+    pub(crate) fn rewrite(&self, keep: impl Fn(&Record) -> bool) -> Result<usize> {
+        let records = self.records()?;
+        let mut seen = HashSet::new();
+        let kept: Vec<&Record> = records.iter().filter(|record| keep(record) && seen.insert(&record.cid)).collect();
+        let data: Vec<u8> = kept.iter().flat_map(|record| encode_record(record.node, &record.cid, &record.bytes)).collect();
+        write_durably(&self.path, &data, self.durability).with_context(|| format!("Failed to write journal {}", self.path.display()))?;
+        Ok(records.len() - kept.len())
+    }
+
+    /// Empties the journal.
+    pub(crate) fn clear(&self) -> Result<()> {
+        match std::fs::File::options().write(true).open(&self.path) {
+            Ok(file) if self.durability.syncs_checkpoints() => file.set_len(0).and_then(|_| file.sync_data()),
+            Ok(file) => file.set_len(0),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+        .with_context(|| format!("Failed to empty journal {}", self.path.display()))
+    }
+}
+
+fn encode_record(node: bool, cid: &IpfsCid, bytes: &[u8]) -> Vec<u8> {
+    let cid_bytes = cid.0.to_bytes();
+    let mut record = Vec::with_capacity(9 + cid_bytes.len() + bytes.len());
+    record.push(if node { NODE } else { BLOCK });
+    record.extend((cid_bytes.len() as u32).to_le_bytes());
+    record.extend(&cid_bytes);
+    record.extend((bytes.len() as u32).to_le_bytes());
+    record.extend(bytes);
+    record
+}
+
+/// Reads the record at the start of `rest` and moves past it.
+fn read_record(rest: &mut &[u8]) -> Option<Record> {
+    fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        let (taken, after) = rest.split_at_checked(len)?;
+        *rest = after;
+        Some(taken)
+    }
+    let length = |rest: &mut &[u8]| Some(u32::from_le_bytes(take(rest, 4)?.try_into().ok()?) as usize);
+    let kind = take(rest, 1)?[0];
+    let cid_len = length(rest)?;
+    let cid = IpfsCid::try_from(Cid::try_from(take(rest, cid_len)?).ok()?).ok()?;
+    let bytes_len = length(rest)?;
+    let bytes = take(rest, bytes_len)?.to_vec();
+    (kind <= BLOCK && cid.verify(&bytes)).then_some(Record { node: kind == NODE, cid, bytes })
+}
+
+impl Replica {
+    /// When the journal is synced to disk; at checkpoints by default.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self.journal = self.journal.map(|journal| journal.with_durability(durability));
+        self
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Replays the journal at `path` into the replica, merging the nodes it
+    /// holds and queueing them to be pushed again, then journals the
+    /// blocks written from now on there. Nodes they descend from that
+    /// the journal doesn't hold are fetched with `fetch`. Returns the
+    /// number of operations replayed.
+    pub async fn open_journal<F>(&mut self, path: impl Into<PathBuf>, mut fetch: F) -> Result<usize>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let journal = Journal::new(path).with_durability(self.durability);
+        let records = journal.records()?;
+        let mut replayed = 0;
+        // each node in turn, since a merge stops at a snapshot and would
+        // skip the nodes written before it
+        let blocks: HashMap<&IpfsCid, &Vec<u8>> = records.iter().map(|record| (&record.cid, &record.bytes)).collect();
+        for head in records.iter().filter(|record| record.node) {
+            replayed += self
+                .merge_head(&head.cid, async |cid| match blocks.get(&cid) {
+                    Some(bytes) => Ok(bytes.to_vec()),
+                    None => fetch(cid).await,
+                })
+                .await
+                .context("Failed to replay the journal")?;
+        }
+        for record in &records {
+            if self.block_bytes(&record.cid)?.is_none() {
+                bail!("Journaled block {} wasn't replayed", record.cid);
+            }
+            if !self.unpublished.contains(&record.cid) {
+                self.unpublished.push(record.cid.clone());
+            }
+        }
+        self.journal = Some(journal);
+        Ok(replayed)
+    }
+
+    /// Syncs the journal before a scan records what it committed, if the
+    /// replica keeps one.
+    pub(crate) fn checkpoint_journal(&self) -> Result<()> {
+        self.journal.as_ref().map_or(Ok(()), Journal::checkpoint)
+    }
+
+    /// Empties the journal once everything it holds has been pushed.
+    pub(crate) fn acknowledge_published(&mut self) -> Result<()> {
+        match &self.journal {
+            Some(journal) if self.unpublished.is_empty() => journal.clear(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod journal_test {
+    use super::*;
+    use crate::crdt::sign::ReplicaKeypair;
+    use crate::test_util::{alice, entry};
+    use anyhow::anyhow;
+
+    #[tokio::test]
+    async fn test_journal_survives_a_crash() {
+        let author = alice();
+        let keypair = ReplicaKeypair::generate().unwrap();
+        let path = std::env::temp_dir().join(format!("crdt-journal-{}", std::process::id())).join("journal");
+        let _ = std::fs::remove_file(&path);
+        let entry = |data: &[u8]| {
+            entry(data)
+        };
+        let no_daemon = async |cid: IpfsCid| -> Result<Vec<u8>> { Err(anyhow!("missing {}", cid)) };
+
+        let mut replica = Replica::new(author.clone()).with_keypair(keypair.clone());
+        assert_eq!(replica.open_journal(&path, no_daemon).await.unwrap(), 0);
+        replica.put("a.txt", entry(b"a")).unwrap();
+        replica.put("b.txt", entry(b"b")).unwrap();
+        // the last record is cut short
+        let mut file = std::fs::File::options().append(true).open(&path).unwrap();
+        file.write_all(&[NODE, 36, 0]).unwrap();
+        drop(replica);
+
+        let mut restarted = Replica::new(author.clone()).with_keypair(keypair.clone());
+        assert_eq!(restarted.open_journal(&path, no_daemon).await.unwrap(), 2);
+        assert!(restarted.state().get("b.txt").is_some());
+        restarted.put("c.txt", entry(b"c")).unwrap();
+        let mut pushed = Vec::new();
+        restarted
+            .push(async |cid, _bytes| {
+                pushed.push(cid);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(pushed.len(), 3);
+        restarted.acknowledge_published().unwrap();
+        assert!(Journal::new(&path).records().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
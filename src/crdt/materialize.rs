@@ -0,0 +1,345 @@
+use anyhow::{bail, Context, Result};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+use super::chunk::read_entry;
+use super::op::Entry;
+use super::replica::Replica;
+use super::state::State;
+use crate::crypto::get_sealed;
+use crate::kubo_rpc::ipfs::{cat, IpfsCid};
+
+/// Top-level subtrees written at once by `materialize_read_only`.
+pub const MATERIALIZE_TASKS: usize = 8;
+
+/// Joins a CRDT path onto `root`, refusing anything that would escape it.
+/// Names are pushed one by one, as a Windows root with the `\\?\` prefix
+/// takes `/` literally.
+pub fn safe_join(root: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    if path.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        bail!("Refusing to materialize unsafe path {:?}", path);
+    }
+    let mut joined = root.to_path_buf();
+    joined.extend(relative.components());
+    Ok(joined)
+}
+
+/// Fails if a directory `path` is under in `root` is a symlink, which could
+/// send a write outside `root`. Directories not created yet are fine.
+pub(crate) async fn refuse_symlinked_parents(root: &Path, path: &str) -> Result<()> {
+    let mut dir = root.to_path_buf();
+    for name in path.split('/').take(path.split('/').count() - 1) {
+        dir.push(name);
+        match tokio::fs::symlink_metadata(&dir).await {
+            Ok(metadata) if metadata.is_symlink() => bail!("Refusing to write {} through a symlink", path),
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => break,
+            Err(err) => return Err(err).with_context(|| format!("Failed to stat {}", dir.display())),
+        }
+    }
+    Ok(())
+}
+
+/// Writes every file in `state` under `target`, fetching content with
+/// `fetch`. Files are made read-only, keeping their other mode bits and
+/// their mtimes. Up to `MATERIALIZE_TASKS` top-level subtrees are written
+/// at once, the files of each in order. Symlinks are created after all
+/// files, and none through another, so none can redirect a write outside
+/// `target`; one whose path a file already took fails. `target` must not
+/// exist or be empty.
+pub async fn materialize_read_only<F>(state: &State, target: &Path, fetch: F) -> Result<usize>
+where
+    F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+{
+    materialize_filtered(state, target, |_| true, fetch).await
+}
+
+/// `materialize_read_only` writing only the paths `keep` accepts.
+pub async fn materialize_filtered<F>(state: &State, target: &Path, keep: impl Fn(&str) -> bool, fetch: F) -> Result<usize>
+where
+    F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+{
+    if target.exists() && std::fs::read_dir(target)?.next().is_some() {
+        bail!("Checkout target {} is not empty", target.display());
+    }
+    tokio::fs::create_dir_all(target).await?;
+
+    // files at the top of `target` share a subtree, keyed ""
+    let mut subtrees: BTreeMap<&str, Vec<_>> = BTreeMap::new();
+    let mut links = Vec::new();
+    for (path, entry) in state.iter().filter(|(path, _)| keep(path)) {
+        let dest = safe_join(target, path)?;
+        if let Some(link) = entry.symlink {
+            links.push((path, dest, link));
+            continue;
+        }
+        let top = path.split_once('/').map_or("", |(top, _)| top);
+        subtrees.entry(top).or_default().push((path, dest, entry));
+    }
+
+    let fetch = &fetch;
+    let mut written = stream::iter(subtrees.into_values())
+        .map(|files| write_subtree(files, fetch))
+        .buffer_unordered(MATERIALIZE_TASKS)
+        .try_fold(0, async |written, files| Ok(written + files))
+        .await?;
+    for (path, dest, link) in links {
+        refuse_symlinked_parents(target, path).await?;
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        create_symlink(&link, &dest).await?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Writes the files of one subtree, in order. Returns how many there were.
+async fn write_subtree<F>(files: Vec<(&str, PathBuf, Entry)>, fetch: &F) -> Result<usize>
+where
+    F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+{
+    for (path, dest, entry) in &files {
+        write_read_only(path, dest, entry, fetch).await?;
+    }
+    Ok(files.len())
+}
+
+/// Fetches the content of the file at `path` and writes it to `dest`,
+/// read-only.
+async fn write_read_only<F>(path: &str, dest: &Path, entry: &Entry, mut fetch: &F) -> Result<()>
+where
+    F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+{
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let data = read_entry(entry, &mut fetch)
+        .await
+        .with_context(|| format!("Failed to fetch content of {}", path))?;
+    if data.len() as u64 != entry.size {
+        bail!("Content of {} is {} bytes, expected {}", path, data.len(), entry.size);
+    }
+    let mut file = tokio::fs::File::create(dest).await?;
+    file.write_all(&data).await?;
+    file.into_std().await.set_modified(system_time(entry.mtime))?;
+    tokio::fs::set_permissions(dest, read_only(tokio::fs::metadata(dest).await?.permissions(), entry.mode)).await?;
+    Ok(())
+}
+
+/// `permissions` with `mode` minus its write bits.
+#[cfg(unix)]
+fn read_only(mut permissions: std::fs::Permissions, mode: u32) -> std::fs::Permissions {
+    use std::os::unix::fs::PermissionsExt;
+    permissions.set_mode(mode & !0o222);
+    permissions
+}
+
+#[cfg(not(unix))]
+fn read_only(mut permissions: std::fs::Permissions, _mode: u32) -> std::fs::Permissions {
+    permissions.set_readonly(true);
+    permissions
+}
+
+/// An mtime in seconds since the Unix epoch.
+pub(crate) fn system_time(mtime: i64) -> SystemTime {
+    if mtime >= 0 {
+        UNIX_EPOCH + Duration::from_secs(mtime as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs())
+    }
+}
+
+#[cfg(unix)]
+pub(crate) async fn create_symlink(link: &str, dest: &Path) -> Result<()> {
+    tokio::fs::symlink(link, dest)
+        .await
+        .with_context(|| format!("Failed to create symlink {}", dest.display()))
+}
+
+/// Windows tells file and directory symlinks apart, so the target is
+/// looked at; one that doesn't exist yet gets a file symlink. Its
+/// separators are turned into `\`, which Windows resolves links with.
+#[cfg(windows)]
+pub(crate) async fn create_symlink(link: &str, dest: &Path) -> Result<()> {
+    let link = &link.replace('/', "\\");
+    let target = dest.parent().unwrap_or(Path::new("")).join(link);
+    let created = match tokio::fs::metadata(&target).await {
+        Ok(metadata) if metadata.is_dir() => tokio::fs::symlink_dir(link, dest).await,
+        _ => tokio::fs::symlink_file(link, dest).await,
+    };
+    created.with_context(|| format!("Failed to create symlink {}", dest.display()))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) async fn create_symlink(_link: &str, dest: &Path) -> Result<()> {
+    bail!("Symlinks aren't supported on this platform: {}", dest.display())
+}
+
+/// Whether `err`, from `create_symlink`, means this process can't create
+/// symlinks at all rather than this one: on Windows without the privilege
+/// or developer mode, or on a platform without symlinks.
+#[cfg(unix)]
+pub(crate) fn symlinks_unavailable(_err: &anyhow::Error) -> bool {
+    false
+}
+
+#[cfg(windows)]
+pub(crate) fn symlinks_unavailable(err: &anyhow::Error) -> bool {
+    // ERROR_PRIVILEGE_NOT_HELD
+    err.root_cause().downcast_ref::<std::io::Error>().is_some_and(|err| err.raw_os_error() == Some(1314))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn symlinks_unavailable(_err: &anyhow::Error) -> bool {
+    true
+}
+
+impl Replica {
+    /// Materializes the directory as it was at the snapshot or op node `at`
+    /// into `target` (read-only), leaving the replica's own state untouched.
+    /// Returns the number of files written.
+    pub async fn checkout_historical<F>(&self, at: &IpfsCid, target: &Path, fetch: F) -> Result<usize>
+    where
+        F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let state = self.state_at(at)?;
+        materialize_read_only(&state, target, fetch).await
+    }
+
+    /// `checkout_historical` fetching content from the IPFS daemon at
+    /// `base_url`, decrypting it if the replica has a workspace key.
+    pub async fn checkout_historical_from(&self, base_url: &str, at: &IpfsCid, target: &Path) -> Result<usize> {
+        self.checkout_historical(at, target, async |cid| self.fetch_content(base_url, &cid).await)
+            .await
+    }
+
+    /// Materializes the current state into `target` (read-only), fetching
+    /// content from the IPFS daemon at `base_url`.
+    pub async fn checkout_from(&self, base_url: &str, target: &Path) -> Result<usize> {
+        materialize_read_only(self.state(), target, async |cid| self.fetch_content(base_url, &cid).await).await
+    }
+
+    /// Reads the file content `cid` from the daemon at `base_url`,
+    /// decrypting it if the replica has a workspace key.
+    pub(crate) async fn fetch_content(&self, base_url: &str, cid: &IpfsCid) -> Result<Vec<u8>> {
+        match self.workspace_key() {
+            Some(key) => get_sealed(base_url, key, cid).await,
+            None => cat(base_url, cid).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod materialize_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use crate::test_util::{alice, entry};
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_safe_join_rejects_escapes() {
+        let root = Path::new("/tmp/root");
+        assert!(safe_join(root, "a/b.txt").is_ok());
+        assert!(safe_join(root, "../etc/passwd").is_err());
+        assert!(safe_join(root, "/etc/passwd").is_err());
+        assert!(safe_join(root, "a/./b").is_ok());
+        assert!(safe_join(root, "").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_materialize_creates_symlinks() {
+        let mut replica = Replica::new(alice());
+        replica.put("dir/file.txt", entry(b"data")).unwrap();
+        replica.put("dir/link", Entry::symlink("file.txt", 0)).unwrap();
+        let data = b"data".to_vec();
+        let fetch = async |cid: IpfsCid| {
+            (cid == IpfsCid::compute(RAW_CODE, &data)).then(|| data.clone()).ok_or_else(|| anyhow!("missing {}", cid))
+        };
+
+        let target = std::env::temp_dir().join(format!("crdt-symlink-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&target);
+        assert_eq!(materialize_read_only(replica.state(), &target, fetch).await.unwrap(), 2);
+        assert_eq!(std::fs::read_link(target.join("dir/link")).unwrap(), Path::new("file.txt"));
+        assert_eq!(std::fs::read(target.join("dir/link")).unwrap(), b"data");
+        std::fs::remove_dir_all(&target).unwrap();
+
+        // a link can't take the place of a directory files were written into
+        replica.put("dir", Entry::symlink("/tmp", 0)).unwrap();
+        assert!(materialize_read_only(replica.state(), &target, fetch).await.is_err());
+        assert!(std::fs::symlink_metadata(target.join("dir")).unwrap().is_dir());
+        std::fs::remove_dir_all(&target).unwrap();
+
+        // nor can a link be created through another
+        let mut replica = Replica::new(alice());
+        replica.put("a", Entry::symlink("..", 0)).unwrap();
+        replica.put("a/b", Entry::symlink("anything", 0)).unwrap();
+        let outer = std::env::temp_dir().join(format!("crdt-symlink-escape-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&outer);
+        let target = outer.join("tree");
+        assert!(materialize_read_only(replica.state(), &target, fetch).await.is_err());
+        assert!(std::fs::symlink_metadata(outer.join("b")).is_err());
+        std::fs::remove_dir_all(&outer).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_materialize_writes_subtrees_at_once() {
+        let mut replica = Replica::new(alice());
+        replica.put("a/file.txt", entry(b"a")).unwrap();
+        replica.put("b/file.txt", entry(b"b")).unwrap();
+        let blobs: HashMap<IpfsCid, Vec<u8>> =
+            [b"a".to_vec(), b"b".to_vec()].into_iter().map(|data| (IpfsCid::compute(RAW_CODE, &data), data)).collect();
+        // neither fetch finishes until the other subtree's has started
+        let barrier = tokio::sync::Barrier::new(2);
+        let fetch = async |cid: IpfsCid| {
+            barrier.wait().await;
+            blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid))
+        };
+
+        let target = std::env::temp_dir().join(format!("crdt-subtrees-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&target);
+        let written = tokio::time::timeout(Duration::from_secs(10), materialize_read_only(replica.state(), &target, fetch))
+            .await
+            .expect("subtrees were written one after another")
+            .unwrap();
+        assert_eq!(written, 2);
+        assert_eq!(std::fs::read(target.join("b/file.txt")).unwrap(), b"b");
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkout_historical_op() {
+        let mut replica = Replica::new(alice()).with_snapshot_interval(2);
+        let blobs: HashMap<IpfsCid, Vec<u8>> = [b"old".to_vec(), b"new".to_vec(), b"keep".to_vec()]
+            .into_iter()
+            .map(|data| (IpfsCid::compute(RAW_CODE, &data), data))
+            .collect();
+
+        replica.put("dir/file.txt", entry(b"old")).unwrap();
+        replica.put("keep.txt", entry(b"keep")).unwrap();
+        let before = replica.put("gone.txt", entry(b"keep")).unwrap();
+        replica.put("dir/file.txt", entry(b"new")).unwrap();
+        replica.remove("gone.txt").unwrap();
+
+        let target = std::env::temp_dir().join(format!("crdt-checkout-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&target);
+        let written = replica
+            .checkout_historical(&before, &target, async |cid| {
+                blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid))
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(written, 3);
+        assert_eq!(std::fs::read(target.join("dir/file.txt")).unwrap(), b"old");
+        assert!(std::fs::metadata(target.join("gone.txt")).unwrap().permissions().readonly());
+        assert_eq!(replica.state().get("dir/file.txt"), Some(entry(b"new")));
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+}
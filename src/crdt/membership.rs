@@ -0,0 +1,134 @@
+use anyhow::{bail, Result};
+
+use super::identity::ReplicaId;
+use super::op::{Membership, OpKind};
+use super::replica::Replica;
+use super::state::State;
+use super::wire::Node;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+impl Replica {
+    /// Adds `member` to the membership document, expecting its nodes to be
+    /// signed by `signer`. The first member added also enrolls this replica
+    /// itself, after which merges only accept heads written by members.
+    pub fn add_member(&mut self, member: ReplicaId, signer: &[u8]) -> Result<IpfsCid> {
+        if self.state().member(self.author()).is_none() && member != *self.author() {
+            let own = Membership::Active { signer: self.keypair().public_key().to_vec() };
+            self.commit(OpKind::SetMember { member: self.author().clone(), membership: own })?;
+        }
+        let membership = Membership::Active { signer: signer.to_vec() };
+        self.commit(OpKind::SetMember { member, membership })
+    }
+
+    /// Removes `member`. Operations of theirs this replica has already seen
+    /// stay valid; later ones are rejected. Returns `None` if `member`
+    /// wasn't an active member.
+    pub fn remove_member(&mut self, member: &ReplicaId) -> Result<Option<IpfsCid>> {
+        if member == self.author() {
+            bail!("A replica can't remove itself from the workspace");
+        }
+        let Some(Membership::Active { signer }) = self.state().member(member) else {
+            return Ok(None);
+        };
+        let membership = Membership::Removed {
+            signer: signer.clone(),
+            last_seq: self.state().version_vector().get(member),
+        };
+        self.commit(OpKind::SetMember { member: member.clone(), membership }).map(Some)
+    }
+
+    /// Iterates over the active members, in id order.
+    pub fn members(&self) -> impl Iterator<Item = &ReplicaId> {
+        self.state()
+            .members()
+            .filter(|(_, membership)| matches!(membership, Membership::Active { .. }))
+            .map(|(member, _)| member)
+    }
+}
+
+/// Checks that `node` was written by a member allowed to write it according
+/// to the membership document in `state`.
+pub(crate) fn admit(state: &State, node: &Node) -> Result<()> {
+    let author = node.author();
+    let seq = match node {
+        Node::Op(op_node) => op_node.op.seq,
+        Node::Snapshot(snapshot) => snapshot.version_vector().get(author),
+    };
+    match state.member(author) {
+        None => bail!("{} is not a member of the workspace", author),
+        Some(membership) if membership.signer() != node.signer() => {
+            bail!("not signed by the member key of {}", author)
+        }
+        Some(membership) if !membership.admits(node.signer(), seq) => {
+            bail!("{} was removed from the workspace before writing operation {}", author, seq)
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod membership_test {
+    use super::*;
+    use crate::test_util::{alice, bob, carol, entry};
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+
+    async fn merge(into: &mut Replica, from: &mut Replica) -> Result<usize> {
+        let mut store = HashMap::new();
+        from.push(async |cid, bytes| {
+            store.insert(cid, bytes);
+            Ok(())
+        })
+        .await?;
+        let head = from.heads()[0].clone();
+        into.merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid)))
+            .await
+    }
+
+    #[test]
+    fn test_first_member_enrolls_self() {
+        let mut a = Replica::new(alice());
+        a.add_member(bob(), &[2; 32]).unwrap();
+        assert_eq!(a.members().cloned().collect::<Vec<_>>(), vec![alice(), bob()]);
+
+        assert!(a.remove_member(&alice()).is_err());
+        assert!(a.remove_member(&bob()).unwrap().is_some());
+        assert!(a.remove_member(&bob()).unwrap().is_none());
+        assert_eq!(a.members().cloned().collect::<Vec<_>>(), vec![alice()]);
+    }
+
+    #[tokio::test]
+    async fn test_merge_only_accepts_members() {
+        let mut a = Replica::new(alice());
+        let mut b = Replica::new(bob());
+        let mut c = Replica::new(carol());
+        a.add_member(bob(), &b.keypair().public_key()).unwrap();
+        merge(&mut b, &mut a).await.unwrap();
+
+        b.put("from-bob", entry(b"b")).unwrap();
+        c.put("from-carol", entry(b"c")).unwrap();
+        assert_eq!(merge(&mut a, &mut b).await.unwrap(), 1);
+        assert_eq!(merge(&mut a, &mut c).await.unwrap(), 0);
+        let (_, quarantined) = a.quarantined().next().unwrap();
+        assert!(quarantined.reason.contains("not a member"), "{}", quarantined.reason);
+        assert_eq!(a.state().get("from-bob"), Some(entry(b"b")));
+        assert_eq!(a.state().get("from-carol"), None);
+    }
+
+    #[tokio::test]
+    async fn test_removed_member_keeps_earlier_ops_only() {
+        let mut a = Replica::new(alice());
+        let mut b = Replica::new(bob());
+        a.add_member(bob(), &b.keypair().public_key()).unwrap();
+        merge(&mut b, &mut a).await.unwrap();
+        b.put("before", entry(b"1")).unwrap();
+        merge(&mut a, &mut b).await.unwrap();
+
+        a.remove_member(&bob()).unwrap();
+        b.put("after", entry(b"2")).unwrap();
+        assert_eq!(merge(&mut a, &mut b).await.unwrap(), 0);
+        assert_eq!(a.quarantined().count(), 1);
+        assert_eq!(a.state().get("before"), Some(entry(b"1")));
+        assert_eq!(a.state().get("after"), None);
+    }
+}
@@ -0,0 +1,274 @@
+use anyhow::{Context, Result};
+
+use super::chunk::read_file;
+use super::op::Content;
+use super::replica::Replica;
+use super::sniff::{kind_of_path, sniff, ContentKind};
+use super::state::lww_cmp;
+use crate::crypto::get_sealed;
+use crate::kubo_rpc::ipfs::{cat, IpfsCid, DAG_CBOR_CODE};
+
+/// Outcome of a three-way merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Merged {
+    /// Every change applied without overlapping another.
+    Clean(Vec<u8>),
+    /// Overlapping changes are kept side by side between conflict markers.
+    Conflicted(Vec<u8>),
+}
+
+impl Merged {
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Merged::Clean(bytes) | Merged::Conflicted(bytes) => bytes,
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        matches!(self, Merged::Clean(_))
+    }
+}
+
+/// Most lines a side of `merge3` may have: matching them takes a table
+/// quadratic in the line count.
+pub const MAX_MERGE_LINES: usize = 4096;
+
+/// Largest version `merge_content` reads to merge.
+pub const MAX_MERGE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// diff3-style line merge of `ours` and `theirs`, which both changed
+/// `base`. A region only one side changed takes that side; a region both
+/// changed the same way takes the change once. `None` if a side has more
+/// than `MAX_MERGE_LINES` lines.
+pub fn merge3(base: &[u8], ours: &[u8], theirs: &[u8]) -> Option<Merged> {
+    let base: Vec<&[u8]> = base.split_inclusive(|b| *b == b'\n').collect();
+    let ours: Vec<&[u8]> = ours.split_inclusive(|b| *b == b'\n').collect();
+    let theirs: Vec<&[u8]> = theirs.split_inclusive(|b| *b == b'\n').collect();
+    if [&base, &ours, &theirs].iter().any(|lines| lines.len() > MAX_MERGE_LINES) {
+        return None;
+    }
+    let in_ours = matching(&base, &ours);
+    let in_theirs = matching(&base, &theirs);
+
+    let mut out = Vec::new();
+    let mut clean = true;
+    let (mut i, mut a, mut b) = (0, 0, 0);
+    while i < base.len() || a < ours.len() || b < theirs.len() {
+        if i < base.len() && in_ours[i] == Some(a) && in_theirs[i] == Some(b) {
+            out.extend_from_slice(base[i]);
+            (i, a, b) = (i + 1, a + 1, b + 1);
+            continue;
+        }
+        // the next base line both sides kept closes the unstable region
+        let next = (i..base.len()).find(|&j| in_ours[j].is_some() && in_theirs[j].is_some());
+        let (j, a_end, b_end) = match next {
+            Some(j) => (j, in_ours[j].unwrap(), in_theirs[j].unwrap()),
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+        let (o, x, t) = (&base[i..j], &ours[a..a_end], &theirs[b..b_end]);
+        if x == o || x == t {
+            t.iter().for_each(|line| out.extend_from_slice(line));
+        } else if t == o {
+            x.iter().for_each(|line| out.extend_from_slice(line));
+        } else {
+            clean = false;
+            push_side(&mut out, b"<<<<<<< ours\n", x);
+            push_side(&mut out, b"=======\n", t);
+            out.extend_from_slice(b">>>>>>> theirs\n");
+        }
+        (i, a, b) = (j, a_end, b_end);
+    }
+    Some(if clean { Merged::Clean(out) } else { Merged::Conflicted(out) })
+}
+
+fn push_side(out: &mut Vec<u8>, marker: &[u8], lines: &[&[u8]]) {
+    out.extend_from_slice(marker);
+    lines.iter().for_each(|line| out.extend_from_slice(line));
+    if !out.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+}
+
+/// For each line of `base`, the line of `other` it is matched with by a
+/// longest common subsequence. Quadratic; meant for text files.
+fn matching(base: &[&[u8]], other: &[&[u8]]) -> Vec<Option<usize>> {
+    let (n, m) = (base.len(), other.len());
+    // lcs[i][j]: LCS length of base[i..] and other[j..]
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if base[i] == other[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let mut matched = vec![None; n];
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if base[i] == other[j] {
+            matched[i] = Some(j);
+            (i, j) = (i + 1, j + 1);
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    matched
+}
+
+impl Replica {
+    /// Three-way merges the concurrent contents at `path` against their
+    /// merge base (see `PathRegister::merge_base`), fetching content with
+    /// `fetch`. The last-writer-wins pick is "ours". `None` if the content
+    /// isn't conflicted or has no recorded base, the base was chunked and
+    /// its chunk list is no longer known, or a version is a symlink, whose
+    /// target stays last-writer-wins, binary (see `sniff`), or too big to
+    /// merge (see `MAX_MERGE_SIZE` and `merge3`), which keeps the pick with
+    /// the other versions as conflict copies. Putting the result supersedes
+    /// every merged version.
+    pub async fn merge_content<F>(&self, path: &str, mut fetch: F) -> Result<Option<Merged>>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let Some(register) = self.state().register(path) else {
+            return Ok(None);
+        };
+        let Some(base) = register.merge_base() else {
+            return Ok(None);
+        };
+        let unmergeable = |content: &Content| content.symlink.is_some() || content.size > MAX_MERGE_SIZE;
+        if register.content.versions().iter().any(|version| unmergeable(&version.value))
+            || kind_of_path(path) == Some(ContentKind::Binary)
+        {
+            return Ok(None);
+        }
+        let base_chunks = match base.0.codec() {
+            DAG_CBOR_CODE => match self.chunks_of(base) {
+                Some(chunks) => chunks,
+                None => return Ok(None),
+            },
+            _ => Vec::new(),
+        };
+        let base = read_file(base, &base_chunks, &mut fetch)
+            .await
+            .with_context(|| format!("Failed to fetch merge base of {}", path))?;
+        if sniff(path, &base) == ContentKind::Binary || base.len() as u64 > MAX_MERGE_SIZE {
+            return Ok(None);
+        }
+        let mut versions: Vec<_> = register.content.versions().iter().collect();
+        versions.sort_by(|a, b| lww_cmp(b, a));
+
+        let read = async |content: &Content, fetch: &mut F| read_file(&content.content, &content.chunks, fetch).await;
+        let ours = read(&versions[0].value, &mut fetch).await?;
+        if sniff(path, &ours) == ContentKind::Binary {
+            return Ok(None);
+        }
+        let mut merged = Merged::Clean(ours);
+        for version in &versions[1..] {
+            let theirs = read(&version.value, &mut fetch).await?;
+            if sniff(path, &theirs) == ContentKind::Binary {
+                return Ok(None);
+            }
+            merged = match merge3(&base, merged.bytes(), &theirs) {
+                Some(Merged::Clean(bytes)) if merged.is_clean() => Merged::Clean(bytes),
+                Some(other) => Merged::Conflicted(other.bytes().to_vec()),
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(merged))
+    }
+
+    /// `merge_content` fetching content from the IPFS daemon at
+    /// `base_url`, decrypting it if the replica has a workspace key.
+    pub async fn merge_content_from(&self, base_url: &str, path: &str) -> Result<Option<Merged>> {
+        match self.workspace_key() {
+            Some(key) => self.merge_content(path, async |cid| get_sealed(base_url, key, &cid).await).await,
+            None => self.merge_content(path, async |cid| cat(base_url, &cid).await).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod merge3_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use crate::test_util::{alice, bob, entry};
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_merge3_takes_each_sides_changes() {
+        let base = b"a\nb\nc\nd\n";
+        let merged = merge3(base, b"A\nb\nc\nd\n", b"a\nb\nc\nD\ne\n");
+        assert_eq!(merged, Some(Merged::Clean(b"A\nb\nc\nD\ne\n".to_vec())));
+        let same = merge3(base, b"a\nX\nc\nd\n", b"a\nX\nc\nd\n");
+        assert_eq!(same, Some(Merged::Clean(b"a\nX\nc\nd\n".to_vec())));
+        let long = b"line\n".repeat(MAX_MERGE_LINES + 1);
+        assert_eq!(merge3(base, &long, base), None);
+    }
+
+    #[test]
+    fn test_merge3_marks_overlapping_changes() {
+        let merged = merge3(b"a\nb\nc\n", b"a\nours\nc\n", b"a\ntheirs\nc\n");
+        let expected = b"a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\n";
+        assert_eq!(merged, Some(Merged::Conflicted(expected.to_vec())));
+    }
+
+    #[tokio::test]
+    async fn test_merge_content_uses_common_ancestor() {
+        let alice = alice();
+        let bob = bob();
+        let mut blobs = HashMap::new();
+        let mut write = |replica: &mut Replica, data: &'static [u8]| {
+            let content = IpfsCid::compute(RAW_CODE, data);
+            blobs.insert(content, data.to_vec());
+            replica.put("notes.txt", entry(data)).unwrap();
+        };
+        let mut a = Replica::new(alice);
+        let mut b = Replica::new(bob);
+        write(&mut a, b"one\ntwo\nthree\nfour\n");
+        b.apply_delta(&a.delta_since(b.state().version_vector())).unwrap();
+        // a edits twice, so the merge base isn't a's direct parent
+        write(&mut a, b"ONE\ntwo\nthree\nfour\n");
+        write(&mut a, b"ONE\ntwo\nthree\nfour\nfive\n");
+        write(&mut b, b"one\ntwo\nTHREE\nfour\n");
+        a.apply_delta(&b.delta_since(a.state().version_vector())).unwrap();
+
+        let merged = a
+            .merge_content("notes.txt", async |cid| blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(merged, Merged::Clean(b"ONE\ntwo\nTHREE\nfour\nfive\n".to_vec()));
+
+        a.put("notes.txt", entry(merged.bytes())).unwrap();
+        assert!(!a.state().register("notes.txt").unwrap().is_conflicted());
+    }
+
+    #[tokio::test]
+    async fn test_merge_content_leaves_binary_alone() {
+        let alice = alice();
+        let bob = bob();
+        let mut blobs = HashMap::new();
+        let mut write = |replica: &mut Replica, path: &str, data: &'static [u8]| {
+            let content = IpfsCid::compute(RAW_CODE, data);
+            blobs.insert(content, data.to_vec());
+            replica.put(path, entry(data)).unwrap();
+        };
+        let mut a = Replica::new(alice);
+        let mut b = Replica::new(bob);
+        // both would merge cleanly line by line
+        write(&mut a, "blob", b"one\n\0\ntwo\n");
+        write(&mut a, "image.png", b"one\ntwo\n");
+        b.apply_delta(&a.delta_since(b.state().version_vector())).unwrap();
+        write(&mut a, "blob", b"ONE\n\0\ntwo\n");
+        write(&mut a, "image.png", b"ONE\ntwo\n");
+        write(&mut b, "blob", b"one\n\0\nTWO\n");
+        write(&mut b, "image.png", b"one\nTWO\n");
+        a.apply_delta(&b.delta_since(a.state().version_vector())).unwrap();
+
+        for path in ["blob", "image.png"] {
+            let fetch = async |cid: IpfsCid| blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing"));
+            assert_eq!(a.merge_content(path, fetch).await.unwrap(), None, "{}", path);
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use super::chunk::{ChunkRule, Chunker};
+use super::clock::{Dot, Hlc, VersionVector};
+use super::identity::ReplicaId;
+use super::policy::FilePolicy;
+use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+
+/// Length of `Op::hash`.
+pub const OP_HASH_LENGTH: usize = 32;
+
+/// One piece of a chunked file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub content: IpfsCid,
+    pub size: u64,
+}
+
+/// Metadata and content pointer for a single file in the directory.
+/// - `content`: the file as a single blob, or for a chunked file the id of
+///   its chunk list (see `chunk::chunk_list_id`).
+/// - `chunks`: the pieces of a chunked file in order, empty for a single
+///   blob. Left out of the encoding when empty.
+/// - `symlink`: the target if the entry is a symbolic link, whose
+///   `content` is then the raw CID of the target and `size` its length.
+///   Left out of the encoding for regular files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub content: IpfsCid,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: i64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<Chunk>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+}
+
+impl Entry {
+    /// A symbolic link to `target`. Its content never needs storing.
+    pub fn symlink(target: &str, mtime: i64) -> Self {
+        Entry {
+            content: IpfsCid::compute(RAW_CODE, target.as_bytes()),
+            size: target.len() as u64,
+            mode: 0o777,
+            mtime,
+            chunks: Vec::new(),
+            symlink: Some(target.to_string()),
+        }
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.symlink.is_some()
+    }
+}
+
+/// Most content versions a write records as its lineage.
+pub const MAX_LINEAGE: usize = 16;
+
+/// A content version a write descends from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ancestor {
+    pub dot: Dot,
+    pub timestamp: Hlc,
+    pub content: IpfsCid,
+}
+
+/// A content pointer and its size, which always change together.
+/// - `lineage`: the content versions the writer had seen at the path,
+///   newest first, so concurrent versions can find their merge base.
+/// - `chunks`, `symlink`: as in `Entry`. A link's target is its content,
+///   so concurrent retargets resolve last-writer-wins like file contents.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Content {
+    pub content: IpfsCid,
+    pub size: u64,
+    pub lineage: Vec<Ancestor>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<Chunk>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+}
+
+/// The fields an `OpKind::Patch` changes; `None` leaves a field alone.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryPatch {
+    pub content: Option<Content>,
+    pub mode: Option<u32>,
+    pub mtime: Option<i64>,
+    /// Extended attributes to set, or with `None` to delete. Left out of the
+    /// encoding when empty.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, Option<ByteBuf>>,
+}
+
+impl EntryPatch {
+    /// What an edit of a file with mode `mode` to `entry` changes: content
+    /// and mtime, and the mode only if it differs, so a concurrent change
+    /// of the mode survives. The lineage is filled in on commit.
+    pub fn edit(mode: u32, entry: Entry) -> Self {
+        EntryPatch {
+            content: Some(Content {
+                content: entry.content,
+                size: entry.size,
+                lineage: Vec::new(),
+                chunks: entry.chunks,
+                symlink: entry.symlink,
+            }),
+            mode: (entry.mode != mode).then_some(entry.mode),
+            mtime: Some(entry.mtime),
+            xattrs: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.content.is_none() && self.mode.is_none() && self.mtime.is_none() && self.xattrs.is_empty()
+    }
+}
+
+/// A collaborator's standing in the membership document.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Membership {
+    /// May write; nodes must be signed by `signer`.
+    Active {
+        #[serde(with = "serde_bytes")]
+        signer: Vec<u8>,
+    },
+    /// Removed after writing `last_seq` operations. Those stay valid,
+    /// anything later is rejected.
+    Removed {
+        #[serde(with = "serde_bytes")]
+        signer: Vec<u8>,
+        last_seq: u64,
+    },
+}
+
+impl Membership {
+    pub fn signer(&self) -> &[u8] {
+        match self {
+            Membership::Active { signer } => signer,
+            Membership::Removed { signer, .. } => signer,
+        }
+    }
+
+    /// Returns true if the member's `seq`-th operation, signed by `signer`,
+    /// is accepted.
+    pub fn admits(&self, signer: &[u8], seq: u64) -> bool {
+        match self {
+            Membership::Active { signer: expected } => expected.as_slice() == signer,
+            Membership::Removed { signer: expected, last_seq } => expected.as_slice() == signer && seq <= *last_seq,
+        }
+    }
+}
+
+/// What an author may write once the access-control document lists anyone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Access {
+    /// Any path, workspace settings and snapshots included.
+    Write,
+    /// Files under these path prefixes only.
+    WriteUnder { prefixes: Vec<String> },
+    /// Nothing; every op is rejected.
+    ReadOnly,
+}
+
+/// How concurrent writes to the same path resolve. Recorded in the CRDT so
+/// every replica of a workspace applies the same policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConflictPolicy {
+    /// The write with the latest timestamp wins, puts and removes alike.
+    #[default]
+    LastWriterWins,
+    /// A put concurrent with a remove wins; re-adding a file undoes a
+    /// concurrent deletion.
+    AddWins,
+    /// A remove concurrent with a put wins.
+    RemoveWins,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OpKind {
+    /// Create or overwrite the file at `path`. `lineage` as in `Content`.
+    Put { path: String, entry: Entry, lineage: Vec<Ancestor> },
+    /// Delete the file at `path`.
+    Remove { path: String },
+    /// Change some fields of the file at `path`. Each field merges on its
+    /// own, so concurrent patches to different fields all survive.
+    Patch { path: String, patch: EntryPatch },
+    /// Add, re-key or remove a collaborator.
+    SetMember { member: ReplicaId, membership: Membership },
+    /// Change how concurrent puts and removes resolve.
+    SetConflictPolicy { policy: ConflictPolicy },
+    /// Grant or revoke `member`'s write permission.
+    SetAccess { member: ReplicaId, access: Access },
+    /// Change how every replica of the workspace chunks files.
+    SetChunker {
+        chunker: Chunker,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        rules: Vec<ChunkRule>,
+    },
+    /// Change which files no replica of the workspace syncs.
+    SetFilePolicy { policy: FilePolicy },
+    /// Several puts, removes and patches under one dot, so every replica
+    /// applies all of them or none. Applied in order.
+    Transaction { ops: Vec<OpKind> },
+}
+
+impl OpKind {
+    /// The file the operation touches, `None` for workspace settings and
+    /// transactions.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            OpKind::Put { path, .. } => Some(path),
+            OpKind::Remove { path } | OpKind::Patch { path, .. } => Some(path),
+            OpKind::SetMember { .. }
+            | OpKind::SetConflictPolicy { .. }
+            | OpKind::SetAccess { .. }
+            | OpKind::SetChunker { .. }
+            | OpKind::SetFilePolicy { .. }
+            | OpKind::Transaction { .. } => None,
+        }
+    }
+
+    /// Every file the operation touches, in order.
+    pub fn paths(&self) -> Vec<&str> {
+        match self {
+            OpKind::Transaction { ops } => ops.iter().filter_map(OpKind::path).collect(),
+            other => other.path().into_iter().collect(),
+        }
+    }
+
+    /// The single-path changes the operation makes: itself, or the ops of
+    /// a transaction.
+    pub fn parts(&self) -> &[OpKind] {
+        match self {
+            OpKind::Transaction { ops } => ops,
+            other => std::slice::from_ref(other),
+        }
+    }
+}
+
+/// A single CRDT operation.
+/// - `author`/`seq`: identify the operation (see `Dot`).
+/// - `timestamp`: HLC time used for last-writer-wins resolution.
+/// - `context`: everything the author had seen when writing it; versions
+///   covered by the context are overwritten, the rest are concurrent.
+/// - `reverts`: the author's earlier ops this one undoes, see
+///   `Replica::undo_last`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Op {
+    pub author: ReplicaId,
+    pub seq: u64,
+    pub timestamp: Hlc,
+    pub context: VersionVector,
+    pub kind: OpKind,
+    /// Sequence numbers, ascending. Left out of the encoding when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reverts: Vec<u64>,
+}
+
+impl Op {
+    pub fn dot(&self) -> Dot {
+        Dot { author: self.author.clone(), seq: self.seq }
+    }
+
+    /// SHA-256 of the op's canonical DAG-CBOR encoding. Independent of the
+    /// node it travels in, so every replica computes the same hash.
+    pub fn hash(&self) -> Vec<u8> {
+        let bytes = serde_ipld_dagcbor::to_vec(self).expect("ops contain no floats or non-string map keys");
+        Sha256::digest(bytes).to_vec()
+    }
+}
@@ -0,0 +1,71 @@
+//! File policies: limits a workspace puts on what its members sync,
+//! recorded in the CRDT so every replica keeps to the same ones. A scan
+//! leaves out files above the size cap or matching a skipped glob, and
+//! reports each with the reason; files the workspace has already keep
+//! their last synced version, and checkouts still write them.
+
+use serde::{Deserialize, Serialize};
+
+use super::chunk::compile_glob;
+
+/// What a workspace doesn't sync.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilePolicy {
+    /// Largest file synced, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// Globs over the whole path of files never synced, such as
+    /// `**/*.iso`; `*` stops at `/` and `**` doesn't.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip: Vec<String>,
+}
+
+/// Why a policy leaves a file out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Larger than the size cap, in bytes.
+    TooLarge { limit: u64 },
+    /// Matched by this glob.
+    Skipped { glob: String },
+}
+
+impl FilePolicy {
+    pub fn is_empty(&self) -> bool {
+        self.max_size.is_none() && self.skip.is_empty()
+    }
+
+    /// Why the policy leaves out a file of `size` bytes at `path`, if it
+    /// does; a skipped glob is reported over the size cap.
+    pub fn skips(&self, path: &str, size: u64) -> Option<SkipReason> {
+        if let Some(glob) = self.skip.iter().find(|glob| compile_glob(glob).is_ok_and(|glob| glob.is_match(path))) {
+            return Some(SkipReason::Skipped { glob: glob.clone() });
+        }
+        self.max_size.filter(|limit| size > *limit).map(|limit| SkipReason::TooLarge { limit })
+    }
+}
+
+#[cfg(test)]
+mod policy_test {
+    use super::*;
+    use crate::crdt::replica::Replica;
+    use crate::test_util::{alice, bob};
+
+    #[test]
+    fn test_file_policy_applies_to_every_replica() {
+        let alice = alice();
+        let bob = bob();
+        let mut a = Replica::new(alice);
+        let mut b = Replica::new(bob);
+        let invalid = FilePolicy { max_size: None, skip: vec!["[".to_string()] };
+        assert!(a.set_file_policy(invalid).is_err());
+        let policy = FilePolicy { max_size: Some(1024), skip: vec!["**/*.iso".to_string()] };
+        a.set_file_policy(policy.clone()).unwrap();
+        b.apply_delta(&a.delta_since(b.state().version_vector())).unwrap();
+        assert_eq!(b.state().file_policy(), policy);
+
+        let policy = b.state().file_policy();
+        assert_eq!(policy.skips("images/disk.iso", 10), Some(SkipReason::Skipped { glob: "**/*.iso".to_string() }));
+        assert_eq!(policy.skips("video.mp4", 4096), Some(SkipReason::TooLarge { limit: 1024 }));
+        assert_eq!(policy.skips("notes.md", 1024), None);
+    }
+}
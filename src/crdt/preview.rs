@@ -0,0 +1,120 @@
+use anyhow::Result;
+
+use super::replica::Replica;
+use super::state::State;
+use crate::kubo_rpc::ipfs::{get_block, IpfsCid};
+
+/// What a merge would do to the directory, path by path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    pub added: Vec<String>,
+    pub modified: Vec<String>,
+    pub deleted: Vec<String>,
+    /// Changed paths left holding concurrent versions, which the conflict
+    /// policy picks between. Not repeated in the lists above.
+    pub conflicted: Vec<String>,
+    /// Operations the merge would apply.
+    pub operations: usize,
+}
+
+impl MergeReport {
+    /// Compares the directory before and after a merge, in path order.
+    pub fn between(before: &State, after: &State) -> Self {
+        let mut report = MergeReport::default();
+        for (path, register) in after.registers() {
+            if before.register(path) == Some(register) {
+                continue;
+            }
+            if register.is_conflicted() {
+                report.conflicted.push(path.to_string());
+                continue;
+            }
+            match (before.get(path), after.get(path)) {
+                (None, Some(_)) => report.added.push(path.to_string()),
+                (Some(_), None) => report.deleted.push(path.to_string()),
+                (Some(old), Some(new)) if old != new => report.modified.push(path.to_string()),
+                _ => {}
+            }
+        }
+        report
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.deleted.is_empty() && self.conflicted.is_empty()
+    }
+}
+
+impl Replica {
+    /// Reports what `merge_head(head, fetch)` would change, without
+    /// changing this replica: the merge runs on a copy of it.
+    pub async fn preview_merge<F>(&self, head: &IpfsCid, fetch: F) -> Result<MergeReport>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let mut merged = self.clone();
+        let operations = merged.merge_head(head, fetch).await?;
+        Ok(MergeReport { operations, ..MergeReport::between(self.state(), merged.state()) })
+    }
+
+    /// `preview_merge` fetching nodes from the IPFS daemon at `base_url`.
+    pub async fn preview_pull_from(&self, base_url: &str, head: &IpfsCid) -> Result<MergeReport> {
+        self.preview_merge(head, async |cid| get_block(base_url, &cid).await).await
+    }
+}
+
+#[cfg(test)]
+mod preview_test {
+    use super::*;
+    use crate::test_util::{alice, bob, entry};
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+
+    async fn publish(replica: &mut Replica, store: &mut HashMap<IpfsCid, Vec<u8>>) {
+        replica
+            .push(async |cid, bytes| {
+                store.insert(cid, bytes);
+                Ok(())
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_preview_reports_changes_without_merging() {
+        let alice = alice();
+        let bob = bob();
+        let mut store = HashMap::new();
+        let mut local = Replica::new(alice);
+        local.put("edit.txt", entry(b"1")).unwrap();
+        local.put("gone.txt", entry(b"1")).unwrap();
+        local.put("both.txt", entry(b"1")).unwrap();
+        publish(&mut local, &mut store).await;
+
+        let mut remote = Replica::new(bob);
+        let head = local.heads()[0].clone();
+        remote
+            .merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        remote.put("new.txt", entry(b"2")).unwrap();
+        remote.put("edit.txt", entry(b"2")).unwrap();
+        remote.remove("gone.txt").unwrap();
+        remote.put("both.txt", entry(b"remote")).unwrap();
+        local.put("both.txt", entry(b"local")).unwrap();
+        publish(&mut remote, &mut store).await;
+
+        let before = local.clone();
+        let head = remote.heads()[0].clone();
+        let report = local
+            .preview_merge(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(report.added, vec!["new.txt"]);
+        assert_eq!(report.modified, vec!["edit.txt"]);
+        assert_eq!(report.deleted, vec!["gone.txt"]);
+        assert_eq!(report.conflicted, vec!["both.txt"]);
+        assert_eq!(report.operations, 4);
+        assert_eq!(local.state(), before.state());
+        assert_eq!(local.heads(), before.heads());
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+use super::op::OpKind;
+use super::replica::Replica;
+use super::state::State;
+
+/// Bounds on the content a replica's own writes may add. `None` is
+/// unlimited. Symlinks store no content and don't count.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Total size of every current file, in bytes.
+    pub max_total_size: Option<u64>,
+    pub max_file_size: Option<u64>,
+}
+
+/// What the current files take up, against the replica's quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub files: usize,
+    pub symlinks: usize,
+    /// Sum of the files' sizes; content shared between files or chunks
+    /// shared between versions counts every time.
+    pub total_size: u64,
+    pub largest_file: u64,
+    pub quota: Quota,
+}
+
+impl Usage {
+    /// Bytes left under `quota.max_total_size`, if set.
+    pub fn remaining(&self) -> Option<u64> {
+        self.quota.max_total_size.map(|max| max.saturating_sub(self.total_size))
+    }
+}
+
+impl State {
+    /// Sizes of the visible entries. Linear in the number of files.
+    pub fn usage(&self) -> Usage {
+        let mut usage = Usage::default();
+        for (_, entry) in self.iter() {
+            if entry.is_symlink() {
+                usage.symlinks += 1;
+                continue;
+            }
+            usage.files += 1;
+            usage.total_size += entry.size;
+            usage.largest_file = usage.largest_file.max(entry.size);
+        }
+        usage
+    }
+
+    /// Size of the file at `path`, 0 for none or a symlink.
+    fn file_size(&self, path: &str) -> u64 {
+        self.get(path).filter(|entry| !entry.is_symlink()).map_or(0, |entry| entry.size)
+    }
+}
+
+impl Replica {
+    pub fn usage(&self) -> Usage {
+        Usage { quota: self.quota(), ..self.state().usage() }
+    }
+
+    /// Rejects a local op that would put a file over `max_file_size` or
+    /// grow the total past `max_total_size`. Remote ops are never rejected,
+    /// so merges can leave the workspace over quota; writes that shrink it
+    /// are still allowed then.
+    pub(crate) fn check_quota(&self, kind: &OpKind) -> Result<()> {
+        if self.quota() == Quota::default() {
+            return Ok(());
+        }
+        // size each touched path ends up with, in op order
+        let mut sizes: HashMap<&str, u64> = HashMap::new();
+        for part in kind.parts() {
+            let (path, size) = match part {
+                OpKind::Put { path, entry, .. } if entry.is_symlink() => (path, 0),
+                OpKind::Put { path, entry, .. } => (path, entry.size),
+                OpKind::Remove { path } => (path, 0),
+                OpKind::Patch { path, patch } => match &patch.content {
+                    Some(content) if content.symlink.is_none() => (path, content.size),
+                    Some(_) => (path, 0),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            self.check_file_size(path, size)?;
+            sizes.insert(path, size);
+        }
+        self.check_total(sizes)
+    }
+
+    pub(crate) fn check_file_size(&self, path: &str, size: u64) -> Result<()> {
+        if let Some(max) = self.quota().max_file_size
+            && size > max
+        {
+            bail!("{} would be {} bytes, more than the {} allowed per file", path, size, max);
+        }
+        Ok(())
+    }
+
+    /// Rejects writing files of the given sizes if the total would grow
+    /// past `max_total_size`.
+    pub(crate) fn check_total<'a>(&self, sizes: impl IntoIterator<Item = (&'a str, u64)>) -> Result<()> {
+        let Some(max) = self.quota().max_total_size else {
+            return Ok(());
+        };
+        let before = self.state().usage().total_size;
+        let after = sizes
+            .into_iter()
+            .fold(before, |total, (path, size)| total - self.state().file_size(path) + size);
+        if after > max && after > before {
+            bail!("Workspace would hold {} bytes, more than the {} allowed", after, max);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod quota_test {
+    use super::*;
+    use crate::crdt::clock::VersionVector;
+    use crate::crdt::op::Entry;
+    use crate::crdt::transaction::Transaction;
+    use crate::test_util::{alice, bob, entry};
+
+    #[test]
+    fn test_quota_rejects_growth_past_limits() {
+        let alice = alice();
+        let bob = bob();
+        let quota = Quota { max_total_size: Some(10), max_file_size: Some(6) };
+        let mut a = Replica::new(alice).with_quota(quota);
+        a.put("a", entry(b"12345")).unwrap();
+        a.put("b", entry(b"1234")).unwrap();
+        a.put("link", Entry::symlink("a-very-long-target", 0)).unwrap();
+        assert!(a.put("big", entry(b"1234567")).is_err());
+        assert!(a.put("c", entry(b"12")).is_err());
+        // replacing a file only counts the difference
+        a.put("a", entry(b"123456")).unwrap();
+        assert!(a.commit_transaction(Transaction::new().remove("b").put("c", entry(b"12"))).is_ok());
+
+        let usage = a.usage();
+        assert_eq!((usage.files, usage.symlinks, usage.total_size, usage.largest_file), (2, 1, 8, 6));
+        assert_eq!(usage.remaining(), Some(2));
+
+        // merged writes aren't checked, but only shrinking is allowed after
+        let mut b = Replica::new(bob);
+        b.put("d", entry(b"12345")).unwrap();
+        a.apply_delta(&b.delta_since(&VersionVector::new())).unwrap();
+        assert_eq!(a.usage().total_size, 13);
+        assert!(a.put("e", entry(b"1")).is_err());
+        a.put("d", entry(b"1")).unwrap();
+        assert_eq!(a.usage().remaining(), Some(1));
+    }
+}
@@ -0,0 +1,1485 @@
+use anyhow::{anyhow, bail, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use super::audit::{AuditRecord, Origin};
+use super::causal::{PendingBuffer, DEFAULT_MAX_PENDING};
+use super::chunk::{ChunkProfile, ChunkRule, Chunker};
+use super::quota::Quota;
+use super::upload::DEFAULT_UPLOAD_WINDOW;
+use super::clock::{HlcClock, VersionVector};
+use super::fork::ForkTracker;
+use super::hamt::{self, ShardNode, Walk, DEFAULT_FANOUT};
+use super::journal::Journal;
+use super::history::path_matches;
+use super::identity::ReplicaId;
+use super::wire::{
+    compress_block, decode_block, decompress_block, encode_block, open_block, seal_block, seal_compressed_block,
+    DeltaBundle, HeadAnnouncement, Node, OpNode, SnapshotNode, StateBlock, Versioned,
+};
+use super::op::{Access, ConflictPolicy, Entry, EntryPatch, Op, OpKind};
+use super::policy::FilePolicy;
+use super::sign::ReplicaKeypair;
+use super::state::{PathRegister, State};
+use super::validate::{
+    default_validators, validate_announcement, validate_chunk_rules, validate_chunker, validate_file_policy, validate_parents,
+    validate_path, validate_state, validate_state_block,
+    Limits, NodeContext, OpValidator, Quarantined,
+};
+use crate::crypto::WorkspaceKey;
+use crate::durability::Durability;
+use crate::kubo_rpc::ipfs::{get_block, put_block_with_codec, IpfsCid, RAW_CODE};
+
+/// Number of operations between automatic snapshots.
+pub const DEFAULT_SNAPSHOT_INTERVAL: usize = 1000;
+
+/// One replica of the directory CRDT: the materialized state, the current
+/// heads of the op DAG and every DAG node this replica knows about.
+#[derive(Debug, Clone)]
+pub struct Replica {
+    author: ReplicaId,
+    keypair: ReplicaKeypair,
+    /// Seals every block this replica stores or fetches, if set.
+    workspace_key: Option<WorkspaceKey>,
+    /// Blocks this replica writes of at least this many bytes are
+    /// zstd-compressed; `None` never compresses.
+    compression: Option<usize>,
+    /// Signing key pinned for each author, first one seen wins.
+    signers: HashMap<ReplicaId, Vec<u8>>,
+    clock: HlcClock,
+    state: State,
+    heads: Vec<IpfsCid>,
+    nodes: HashMap<IpfsCid, Node>,
+    /// States linked from snapshot nodes, by CID.
+    states: HashMap<IpfsCid, StateBlock>,
+    /// Shards of those states, plus any fetched by lookups.
+    shards: HashMap<IpfsCid, ShardNode>,
+    shard_fanout: u32,
+    /// Where `put_chunked` cuts files.
+    chunker: Chunker,
+    /// Chunks `put_stream` stores at once.
+    upload_window: usize,
+    /// Bounds on what local writes may add.
+    quota: Quota,
+    /// Subtree this replica tracks; empty for the whole workspace.
+    scope: String,
+    pub(crate) unpublished: Vec<IpfsCid>,
+    /// Where blocks written locally are journaled until published, if
+    /// anywhere.
+    pub(crate) journal: Option<Journal>,
+    /// When the journal is synced to disk.
+    pub(crate) durability: Durability,
+    snapshot_interval: usize,
+    ops_since_snapshot: usize,
+    /// Bounds remote blocks must respect to be merged.
+    limits: Limits,
+    /// Run on every remote node before it is applied.
+    validators: Vec<Arc<dyn OpValidator>>,
+    /// Remote nodes the validators rejected, never applied.
+    quarantine: BTreeMap<IpfsCid, Quarantined>,
+    /// Received nodes and bundles waiting for their dependencies.
+    pub(crate) pending: PendingBuffer,
+    /// Last adopted announcement per author and unresolved forks.
+    pub(crate) forks: ForkTracker,
+    /// Records of applied ops not yet drained, if recording.
+    pub(crate) audit: Option<Vec<AuditRecord>>,
+}
+
+impl Replica {
+    /// Creates a replica signing with a freshly generated keypair; use
+    /// `with_keypair` to sign with a persisted one.
+    pub fn new(author: impl Into<ReplicaId>) -> Self {
+        let author = author.into();
+        let keypair = ReplicaKeypair::generate().expect("OS random number generator is unavailable");
+        let signers = HashMap::from([(author.clone(), keypair.public_key().to_vec())]);
+        Replica {
+            author,
+            keypair,
+            workspace_key: None,
+            compression: None,
+            signers,
+            clock: HlcClock::new(),
+            state: State::new(),
+            heads: Vec::new(),
+            nodes: HashMap::new(),
+            states: HashMap::new(),
+            shards: HashMap::new(),
+            shard_fanout: DEFAULT_FANOUT,
+            chunker: Chunker::default(),
+            upload_window: DEFAULT_UPLOAD_WINDOW,
+            quota: Quota::default(),
+            scope: String::new(),
+            unpublished: Vec::new(),
+            journal: None,
+            durability: Durability::default(),
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            ops_since_snapshot: 0,
+            limits: Limits::default(),
+            validators: default_validators(),
+            quarantine: BTreeMap::new(),
+            pending: PendingBuffer::new(DEFAULT_MAX_PENDING),
+            forks: ForkTracker::default(),
+            audit: None,
+        }
+    }
+
+    /// Writes a snapshot node after every `interval` operations.
+    /// An interval of 0 disables automatic snapshots.
+    pub fn with_snapshot_interval(mut self, interval: usize) -> Self {
+        self.snapshot_interval = interval;
+        self
+    }
+
+    pub fn snapshot_interval(&self) -> usize {
+        self.snapshot_interval
+    }
+
+    /// Shards snapshot states over trie nodes of `fanout` slots, a power of
+    /// two between 2 and 256. Replicas may use different fanouts.
+    pub fn with_shard_fanout(mut self, fanout: u32) -> Self {
+        self.shard_fanout = fanout;
+        self
+    }
+
+    pub fn shard_fanout(&self) -> u32 {
+        self.shard_fanout
+    }
+
+    /// Tracks only the files at or beneath `prefix`, e.g. `photos/2024`.
+    /// Merges fetch only the snapshot shards beneath it and skip operations
+    /// elsewhere; writes outside it are refused. A scoped replica doesn't
+    /// hold the whole state, so it publishes operations but never
+    /// snapshots.
+    pub fn with_scope(mut self, prefix: &str) -> Self {
+        self.scope = prefix.trim_matches('/').to_string();
+        self
+    }
+
+    pub fn scope(&self) -> &str {
+        &self.scope
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Runs `validator` on every remote node after the default ones.
+    pub fn with_validator(mut self, validator: impl OpValidator + 'static) -> Self {
+        self.validators.push(Arc::new(validator));
+        self
+    }
+
+    /// Remote nodes the validators rejected, with the reason, by CID.
+    pub fn quarantined(&self) -> impl Iterator<Item = (&IpfsCid, &Quarantined)> {
+        self.quarantine.iter()
+    }
+
+    pub fn is_quarantined(&self, cid: &IpfsCid) -> bool {
+        self.quarantine.contains_key(cid)
+    }
+
+    /// Forgets every quarantined node, so the next merge fetches and
+    /// validates them again, e.g. after a membership change.
+    pub fn clear_quarantine(&mut self) {
+        self.quarantine.clear();
+    }
+
+    /// Holds at most `max` received nodes and bundles waiting for their
+    /// dependencies, evicting the oldest beyond that.
+    pub fn with_max_pending(mut self, max: usize) -> Self {
+        self.pending = PendingBuffer::new(max);
+        self
+    }
+
+    pub fn with_keypair(mut self, keypair: ReplicaKeypair) -> Self {
+        self.signers.insert(self.author.clone(), keypair.public_key().to_vec());
+        self.keypair = keypair;
+        self
+    }
+
+    /// Encrypts every DAG node and announcement with `key`. All replicas of
+    /// a workspace must use the same key.
+    pub fn with_workspace_key(mut self, key: WorkspaceKey) -> Self {
+        self.workspace_key = Some(key);
+        self
+    }
+
+    /// Cuts files written with `put_chunked` at `chunker`'s boundaries
+    /// while the workspace sets none. Writers using the same chunker share
+    /// more chunks.
+    pub fn with_chunker(mut self, chunker: Chunker) -> Self {
+        self.chunker = chunker;
+        self
+    }
+
+    /// The chunker files are cut with: the workspace's, else this
+    /// replica's own.
+    pub fn chunker(&self) -> Chunker {
+        self.state.chunker().unwrap_or(self.chunker)
+    }
+
+    /// How the file at `path` is cut: by the first of the workspace's
+    /// chunking rules it matches, else by `chunker`.
+    pub fn chunk_profile(&self, path: &str) -> ChunkProfile {
+        match self.state.chunk_rules().iter().find(|rule| rule.matches(path)) {
+            Some(rule) => rule.profile,
+            None => ChunkProfile::ContentDefined(self.chunker()),
+        }
+    }
+
+    /// Lets `put_stream` store up to `window` chunks at once; reading the
+    /// file waits while that many are in flight.
+    pub fn with_upload_window(mut self, window: usize) -> Self {
+        self.upload_window = window.max(1);
+        self
+    }
+
+    pub fn upload_window(&self) -> usize {
+        self.upload_window
+    }
+
+    /// Rejects local writes that would exceed `quota`; see `check_quota`.
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Compresses every block this replica writes whose DAG-CBOR encoding
+    /// is at least `min_size` bytes. Replicas read compressed blocks either
+    /// way, so members may differ in this setting. Compressed blocks are
+    /// raw blocks, so the daemon can't follow their links.
+    pub fn with_compression(mut self, min_size: usize) -> Self {
+        self.compression = Some(min_size);
+        self
+    }
+
+    pub fn compression(&self) -> Option<usize> {
+        self.compression
+    }
+
+    pub fn workspace_key(&self) -> Option<&WorkspaceKey> {
+        self.workspace_key.as_ref()
+    }
+
+    pub fn author(&self) -> &ReplicaId {
+        &self.author
+    }
+
+    pub fn keypair(&self) -> &ReplicaKeypair {
+        &self.keypair
+    }
+
+    /// Pins the signing key expected on nodes written by `author`.
+    pub fn trust_signer(&mut self, author: ReplicaId, public_key: &[u8]) {
+        self.signers.insert(author, public_key.to_vec());
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn heads(&self) -> &[IpfsCid] {
+        &self.heads
+    }
+
+    pub fn node(&self, cid: &IpfsCid) -> Option<&Node> {
+        self.nodes.get(cid)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (&IpfsCid, &Node)> {
+        self.nodes.iter()
+    }
+
+    pub(crate) fn state_block(&self, cid: &IpfsCid) -> Option<&StateBlock> {
+        self.states.get(cid)
+    }
+
+    pub(crate) fn shard(&self, cid: &IpfsCid) -> Option<&ShardNode> {
+        self.shards.get(cid)
+    }
+
+    /// CIDs of every node, state block and shard held.
+    pub(crate) fn held_blocks(&self) -> impl Iterator<Item = &IpfsCid> {
+        self.nodes.keys().chain(self.states.keys()).chain(self.shards.keys())
+    }
+
+    /// Encodes the held node, state block or shard `cid`, as pushed.
+    pub(crate) fn block_bytes(&self, cid: &IpfsCid) -> Result<Option<Vec<u8>>> {
+        let bytes = match (self.nodes.get(cid), self.shards.get(cid), self.states.get(cid)) {
+            (Some(node), _, _) => self.encode_held(cid, node)?,
+            (None, Some(shard), _) => self.encode_held(cid, shard)?,
+            (None, None, Some(state)) => self.encode_held(cid, state)?,
+            (None, None, None) => return Ok(None),
+        };
+        Ok(Some(bytes))
+    }
+
+    pub(crate) fn is_unpublished(&self, cid: &IpfsCid) -> bool {
+        self.unpublished.contains(cid)
+    }
+
+    pub(crate) fn forget_blocks(&mut self, cids: &[IpfsCid]) {
+        for cid in cids {
+            self.nodes.remove(cid);
+            self.states.remove(cid);
+            self.shards.remove(cid);
+        }
+    }
+
+    /// Creates or overwrites the file at `path`. Overwriting an existing
+    /// file commits a patch of its content and mtime, and of its mode only
+    /// if that changed, so a concurrent `set_mode` survives.
+    pub fn put(&mut self, path: &str, entry: Entry) -> Result<IpfsCid> {
+        validate_path(path, &self.limits)?;
+        if !path_matches(&self.scope, path) {
+            bail!("{} is outside the scope {} of this replica", path, self.scope);
+        }
+        if let Some(current) = self.state.get(path) {
+            let edited = self.patch(path, EntryPatch::edit(current.mode, entry))?;
+            return Ok(edited.expect("the file exists"));
+        }
+        let lineage = self.state.lineage(path);
+        self.commit(OpKind::Put { path: path.to_string(), entry, lineage })
+    }
+
+    /// Changes only the fields set in `patch` on the file at `path`, so a
+    /// concurrent change to another field survives the merge. Returns
+    /// `None` if there was no such file.
+    pub fn patch(&mut self, path: &str, mut patch: EntryPatch) -> Result<Option<IpfsCid>> {
+        if self.state.get(path).is_none() {
+            return Ok(None);
+        }
+        if patch.is_empty() {
+            bail!("Patch of {} changes nothing", path);
+        }
+        if let Some(content) = &mut patch.content {
+            content.lineage = self.state.lineage(path);
+        }
+        self.commit(OpKind::Patch { path: path.to_string(), patch }).map(Some)
+    }
+
+    /// `chmod`: a patch changing only the mode.
+    pub fn set_mode(&mut self, path: &str, mode: u32) -> Result<Option<IpfsCid>> {
+        self.patch(path, EntryPatch { mode: Some(mode), ..EntryPatch::default() })
+    }
+
+    /// Deletes the file at `path`. Returns `None` if there was no such file.
+    pub fn remove(&mut self, path: &str) -> Result<Option<IpfsCid>> {
+        if self.state.get(path).is_none() {
+            return Ok(None);
+        }
+        self.commit(OpKind::Remove { path: path.to_string() }).map(Some)
+    }
+
+    /// Records `policy` as the workspace's conflict policy. It applies to
+    /// every replica once they've merged this op, existing conflicts
+    /// included.
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) -> Result<IpfsCid> {
+        self.commit(OpKind::SetConflictPolicy { policy })
+    }
+
+    /// Records `chunker` as the one every replica of the workspace cuts
+    /// files with, so they share chunks. Files already written keep their
+    /// chunks until rewritten.
+    pub fn set_chunker(&mut self, chunker: Chunker) -> Result<IpfsCid> {
+        validate_chunker(&chunker)?;
+        let rules = self.state.chunk_rules().to_vec();
+        self.commit(OpKind::SetChunker { chunker, rules })
+    }
+
+    /// Records per-glob chunking rules for every replica of the workspace,
+    /// replacing the previous ones; see `chunk_profile`.
+    pub fn set_chunk_rules(&mut self, rules: Vec<ChunkRule>) -> Result<IpfsCid> {
+        validate_chunk_rules(&rules)?;
+        self.commit(OpKind::SetChunker { chunker: self.chunker(), rules })
+    }
+
+    /// Records which files no replica of the workspace syncs, replacing the
+    /// previous policy; see `FilePolicy`.
+    pub fn set_file_policy(&mut self, policy: FilePolicy) -> Result<IpfsCid> {
+        validate_file_policy(&policy)?;
+        self.commit(OpKind::SetFilePolicy { policy })
+    }
+
+    pub(crate) fn commit(&mut self, kind: OpKind) -> Result<IpfsCid> {
+        self.commit_reverting(kind, Vec::new())
+    }
+
+    /// Commits `kind` as the undo of the own ops numbered `reverts`.
+    pub(crate) fn commit_reverting(&mut self, kind: OpKind, reverts: Vec<u64>) -> Result<IpfsCid> {
+        self.check_access(&kind)?;
+        self.check_quota(&kind)?;
+        let op = Op {
+            author: self.author.clone(),
+            seq: self.state.version_vector().get(&self.author) + 1,
+            timestamp: self.clock.tick(),
+            context: self.state.version_vector().clone(),
+            kind,
+            reverts,
+        };
+        self.state.apply(&op);
+        let node = Node::Op(OpNode::new(op, self.heads.clone()));
+        let cid = self.insert_local(node)?;
+        if let Some(Node::Op(op_node)) = self.nodes.get(&cid) {
+            let op = op_node.op.clone();
+            self.record_applied(&cid, &op, Origin::Local);
+        }
+        self.ops_since_snapshot += 1;
+        if self.snapshot_interval > 0
+            && self.ops_since_snapshot >= self.snapshot_interval
+            && self.scope.is_empty()
+            && self.state.write_access(&self.author) == Access::Write
+        {
+            self.snapshot()?;
+        }
+        Ok(cid)
+    }
+
+    /// Writes a snapshot node on top of the current heads.
+    pub fn snapshot(&mut self) -> Result<IpfsCid> {
+        if !self.scope.is_empty() {
+            bail!("A replica scoped to {} can't snapshot the whole workspace", self.scope);
+        }
+        if self.state.write_access(&self.author) != Access::Write {
+            bail!("{} may not write snapshots of the workspace", self.author);
+        }
+        let encode = |shard: &ShardNode| Ok(self.encode_block(shard)?.0);
+        let (entries, shards) = hamt::build(self.state.registers(), self.shard_fanout, &encode)?;
+        for (cid, shard) in shards {
+            if !self.shards.contains_key(&cid) {
+                self.journal_block(&cid, &shard)?;
+                self.unpublished.push(cid.clone());
+                self.shards.insert(cid, shard);
+            }
+        }
+
+        let block = StateBlock::new(self.state.without_entries(), entries, self.shard_fanout);
+        let (state_cid, _) = self.encode_block(&block)?;
+        if !self.states.contains_key(&state_cid) {
+            self.journal_block(&state_cid, &block)?;
+            self.states.insert(state_cid.clone(), block);
+            self.unpublished.push(state_cid.clone());
+        }
+
+        let node = Node::Snapshot(SnapshotNode::new(
+            self.author.clone(),
+            self.clock.tick(),
+            self.state.version_vector().clone(),
+            state_cid,
+            self.heads.clone(),
+        ));
+        self.ops_since_snapshot = 0;
+        self.insert_local(node)
+    }
+
+    /// Reassembles the state a snapshot node links to from the blocks this
+    /// replica holds.
+    pub fn snapshot_state(&self, snapshot: &SnapshotNode) -> Result<State> {
+        let Some(block) = self.states.get(&snapshot.state) else {
+            bail!("State {} of snapshot is not available on this replica", snapshot.state);
+        };
+        match self.assemble(block, &HashMap::new())? {
+            Walk::Done(state) => Ok(state),
+            Walk::Missing(cids) => bail!("Shard {} of state {} is not available on this replica", cids[0], snapshot.state),
+        }
+    }
+
+    fn insert_local(&mut self, mut node: Node) -> Result<IpfsCid> {
+        node.sign(&self.keypair)?;
+        let (cid, bytes) = self.encode_block(&node)?;
+        if let Some(journal) = &self.journal {
+            journal.append(true, &cid, &bytes)?;
+        }
+        self.nodes.insert(cid.clone(), node);
+        self.unpublished.push(cid.clone());
+        self.heads = vec![cid.clone()];
+        Ok(cid)
+    }
+
+    /// Journals a block a snapshot links to, if the replica keeps a journal.
+    fn journal_block<T: Serialize>(&self, cid: &IpfsCid, value: &T) -> Result<()> {
+        match &self.journal {
+            Some(journal) => journal.append(false, cid, &self.encode_held(cid, value)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Merges the history ending at `head` into this replica.
+    ///
+    /// Walks backwards from `head`, fetching unknown nodes with `fetch`, and
+    /// stops at snapshots and at operations already covered, so a fresh
+    /// replica only downloads the latest snapshot and the ops after it. An
+    /// op reusing the dot of a held one, from a forked author, is merged.
+    /// Returns the number of operations applied.
+    pub async fn merge_head<F>(&mut self, head: &IpfsCid, mut fetch: F) -> Result<usize>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        if self.nodes.contains_key(head) {
+            return Ok(0);
+        }
+
+        let mut queue = VecDeque::from([head.clone()]);
+        let mut visited = HashSet::new();
+        let mut covered = self.state.version_vector().clone();
+        let mut fetched = Vec::new();
+        let mut states = HashMap::new();
+        let mut shards = HashMap::new();
+
+        while let Some(cid) = queue.pop_front() {
+            if !visited.insert(cid.clone()) || self.nodes.contains_key(&cid) || self.quarantine.contains_key(&cid) {
+                continue;
+            }
+
+            let node: Node = self.fetch_block(&cid, &mut fetch).await?;
+            validate_parents(node.parents(), &self.limits).map_err(|e| anyhow!("Rejecting node {}: {}", cid, e))?;
+            match &node {
+                Node::Op(op_node) => {
+                    if covered.contains(&op_node.op.dot()) && !self.reuses_dot(&op_node.op) {
+                        continue;
+                    }
+                    queue.extend(op_node.parents.iter().cloned());
+                }
+                Node::Snapshot(snapshot) => {
+                    if !self.states.contains_key(&snapshot.state) && !states.contains_key(&snapshot.state) {
+                        let received = self.fetch_state(&snapshot.state, &mut shards, &mut fetch).await?;
+                        snapshot.check_state(&received.state)?;
+                        states.insert(snapshot.state.clone(), received);
+                    }
+                    covered.join(snapshot.version_vector());
+                }
+            }
+            fetched.push((cid, node));
+        }
+
+        let accepted = self.screen(fetched, &states)?;
+        let applied = self.integrate(accepted, states, shards, std::slice::from_ref(head))?;
+        Ok(applied + self.release_pending())
+    }
+
+    /// Fetches the state block at `cid` and every shard of it not held yet,
+    /// one trie level per round, collecting the shards into `shards`.
+    async fn fetch_state<F>(
+        &self,
+        cid: &IpfsCid,
+        shards: &mut HashMap<IpfsCid, ShardNode>,
+        fetch: &mut F,
+    ) -> Result<ReceivedState>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let block: StateBlock = self.fetch_block(cid, fetch).await?;
+        loop {
+            match self.receive_state(cid, &block, shards)? {
+                Walk::Done(state) => return Ok(ReceivedState { block, state }),
+                Walk::Missing(cids) => {
+                    for shard_cid in cids {
+                        let shard: ShardNode = self.fetch_block(&shard_cid, fetch).await?;
+                        shards.insert(shard_cid, shard);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether a held op other than `op` has its dot: a forked author wrote
+    /// both, and `op` still has to be merged, see `State::apply_where`.
+    fn reuses_dot(&self, op: &Op) -> bool {
+        let dot = op.dot();
+        self.nodes.values().any(|node| matches!(node, Node::Op(held) if held.op.dot() == dot && held.op != *op))
+    }
+
+    /// Validates a remote state block and, once all its shards are at hand,
+    /// the state reassembled from them.
+    fn receive_state(
+        &self,
+        cid: &IpfsCid,
+        block: &StateBlock,
+        shards: &HashMap<IpfsCid, ShardNode>,
+    ) -> Result<Walk<State>> {
+        let reject = |e: anyhow::Error| anyhow!("Rejecting state {}: {}", cid, e);
+        validate_state_block(block, &self.limits).map_err(reject)?;
+        let walk = self.assemble(block, shards).map_err(reject)?;
+        if let Walk::Done(state) = &walk {
+            validate_state(state, &self.limits).map_err(reject)?;
+        }
+        Ok(walk)
+    }
+
+    /// Puts a state block back together with its shards, found among
+    /// `incoming` or those already held. Only the replica's scope is read.
+    fn assemble(&self, block: &StateBlock, incoming: &HashMap<IpfsCid, ShardNode>) -> Result<Walk<State>> {
+        let get = |cid: &IpfsCid| incoming.get(cid).or_else(|| self.shards.get(cid));
+        Ok(match hamt::collect(&block.entries, block.fanout, &self.scope, &get)? {
+            Walk::Done(entries) => Walk::Done(block.state.clone().with_entries(entries)),
+            Walk::Missing(cids) => Walk::Missing(cids),
+        })
+    }
+
+    /// Runs every validator over `fetched` in causal order, each node
+    /// judged by the membership its parents knew, and moves the nodes one
+    /// rejects, and every node built on those, to the quarantine. Returns
+    /// the nodes that passed.
+    fn screen(
+        &mut self,
+        fetched: Vec<(IpfsCid, Node)>,
+        states: &HashMap<IpfsCid, ReceivedState>,
+    ) -> Result<Vec<(IpfsCid, Node)>> {
+        let mut pinned = self.signers.clone();
+        let mut view = self.state.clone();
+        let order = topological_order(&fetched);
+        let mut slots: Vec<Option<(IpfsCid, Node)>> = fetched.into_iter().map(Some).collect();
+        let mut accepted = Vec::new();
+        for index in order {
+            let (cid, node) = slots[index].take().expect("each index appears once");
+            let verdict = match node.parents().iter().find(|parent| self.quarantine.contains_key(*parent)) {
+                Some(parent) => Err(anyhow!("built on quarantined node {}", parent)),
+                None => {
+                    let context = NodeContext { state: &view, signers: &pinned, limits: &self.limits };
+                    self.validators.iter().try_for_each(|validator| validator.validate(&node, &context))
+                }
+            };
+            if let Err(e) = verdict {
+                self.quarantine.insert(cid, Quarantined { node, reason: e.to_string() });
+                continue;
+            }
+            if !view.has_members() {
+                pinned.entry(node.author().clone()).or_insert_with(|| node.signer().to_vec());
+            }
+            match &node {
+                Node::Op(op_node) => {
+                    view.apply(&op_node.op);
+                }
+                Node::Snapshot(snapshot) => view.join(&self.linked_state(snapshot, states)?),
+            }
+            accepted.push((cid, node));
+        }
+        self.signers = pinned;
+        Ok(accepted)
+    }
+
+    /// Applies freshly received nodes and adds those of `remote_heads`
+    /// among them to the heads, dropping local heads the remote history
+    /// covers. Fails, changing nothing, if a snapshot's state can't be put
+    /// together, as when its shards were forgotten since `screen` read it.
+    fn integrate(
+        &mut self,
+        fetched: Vec<(IpfsCid, Node)>,
+        states: HashMap<IpfsCid, ReceivedState>,
+        shards: HashMap<IpfsCid, ShardNode>,
+        remote_heads: &[IpfsCid],
+    ) -> Result<usize> {
+        let mut remote_seen = VersionVector::new();
+        let mut reached = HashSet::new();
+        for (_, node) in &fetched {
+            match node {
+                Node::Op(op_node) => {
+                    remote_seen.join(&op_node.op.context);
+                    remote_seen.observe(&op_node.op.dot());
+                }
+                Node::Snapshot(snapshot) => remote_seen.join(snapshot.version_vector()),
+            }
+            reached.extend(node.parents().iter().filter(|p| self.nodes.contains_key(*p)).cloned());
+        }
+
+        let mut linked = Vec::new();
+        for (_, node) in &fetched {
+            if let Node::Snapshot(snapshot) = node {
+                let state = match states.get(&snapshot.state) {
+                    Some(received) => received.state.clone(),
+                    None => self.snapshot_state(snapshot)?,
+                };
+                linked.push((state, snapshot.timestamp));
+            }
+        }
+        for (state, timestamp) in linked {
+            self.state.join(&state);
+            self.clock.observe(timestamp);
+        }
+
+        let mut applied = 0;
+        self.shards.extend(shards);
+        self.states.extend(states.into_iter().map(|(cid, received)| (cid, received.block)));
+        for index in topological_order(&fetched) {
+            if let (cid, Node::Op(op_node)) = &fetched[index] {
+                let op = &op_node.op;
+                self.clock.observe(op.timestamp);
+                let paths = op.kind.paths();
+                if !paths.is_empty() && !paths.iter().any(|path| path_matches(&self.scope, path)) {
+                    self.state.skip(op);
+                } else if self.state.apply_where(op, |path| path_matches(&self.scope, path)) {
+                    self.record_applied(cid, op, Origin::Remote);
+                    applied += 1;
+                }
+            }
+        }
+
+        let nodes = &self.nodes;
+        self.heads.retain(|h| {
+            !reached.contains(h)
+                && match nodes.get(h) {
+                    Some(Node::Op(op_node)) => !remote_seen.contains(&op_node.op.dot()),
+                    Some(Node::Snapshot(snapshot)) => !remote_seen.dominates(snapshot.version_vector()),
+                    None => true,
+                }
+        });
+        for head in remote_heads {
+            if !self.heads.contains(head) && fetched.iter().any(|(cid, _)| cid == head) {
+                self.heads.push(head.clone());
+            }
+        }
+        self.nodes.extend(fetched);
+        self.ops_since_snapshot += applied;
+        Ok(applied)
+    }
+
+    /// Bundles every node a peer that has seen `since` is missing.
+    pub fn delta_since(&self, since: &VersionVector) -> DeltaBundle {
+        let nodes: Vec<(IpfsCid, Node)> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| match node {
+                Node::Op(op_node) => !since.contains(&op_node.op.dot()),
+                Node::Snapshot(snapshot) => !since.dominates(snapshot.version_vector()),
+            })
+            .map(|(cid, node)| (cid.clone(), node.clone()))
+            .collect();
+        let sorted: Vec<(IpfsCid, Node)> = topological_order(&nodes).into_iter().map(|i| nodes[i].clone()).collect();
+        let mut states = Vec::new();
+        let mut shards = Vec::new();
+        let mut shipped = HashSet::new();
+        for (_, node) in &sorted {
+            if let Node::Snapshot(snapshot) = node
+                && let Some(block) = self.states.get(&snapshot.state)
+            {
+                if let Ok(Walk::Done(cids)) = hamt::shard_cids(&block.entries, &|cid| self.shards.get(cid)) {
+                    let new = cids.into_iter().filter(|cid| shipped.insert(cid.clone()));
+                    shards.extend(new.map(|cid| (cid.clone(), self.shards[&cid].clone())));
+                }
+                states.push((snapshot.state.clone(), block.clone()));
+            }
+        }
+        DeltaBundle::new(self.author.clone(), since.clone(), self.heads.clone(), sorted, states, shards)
+    }
+
+    /// Applies a bundle produced by a peer's `delta_since`. A bundle that
+    /// assumes operations this replica hasn't seen yet waits in the pending
+    /// buffer until they arrive. Every block must encode to the CID it
+    /// came with, under any compression setting.
+    pub fn apply_delta(&mut self, bundle: &DeltaBundle) -> Result<usize> {
+        if !self.state.version_vector().dominates(&bundle.since) {
+            self.pending.hold_delta(bundle.clone());
+            return Ok(0);
+        }
+        let check = |cid: &IpfsCid, result: Result<Vec<u8>>| {
+            result.map_err(|_| anyhow!("Delta from {} carries a block that doesn't encode to {}", bundle.author, cid))
+        };
+        let mut shards = HashMap::new();
+        for (cid, shard) in &bundle.shards {
+            check(cid, self.encode_held(cid, shard))?;
+            shards.insert(cid.clone(), shard.clone());
+        }
+        let mut states = HashMap::new();
+        for (cid, block) in &bundle.states {
+            check(cid, self.encode_held(cid, block))?;
+            match self.receive_state(cid, block, &shards)? {
+                Walk::Done(state) => states.insert(cid.clone(), ReceivedState { block: block.clone(), state }),
+                Walk::Missing(missing) => bail!("Delta from {} lacks shard {} of state {}", bundle.author, missing[0], cid),
+            };
+        }
+        let mut fetched = Vec::with_capacity(bundle.nodes.len());
+        for (cid, node) in &bundle.nodes {
+            if !self.nodes.contains_key(cid) && !self.quarantine.contains_key(cid) {
+                check(cid, self.encode_held(cid, node))?;
+                if let Node::Snapshot(snapshot) = node {
+                    self.linked_state(snapshot, &states)?;
+                }
+                fetched.push((cid.clone(), node.clone()));
+            }
+        }
+        let accepted = self.screen(fetched, &states)?;
+        let applied = self.integrate(accepted, states, shards, &bundle.heads)?;
+        Ok(applied + self.release_pending())
+    }
+
+    /// Applies validated nodes whose parents are all held. Snapshot nodes
+    /// must link a state this replica already holds.
+    pub(crate) fn apply_nodes(&mut self, nodes: Vec<(IpfsCid, Node)>, heads: &[IpfsCid]) -> Result<usize> {
+        for (_, node) in &nodes {
+            if let Node::Snapshot(snapshot) = node {
+                self.linked_state(snapshot, &HashMap::new())?;
+            }
+        }
+        let accepted = self.screen(nodes, &HashMap::new())?;
+        self.integrate(accepted, HashMap::new(), HashMap::new(), heads)
+    }
+
+    /// The announcement this replica publishes for its current heads.
+    pub fn announcement(&self) -> HeadAnnouncement {
+        HeadAnnouncement::new(self.author.clone(), self.heads.clone(), self.state.version_vector().clone())
+    }
+
+    /// Fetches the head announcement at `cid` and merges every head in it.
+    /// Refuses an announcement that doesn't descend from the one merged
+    /// before from the same author, recording a `Fork` instead (see
+    /// `resolve_fork`). Returns the number of operations applied.
+    pub async fn merge_announcement<F>(&mut self, cid: &IpfsCid, mut fetch: F) -> Result<usize>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let announcement: HeadAnnouncement = self.fetch_block(cid, &mut fetch).await?;
+        validate_announcement(&announcement, &self.limits)
+            .map_err(|e| anyhow!("Rejecting announcement {}: {}", cid, e))?;
+        if !self.check_announcement(cid, &announcement, &mut fetch).await? {
+            return Ok(0);
+        }
+
+        let mut applied = 0;
+        if !self.state.version_vector().dominates(&announcement.version_vector) {
+            for head in &announcement.heads {
+                applied += self.merge_head(head, async |cid| fetch(cid).await).await?;
+            }
+        }
+        self.forks.adopt(cid, &announcement);
+        Ok(applied)
+    }
+
+    /// Rebuilds the state as it was right after the node `cid` was written,
+    /// using only nodes this replica holds.
+    pub fn state_at(&self, cid: &IpfsCid) -> Result<State> {
+        if !self.nodes.contains_key(cid) {
+            bail!("Unknown DAG node {}", cid);
+        }
+
+        let mut stack = vec![cid.clone()];
+        let mut visited = HashSet::new();
+        let mut ancestors = Vec::new();
+        while let Some(cid) = stack.pop() {
+            if !visited.insert(cid.clone()) {
+                continue;
+            }
+            // Missing parents are fine as long as a snapshot covers them,
+            // which is checked once everything is applied.
+            let Some(node) = self.nodes.get(&cid) else { continue };
+            if let Node::Op(op_node) = node {
+                stack.extend(op_node.parents.iter().cloned());
+            }
+            ancestors.push((cid, node.clone()));
+        }
+
+        let mut state = State::new();
+        for (_, node) in &ancestors {
+            if let Node::Snapshot(snapshot) = node {
+                state.join(&self.snapshot_state(snapshot)?);
+            }
+        }
+        for index in topological_order(&ancestors) {
+            if let Node::Op(op_node) = &ancestors[index].1 {
+                state.apply(&op_node.op);
+            }
+        }
+        for (cid, node) in &ancestors {
+            if let Node::Op(op_node) = node
+                && !state.version_vector().dominates(&op_node.op.context)
+            {
+                bail!("History before {} is not available on this replica", cid);
+            }
+        }
+        Ok(state)
+    }
+
+    /// Hands every locally written node that hasn't been pushed yet to `put`.
+    pub async fn push<F>(&mut self, mut put: F) -> Result<()>
+    where
+        F: AsyncFnMut(IpfsCid, Vec<u8>) -> Result<()>,
+    {
+        while let Some(cid) = self.unpublished.first().cloned() {
+            let bytes = self.block_bytes(&cid)?.expect("unpublished blocks are held");
+            put(cid, bytes).await?;
+            self.unpublished.remove(0);
+        }
+        Ok(())
+    }
+
+    /// Finds the state `snapshot` links to among `incoming` or the states
+    /// already held, checking it matches the snapshot.
+    fn linked_state(&self, snapshot: &SnapshotNode, incoming: &HashMap<IpfsCid, ReceivedState>) -> Result<State> {
+        let state = match incoming.get(&snapshot.state) {
+            Some(received) => received.state.clone(),
+            None => self.snapshot_state(snapshot)?,
+        };
+        snapshot.check_state(&state)?;
+        Ok(state)
+    }
+
+    /// Reads the register of `path` in the state block `state` (a snapshot's
+    /// `state` link), fetching only that block and the shards on the way.
+    pub async fn lookup_in<F>(
+        &mut self,
+        state: &IpfsCid,
+        path: &str,
+        mut fetch: F,
+    ) -> Result<Option<PathRegister>>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let block = self.fetch_state_block(state, &mut fetch).await?;
+        loop {
+            match hamt::lookup(&block.entries, path, block.fanout, &|cid| self.shards.get(cid))? {
+                Walk::Done(register) => return Ok(register),
+                Walk::Missing(cids) => self.fetch_shards(cids, &mut fetch).await?,
+            }
+        }
+    }
+
+    /// Paths whose registers differ between the state blocks `a` and `b`,
+    /// fetching only the shards in which they differ.
+    pub async fn diff_states<F>(&mut self, a: &IpfsCid, b: &IpfsCid, mut fetch: F) -> Result<BTreeSet<String>>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let a = self.fetch_state_block(a, &mut fetch).await?;
+        let b = self.fetch_state_block(b, &mut fetch).await?;
+        if a.fanout != b.fanout {
+            bail!("Can't diff states sharded with fanouts {} and {}", a.fanout, b.fanout);
+        }
+        loop {
+            match hamt::diff(&a.entries, &b.entries, a.fanout, &|cid| self.shards.get(cid))? {
+                Walk::Done(paths) => return Ok(paths),
+                Walk::Missing(cids) => self.fetch_shards(cids, &mut fetch).await?,
+            }
+        }
+    }
+
+    async fn fetch_state_block<F>(&self, cid: &IpfsCid, fetch: &mut F) -> Result<StateBlock>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        if let Some(block) = self.states.get(cid) {
+            return Ok(block.clone());
+        }
+        let block: StateBlock = self.fetch_block(cid, fetch).await?;
+        validate_state_block(&block, &self.limits).map_err(|e| anyhow!("Rejecting state {}: {}", cid, e))?;
+        Ok(block)
+    }
+
+    /// Fetches shards into the local cache. They are checked against their
+    /// CIDs, so holding some no local snapshot links to is harmless.
+    async fn fetch_shards<F>(&mut self, cids: Vec<IpfsCid>, fetch: &mut F) -> Result<()>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        for cid in cids {
+            let shard: ShardNode = self.fetch_block(&cid, fetch).await?;
+            self.shards.insert(cid, shard);
+        }
+        Ok(())
+    }
+
+    /// Fetches the block at `cid` and decodes it, rejecting oversized blocks
+    /// before decoding.
+    pub(crate) async fn fetch_block<T, F>(&self, cid: &IpfsCid, fetch: &mut F) -> Result<T>
+    where
+        T: DeserializeOwned + Serialize + Versioned,
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let bytes = fetch(cid.clone()).await?;
+        self.limits.check_size(cid, &bytes, self.limits.max_block_size)?;
+        self.decode_block(cid, &bytes)
+    }
+
+    /// Encodes a block the way this replica stores it: DAG-CBOR, compressed
+    /// if it is large enough (see `with_compression`), sealed into a raw
+    /// block when a workspace key is set.
+    pub fn encode_block<T: Serialize>(&self, value: &T) -> Result<(IpfsCid, Vec<u8>)> {
+        self.encode_block_with(value, self.compression)
+    }
+
+    fn encode_block_with<T: Serialize>(&self, value: &T, compression: Option<usize>) -> Result<(IpfsCid, Vec<u8>)> {
+        let compress = match compression {
+            Some(min_size) => encode_block(value)?.1.len() >= min_size,
+            None => false,
+        };
+        match (&self.workspace_key, compress) {
+            (Some(key), false) => seal_block(value, key),
+            (Some(key), true) => seal_compressed_block(value, key),
+            (None, false) => encode_block(value),
+            (None, true) => compress_block(value),
+        }
+    }
+
+    /// Encodes the held `value` so it matches `cid`, whichever compression
+    /// setting the replica that wrote it used.
+    fn encode_held<T: Serialize>(&self, cid: &IpfsCid, value: &T) -> Result<Vec<u8>> {
+        for compression in [self.compression, None, Some(0)] {
+            let (encoded, bytes) = self.encode_block_with(value, compression)?;
+            if encoded == *cid {
+                return Ok(bytes);
+            }
+        }
+        bail!("Can't reproduce the encoding of block {}", cid)
+    }
+
+    /// Reverses `encode_block`, checking the block against `cid`.
+    /// Compressed and uncompressed blocks are both accepted.
+    pub fn decode_block<T: DeserializeOwned + Serialize + Versioned>(&self, cid: &IpfsCid, bytes: &[u8]) -> Result<T> {
+        match &self.workspace_key {
+            Some(key) => open_block(cid, bytes, key),
+            None if cid.0.codec() == RAW_CODE => decompress_block(cid, bytes),
+            None => decode_block(cid, bytes),
+        }
+    }
+
+    /// Merges `head` fetching nodes from the IPFS daemon at `base_url`.
+    pub async fn pull_from(&mut self, base_url: &str, head: &IpfsCid) -> Result<usize> {
+        self.merge_head(head, async |cid| get_block(base_url, &cid).await).await
+    }
+
+    /// Stores unpublished nodes on the IPFS daemon at `base_url`.
+    pub async fn push_to(&mut self, base_url: &str) -> Result<()> {
+        self.push(async |cid, bytes| {
+            let stored = put_block_with_codec(base_url, bytes, cid.codec_name()).await?;
+            if stored != cid {
+                bail!("Daemon stored node as {} but expected {}", stored, cid);
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// A state block received from a peer, with the state reassembled from it
+/// and its shards.
+#[derive(Debug)]
+struct ReceivedState {
+    block: StateBlock,
+    state: State,
+}
+
+/// Orders nodes so that parents come before their children (Kahn's
+/// algorithm, restricted to parents inside `nodes`).
+pub(crate) fn topological_order(nodes: &[(IpfsCid, Node)]) -> Vec<usize> {
+    let index: HashMap<&IpfsCid, usize> = nodes.iter().enumerate().map(|(i, (cid, _))| (cid, i)).collect();
+    let mut pending = vec![0usize; nodes.len()];
+    let mut children = vec![Vec::new(); nodes.len()];
+    for (i, (_, node)) in nodes.iter().enumerate() {
+        for parent in node.parents() {
+            if let Some(&p) = index.get(parent) {
+                pending[i] += 1;
+                children[p].push(i);
+            }
+        }
+    }
+
+    let mut ready: VecDeque<usize> = (0..nodes.len()).filter(|&i| pending[i] == 0).collect();
+    let mut order = Vec::with_capacity(nodes.len());
+    while let Some(i) = ready.pop_front() {
+        order.push(i);
+        for &child in &children[i] {
+            pending[child] -= 1;
+            if pending[child] == 0 {
+                ready.push_back(child);
+            }
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod replica_test {
+    use super::*;
+    use crate::crdt::transaction::Transaction;
+    use crate::crdt::wire::COMPRESSED_PAYLOAD;
+    use crate::test_util::{alice, bob, entry};
+
+    async fn publish(replica: &mut Replica, store: &mut HashMap<IpfsCid, Vec<u8>>) {
+        replica
+            .push(async |cid, bytes| {
+                store.insert(cid, bytes);
+                Ok(())
+            })
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_every_interval() {
+        let mut replica = Replica::new(alice()).with_snapshot_interval(3);
+        for i in 0..7 {
+            replica.put(&format!("file{}", i), entry(&[i])).unwrap();
+        }
+
+        let snapshots = replica.nodes.values().filter(|n| matches!(n, Node::Snapshot(_))).count();
+        assert_eq!(snapshots, 2);
+        assert_eq!(replica.state().iter().count(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_cold_sync_starts_from_latest_snapshot() {
+        let mut store = HashMap::new();
+        let mut source = Replica::new(alice()).with_snapshot_interval(5);
+        for i in 0..12 {
+            source.put(&format!("file{}", i), entry(&[i])).unwrap();
+        }
+        source.remove("file3").unwrap();
+        publish(&mut source, &mut store).await;
+
+        let mut fetches = 0;
+        let mut fresh = Replica::new(bob());
+        let head = source.heads()[0].clone();
+        fresh
+            .merge_head(&head, async |cid| {
+                fetches += 1;
+                store.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid))
+            })
+            .await
+            .unwrap();
+
+        // ops 11, 12, 13 plus the snapshot taken after op 10, its state
+        // block and the state's single shard
+        assert_eq!(fetches, 6);
+        assert_eq!(fresh.state(), source.state());
+        assert_eq!(fresh.heads(), source.heads());
+    }
+
+    #[test]
+    fn test_delta_bundle_roundtrip() {
+        let mut a = Replica::new(alice()).with_snapshot_interval(2);
+        let mut b = Replica::new(bob());
+        a.put("one", entry(b"1")).unwrap();
+        a.put("two", entry(b"2")).unwrap();
+        b.apply_delta(&a.delta_since(&VersionVector::new())).unwrap();
+
+        a.put("three", entry(b"3")).unwrap();
+        let delta = a.delta_since(b.state().version_vector());
+        let (cid, bytes) = delta.encode().unwrap();
+        let decoded = DeltaBundle::decode(&cid, &bytes).unwrap();
+        assert_eq!(decoded.nodes.len(), 1);
+        assert_eq!(b.apply_delta(&decoded).unwrap(), 1);
+        assert_eq!(a.state(), b.state());
+        assert_eq!(a.heads(), b.heads());
+
+        let mut fresh = Replica::new(bob());
+        assert_eq!(fresh.apply_delta(&decoded).unwrap(), 0);
+        assert_eq!(fresh.pending_len(), 1);
+    }
+
+    #[test]
+    fn test_chmod_survives_concurrent_edit() {
+        let mut a = Replica::new(alice());
+        let mut b = Replica::new(bob());
+        a.put("f", entry(b"old")).unwrap();
+        b.apply_delta(&a.delta_since(&VersionVector::new())).unwrap();
+
+        a.set_mode("f", 0o755).unwrap();
+        b.put("f", Entry { mtime: 7, ..entry(b"new") }).unwrap();
+        b.commit_transaction(Transaction::new().put("g", entry(b"g")).put("f", Entry { mtime: 9, ..entry(b"newer") })).unwrap();
+        let (from_a, from_b) = (a.delta_since(b.state().version_vector()), b.delta_since(a.state().version_vector()));
+        a.apply_delta(&from_b).unwrap();
+        b.apply_delta(&from_a).unwrap();
+
+        assert_eq!(a.state(), b.state());
+        assert_eq!(a.state().get("f"), Some(Entry { mode: 0o755, mtime: 9, ..entry(b"newer") }));
+    }
+
+    #[test]
+    fn test_same_state_same_snapshot_state_block() {
+        let mut a = Replica::new(alice()).with_snapshot_interval(0);
+        let mut b = Replica::new(bob()).with_snapshot_interval(0);
+        a.put("x", entry(b"x")).unwrap();
+        a.put("y", entry(b"1")).unwrap();
+        b.put("y", entry(b"2")).unwrap();
+        b.remove("y").unwrap();
+
+        let from_a = a.delta_since(&VersionVector::new());
+        let from_b = b.delta_since(&VersionVector::new());
+        a.apply_delta(&from_b).unwrap();
+        b.apply_delta(&from_a).unwrap();
+
+        let link = |replica: &mut Replica| {
+            let cid = replica.snapshot().unwrap();
+            match replica.node(&cid) {
+                Some(Node::Snapshot(snapshot)) => snapshot.state.clone(),
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(link(&mut a), link(&mut b));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_remote_node_rejected() {
+        let mut store = HashMap::new();
+        let mut hostile = Replica::new(alice());
+        assert!(hostile.put("../escape", entry(b"x")).is_err());
+        hostile.commit(OpKind::Put { path: "../escape".into(), entry: entry(b"x"), lineage: Vec::new() }).unwrap();
+        publish(&mut hostile, &mut store).await;
+
+        let mut victim = Replica::new(bob());
+        let head = hostile.heads()[0].clone();
+        let applied = victim
+            .merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(applied, 0);
+        assert!(victim.heads().is_empty());
+        let (cid, quarantined) = victim.quarantined().next().unwrap();
+        assert_eq!(cid, &head);
+        assert!(quarantined.reason.contains("not a normalized relative path"), "{}", quarantined.reason);
+    }
+
+    #[tokio::test]
+    async fn test_forged_author_rejected() {
+        let mut store = HashMap::new();
+        let mut honest = Replica::new(alice());
+        honest.put("a", entry(b"a")).unwrap();
+        publish(&mut honest, &mut store).await;
+
+        // Same author key, different signing key.
+        let mut forger = Replica::new(alice());
+        forger.put("a", entry(b"evil")).unwrap();
+        publish(&mut forger, &mut store).await;
+
+        let mut victim = Replica::new(bob());
+        victim.trust_signer(alice(), &honest.keypair().public_key());
+        let forged_head = forger.heads()[0].clone();
+        let applied = victim
+            .merge_head(&forged_head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(applied, 0);
+        assert!(victim.is_quarantined(&forged_head));
+        assert_eq!(victim.state().iter().count(), 0);
+
+        let honest_head = honest.heads()[0].clone();
+        victim
+            .merge_head(&honest_head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(victim.state().get("a"), Some(entry(b"a")));
+    }
+
+    #[derive(Debug)]
+    struct NoSecrets;
+
+    impl OpValidator for NoSecrets {
+        fn validate(&self, node: &Node, _: &NodeContext) -> Result<()> {
+            match node {
+                Node::Op(op_node) if op_node.op.kind.path().is_some_and(|p| p.starts_with("secrets/")) => {
+                    bail!("writes under secrets/")
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_validator_quarantines_op_and_descendants() {
+        let mut store = HashMap::new();
+        let mut a = Replica::new(alice()).with_snapshot_interval(0);
+        a.put("ok", entry(b"1")).unwrap();
+        let secret = a.put("secrets/key", entry(b"2")).unwrap();
+        let after = a.put("later", entry(b"3")).unwrap();
+        publish(&mut a, &mut store).await;
+
+        let mut b = Replica::new(bob()).with_validator(NoSecrets);
+        let head = a.heads()[0].clone();
+        let applied = b
+            .merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(applied, 1);
+        assert_eq!(b.state().iter().map(|(path, _)| path).collect::<Vec<_>>(), vec!["ok"]);
+        let reasons: Vec<&str> = b.quarantined().map(|(_, q)| q.reason.as_str()).collect();
+        assert_eq!(reasons.len(), 2);
+        assert!(b.is_quarantined(&secret) && b.is_quarantined(&after));
+        assert!(b.quarantine[&after].reason.contains("quarantined node"));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_replicas_sync() {
+        let key = WorkspaceKey::from_bytes([5; 32]);
+        let mut store = HashMap::new();
+        let mut a = Replica::new(alice()).with_workspace_key(key.clone());
+        a.put("secret-name.txt", entry(b"a")).unwrap();
+        publish(&mut a, &mut store).await;
+        assert!(store.values().all(|bytes| !bytes.windows(11).any(|w| w == b"secret-name")));
+
+        let head = a.heads()[0].clone();
+        let mut outsider = Replica::new(bob());
+        assert!(outsider
+            .merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .is_err());
+
+        let mut b = Replica::new(bob()).with_workspace_key(key);
+        b.merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(a.state(), b.state());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_and_plain_replicas_interoperate() {
+        let mut store = HashMap::new();
+        let mut a = Replica::new(alice()).with_compression(0).with_snapshot_interval(3);
+        for i in 0..4u32 {
+            a.put(&format!("docs/file{}", i), entry(&i.to_le_bytes())).unwrap();
+        }
+        publish(&mut a, &mut store).await;
+        assert!(store.iter().all(|(cid, bytes)| cid.0.codec() == RAW_CODE && bytes[0] == COMPRESSED_PAYLOAD));
+
+        let mut b = Replica::new(bob());
+        let head = a.heads()[0].clone();
+        b.merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        b.put("docs/from-b", entry(b"b")).unwrap();
+        publish(&mut b, &mut store).await;
+        let head = b.heads()[0].clone();
+        a.merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(a.state(), b.state());
+        // b re-encodes a's compressed blocks under their original CIDs
+        for (cid, bytes) in b.to_car().unwrap().blocks.iter().skip(1) {
+            assert_eq!(store.get(cid), Some(bytes));
+        }
+
+        let key = WorkspaceKey::from_bytes([6; 32]);
+        let mut sealed = Replica::new(alice()).with_workspace_key(key.clone()).with_compression(0);
+        sealed.put("x", entry(b"x")).unwrap();
+        publish(&mut sealed, &mut store).await;
+        let mut reader = Replica::new(bob()).with_workspace_key(key);
+        let head = sealed.heads()[0].clone();
+        reader
+            .merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(reader.state(), sealed.state());
+    }
+
+    #[tokio::test]
+    async fn test_sharded_snapshot_partial_reads() {
+        let mut store = HashMap::new();
+        let mut source = Replica::new(alice()).with_snapshot_interval(0).with_shard_fanout(8);
+        for i in 0..300u32 {
+            source.put(&format!("dir/file{}", i), entry(&i.to_le_bytes())).unwrap();
+        }
+        let before = source.snapshot().unwrap();
+        source.put("dir/file7", entry(b"changed")).unwrap();
+        let after = source.snapshot().unwrap();
+        publish(&mut source, &mut store).await;
+        let state_of = |cid: &IpfsCid| match source.node(cid) {
+            Some(Node::Snapshot(snapshot)) => snapshot.state.clone(),
+            _ => unreachable!(),
+        };
+        let (before, after) = (state_of(&before), state_of(&after));
+
+        let mut fetches = 0;
+        let mut reader = Replica::new(bob());
+        let register = reader
+            .lookup_in(&after, "dir/file7", async |cid| {
+                fetches += 1;
+                store.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid))
+            })
+            .await
+            .unwrap();
+        assert_eq!(register.unwrap().content.winner().unwrap().value.content, entry(b"changed").content);
+        assert!(fetches < 8, "{} fetches", fetches);
+
+        let changed = reader
+            .diff_states(&before, &after, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(changed, BTreeSet::from(["dir/file7".to_string()]));
+        assert!(reader.shards.len() < 20);
+
+        let head = source.heads()[0].clone();
+        reader
+            .merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(reader.state(), source.state());
+    }
+
+    #[tokio::test]
+    async fn test_scoped_replica_reads_and_writes_subtree() {
+        let mut store = HashMap::new();
+        let mut full = Replica::new(alice()).with_snapshot_interval(0).with_shard_fanout(16);
+        for i in 0..200u32 {
+            let dir = ["photos/2024", "photos/2023", "docs"][i as usize % 3];
+            full.put(&format!("{}/file{}", dir, i), entry(&i.to_le_bytes())).unwrap();
+        }
+        full.snapshot().unwrap();
+        full.put("docs/after-snapshot", entry(b"d")).unwrap();
+        full.put("photos/2024/after-snapshot", entry(b"p")).unwrap();
+        publish(&mut full, &mut store).await;
+
+        let mut fetched = HashSet::new();
+        let mut scoped = Replica::new(bob()).with_scope("photos/2024/");
+        let head = full.heads()[0].clone();
+        scoped
+            .merge_head(&head, async |cid| {
+                fetched.insert(cid.clone());
+                store.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid))
+            })
+            .await
+            .unwrap();
+        let in_scope = full.state().iter().filter(|(path, _)| path.starts_with("photos/2024/")).count();
+        assert_eq!(scoped.state().iter().count(), in_scope);
+        assert_eq!(scoped.state().version_vector(), full.state().version_vector());
+        assert!(fetched.len() < store.len() / 2, "fetched {} of {} blocks", fetched.len(), store.len());
+
+        assert!(scoped.put("docs/elsewhere", entry(b"x")).is_err());
+        assert!(scoped.snapshot().is_err());
+        scoped.put("photos/2024/new.jpg", entry(b"new")).unwrap();
+        publish(&mut scoped, &mut store).await;
+        let head = scoped.heads()[0].clone();
+        full.merge_head(&head, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        assert_eq!(full.state().get("photos/2024/new.jpg"), Some(entry(b"new")));
+        assert_eq!(full.state().iter().count(), 203);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_replicas_converge() {
+        let mut store = HashMap::new();
+        let mut a = Replica::new(alice()).with_snapshot_interval(2);
+        let mut b = Replica::new(bob()).with_snapshot_interval(0);
+
+        a.put("shared", entry(b"a")).unwrap();
+        a.put("only-a", entry(b"a")).unwrap();
+        a.put("more-a", entry(b"a")).unwrap();
+        b.put("shared", entry(b"b")).unwrap();
+        b.put("only-b", entry(b"b")).unwrap();
+        publish(&mut a, &mut store).await;
+        publish(&mut b, &mut store).await;
+
+        let a_head = a.heads()[0].clone();
+        let b_head = b.heads()[0].clone();
+        let fetch_a = store.clone();
+        let fetch_b = store.clone();
+        a.merge_head(&b_head, async |cid| fetch_a.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+        b.merge_head(&a_head, async |cid| fetch_b.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+            .await
+            .unwrap();
+
+        assert_eq!(a.state(), b.state());
+        assert_eq!(a.heads().len(), 2);
+        assert_eq!(a.state().register("shared").unwrap().exists.versions().len(), 2);
+    }
+}
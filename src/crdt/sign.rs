@@ -0,0 +1,121 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::path::Path;
+
+pub const PUBLIC_KEY_LENGTH: usize = 32;
+pub const SIGNATURE_LENGTH: usize = 64;
+
+/// Ed25519 keypair a replica signs its DAG nodes with.
+#[derive(Clone)]
+pub struct ReplicaKeypair {
+    signing: SigningKey,
+}
+
+impl std::fmt::Debug for ReplicaKeypair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // never print the secret half
+        f.debug_struct("ReplicaKeypair").field("public_key", &self.public_key()).finish()
+    }
+}
+
+impl ReplicaKeypair {
+    /// Generates a fresh keypair from the OS random number generator.
+    pub fn generate() -> Result<Self> {
+        let mut seed = [0u8; 32];
+        getrandom::getrandom(&mut seed).map_err(|e| anyhow!("Failed to gather randomness: {}", e))?;
+        Ok(Self::from_seed(seed))
+    }
+
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        ReplicaKeypair { signing: SigningKey::from_bytes(&seed) }
+    }
+
+    /// Loads the keypair stored at `path`, generating and saving a new one
+    /// if the file doesn't exist yet.
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let bytes = std::fs::read(path).with_context(|| format!("Failed to read keypair {}", path.display()))?;
+            let seed: [u8; 32] = bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("Keypair file {} is not 32 bytes", path.display()))?;
+            return Ok(Self::from_seed(seed));
+        }
+
+        let keypair = Self::generate()?;
+        keypair.save(path)?;
+        Ok(keypair)
+    }
+
+    /// Writes the secret seed to `path`, readable only by the owner on Unix.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, self.signing.to_bytes())
+            .with_context(|| format!("Failed to write keypair {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+
+    /// The secret seed, as `save` writes it.
+    pub fn seed(&self) -> [u8; 32] {
+        self.signing.to_bytes()
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LENGTH] {
+        self.signing.verifying_key().to_bytes()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; SIGNATURE_LENGTH] {
+        self.signing.sign(message).to_bytes()
+    }
+}
+
+/// Checks that `signature` over `message` was made by `public_key`.
+pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<()> {
+    let public_key: &[u8; PUBLIC_KEY_LENGTH] = public_key
+        .try_into()
+        .map_err(|_| anyhow!("Public key must be {} bytes", PUBLIC_KEY_LENGTH))?;
+    let signature: &[u8; SIGNATURE_LENGTH] = signature
+        .try_into()
+        .map_err(|_| anyhow!("Signature must be {} bytes", SIGNATURE_LENGTH))?;
+
+    let key = VerifyingKey::from_bytes(public_key).map_err(|e| anyhow!("Invalid public key: {}", e))?;
+    if key.verify(message, &Signature::from_bytes(signature)).is_err() {
+        bail!("Invalid signature");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod sign_test {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let keypair = ReplicaKeypair::from_seed([7; 32]);
+        let signature = keypair.sign(b"message");
+        assert!(verify(&keypair.public_key(), b"message", &signature).is_ok());
+        assert!(verify(&keypair.public_key(), b"tampered", &signature).is_err());
+
+        let other = ReplicaKeypair::from_seed([8; 32]);
+        assert!(verify(&other.public_key(), b"message", &signature).is_err());
+    }
+
+    #[test]
+    fn test_load_or_generate_persists_key() {
+        let path = std::env::temp_dir().join(format!("crdt-keypair-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let first = ReplicaKeypair::load_or_generate(&path).unwrap();
+        let second = ReplicaKeypair::load_or_generate(&path).unwrap();
+        assert_eq!(first.public_key(), second.public_key());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
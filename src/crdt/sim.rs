@@ -0,0 +1,211 @@
+//! Randomized convergence simulator: drives several in-memory replicas
+//! through concurrent edits while the network delays, duplicates and
+//! reorders deltas, then checks every replica ends up in the same state.
+//! Built for tests and with the `sim` feature.
+
+use anyhow::{bail, Result};
+
+use super::identity::ReplicaId;
+use super::op::{ConflictPolicy, Entry};
+use super::replica::Replica;
+use super::sign::ReplicaKeypair;
+use super::wire::DeltaBundle;
+use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+
+/// Small deterministic PRNG (SplitMix64) so a failing seed replays exactly.
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        SimRng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`. `n` must be non-zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// True with probability `percent`/100.
+    pub fn chance(&mut self, percent: u32) -> bool {
+        self.below(100) < percent as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub seed: u64,
+    pub replicas: usize,
+    /// Number of simulation steps before the final flush.
+    pub steps: usize,
+    /// Distinct paths edits are drawn from; fewer paths, more conflicts.
+    pub paths: usize,
+    /// Percent chance a sent delta is delivered twice.
+    pub duplicate_percent: u32,
+    /// Percent chance a step delivers a message instead of editing.
+    pub deliver_percent: u32,
+    pub snapshot_interval: usize,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            seed: 0,
+            replicas: 3,
+            steps: 200,
+            paths: 5,
+            duplicate_percent: 20,
+            deliver_percent: 40,
+            snapshot_interval: 7,
+        }
+    }
+}
+
+/// A delta on its way to replica `to`.
+struct Message {
+    to: usize,
+    bundle: DeltaBundle,
+}
+
+pub struct Simulator {
+    config: SimConfig,
+    rng: SimRng,
+    replicas: Vec<Replica>,
+    in_flight: Vec<Message>,
+}
+
+impl Simulator {
+    pub fn new(config: SimConfig) -> Self {
+        let replicas = (0..config.replicas)
+            .map(|i| {
+                let keypair = ReplicaKeypair::from_seed([i as u8 + 1; 32]);
+                Replica::new(ReplicaId::from_ed25519(&keypair.public_key()))
+                    .with_keypair(keypair)
+                    .with_snapshot_interval(config.snapshot_interval)
+            })
+            .collect();
+        Simulator { rng: SimRng::new(config.seed), config, replicas, in_flight: Vec::new() }
+    }
+
+    pub fn replicas(&self) -> &[Replica] {
+        &self.replicas
+    }
+
+    /// Runs the configured number of random steps, then delivers everything
+    /// and syncs all pairs until nothing new arrives.
+    pub fn run(&mut self) -> Result<()> {
+        for _ in 0..self.config.steps {
+            if !self.in_flight.is_empty() && self.rng.chance(self.config.deliver_percent) {
+                self.deliver_random()?;
+            } else {
+                self.random_edit()?;
+                self.send_random();
+            }
+        }
+        while !self.in_flight.is_empty() {
+            self.deliver_random()?;
+        }
+        self.sync_all()
+    }
+
+    /// Checks that every replica reached the same state.
+    pub fn assert_converged(&self) -> Result<()> {
+        let first = self.replicas[0].state();
+        for (i, replica) in self.replicas.iter().enumerate().skip(1) {
+            if replica.state() != first || replica.state().digest() != first.digest() {
+                bail!("Seed {}: replica {} diverged from replica 0", self.config.seed, i);
+            }
+        }
+        Ok(())
+    }
+
+    fn random_edit(&mut self) -> Result<()> {
+        let who = self.rng.below(self.replicas.len());
+        let path = format!("dir{}/file{}", self.rng.below(2), self.rng.below(self.config.paths));
+        let replica = &mut self.replicas[who];
+        if self.rng.chance(3) {
+            let policies = [ConflictPolicy::LastWriterWins, ConflictPolicy::AddWins, ConflictPolicy::RemoveWins];
+            replica.set_conflict_policy(policies[self.rng.below(policies.len())])?;
+        } else if self.rng.chance(30) {
+            replica.remove(&path)?;
+        } else if self.rng.chance(20) {
+            replica.set_mode(&path, [0o644, 0o600, 0o755][self.rng.below(3)])?;
+        } else {
+            let data = self.rng.next_u64().to_le_bytes();
+            let entry = Entry { content: IpfsCid::compute(RAW_CODE, &data), size: 8, mode: 0o644, mtime: 0, chunks: Vec::new(), symlink: None };
+            replica.put(&path, entry)?;
+        }
+        Ok(())
+    }
+
+    /// Queues a delta from a random replica to another, computed against
+    /// what the receiver has right now; it may arrive much later.
+    fn send_random(&mut self) {
+        if self.replicas.len() < 2 {
+            return;
+        }
+        let from = self.rng.below(self.replicas.len());
+        let to = (from + 1 + self.rng.below(self.replicas.len() - 1)) % self.replicas.len();
+        let bundle = self.replicas[from].delta_since(self.replicas[to].state().version_vector());
+        if self.rng.chance(self.config.duplicate_percent) {
+            self.in_flight.push(Message { to, bundle: bundle.clone() });
+        }
+        self.in_flight.push(Message { to, bundle });
+    }
+
+    fn deliver_random(&mut self) -> Result<()> {
+        let index = self.rng.below(self.in_flight.len());
+        let message = self.in_flight.swap_remove(index);
+        self.replicas[message.to].apply_delta(&message.bundle)?;
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        loop {
+            let mut applied = 0;
+            for from in 0..self.replicas.len() {
+                for to in 0..self.replicas.len() {
+                    if from != to {
+                        let bundle = self.replicas[from].delta_since(self.replicas[to].state().version_vector());
+                        applied += self.replicas[to].apply_delta(&bundle)?;
+                    }
+                }
+            }
+            if applied == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod sim_test {
+    use super::*;
+
+    #[test]
+    fn test_random_histories_converge() {
+        for seed in 0..40 {
+            let mut sim = Simulator::new(SimConfig { seed, ..SimConfig::default() });
+            sim.run().unwrap();
+            sim.assert_converged().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_many_replicas_few_paths_converge() {
+        for seed in 0..10 {
+            let config = SimConfig { seed, replicas: 6, paths: 2, steps: 300, ..SimConfig::default() };
+            let mut sim = Simulator::new(config);
+            sim.run().unwrap();
+            sim.assert_converged().unwrap();
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Telling text from binary content, which decides how concurrent versions
+//! of a file are merged: text three-way merges line by line, binary keeps
+//! the last-writer-wins pick with the other versions as conflict copies.
+//! Well-known extensions settle it outright; otherwise content with a NUL
+//! byte near its start is binary, as git has it.
+
+use std::path::Path;
+
+/// Bytes looked at for a NUL.
+pub const SNIFF_LEN: usize = 8000;
+
+/// Extensions always taken as text.
+const TEXT_EXTENSIONS: [&str; 24] = [
+    "c", "cpp", "css", "csv", "go", "h", "html", "java", "js", "json", "md", "py", "rs", "sh", "sql", "svg", "tex", "toml", "ts", "tsv",
+    "txt", "xml", "yaml", "yml",
+];
+
+/// Extensions always taken as binary, though their content may have no NUL
+/// near the start or merge line by line without complaint.
+const BINARY_EXTENSIONS: [&str; 28] = [
+    "7z", "avi", "bin", "bz2", "class", "dll", "doc", "docx", "exe", "gif", "gz", "ico", "jar", "jpeg", "jpg", "mkv", "mov", "mp3", "mp4",
+    "pdf", "png", "pptx", "so", "tar", "wasm", "webp", "xlsx", "zip",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Text,
+    Binary,
+}
+
+/// What the extension of `path` says the file is, if it settles it.
+pub fn kind_of_path(path: &str) -> Option<ContentKind> {
+    let extension = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+    if TEXT_EXTENSIONS.contains(&extension.as_str()) {
+        Some(ContentKind::Text)
+    } else if BINARY_EXTENSIONS.contains(&extension.as_str()) {
+        Some(ContentKind::Binary)
+    } else {
+        None
+    }
+}
+
+/// Whether `data`, the content of the file at `path`, is text or binary.
+pub fn sniff(path: &str, data: &[u8]) -> ContentKind {
+    if let Some(kind) = kind_of_path(path) {
+        return kind;
+    }
+    if data[..data.len().min(SNIFF_LEN)].contains(&0) {
+        ContentKind::Binary
+    } else {
+        ContentKind::Text
+    }
+}
+
+#[cfg(test)]
+mod sniff_test {
+    use super::*;
+
+    #[test]
+    fn test_sniff_uses_extensions_then_nul_bytes() {
+        assert_eq!(sniff("notes", b"plain words\n"), ContentKind::Text);
+        assert_eq!(sniff("blob", b"\x89PNG\r\n\x1a\n\0\0"), ContentKind::Binary);
+        // past the sniffed prefix
+        let mut late = vec![b'a'; SNIFF_LEN];
+        late.push(0);
+        assert_eq!(sniff("blob", &late), ContentKind::Text);
+        assert_eq!(sniff("image.PNG", b"no nul here"), ContentKind::Binary);
+        assert_eq!(sniff("data.json", b"{\"a\": \"\0\"}"), ContentKind::Text);
+        assert_eq!(kind_of_path("Makefile"), None);
+    }
+}
@@ -0,0 +1,782 @@
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use super::chunk::{ChunkRule, ChunkSettings, Chunker};
+use super::clock::{Dot, Hlc, VersionVector};
+use super::identity::ReplicaId;
+use super::policy::FilePolicy;
+use super::op::{Access, Ancestor, ConflictPolicy, Content, Entry, Membership, Op, OpKind, MAX_LINEAGE};
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// One value written to a register, tagged with the write that produced it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version<T> {
+    pub dot: Dot,
+    pub timestamp: Hlc,
+    /// `Op::hash` of the write, the last tie-breaker.
+    #[serde(with = "serde_bytes")]
+    pub hash: Vec<u8>,
+    pub value: T,
+}
+
+impl<T> Version<T> {
+    pub fn of(op: &Op, value: T) -> Self {
+        Self::hashed(op, op.hash(), value)
+    }
+
+    fn hashed(op: &Op, hash: Vec<u8>, value: T) -> Self {
+        Version { dot: op.dot(), timestamp: op.timestamp, hash, value }
+    }
+}
+
+/// Last-writer-wins order between two versions: by HLC timestamp, then by
+/// author id, then by op hash. Every component is part of the replicated
+/// data, so every replica picks the same winner; the hash only decides
+/// between two different ops a forked author wrote with the same timestamp.
+pub fn lww_cmp<T>(a: &Version<T>, b: &Version<T>) -> Ordering {
+    (a.timestamp, &a.dot.author, &a.hash).cmp(&(b.timestamp, &b.dot.author, &b.hash))
+}
+
+/// Multi-value register: keeps every causally concurrent write so nothing
+/// is lost on merge. Readers pick a winner with `winner()`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MvRegister<T> {
+    versions: Vec<Version<T>>,
+}
+
+impl<T> Default for MvRegister<T> {
+    fn default() -> Self {
+        MvRegister { versions: Vec::new() }
+    }
+}
+
+impl<T: Clone> MvRegister<T> {
+    /// Records a write, discarding every version the writer had already seen.
+    pub fn write(&mut self, version: Version<T>, context: &VersionVector) {
+        self.versions.retain(|v| !context.contains(&v.dot) && v.dot != version.dot);
+        self.versions.push(version);
+        self.versions.sort_by(|a, b| a.dot.cmp(&b.dot));
+    }
+
+    /// Merges `other` into self. `self_seen`/`other_seen` are the version
+    /// vectors of the states the registers belong to.
+    pub fn join(&mut self, other: &MvRegister<T>, self_seen: &VersionVector, other_seen: &VersionVector) {
+        self.versions
+            .retain(|v| other.versions.iter().any(|o| o.dot == v.dot) || !other_seen.contains(&v.dot));
+        for v in &other.versions {
+            match self.versions.iter_mut().find(|s| s.dot == v.dot) {
+                // a forked author wrote two ops with one dot: keep the same
+                // one on every replica
+                Some(ours) if v.hash > ours.hash => *ours = v.clone(),
+                Some(_) => {}
+                None if !self_seen.contains(&v.dot) => self.versions.push(v.clone()),
+                None => {}
+            }
+        }
+        self.versions.sort_by(|a, b| a.dot.cmp(&b.dot));
+    }
+
+    /// Last-writer-wins pick among the concurrent versions, see `lww_cmp`.
+    pub fn winner(&self) -> Option<&Version<T>> {
+        self.versions.iter().max_by(|a, b| lww_cmp(a, b))
+    }
+
+    pub fn versions(&self) -> &[Version<T>] {
+        &self.versions
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+
+    /// Whether the op with `dot` and `hash` still has a version here.
+    fn holds(&self, dot: &Dot, hash: &[u8]) -> bool {
+        self.versions.iter().any(|v| v.dot == *dot && v.hash == hash)
+    }
+
+    fn strip(&mut self, dot: &Dot) {
+        self.versions.retain(|v| v.dot != *dot);
+    }
+}
+
+/// Everything recorded about one path: whether it exists, and one register
+/// per field so a patch to one field doesn't clobber a concurrent change to
+/// another. Puts write the four file registers, removes `exists` and a
+/// deletion of every extended attribute seen.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathRegister {
+    /// `true` for puts, `false` for removes; resolved under the conflict
+    /// policy.
+    pub exists: MvRegister<bool>,
+    pub content: MvRegister<Content>,
+    pub mode: MvRegister<u32>,
+    pub mtime: MvRegister<i64>,
+    /// One register per extended attribute, `None` once deleted. Left out
+    /// of the encoding when empty.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, MvRegister<Option<ByteBuf>>>,
+}
+
+impl PathRegister {
+    fn join(&mut self, other: &PathRegister, self_seen: &VersionVector, other_seen: &VersionVector) {
+        self.exists.join(&other.exists, self_seen, other_seen);
+        self.content.join(&other.content, self_seen, other_seen);
+        self.mode.join(&other.mode, self_seen, other_seen);
+        self.mtime.join(&other.mtime, self_seen, other_seen);
+        join_keyed(&mut self.xattrs, &other.xattrs, |a, b| a.join(b, self_seen, other_seen), MvRegister::is_empty);
+    }
+
+    fn holds(&self, dot: &Dot, hash: &[u8]) -> bool {
+        self.exists.holds(dot, hash)
+            || self.content.holds(dot, hash)
+            || self.mode.holds(dot, hash)
+            || self.mtime.holds(dot, hash)
+            || self.xattrs.values().any(|xattr| xattr.holds(dot, hash))
+    }
+
+    fn strip(&mut self, dot: &Dot) {
+        self.exists.strip(dot);
+        self.content.strip(dot);
+        self.mode.strip(dot);
+        self.mtime.strip(dot);
+        self.xattrs.values_mut().for_each(|xattr| xattr.strip(dot));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exists.is_empty()
+            && self.content.is_empty()
+            && self.mode.is_empty()
+            && self.mtime.is_empty()
+            && self.xattrs.is_empty()
+    }
+
+    /// Returns true if any register holds concurrent versions.
+    pub fn is_conflicted(&self) -> bool {
+        self.exists.versions().len() > 1
+            || self.content.versions().len() > 1
+            || self.mode.versions().len() > 1
+            || self.mtime.versions().len() > 1
+            || self.xattrs.values().any(|xattr| xattr.versions().len() > 1)
+    }
+
+    /// The newest content every concurrent content version descends from:
+    /// the base for a three-way merge of them. `None` without concurrent
+    /// versions, or if their recorded lineages share nothing.
+    pub fn merge_base(&self) -> Option<&IpfsCid> {
+        let (first, rest) = self.content.versions().split_first()?;
+        if rest.is_empty() {
+            return None;
+        }
+        let shared = |a: &Ancestor| rest.iter().all(|v| v.value.lineage.iter().any(|b| b.dot == a.dot));
+        first.value.lineage.iter().find(|a| shared(a)).map(|a| &a.content)
+    }
+
+    /// The newest write among the field registers, as `(timestamp, author,
+    /// hash, dot)` so it orders like `lww_cmp`.
+    fn latest_field_write(&self) -> Option<(Hlc, &ReplicaId, &[u8], &Dot)> {
+        let content = self.content.winner().map(write_key);
+        let mode = self.mode.winner().map(write_key);
+        let mtime = self.mtime.winner().map(write_key);
+        let xattrs = self.xattrs.values().filter_map(|xattr| xattr.winner().map(write_key));
+        [content, mode, mtime].into_iter().flatten().chain(xattrs).max()
+    }
+}
+
+fn write_key<T>(v: &Version<T>) -> (Hlc, &ReplicaId, &[u8], &Dot) {
+    (v.timestamp, &v.dot.author, &v.hash, &v.dot)
+}
+
+/// Materialized directory state: one register per path, plus the
+/// membership and access-control documents and the conflict policy.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    entries: BTreeMap<String, PathRegister>,
+    members: BTreeMap<ReplicaId, MvRegister<Membership>>,
+    /// Left out of the encoding when empty.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    access: BTreeMap<ReplicaId, MvRegister<Access>>,
+    policy: MvRegister<ConflictPolicy>,
+    /// Left out of the encoding when empty.
+    #[serde(default, skip_serializing_if = "MvRegister::is_empty")]
+    chunker: MvRegister<ChunkSettings>,
+    /// Left out of the encoding when empty.
+    #[serde(default, skip_serializing_if = "MvRegister::is_empty")]
+    file_policy: MvRegister<FilePolicy>,
+    version_vector: VersionVector,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies `op`. Returns false if it was already applied.
+    pub fn apply(&mut self, op: &Op) -> bool {
+        self.apply_where(op, |_| true)
+    }
+
+    /// Applies `op`, leaving out its changes to paths `keep` rejects, for
+    /// replicas that only track part of the workspace. Returns false if it
+    /// was already applied.
+    pub(crate) fn apply_where(&mut self, op: &Op, keep: impl Fn(&str) -> bool) -> bool {
+        let dot = op.dot();
+        let hash = op.hash();
+        let parts: Vec<&OpKind> = op.kind.parts().iter().filter(|kind| kind.path().is_none_or(&keep)).collect();
+        if self.version_vector.contains(&dot) {
+            return self.apply_equivocating(op, &hash, &parts);
+        }
+        for kind in parts {
+            self.apply_kind(op, &hash, kind);
+        }
+        self.version_vector.observe(&dot);
+        true
+    }
+
+    /// Merges in `op`, whose dot an op already applied used: a forked
+    /// author wrote both. `op` goes into a copy without that dot, which is
+    /// joined back as a snapshot would be: where both ops wrote a register
+    /// the higher hash stays, where only one did its write goes. Every
+    /// replica ends up the same whichever op came first. Returns false if
+    /// nothing changed, as for `op` itself applied again.
+    fn apply_equivocating(&mut self, op: &Op, hash: &[u8], parts: &[&OpKind]) -> bool {
+        let dot = op.dot();
+        if parts.iter().any(|kind| self.holds(kind, &dot, hash)) {
+            return false;
+        }
+        let mut other = self.clone();
+        other.strip(&dot);
+        for kind in parts {
+            other.apply_kind(op, hash, kind);
+        }
+        let mut joined = self.clone();
+        joined.join(&other);
+        let changed = joined != *self;
+        *self = joined;
+        changed
+    }
+
+    /// Whether a register `kind` writes still holds the version of the op
+    /// with `dot` and `hash`.
+    fn holds(&self, kind: &OpKind, dot: &Dot, hash: &[u8]) -> bool {
+        match kind {
+            OpKind::Put { path, .. } | OpKind::Remove { path } | OpKind::Patch { path, .. } => {
+                self.entries.get(path).is_some_and(|register| register.holds(dot, hash))
+            }
+            OpKind::SetMember { member, .. } => self.members.get(member).is_some_and(|register| register.holds(dot, hash)),
+            OpKind::SetAccess { member, .. } => self.access.get(member).is_some_and(|register| register.holds(dot, hash)),
+            OpKind::SetConflictPolicy { .. } => self.policy.holds(dot, hash),
+            OpKind::SetChunker { .. } => self.chunker.holds(dot, hash),
+            OpKind::SetFilePolicy { .. } => self.file_policy.holds(dot, hash),
+            OpKind::Transaction { .. } => false,
+        }
+    }
+
+    /// Drops every version written by the op with `dot`.
+    fn strip(&mut self, dot: &Dot) {
+        self.entries.values_mut().for_each(|register| register.strip(dot));
+        self.members.values_mut().for_each(|register| register.strip(dot));
+        self.access.values_mut().for_each(|register| register.strip(dot));
+        self.policy.strip(dot);
+        self.chunker.strip(dot);
+        self.file_policy.strip(dot);
+    }
+
+    fn apply_kind(&mut self, op: &Op, hash: &[u8], kind: &OpKind) {
+        match kind {
+            OpKind::Put { path, entry, lineage } => {
+                let register = self.entries.entry(path.clone()).or_default();
+                register.exists.write(Version::hashed(op, hash.to_vec(), true), &op.context);
+                let content = Content {
+                    content: entry.content.clone(),
+                    size: entry.size,
+                    lineage: lineage.clone(),
+                    chunks: entry.chunks.clone(),
+                    symlink: entry.symlink.clone(),
+                };
+                register.content.write(Version::hashed(op, hash.to_vec(), content), &op.context);
+                register.mode.write(Version::hashed(op, hash.to_vec(), entry.mode), &op.context);
+                register.mtime.write(Version::hashed(op, hash.to_vec(), entry.mtime), &op.context);
+            }
+            OpKind::Remove { path } => {
+                let register = self.entries.entry(path.clone()).or_default();
+                register.exists.write(Version::hashed(op, hash.to_vec(), false), &op.context);
+                for xattr in register.xattrs.values_mut() {
+                    xattr.write(Version::hashed(op, hash.to_vec(), None), &op.context);
+                }
+            }
+            OpKind::Patch { path, patch } => {
+                let register = self.entries.entry(path.clone()).or_default();
+                if let Some(content) = &patch.content {
+                    // an edit keeps the file as a put would, against a
+                    // concurrent remove
+                    register.exists.write(Version::hashed(op, hash.to_vec(), true), &op.context);
+                    register.content.write(Version::hashed(op, hash.to_vec(), content.clone()), &op.context);
+                }
+                if let Some(mode) = patch.mode {
+                    register.mode.write(Version::hashed(op, hash.to_vec(), mode), &op.context);
+                }
+                if let Some(mtime) = patch.mtime {
+                    register.mtime.write(Version::hashed(op, hash.to_vec(), mtime), &op.context);
+                }
+                for (key, value) in &patch.xattrs {
+                    let xattr = register.xattrs.entry(key.clone()).or_default();
+                    xattr.write(Version::hashed(op, hash.to_vec(), value.clone()), &op.context);
+                }
+            }
+            OpKind::SetMember { member, membership } => {
+                self.members
+                    .entry(member.clone())
+                    .or_default()
+                    .write(Version::hashed(op, hash.to_vec(), membership.clone()), &op.context);
+            }
+            OpKind::SetAccess { member, access } => {
+                self.access
+                    .entry(member.clone())
+                    .or_default()
+                    .write(Version::hashed(op, hash.to_vec(), access.clone()), &op.context);
+            }
+            OpKind::SetConflictPolicy { policy } => {
+                self.policy.write(Version::hashed(op, hash.to_vec(), *policy), &op.context);
+            }
+            OpKind::SetChunker { chunker, rules } => {
+                let settings = ChunkSettings { chunker: *chunker, rules: rules.clone() };
+                self.chunker.write(Version::hashed(op, hash.to_vec(), settings), &op.context);
+            }
+            OpKind::SetFilePolicy { policy } => {
+                self.file_policy.write(Version::hashed(op, hash.to_vec(), policy.clone()), &op.context);
+            }
+            // validation keeps transactions flat
+            OpKind::Transaction { .. } => {}
+        }
+    }
+
+    /// Records `op` as seen without applying it, for replicas that only
+    /// track part of the workspace. Returns false if it was already seen.
+    pub(crate) fn skip(&mut self, op: &Op) -> bool {
+        let dot = op.dot();
+        if self.version_vector.contains(&dot) {
+            return false;
+        }
+        self.version_vector.observe(&dot);
+        true
+    }
+
+    /// Merges another state into this one (e.g. one embedded in a snapshot).
+    pub fn join(&mut self, other: &State) {
+        let (ours, theirs) = (&self.version_vector, &other.version_vector);
+        join_keyed(&mut self.entries, &other.entries, |a, b| a.join(b, ours, theirs), PathRegister::is_empty);
+        join_keyed(&mut self.members, &other.members, |a, b| a.join(b, ours, theirs), MvRegister::is_empty);
+        join_keyed(&mut self.access, &other.access, |a, b| a.join(b, ours, theirs), MvRegister::is_empty);
+        self.policy.join(&other.policy, &self.version_vector, &other.version_vector);
+        self.chunker.join(&other.chunker, &self.version_vector, &other.version_vector);
+        self.file_policy.join(&other.file_policy, &self.version_vector, &other.version_vector);
+        self.version_vector.join(&other.version_vector);
+    }
+
+    /// Returns the current entry at `path`, if the file exists: each field
+    /// is the last-writer-wins pick of its own register.
+    pub fn get(&self, path: &str) -> Option<Entry> {
+        self.entry_of(self.entries.get(path)?)
+    }
+
+    /// The lineage a new content write at `path` records: the current
+    /// content versions and what they descend from, newest first.
+    pub fn lineage(&self, path: &str) -> Vec<Ancestor> {
+        self.lineage_of(&[path])
+    }
+
+    /// `lineage` of several paths at once, as for a file moved from one to
+    /// another.
+    pub fn lineage_of(&self, paths: &[&str]) -> Vec<Ancestor> {
+        let mut lineage = Vec::new();
+        for register in paths.iter().filter_map(|path| self.entries.get(*path)) {
+            for v in register.content.versions() {
+                lineage.push(Ancestor { dot: v.dot.clone(), timestamp: v.timestamp, content: v.value.content.clone() });
+                lineage.extend(v.value.lineage.iter().cloned());
+            }
+        }
+        lineage.sort_by(|a, b| (b.timestamp, &b.dot).cmp(&(a.timestamp, &a.dot)));
+        lineage.dedup_by(|a, b| a.dot == b.dot);
+        lineage.truncate(MAX_LINEAGE);
+        lineage
+    }
+
+    /// The latest write readers see at `path`: the put or remove deciding
+    /// whether it exists under the conflict policy, or a later patch.
+    pub fn latest_write(&self, path: &str) -> Option<&Dot> {
+        let register = self.entries.get(path)?;
+        let exists = self.current(&register.exists)?;
+        let decided = write_key(exists);
+        match register.latest_field_write() {
+            Some(patched) if exists.value && patched > decided => Some(patched.3),
+            _ => Some(&exists.dot),
+        }
+    }
+
+    /// Concurrent policy changes resolve last-writer-wins.
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        self.policy.winner().map(|v| v.value).unwrap_or_default()
+    }
+
+    /// The chunker the workspace asks every replica to use, if it has set
+    /// one. Concurrent changes resolve last-writer-wins.
+    pub fn chunker(&self) -> Option<Chunker> {
+        self.chunker.winner().map(|v| v.value.chunker)
+    }
+
+    /// The workspace's per-glob chunking rules, in order.
+    pub fn chunk_rules(&self) -> &[ChunkRule] {
+        self.chunker.winner().map_or(&[], |v| v.value.rules.as_slice())
+    }
+
+    /// The files the workspace doesn't sync, see `FilePolicy`. Concurrent
+    /// changes resolve last-writer-wins.
+    pub fn file_policy(&self) -> FilePolicy {
+        self.file_policy.winner().map(|v| v.value.clone()).unwrap_or_default()
+    }
+
+    /// Every chunking setting held, losers of concurrent changes included.
+    pub(crate) fn chunk_settings(&self) -> impl Iterator<Item = &ChunkSettings> {
+        self.chunker.versions().iter().map(|v| &v.value)
+    }
+
+    /// Every file policy held, losers of concurrent changes included.
+    pub(crate) fn file_policies(&self) -> impl Iterator<Item = &FilePolicy> {
+        self.file_policy.versions().iter().map(|v| &v.value)
+    }
+
+    fn current<'a>(&self, register: &'a MvRegister<bool>) -> Option<&'a Version<bool>> {
+        let latest = |put: bool| register.versions().iter().filter(|v| v.value == put).max_by(|a, b| lww_cmp(a, b));
+        match self.conflict_policy() {
+            ConflictPolicy::LastWriterWins => register.winner(),
+            ConflictPolicy::AddWins => latest(true).or_else(|| latest(false)),
+            ConflictPolicy::RemoveWins => latest(false).or_else(|| latest(true)),
+        }
+    }
+
+    fn entry_of(&self, register: &PathRegister) -> Option<Entry> {
+        if !self.current(&register.exists)?.value {
+            return None;
+        }
+        let content = &register.content.winner()?.value;
+        Some(Entry {
+            content: content.content.clone(),
+            size: content.size,
+            mode: register.mode.winner()?.value,
+            mtime: register.mtime.winner()?.value,
+            chunks: content.chunks.clone(),
+            symlink: content.symlink.clone(),
+        })
+    }
+
+    pub fn register(&self, path: &str) -> Option<&PathRegister> {
+        self.entries.get(path)
+    }
+
+    /// Iterates over every path register, deleted paths included.
+    pub fn registers(&self) -> impl Iterator<Item = (&str, &PathRegister)> {
+        self.entries.iter().map(|(path, reg)| (path.as_str(), reg))
+    }
+
+    /// Iterates over existing files in path order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Entry)> {
+        self.entries
+            .iter()
+            .filter_map(|(path, reg)| Some((path.as_str(), self.entry_of(reg)?)))
+    }
+
+    /// Returns the current standing of `member` in the membership document.
+    pub fn member(&self, member: &ReplicaId) -> Option<&Membership> {
+        Some(&self.members.get(member)?.winner()?.value)
+    }
+
+    /// Iterates over everyone the membership document lists, removed
+    /// members included.
+    pub fn members(&self) -> impl Iterator<Item = (&ReplicaId, &Membership)> {
+        self.members
+            .iter()
+            .filter_map(|(member, reg)| Some((member, &reg.winner()?.value)))
+    }
+
+    /// What `member` may write according to the access-control document;
+    /// concurrent changes resolve last-writer-wins. `None` if unlisted.
+    pub fn access(&self, member: &ReplicaId) -> Option<&Access> {
+        Some(&self.access.get(member)?.winner()?.value)
+    }
+
+    /// Iterates over everyone the access-control document lists.
+    pub fn access_list(&self) -> impl Iterator<Item = (&ReplicaId, &Access)> {
+        self.access
+            .iter()
+            .filter_map(|(member, reg)| Some((member, &reg.winner()?.value)))
+    }
+
+    /// Returns true once anyone has been listed in the access-control
+    /// document. Until then every admitted writer may write anything.
+    pub fn has_access_list(&self) -> bool {
+        !self.access.is_empty()
+    }
+
+    /// Returns true once anyone has been added to the membership document.
+    /// Until then the workspace is open to every writer.
+    pub fn has_members(&self) -> bool {
+        !self.members.is_empty()
+    }
+
+    pub fn version_vector(&self) -> &VersionVector {
+        &self.version_vector
+    }
+
+    /// A copy of the state without its path registers, which snapshots
+    /// store sharded.
+    pub(crate) fn without_entries(&self) -> State {
+        State {
+            entries: BTreeMap::new(),
+            members: self.members.clone(),
+            access: self.access.clone(),
+            policy: self.policy.clone(),
+            chunker: self.chunker.clone(),
+            file_policy: self.file_policy.clone(),
+            version_vector: self.version_vector.clone(),
+        }
+    }
+
+    /// Puts back path registers split off by `without_entries`.
+    pub(crate) fn with_entries(mut self, entries: BTreeMap<String, PathRegister>) -> State {
+        self.entries = entries;
+        self
+    }
+}
+
+/// Joins two keyed register maps with `join`, dropping registers left
+/// without versions.
+fn join_keyed<K: Ord + Clone, R: Default>(
+    ours: &mut BTreeMap<K, R>,
+    theirs: &BTreeMap<K, R>,
+    join: impl Fn(&mut R, &R),
+    is_empty: impl Fn(&R) -> bool,
+) {
+    let mut keys: Vec<K> = ours.keys().cloned().collect();
+    keys.extend(theirs.keys().filter(|k| !ours.contains_key(*k)).cloned());
+
+    let empty = R::default();
+    for key in keys {
+        let other = theirs.get(&key).unwrap_or(&empty);
+        let register = ours.entry(key.clone()).or_default();
+        join(register, other);
+        if is_empty(register) {
+            ours.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod state_test {
+    use super::*;
+    use crate::crdt::op::EntryPatch;
+    use crate::crdt::identity::ReplicaId;
+    use crate::test_util::{alice, bob, entry};
+
+    fn op(author: ReplicaId, seq: u64, millis: u64, context: &VersionVector, kind: OpKind) -> Op {
+        Op { author, seq, timestamp: Hlc { millis, counter: 0 }, context: context.clone(), kind, reverts: Vec::new() }
+    }
+
+    #[test]
+    fn test_concurrent_puts_keep_both_versions() {
+        let empty = VersionVector::new();
+        let a = op(alice(), 1, 10, &empty, OpKind::Put { path: "f".into(), entry: entry(b"a"), lineage: Vec::new() });
+        let b = op(bob(), 1, 20, &empty, OpKind::Put { path: "f".into(), entry: entry(b"b"), lineage: Vec::new() });
+
+        let mut state = State::new();
+        assert!(state.apply(&a));
+        assert!(state.apply(&b));
+        assert!(!state.apply(&b));
+
+        assert_eq!(state.register("f").unwrap().exists.versions().len(), 2);
+        assert_eq!(state.get("f"), Some(entry(b"b")));
+    }
+
+    #[test]
+    fn test_concurrent_symlink_targets_last_writer_wins() {
+        let empty = VersionVector::new();
+        let file = op(alice(), 1, 10, &empty, OpKind::Put { path: "l".into(), entry: entry(b"a"), lineage: Vec::new() });
+        let mut state = State::new();
+        state.apply(&file);
+
+        let seen = state.version_vector().clone();
+        let a = op(alice(), 2, 30, &seen, OpKind::Put { path: "l".into(), entry: Entry::symlink("x", 0), lineage: Vec::new() });
+        let b = op(bob(), 1, 20, &seen, OpKind::Put { path: "l".into(), entry: Entry::symlink("../y", 0), lineage: Vec::new() });
+        let mut other = state.clone();
+        state.apply(&a);
+        state.apply(&b);
+        other.apply(&b);
+        other.apply(&a);
+
+        assert_eq!(state, other);
+        assert_eq!(state.register("l").unwrap().content.versions().len(), 2);
+        assert_eq!(state.get("l").unwrap().symlink.as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn test_remove_overwrites_seen_put() {
+        let empty = VersionVector::new();
+        let put = op(alice(), 1, 10, &empty, OpKind::Put { path: "f".into(), entry: entry(b"a"), lineage: Vec::new() });
+        let mut state = State::new();
+        state.apply(&put);
+
+        let rm = op(bob(), 1, 11, state.version_vector(), OpKind::Remove { path: "f".into() });
+        state.apply(&rm);
+
+        assert_eq!(state.get("f"), None);
+        assert_eq!(state.register("f").unwrap().exists.versions().len(), 1);
+        assert_eq!(state.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_conflict_policy_decides_concurrent_put_and_remove() {
+        let empty = VersionVector::new();
+        let put = op(alice(), 1, 10, &empty, OpKind::Put { path: "f".into(), entry: entry(b"a"), lineage: Vec::new() });
+        let mut seen = VersionVector::new();
+        seen.observe(&put.dot());
+        // bob removes what he saw while alice concurrently re-puts
+        let rm = op(bob(), 1, 20, &seen, OpKind::Remove { path: "f".into() });
+        let reput = op(alice(), 2, 15, &seen, OpKind::Put { path: "f".into(), entry: entry(b"b"), lineage: Vec::new() });
+
+        let mut state = State::new();
+        for o in [&put, &rm, &reput] {
+            state.apply(o);
+        }
+        assert_eq!(state.get("f"), None);
+
+        let policy = |state: &State, seq, policy| {
+            op(alice(), seq, 30, state.version_vector(), OpKind::SetConflictPolicy { policy })
+        };
+        let add_wins = policy(&state, 3, ConflictPolicy::AddWins);
+        state.apply(&add_wins);
+        assert_eq!(state.get("f"), Some(entry(b"b")));
+        assert_eq!(state.iter().count(), 1);
+
+        let remove_wins = policy(&state, 4, ConflictPolicy::RemoveWins);
+        state.apply(&remove_wins);
+        assert_eq!(state.get("f"), None);
+    }
+
+    #[test]
+    fn test_concurrent_field_patches_both_survive() {
+        let empty = VersionVector::new();
+        let put = op(alice(), 1, 10, &empty, OpKind::Put { path: "f".into(), entry: entry(b"a"), lineage: Vec::new() });
+        let mut seen = VersionVector::new();
+        seen.observe(&put.dot());
+        let chmod = EntryPatch { mode: Some(0o755), ..EntryPatch::default() };
+        let chmod = op(alice(), 2, 20, &seen, OpKind::Patch { path: "f".into(), patch: chmod });
+        let edited = entry(b"edited");
+        let edit = EntryPatch {
+            content: Some(Content { content: edited.content.clone(), size: edited.size, lineage: Vec::new(), chunks: Vec::new(), symlink: None }),
+            mtime: Some(99),
+            ..EntryPatch::default()
+        };
+        let edit = op(bob(), 1, 15, &seen, OpKind::Patch { path: "f".into(), patch: edit });
+
+        let mut left = State::new();
+        left.apply(&put);
+        left.apply(&chmod);
+        let mut right = State::new();
+        right.apply(&put);
+        right.apply(&edit);
+        left.join(&right);
+        right.join(&left);
+
+        let expected = Entry { mode: 0o755, mtime: 99, ..edited };
+        assert_eq!(left.get("f"), Some(expected.clone()));
+        assert_eq!(right.get("f"), Some(expected));
+        assert_eq!(left.latest_write("f"), Some(&chmod.dot()));
+    }
+
+    #[test]
+    fn test_equal_timestamps_break_ties_by_author() {
+        let empty = VersionVector::new();
+        let a = op(alice(), 1, 10, &empty, OpKind::Put { path: "f".into(), entry: entry(b"a"), lineage: Vec::new() });
+        let b = op(bob(), 1, 10, &empty, OpKind::Put { path: "f".into(), entry: entry(b"b"), lineage: Vec::new() });
+
+        let mut ab = State::new();
+        ab.apply(&a);
+        ab.apply(&b);
+        let mut ba = State::new();
+        ba.apply(&b);
+        ba.apply(&a);
+
+        let expected = if bob() > alice() { entry(b"b") } else { entry(b"a") };
+        assert_eq!(ab.get("f"), Some(expected.clone()));
+        assert_eq!(ba.get("f"), Some(expected));
+    }
+
+    #[test]
+    fn test_forked_author_resolves_by_op_hash() {
+        // the same author key wrote two different ops with the same dot
+        let empty = VersionVector::new();
+        let one = op(alice(), 1, 10, &empty, OpKind::Put { path: "f".into(), entry: entry(b"1"), lineage: Vec::new() });
+        let two = op(alice(), 1, 10, &empty, OpKind::Put { path: "f".into(), entry: entry(b"2"), lineage: Vec::new() });
+        let mut left = State::new();
+        left.apply(&one);
+        let mut right = State::new();
+        right.apply(&two);
+
+        let mut lr = left.clone();
+        lr.join(&right);
+        let mut rl = right.clone();
+        rl.join(&left);
+        assert_eq!(lr, rl);
+
+        let expected = if one.hash() > two.hash() { entry(b"1") } else { entry(b"2") };
+        assert_eq!(lr.get("f"), Some(expected));
+    }
+
+    #[test]
+    fn test_forked_author_ops_apply_the_same_in_either_order() {
+        let put = |path: &str, data: &[u8]| OpKind::Put { path: path.into(), entry: entry(data), lineage: Vec::new() };
+        let mut state = State::new();
+        state.apply(&op(alice(), 1, 10, &VersionVector::new(), put("base", b"0")));
+
+        // a restored backup of alice writes seq 2 a second time
+        let seen = state.version_vector().clone();
+        let x = op(alice(), 2, 20, &seen, OpKind::Transaction { ops: vec![put("f", b"x"), put("x", b"x")] });
+        let y = op(alice(), 2, 20, &seen, OpKind::Transaction { ops: vec![put("f", b"y"), put("y", b"y")] });
+        let mut other = state.clone();
+        assert!(state.apply(&x) && state.apply(&y));
+        assert!(other.apply(&y) && other.apply(&x));
+        assert!(!state.apply(&x) && !state.apply(&y));
+
+        assert_eq!(state, other);
+        let winner = if x.hash() > y.hash() { b"x" } else { b"y" };
+        assert_eq!(state.get("f"), Some(entry(winner)));
+        assert!(state.get("x").is_none() && state.get("y").is_none());
+        assert!(state.get("base").is_some());
+    }
+
+    #[test]
+    fn test_join_is_commutative() {
+        let empty = VersionVector::new();
+        let a1 = op(alice(), 1, 10, &empty, OpKind::Put { path: "x".into(), entry: entry(b"1"), lineage: Vec::new() });
+        let b1 = op(bob(), 1, 12, &empty, OpKind::Put { path: "y".into(), entry: entry(b"2"), lineage: Vec::new() });
+
+        let mut left = State::new();
+        left.apply(&a1);
+        let a2 = op(alice(), 2, 13, left.version_vector(), OpKind::Remove { path: "x".into() });
+        left.apply(&a2);
+
+        let mut right = State::new();
+        right.apply(&a1);
+        right.apply(&b1);
+
+        let mut lr = left.clone();
+        lr.join(&right);
+        let mut rl = right.clone();
+        rl.join(&left);
+
+        assert_eq!(lr, rl);
+        assert_eq!(lr.get("x"), None);
+        assert_eq!(lr.get("y"), Some(entry(b"2")));
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeMap;
+
+use super::history::path_matches;
+use super::op::{Entry, EntryPatch, OpKind};
+use super::replica::Replica;
+use super::validate::validate_path;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// Changes to several paths committed as one operation, e.g. a rename plus
+/// a content update or a refactor across many files. Every replica applies
+/// all of them at once, so no one sees half of it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transaction {
+    ops: Vec<OpKind>,
+    /// The path each moved file came from, by the index of its put.
+    moved_from: BTreeMap<usize, String>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Transaction::default()
+    }
+
+    /// Creates or overwrites the file at `path`, the latter as a patch
+    /// like `Replica::put`.
+    pub fn put(mut self, path: &str, entry: Entry) -> Self {
+        self.ops.push(OpKind::Put { path: path.to_string(), entry, lineage: Vec::new() });
+        self
+    }
+
+    /// Moves the file at `from` to `to`, where it has `entry`. The put at
+    /// `to` descends from the content `from` had, so a concurrent edit of
+    /// the file merges with the moved copy as with any other version.
+    pub fn rename(mut self, from: &str, to: &str, entry: Entry) -> Self {
+        self.moved_from.insert(self.ops.len(), from.to_string());
+        self.ops.push(OpKind::Put { path: to.to_string(), entry, lineage: Vec::new() });
+        self.ops.push(OpKind::Remove { path: from.to_string() });
+        self
+    }
+
+    /// Moves the directory `from` to `to`: each of `files`, by its path
+    /// under `from`, as `rename` does.
+    pub fn rename_dir(mut self, from: &str, to: &str, files: impl IntoIterator<Item = (String, Entry)>) -> Self {
+        for (relative, entry) in files {
+            self = self.rename(&format!("{}/{}", from, relative), &format!("{}/{}", to, relative), entry);
+        }
+        self
+    }
+
+    /// Deletes the file at `path`.
+    pub fn remove(mut self, path: &str) -> Self {
+        self.ops.push(OpKind::Remove { path: path.to_string() });
+        self
+    }
+
+    /// Changes only the fields set in `patch` on the file at `path`.
+    pub fn patch(mut self, path: &str, patch: EntryPatch) -> Self {
+        self.ops.push(OpKind::Patch { path: path.to_string(), patch });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl Replica {
+    /// Commits every change in `tx` as a single operation. Changes apply in
+    /// order, so a later one to the same path overrides an earlier one.
+    /// Replicas scoped to part of the workspace apply the changes within
+    /// their scope. Unlike `patch` and `remove`, changes to paths that
+    /// don't exist yet aren't skipped.
+    pub fn commit_transaction(&mut self, tx: Transaction) -> Result<IpfsCid> {
+        if tx.is_empty() {
+            bail!("Transaction makes no change");
+        }
+        let mut ops = Vec::with_capacity(tx.ops.len());
+        for (index, kind) in tx.ops.into_iter().enumerate() {
+            ops.push(self.prepare(kind, tx.moved_from.get(&index))?);
+        }
+        self.commit(OpKind::Transaction { ops })
+    }
+
+    /// Moves the file at `from` to `to` as one operation.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<IpfsCid> {
+        let entry = self.state().get(from).ok_or_else(|| anyhow!("No file at {}", from))?;
+        self.commit_transaction(Transaction::new().rename(from, to, entry))
+    }
+
+    /// Moves every file under the directory `from` to `to` as one
+    /// operation.
+    pub fn rename_dir(&mut self, from: &str, to: &str) -> Result<IpfsCid> {
+        let (from, to) = (from.trim_matches('/'), to.trim_matches('/'));
+        let files: Vec<(String, Entry)> = self
+            .state()
+            .iter()
+            .filter_map(|(path, entry)| Some((path.strip_prefix(from)?.strip_prefix('/')?.to_string(), entry)))
+            .collect();
+        if files.is_empty() {
+            bail!("No files under {}", from);
+        }
+        self.commit_transaction(Transaction::new().rename_dir(from, to, files))
+    }
+
+    /// Checks one change of a transaction and records its lineage, that of
+    /// the path a put was `moved_from` too.
+    fn prepare(&self, kind: OpKind, moved_from: Option<&String>) -> Result<OpKind> {
+        let path = kind.path().unwrap_or_default();
+        validate_path(path, self.limits())?;
+        if !path_matches(self.scope(), path) {
+            bail!("{} is outside the scope {} of this replica", path, self.scope());
+        }
+        Ok(match kind {
+            OpKind::Put { path, entry, .. } => match (moved_from, self.state().get(&path)) {
+                (Some(from), _) => {
+                    let lineage = self.state().lineage_of(&[from, &path]);
+                    OpKind::Put { path, entry, lineage }
+                }
+                // an edit, as in `Replica::put`
+                (None, Some(current)) => self.prepare(OpKind::Patch { path, patch: EntryPatch::edit(current.mode, entry) }, None)?,
+                (None, None) => {
+                    let lineage = self.state().lineage(&path);
+                    OpKind::Put { path, entry, lineage }
+                }
+            },
+            OpKind::Patch { path, mut patch } => {
+                if patch.is_empty() {
+                    bail!("Patch of {} changes nothing", path);
+                }
+                if let Some(content) = &mut patch.content {
+                    content.lineage = self.state().lineage(&path);
+                }
+                OpKind::Patch { path, patch }
+            }
+            other => other,
+        })
+    }
+}
+
+#[cfg(test)]
+mod transaction_test {
+    use super::*;
+    use crate::crdt::clock::VersionVector;
+    use crate::test_util::{alice, bob, entry};
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn test_transaction_applies_as_one_op() {
+        let mut a = Replica::new(alice());
+        a.put("src/old.rs", entry(b"v1")).unwrap();
+        let mut b = Replica::new(bob());
+        b.apply_delta(&a.delta_since(&VersionVector::new())).unwrap();
+
+        let tx = Transaction::new()
+            .remove("src/old.rs")
+            .put("src/new.rs", entry(b"v2"))
+            .put("docs/notes.md", entry(b"n"))
+            .patch("docs/notes.md", EntryPatch { mode: Some(0o600), ..EntryPatch::default() });
+        let cid = a.commit_transaction(tx).unwrap();
+        assert_eq!(a.state().get("docs/notes.md").unwrap().mode, 0o600);
+
+        assert_eq!(b.apply_delta(&a.delta_since(b.state().version_vector())).unwrap(), 1);
+        assert_eq!(b.state(), a.state());
+        assert!(b.state().get("src/old.rs").is_none());
+
+        let records: Vec<_> = b.history("src").collect().await;
+        assert_eq!(records.len(), 3);
+        assert!(records[..2].iter().all(|record| record.node == cid));
+        assert_eq!(records[1].content, Some(entry(b"v2").content));
+    }
+
+    #[test]
+    fn test_scoped_replica_applies_its_part() {
+        let mut a = Replica::new(alice());
+        let mut docs = Replica::new(bob()).with_scope("docs");
+        let tx = Transaction::new().put("docs/a.md", entry(b"a")).put("src/b.rs", entry(b"b"));
+        a.commit_transaction(tx).unwrap();
+        docs.apply_delta(&a.delta_since(&VersionVector::new())).unwrap();
+        assert!(docs.state().get("docs/a.md").is_some());
+        assert!(docs.state().get("src/b.rs").is_none());
+        assert_eq!(docs.state().version_vector(), a.state().version_vector());
+
+        assert!(docs.commit_transaction(Transaction::new()).is_err());
+        assert!(docs.commit_transaction(Transaction::new().put("src/c.rs", entry(b"c"))).is_err());
+        assert!(a.commit_transaction(Transaction::new().remove("../escape")).is_err());
+    }
+}
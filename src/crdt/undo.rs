@@ -0,0 +1,143 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, HashSet};
+
+use super::clock::Dot;
+use super::op::{Op, OpKind};
+use super::replica::{topological_order, Replica};
+use super::state::State;
+use super::wire::Node;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+impl Replica {
+    /// Reverts this replica's `n` most recent operations by writing
+    /// compensating operations, so the revert replicates like any other
+    /// change instead of rewriting history. They go out as one
+    /// transaction, so no other replica sees half an undo.
+    ///
+    /// A path is only reverted while one of the undone operations is still
+    /// its current value; later changes by other replicas are left alone.
+    /// Membership changes are never undone, nor are ops already reverted or
+    /// written as reverts, which the reverts record in `Op::reverts`.
+    /// Returns the CID of the compensating op node, if anything was left
+    /// to revert.
+    pub fn undo_last(&mut self, n: usize) -> Result<Option<IpfsCid>> {
+        let mut own: Vec<(IpfsCid, Op)> = self
+            .nodes()
+            .filter_map(|(cid, node)| match node {
+                Node::Op(op_node) if op_node.op.author == *self.author() => Some((cid.clone(), op_node.op.clone())),
+                _ => None,
+            })
+            .collect();
+        let reverted: HashSet<u64> = own.iter().flat_map(|(_, op)| op.reverts.iter().copied()).collect();
+        own.retain(|(_, op)| !op.kind.paths().is_empty() && op.reverts.is_empty() && !reverted.contains(&op.seq));
+        own.sort_by_key(|(_, op)| std::cmp::Reverse(op.seq));
+        own.truncate(n);
+        let Some((oldest, _)) = own.last().cloned() else {
+            return Ok(None);
+        };
+
+        let mut by_path: BTreeMap<String, Vec<Dot>> = BTreeMap::new();
+        for (_, op) in &own {
+            for path in op.kind.paths() {
+                by_path.entry(path.to_string()).or_default().push(op.dot());
+            }
+        }
+        let mut reverts: Vec<u64> = own.iter().map(|(_, op)| op.seq).collect();
+        reverts.sort();
+        let undone: HashSet<Dot> = own.into_iter().map(|(_, op)| op.dot()).collect();
+        let without = self.state_without(&oldest, &undone)?;
+
+        let mut compensations = Vec::new();
+        for (path, targets) in by_path {
+            let still_current = self.state().latest_write(&path).is_some_and(|latest| targets.contains(latest));
+            if !still_current {
+                continue;
+            }
+            compensations.push(match without.get(&path) {
+                Some(entry) => {
+                    let lineage = self.state().lineage(&path);
+                    OpKind::Put { path, entry, lineage }
+                }
+                None if self.state().get(&path).is_some() => OpKind::Remove { path },
+                None => continue,
+            });
+        }
+        let kind = match compensations.len() {
+            0 => return Ok(None),
+            1 => compensations.pop().expect("one compensation"),
+            _ => OpKind::Transaction { ops: compensations },
+        };
+        self.commit_reverting(kind, reverts).map(Some)
+    }
+
+    /// The state as if none of the `undone` operations, the oldest of which
+    /// is in the node `oldest`, had been written: the state before `oldest`
+    /// plus every other operation held since.
+    fn state_without(&self, oldest: &IpfsCid, undone: &HashSet<Dot>) -> Result<State> {
+        let mut state = State::new();
+        if let Some(node) = self.node(oldest) {
+            for parent in node.parents() {
+                state.join(&self.state_at(parent)?);
+            }
+        }
+        let since: Vec<(IpfsCid, Node)> = self
+            .nodes()
+            .filter(|(_, node)| match node {
+                Node::Op(op_node) => !state.version_vector().contains(&op_node.op.dot()) && !undone.contains(&op_node.op.dot()),
+                Node::Snapshot(_) => false,
+            })
+            .map(|(cid, node)| (cid.clone(), node.clone()))
+            .collect();
+        for index in topological_order(&since) {
+            if let Node::Op(op_node) = &since[index].1 {
+                state.apply(&op_node.op);
+            }
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod undo_test {
+    use super::*;
+    use crate::test_util::{alice, entry};
+
+    fn replica() -> Replica {
+        Replica::new(alice()).with_snapshot_interval(3)
+    }
+
+    #[test]
+    fn test_undo_restores_deleted_and_overwritten_files() {
+        let mut replica = replica();
+        replica.put("a.txt", entry(b"v1")).unwrap();
+        replica.put("b.txt", entry(b"b")).unwrap();
+        replica.put("a.txt", entry(b"v2")).unwrap();
+        replica.put("a.txt", entry(b"v3")).unwrap();
+        replica.remove("b.txt").unwrap();
+
+        let undone = replica.undo_last(3).unwrap().unwrap();
+        // both paths revert in one transaction
+        assert!(matches!(&replica.node(&undone), Some(Node::Op(op_node)) if matches!(op_node.op.kind, OpKind::Transaction { .. })));
+        assert_eq!(replica.state().get("a.txt"), Some(entry(b"v1")));
+        assert_eq!(replica.state().get("b.txt"), Some(entry(b"b")));
+    }
+
+    #[tokio::test]
+    async fn test_undo_of_create_removes_file_and_skips_reverts() {
+        // no snapshot, so the restart gets the ops back
+        let mut replica = Replica::new(alice());
+        replica.put("keep.txt", entry(b"k")).unwrap();
+        replica.put("new.txt", entry(b"n")).unwrap();
+
+        replica.undo_last(1).unwrap();
+        assert_eq!(replica.state().get("new.txt"), None);
+
+        // The revert itself isn't undone, even after a restart; the next
+        // candidate is keep.txt.
+        let mut restarted = Replica::new(alice()).with_keypair(replica.keypair().clone());
+        restarted.merge_car(&replica.to_car().unwrap()).await.unwrap();
+        restarted.undo_last(1).unwrap();
+        assert_eq!(restarted.state().get("keep.txt"), None);
+        assert_eq!(restarted.state().get("new.txt"), None);
+    }
+}
@@ -0,0 +1,307 @@
+//! Streaming uploads: a file is read, chunked and stored a piece at a
+//! time, so writing a file takes memory for a few chunks however large it
+//! is. Storing runs alongside reading, a bounded number of chunks at once,
+//! and reading waits for it when that many are in flight. An upload that
+//! is cut short can be resumed from its `UploadProgress`. Chunks are split
+//! off the read buffer without a copy and handed to `store` as they are,
+//! so unsealed data is copied once, out of the file, on its way to the
+//! daemon.
+
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::chunk::{chunk_list_id, put_raw_block, ChunkProfile, ChunkedPut};
+use super::op::{Chunk, Entry};
+use super::replica::Replica;
+use crate::crypto::WorkspaceKey;
+use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+
+/// Chunks stored at once by default.
+pub const DEFAULT_UPLOAD_WINDOW: usize = 4;
+/// Bytes asked of the reader at a time.
+const READ_SIZE: usize = 64 * 1024;
+/// Bytes stored between checkpoints of a streamed upload.
+const CHECKPOINT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// How far a streamed upload got: the chunks from the start of the file
+/// known to be stored, in order. Every cut depends only on the bytes since
+/// the one before, so reading on from `offset` gives the same chunks as
+/// starting over.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadProgress {
+    pub chunks: Vec<Chunk>,
+}
+
+impl UploadProgress {
+    /// Bytes of the file the chunks cover.
+    pub fn offset(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.size).sum()
+    }
+}
+
+impl Replica {
+    /// `put_chunked` reading the file from `reader`, see the module docs.
+    /// `store` is called for up to `upload_window` chunks at once.
+    pub async fn put_stream<R, F>(&mut self, path: &str, reader: R, mode: u32, mtime: i64, store: F) -> Result<ChunkedPut>
+    where
+        R: AsyncRead + Unpin,
+        F: AsyncFn(IpfsCid, Bytes) -> Result<()>,
+    {
+        let (entry, stored, reused) = self.store_stream(path, reader, mode, mtime, &store).await?;
+        let node = self.put(path, entry)?;
+        Ok(ChunkedPut { node, stored, reused })
+    }
+
+    /// `put_stream` storing chunks on the IPFS daemon at `base_url`.
+    pub async fn put_stream_to<R>(&mut self, base_url: &str, path: &str, reader: R, mode: u32, mtime: i64) -> Result<ChunkedPut>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.put_stream(path, reader, mode, mtime, async |cid, bytes| put_raw_block(base_url, &cid, bytes).await)
+            .await
+    }
+
+    /// `store_chunked` reading the data from `reader`. Cuts exactly where
+    /// `store_chunked` would, so both give the same entry for the same
+    /// bytes.
+    pub(crate) async fn store_stream<R, F>(
+        &self,
+        path: &str,
+        reader: R,
+        mode: u32,
+        mtime: i64,
+        store: &F,
+    ) -> Result<(Entry, usize, usize)>
+    where
+        R: AsyncRead + Unpin,
+        F: AsyncFn(IpfsCid, Bytes) -> Result<()>,
+    {
+        let mut progress = UploadProgress::default();
+        let (stored, reused) =
+            self.stream_chunks(path, reader, &mut progress, |_: &UploadProgress| Ok(()), |_: &IpfsCid| false, store).await?;
+        Ok((self.streamed_entry(progress.chunks, mode, mtime), stored, reused))
+    }
+
+    /// Stores the chunks of the file after `progress`, reading them from
+    /// `reader`, and leaves them all in `progress`. If this fails `progress`
+    /// still holds what was stored; `checkpoint` is also shown it after
+    /// every `CHECKPOINT_BYTES` stored. Chunks `elsewhere` says are already
+    /// stored for other files are reused too. Returns how many chunks were
+    /// stored and reused, counting those of an earlier attempt as reused.
+    pub(crate) async fn stream_chunks<R, C, E, F>(
+        &self,
+        path: &str,
+        mut reader: R,
+        progress: &mut UploadProgress,
+        mut checkpoint: C,
+        elsewhere: E,
+        store: &F,
+    ) -> Result<(usize, usize)>
+    where
+        R: AsyncRead + Unpin,
+        C: FnMut(&UploadProgress) -> Result<()>,
+        E: Fn(&IpfsCid) -> bool,
+        F: AsyncFn(IpfsCid, Bytes) -> Result<()>,
+    {
+        let (profile, key) = (self.chunk_profile(path), self.workspace_key().cloned());
+        let mut known = self.known_chunks(path);
+        known.extend(progress.chunks.iter().map(|chunk| chunk.content.clone()));
+        let mut chunks = progress.chunks.clone();
+        let (mut stored, mut reused) = (0, chunks.len());
+        // chunks being stored, by index
+        let mut pending = BTreeSet::new();
+        let mut unsaved = 0;
+        let mut buffer = BytesMut::with_capacity(profile.max_chunk_size() + READ_SIZE);
+        let mut in_flight = FuturesUnordered::new();
+        let mut eof = false;
+        let result: Result<()> = async {
+            loop {
+                let (rest, pieces) = cut_off_thread(profile, key.clone(), std::mem::take(&mut buffer), eof).await?;
+                buffer = rest;
+                for (chunk, bytes) in pieces {
+                    if known.insert(chunk.content.clone()) && !elsewhere(&chunk.content) {
+                        while in_flight.len() >= self.upload_window() {
+                            pending.remove(&in_flight.next().await.expect("uploads are in flight")?);
+                        }
+                        let (index, content) = (chunks.len(), chunk.content.clone());
+                        pending.insert(index);
+                        in_flight.push(async move { store(content, bytes).await.map(|()| index) });
+                        stored += 1;
+                    } else {
+                        reused += 1;
+                    }
+                    chunks.push(chunk);
+                }
+                if eof {
+                    break;
+                }
+                buffer.reserve(READ_SIZE);
+                let read = read_while_storing(&mut reader, &mut buffer, &mut in_flight, &mut pending).await?;
+                eof = read == 0;
+
+                unsaved += confirm(progress, &chunks, &pending);
+                if unsaved >= CHECKPOINT_BYTES {
+                    checkpoint(progress)?;
+                    unsaved = 0;
+                }
+            }
+            while let Some(index) = in_flight.next().await {
+                pending.remove(&index?);
+            }
+            Ok(())
+        }
+        .await;
+        confirm(progress, &chunks, &pending);
+        result?;
+
+        if chunks.is_empty() {
+            let (content, bytes) = self.empty_blob();
+            if known.contains(&content) || elsewhere(&content) {
+                reused += 1;
+            } else {
+                store(content, bytes).await?;
+                stored += 1;
+            }
+        }
+        Ok((stored, reused))
+    }
+
+    /// The entry of a file made of `chunks`, as `stream_chunks` left them.
+    pub(crate) fn streamed_entry(&self, chunks: Vec<Chunk>, mode: u32, mtime: i64) -> Entry {
+        if chunks.is_empty() {
+            let (content, _) = self.empty_blob();
+            return Entry { content, size: 0, mode, mtime, chunks, symlink: None };
+        }
+        let size = chunks.iter().map(|chunk| chunk.size).sum();
+        Entry { content: chunk_list_id(&chunks), size, mode, mtime, chunks, symlink: None }
+    }
+}
+
+/// Cuts every chunk `buffer` holds whole, or all of it at the end of the
+/// file, and seals them. Runs on the blocking pool, so the hashing of
+/// several files spreads over all cores; returns what is left of `buffer`.
+/// Unsealed chunks share the buffer's memory.
+async fn cut_off_thread(
+    profile: ChunkProfile,
+    key: Option<WorkspaceKey>,
+    mut buffer: BytesMut,
+    eof: bool,
+) -> Result<(BytesMut, Vec<(Chunk, Bytes)>)> {
+    tokio::task::spawn_blocking(move || -> Result<_> {
+        let mut pieces = Vec::new();
+        while !buffer.is_empty() {
+            let cut = match profile.find_cut(&buffer)? {
+                Some(cut) => cut,
+                None if eof || buffer.len() >= profile.max_chunk_size() => buffer.len().min(profile.max_chunk_size()),
+                None => break,
+            };
+            let piece = buffer.split_to(cut).freeze();
+            let bytes = match &key {
+                Some(key) => Bytes::from(key.seal(&piece)),
+                None => piece,
+            };
+            pieces.push((Chunk { content: IpfsCid::compute(RAW_CODE, &bytes), size: cut as u64 }, bytes));
+        }
+        Ok((buffer, pieces))
+    })
+    .await
+    .context("Chunking task failed")?
+}
+
+/// Moves the chunks stored since the last call, those before the first
+/// still pending, into `progress` and returns their size.
+fn confirm(progress: &mut UploadProgress, chunks: &[Chunk], pending: &BTreeSet<usize>) -> u64 {
+    let end = pending.first().copied().unwrap_or(chunks.len());
+    let confirmed = &chunks[progress.chunks.len()..end];
+    progress.chunks.extend_from_slice(confirmed);
+    confirmed.iter().map(|chunk| chunk.size).sum()
+}
+
+/// Reads into `buf` while driving the uploads in flight.
+async fn read_while_storing<R, Fut>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+    in_flight: &mut FuturesUnordered<Fut>,
+    pending: &mut BTreeSet<usize>,
+) -> Result<usize>
+where
+    R: AsyncRead + Unpin,
+    Fut: Future<Output = Result<usize>>,
+{
+    let read = reader.read_buf(buf);
+    tokio::pin!(read);
+    loop {
+        tokio::select! {
+            read = &mut read => return read.context("Failed to read file"),
+            Some(index) = in_flight.next(), if !in_flight.is_empty() => {
+                pending.remove(&index?);
+            }
+        }
+    }
+}
+
+/// Whether `a` and `b` have the same content, one perhaps as a single blob
+/// and the other as a single chunk.
+pub(crate) fn same_content(a: &Entry, b: &Entry) -> bool {
+    let single = |entry: &Entry| match entry.chunks.as_slice() {
+        [chunk] => chunk.content.clone(),
+        _ => entry.content.clone(),
+    };
+    a.size == b.size && a.is_symlink() == b.is_symlink() && (a.content == b.content || single(a) == single(b))
+}
+
+#[cfg(test)]
+mod upload_test {
+    use super::*;
+    use crate::crdt::chunk::Chunker;
+    use crate::test_util::alice;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// Deterministic bytes that don't repeat.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_stream_matches_in_memory_put_with_bounded_uploads() {
+        let alice = alice();
+        let chunker = Chunker { min_size: 64, avg_size: 256, max_size: 1024 };
+        let mut a = Replica::new(alice).with_chunker(chunker).with_upload_window(3);
+        let data = noise(200 * 1024, 7);
+
+        let blocks = Mutex::new(HashMap::new());
+        let (in_flight, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let store = async |cid: IpfsCid, bytes: Bytes| {
+            peak.fetch_max(in_flight.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            blocks.lock().unwrap().insert(cid, bytes.to_vec());
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        };
+        let put = a.put_stream("big.bin", &data[..], 0o644, 0, &store).await.unwrap();
+        assert!(put.stored > 100);
+        assert!((2..=3).contains(&peak.load(Ordering::SeqCst)), "{} in flight", peak.load(Ordering::SeqCst));
+
+        let streamed = a.state().get("big.bin").unwrap();
+        let mut sink = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let (in_memory, _, _) = a.store_chunked("other.bin", &data, 0o644, 0, &mut sink).await.unwrap();
+        assert_eq!(streamed.content, in_memory.content);
+        assert_eq!(blocks.lock().unwrap().len(), put.stored);
+
+        let (empty, stored, _) = a.store_stream("empty", &b""[..], 0o644, 0, &store).await.unwrap();
+        assert_eq!((empty.size, empty.chunks.len(), stored), (0, 0, 1));
+    }
+}
@@ -0,0 +1,652 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::chunk::{chunk_list_id, compile_glob, ChunkProfile, ChunkRule, Chunker};
+use super::clock::{Hlc, VersionVector};
+use super::hamt::check_fanout;
+use super::identity::ReplicaId;
+use super::access::authorize;
+use super::membership::admit;
+use super::policy::FilePolicy;
+use super::op::{Access, Ancestor, Chunk, Entry, EntryPatch, Membership, Op, OpKind, MAX_LINEAGE, OP_HASH_LENGTH};
+use super::sign::{PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use super::state::{State, Version};
+use super::wire::{HeadAnnouncement, Node, StateBlock};
+use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+
+/// Longest symlink target accepted, as PATH_MAX on Linux.
+const MAX_SYMLINK_TARGET: usize = 4096;
+/// Longest extended attribute name and value, as on Linux.
+const MAX_XATTR_NAME: usize = 255;
+const MAX_XATTR_VALUE: usize = 64 * 1024;
+/// Largest chunk a workspace may ask for.
+const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+const MAX_CHUNK_RULES: usize = 64;
+const MAX_SKIP_GLOBS: usize = 64;
+
+/// The blocks a replica accepts, in IPLD schema notation. Decoding enforces
+/// the shape; `validate_*` enforces the constraints noted in comments.
+pub const SCHEMA: &str = r#"
+type Hlc struct { millis Int  counter Int }          # millis <= now + max_clock_skew
+type VersionVector {String:Int}                       # <= max_authors entries, seq >= 1
+type Chunk struct { content Link  size Int }          # size >= 1
+type Entry struct { content Link  size Int  mode Int  mtime Int  chunks optional [Chunk]
+                    symlink optional String }
+                                                      # mode <= 0o7777; chunks: left out
+                                                      # if empty, else sizes sum to size
+                                                      # and content is their list's CID;
+                                                      # symlink: 1..=4096 bytes, no NUL,
+                                                      # no chunks, content is the raw CID
+                                                      # of the target, size its length
+type Dot struct { author String  seq Int }
+type Ancestor struct { dot Dot  timestamp Hlc  content Link }
+type Content struct { content Link  size Int  lineage [Ancestor]  chunks optional [Chunk]
+                      symlink optional String }
+                                                      # lineage: <= 16, newest first
+type EntryPatch struct { content nullable Content  mode nullable Int  mtime nullable Int
+                         xattrs optional {String:nullable Bytes} }
+                                                      # at least one field set; xattr
+                                                      # names 1..=255 bytes, no NUL,
+                                                      # values <= 64 KiB
+type Membership union {
+  | Active  struct { signer Bytes }                   # 32 bytes
+  | Removed struct { signer Bytes  last_seq Int }
+} representation keyed
+type Access union {                                  # Write and ReadOnly encode
+  | Write                                             # as bare strings
+  | WriteUnder  struct { prefixes [String] }          # >= 1, each a valid path
+  | ReadOnly
+} representation keyed
+type OpKind union {
+  | Put       struct { path String  entry Entry  lineage [Ancestor] }
+                                                      # path: relative, normalized
+  | Remove    struct { path String }
+  | Patch     struct { path String  patch EntryPatch }
+  | SetMember struct { member String  membership Membership }
+  | SetConflictPolicy struct { policy ConflictPolicy }
+  | SetAccess struct { member String  access Access }
+  | SetChunker struct { chunker Chunker  rules optional [ChunkRule] }
+  | SetFilePolicy struct { policy FilePolicy }
+  | Transaction struct { ops [OpKind] }           # >= 1 ops, only Put, Remove, Patch
+} representation keyed
+type ConflictPolicy enum { | LastWriterWins | AddWins | RemoveWins }
+type Chunker struct { min_size Int  avg_size Int  max_size Int }
+                                                      # 64 <= min <= avg <= max <= 64 MiB
+type ChunkProfile union {
+  | ContentDefined Chunker
+  | Fixed struct { size Int }                         # 1..=64 MiB
+} representation keyed
+type ChunkRule struct { glob String  profile ChunkProfile }
+                                                      # rules: left out if empty, <= 64,
+                                                      # each glob valid
+type FilePolicy struct { max_size optional Int  skip optional [String] }
+                                                      # skip: left out if empty, <= 64,
+                                                      # each glob valid
+type Op struct { author String  seq Int  timestamp Hlc  context VersionVector  kind OpKind
+                 reverts optional [Int] }             # seq >= 1, context[author] < seq
+                                                      # reverts: ascending, each in 1..seq
+type OpNode struct { version Int  op Op  parents [Link]  signer Bytes  signature Bytes }
+type SnapshotNode struct { version Int  author String  timestamp Hlc
+                           version_vector VersionVector  state Link  parents [Link]
+                           signer Bytes  signature Bytes }
+type Node union { | OpNode "Op" | SnapshotNode "Snapshot" } representation keyed
+                                                      # parents: sorted, unique, <= max_parents
+type StateBlock struct { version Int  fanout Int  entries Link  state State }
+                                                      # fanout: power of two in 2..=256
+                                                      # state: no entries, every path valid
+type PathRegister struct { exists Register  content Register  mode Register  mtime Register
+                           xattrs optional {String:Register} }
+type BucketEntry struct { path String  register PathRegister }
+type Child union { | Bucket [BucketEntry] | Link Link } representation keyed
+type ShardNode struct { version Int  bitmap Bytes  children [Child] }
+                                                      # one child per bitmap bit, buckets
+                                                      # sorted, paths in the slot they hash to
+type HeadAnnouncement struct { version Int  author String  heads [Link]
+                               version_vector VersionVector }
+"#;
+
+/// Bounds applied to remote blocks before they touch local state.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Largest block of any kind, in bytes.
+    pub max_block_size: usize,
+    pub max_parents: usize,
+    pub max_authors: usize,
+    pub max_path_length: usize,
+    /// How far ahead of the local clock a timestamp may be, in ms. A node
+    /// from the far future would win every last-writer-wins conflict.
+    pub max_clock_skew_millis: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            // what bitswap will transfer
+            max_block_size: 2 * 1024 * 1024,
+            max_parents: 256,
+            max_authors: 4096,
+            max_path_length: 4096,
+            max_clock_skew_millis: 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+impl Limits {
+    /// Rejects a raw block before it is decoded.
+    pub fn check_size(&self, cid: &IpfsCid, bytes: &[u8], max: usize) -> Result<()> {
+        if bytes.len() > max {
+            bail!("Block {} is {} bytes, more than the {} allowed", cid, bytes.len(), max);
+        }
+        Ok(())
+    }
+}
+
+/// What an `OpValidator` sees besides the node itself.
+pub struct NodeContext<'a> {
+    /// The state as of the node's parents, membership document included.
+    pub state: &'a State,
+    /// The signing key pinned for each author while nobody is a member.
+    pub signers: &'a HashMap<ReplicaId, Vec<u8>>,
+    pub limits: &'a Limits,
+}
+
+/// A check every remote DAG node passes before it is applied. Nodes a
+/// validator rejects, and every node built on them, are quarantined
+/// instead of applied.
+pub trait OpValidator: fmt::Debug + Send + Sync {
+    fn validate(&self, node: &Node, context: &NodeContext) -> Result<()>;
+}
+
+/// `SCHEMA`'s constraints: sizes, timestamps, normalized paths.
+#[derive(Debug)]
+pub struct SchemaValidator;
+
+impl OpValidator for SchemaValidator {
+    fn validate(&self, node: &Node, context: &NodeContext) -> Result<()> {
+        validate_node(node, context.limits)
+    }
+}
+
+/// A valid signature, by the pinned key of the author until the
+/// membership document lists anyone.
+#[derive(Debug)]
+pub struct SignatureValidator;
+
+impl OpValidator for SignatureValidator {
+    fn validate(&self, node: &Node, context: &NodeContext) -> Result<()> {
+        node.verify_signature()?;
+        if !context.state.has_members()
+            && let Some(expected) = context.signers.get(node.author())
+            && expected.as_slice() != node.signer()
+        {
+            bail!("not signed by the known key of {}", node.author());
+        }
+        Ok(())
+    }
+}
+
+/// Once the membership document lists anyone, only nodes its members were
+/// allowed to write.
+#[derive(Debug)]
+pub struct MembershipValidator;
+
+impl OpValidator for MembershipValidator {
+    fn validate(&self, node: &Node, context: &NodeContext) -> Result<()> {
+        if context.state.has_members() {
+            admit(context.state, node)?;
+        }
+        Ok(())
+    }
+}
+
+/// Once the access-control document lists anyone, only nodes their author
+/// had write access for.
+#[derive(Debug)]
+pub struct AccessValidator;
+
+impl OpValidator for AccessValidator {
+    fn validate(&self, node: &Node, context: &NodeContext) -> Result<()> {
+        authorize(context.state, node)
+    }
+}
+
+/// The validators every replica runs, before any added with
+/// `Replica::with_validator`.
+pub fn default_validators() -> Vec<Arc<dyn OpValidator>> {
+    vec![
+        Arc::new(SchemaValidator),
+        Arc::new(SignatureValidator),
+        Arc::new(MembershipValidator),
+        Arc::new(AccessValidator),
+    ]
+}
+
+/// A remote node a validator rejected, kept aside for inspection.
+#[derive(Debug, Clone)]
+pub struct Quarantined {
+    pub node: Node,
+    pub reason: String,
+}
+
+/// Checks a decoded DAG node against `SCHEMA`'s constraints.
+pub fn validate_node(node: &Node, limits: &Limits) -> Result<()> {
+    validate_parents(node.parents(), limits)?;
+    validate_timestamp(node.timestamp(), limits)?;
+    if node.signer().len() != PUBLIC_KEY_LENGTH {
+        bail!("signer is {} bytes, expected {}", node.signer().len(), PUBLIC_KEY_LENGTH);
+    }
+    match node {
+        Node::Op(op_node) => {
+            if op_node.signature.len() != SIGNATURE_LENGTH {
+                bail!("signature is {} bytes, expected {}", op_node.signature.len(), SIGNATURE_LENGTH);
+            }
+            validate_op(&op_node.op, limits)
+        }
+        Node::Snapshot(snapshot) => {
+            if snapshot.signature.len() != SIGNATURE_LENGTH {
+                bail!("signature is {} bytes, expected {}", snapshot.signature.len(), SIGNATURE_LENGTH);
+            }
+            validate_version_vector("version_vector", &snapshot.version_vector, limits)
+        }
+    }
+}
+
+/// Checks a state block before its shards are fetched.
+pub fn validate_state_block(block: &StateBlock, limits: &Limits) -> Result<()> {
+    check_fanout(block.fanout)?;
+    if block.state.registers().next().is_some() {
+        bail!("state block carries entries outside its shards");
+    }
+    validate_version_vector("state.version_vector", block.state.version_vector(), limits)
+}
+
+/// Checks a state reassembled from a state block and its shards.
+pub fn validate_state(state: &State, limits: &Limits) -> Result<()> {
+    for (path, register) in state.registers() {
+        validate_path(path, limits)?;
+        validate_versions(path, register.exists.versions())?;
+        validate_versions(path, register.content.versions())?;
+        for version in register.content.versions() {
+            validate_lineage(path, &version.value.lineage)?;
+            validate_chunks(path, &version.value.content, version.value.size, &version.value.chunks)?;
+            validate_symlink(path, &version.value.content, version.value.size, &version.value.chunks, version.value.symlink.as_deref())?;
+        }
+        validate_versions(path, register.mtime.versions())?;
+        validate_versions(path, register.mode.versions())?;
+        for (name, xattr) in &register.xattrs {
+            validate_versions(path, xattr.versions())?;
+            for version in xattr.versions() {
+                validate_xattr(path, name, version.value.as_deref().map(Vec::as_slice))?;
+            }
+        }
+        for version in register.mode.versions() {
+            validate_mode(path, version.value)?;
+        }
+    }
+    for (member, membership) in state.members() {
+        validate_membership(&member.to_string(), membership)?;
+    }
+    for settings in state.chunk_settings() {
+        validate_chunker(&settings.chunker)?;
+        validate_chunk_rules(&settings.rules)?;
+    }
+    state.file_policies().try_for_each(validate_file_policy)
+}
+
+pub fn validate_announcement(announcement: &HeadAnnouncement, limits: &Limits) -> Result<()> {
+    if announcement.heads.len() > limits.max_parents {
+        bail!("{} heads, more than the {} allowed", announcement.heads.len(), limits.max_parents);
+    }
+    validate_version_vector("version_vector", &announcement.version_vector, limits)
+}
+
+fn validate_op(op: &Op, limits: &Limits) -> Result<()> {
+    if op.seq == 0 {
+        bail!("op.seq must be at least 1");
+    }
+    if op.context.get(&op.author) >= op.seq {
+        bail!("op.context already covers the op itself ({}:{})", op.author, op.seq);
+    }
+    if op.reverts.first().is_some_and(|&seq| seq == 0)
+        || op.reverts.windows(2).any(|pair| pair[0] >= pair[1])
+        || op.reverts.last().is_some_and(|&seq| seq >= op.seq)
+    {
+        bail!("op.reverts must be ascending sequence numbers before the op's own");
+    }
+    validate_version_vector("op.context", &op.context, limits)?;
+    validate_kind(&op.kind, limits)
+}
+
+fn validate_kind(kind: &OpKind, limits: &Limits) -> Result<()> {
+    match kind {
+        OpKind::Put { path, entry, lineage } => {
+            validate_path(path, limits)?;
+            validate_lineage(path, lineage)?;
+            validate_entry(path, entry)
+        }
+        OpKind::Remove { path } => validate_path(path, limits),
+        OpKind::Patch { path, patch } => {
+            validate_path(path, limits)?;
+            validate_patch(path, patch)
+        }
+        OpKind::SetMember { member, membership } => validate_membership(&member.to_string(), membership),
+        OpKind::SetConflictPolicy { .. } => Ok(()),
+        OpKind::SetAccess { access, .. } => match access {
+            Access::WriteUnder { prefixes } => {
+                if prefixes.is_empty() {
+                    bail!("write access under no prefix");
+                }
+                prefixes.iter().try_for_each(|prefix| validate_path(prefix, limits))
+            }
+            Access::Write | Access::ReadOnly => Ok(()),
+        },
+        OpKind::SetChunker { chunker, rules } => {
+            validate_chunker(chunker)?;
+            validate_chunk_rules(rules)
+        }
+        OpKind::SetFilePolicy { policy } => validate_file_policy(policy),
+        OpKind::Transaction { ops } => {
+            if ops.is_empty() {
+                bail!("transaction makes no change");
+            }
+            for kind in ops {
+                if kind.path().is_none() {
+                    bail!("transactions may only hold puts, removes and patches");
+                }
+                validate_kind(kind, limits)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+pub(crate) fn validate_parents(parents: &[IpfsCid], limits: &Limits) -> Result<()> {
+    if parents.len() > limits.max_parents {
+        bail!("{} parents, more than the {} allowed", parents.len(), limits.max_parents);
+    }
+    if parents.windows(2).any(|pair| pair[0] >= pair[1]) {
+        bail!("parents must be sorted and unique");
+    }
+    Ok(())
+}
+
+fn validate_timestamp(timestamp: Hlc, limits: &Limits) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    if timestamp.millis > now.saturating_add(limits.max_clock_skew_millis) {
+        bail!("timestamp {} ms is too far in the future", timestamp.millis);
+    }
+    Ok(())
+}
+
+fn validate_version_vector(field: &str, vv: &VersionVector, limits: &Limits) -> Result<()> {
+    let mut authors = 0;
+    for (author, seq) in vv.iter() {
+        if seq == 0 {
+            bail!("{} has a zero entry for {}", field, author);
+        }
+        authors += 1;
+    }
+    if authors > limits.max_authors {
+        bail!("{} has {} authors, more than the {} allowed", field, authors, limits.max_authors);
+    }
+    Ok(())
+}
+
+/// Paths are relative, `/`-separated and normalized: no empty, `.` or `..`
+/// components and no NUL bytes.
+pub fn validate_path(path: &str, limits: &Limits) -> Result<()> {
+    if path.len() > limits.max_path_length {
+        bail!("path is {} bytes, more than the {} allowed", path.len(), limits.max_path_length);
+    }
+    if path.contains('\0') {
+        bail!("path {:?} contains a NUL byte", path);
+    }
+    if path.split('/').any(|name| name.is_empty() || name == "." || name == "..") {
+        bail!("path {:?} is not a normalized relative path", path);
+    }
+    Ok(())
+}
+
+fn validate_versions<T>(path: &str, versions: &[Version<T>]) -> Result<()> {
+    for version in versions {
+        if version.hash.len() != OP_HASH_LENGTH {
+            bail!("version {}:{} of {:?} has a malformed op hash", version.dot.author, version.dot.seq, path);
+        }
+    }
+    Ok(())
+}
+
+fn validate_entry(path: &str, entry: &Entry) -> Result<()> {
+    validate_chunks(path, &entry.content, entry.size, &entry.chunks)?;
+    validate_symlink(path, &entry.content, entry.size, &entry.chunks, entry.symlink.as_deref())?;
+    validate_mode(path, entry.mode)
+}
+
+fn validate_chunks(path: &str, content: &IpfsCid, size: u64, chunks: &[Chunk]) -> Result<()> {
+    if chunks.is_empty() {
+        return Ok(());
+    }
+    if chunks.iter().any(|chunk| chunk.size == 0) {
+        bail!("{:?} has an empty chunk", path);
+    }
+    if chunks.iter().map(|chunk| chunk.size).sum::<u64>() != size {
+        bail!("chunks of {:?} don't add up to its size {}", path, size);
+    }
+    if chunk_list_id(chunks) != *content {
+        bail!("content of {:?} is not the id of its chunk list", path);
+    }
+    Ok(())
+}
+
+fn validate_symlink(path: &str, content: &IpfsCid, size: u64, chunks: &[Chunk], target: Option<&str>) -> Result<()> {
+    let Some(target) = target else {
+        return Ok(());
+    };
+    if target.is_empty() || target.len() > MAX_SYMLINK_TARGET {
+        bail!("symlink {:?} has a target of {} bytes", path, target.len());
+    }
+    if target.contains('\0') {
+        bail!("symlink {:?} has a NUL in its target", path);
+    }
+    if !chunks.is_empty() {
+        bail!("symlink {:?} has chunks", path);
+    }
+    if *content != IpfsCid::compute(RAW_CODE, target.as_bytes()) || size != target.len() as u64 {
+        bail!("content of symlink {:?} doesn't match its target", path);
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_chunker(chunker: &Chunker) -> Result<()> {
+    let Chunker { min_size, avg_size, max_size } = *chunker;
+    if min_size < 64 || min_size > avg_size || avg_size > max_size || max_size > MAX_CHUNK_SIZE {
+        bail!("chunk sizes {}/{}/{} out of order or bounds", min_size, avg_size, max_size);
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_chunk_rules(rules: &[ChunkRule]) -> Result<()> {
+    if rules.len() > MAX_CHUNK_RULES {
+        bail!("{} chunking rules, at most {} allowed", rules.len(), MAX_CHUNK_RULES);
+    }
+    for rule in rules {
+        if compile_glob(&rule.glob).is_err() {
+            bail!("invalid chunking glob {:?}", rule.glob);
+        }
+        match rule.profile {
+            ChunkProfile::ContentDefined(chunker) => validate_chunker(&chunker)?,
+            ChunkProfile::Fixed { size } if size == 0 || size > MAX_CHUNK_SIZE => {
+                bail!("fixed chunk size {} out of bounds", size)
+            }
+            ChunkProfile::Fixed { .. } => {}
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_file_policy(policy: &FilePolicy) -> Result<()> {
+    if policy.skip.len() > MAX_SKIP_GLOBS {
+        bail!("{} skipped globs, at most {} allowed", policy.skip.len(), MAX_SKIP_GLOBS);
+    }
+    if let Some(glob) = policy.skip.iter().find(|glob| compile_glob(glob).is_err()) {
+        bail!("invalid skipped glob {:?}", glob);
+    }
+    Ok(())
+}
+
+fn validate_xattr(path: &str, name: &str, value: Option<&[u8]>) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_XATTR_NAME || name.contains('\0') {
+        bail!("{:?} has a malformed extended attribute name {:?}", path, name);
+    }
+    if let Some(value) = value
+        && value.len() > MAX_XATTR_VALUE
+    {
+        bail!("extended attribute {:?} of {:?} is {} bytes, more than {}", name, path, value.len(), MAX_XATTR_VALUE);
+    }
+    Ok(())
+}
+
+fn validate_patch(path: &str, patch: &EntryPatch) -> Result<()> {
+    if patch.is_empty() {
+        bail!("patch of {:?} changes no field", path);
+    }
+    for (name, value) in &patch.xattrs {
+        validate_xattr(path, name, value.as_deref().map(Vec::as_slice))?;
+    }
+    if let Some(content) = &patch.content {
+        validate_lineage(path, &content.lineage)?;
+        validate_chunks(path, &content.content, content.size, &content.chunks)?;
+        validate_symlink(path, &content.content, content.size, &content.chunks, content.symlink.as_deref())?;
+    }
+    match patch.mode {
+        Some(mode) => validate_mode(path, mode),
+        None => Ok(()),
+    }
+}
+
+fn validate_lineage(path: &str, lineage: &[Ancestor]) -> Result<()> {
+    if lineage.len() > MAX_LINEAGE {
+        bail!("lineage of {:?} has {} ancestors, more than the {} allowed", path, lineage.len(), MAX_LINEAGE);
+    }
+    Ok(())
+}
+
+fn validate_mode(path: &str, mode: u32) -> Result<()> {
+    if mode > 0o7777 {
+        bail!("mode {:o} of {:?} has bits outside 0o7777", mode, path);
+    }
+    Ok(())
+}
+
+fn validate_membership(member: &str, membership: &Membership) -> Result<()> {
+    if membership.signer().len() != PUBLIC_KEY_LENGTH {
+        bail!("signer of member {} is {} bytes, expected {}", member, membership.signer().len(), PUBLIC_KEY_LENGTH);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod validate_test {
+    use super::*;
+    use crate::crdt::clock::Dot;
+    use crate::crdt::identity::ReplicaId;
+    use crate::crdt::sign::ReplicaKeypair;
+    use crate::crdt::wire::OpNode;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use crate::test_util::{alice, entry};
+
+    fn author() -> ReplicaId {
+        alice()
+    }
+
+    fn node(seq: u64, path: &str) -> Node {
+        let mut context = VersionVector::new();
+        if seq > 1 {
+            context.observe(&Dot { author: author(), seq: seq - 1 });
+        }
+        let entry = entry(b"x");
+        let op = Op {
+            author: author(),
+            seq,
+            timestamp: Hlc { millis: 1700000000000, counter: 0 },
+            context,
+            kind: OpKind::Put { path: path.to_string(), entry, lineage: Vec::new() },
+            reverts: Vec::new(),
+        };
+        let mut node = Node::Op(OpNode::new(op, Vec::new()));
+        node.sign(&ReplicaKeypair::from_seed([1; 32])).unwrap();
+        node
+    }
+
+    #[test]
+    fn test_valid_node_passes() {
+        assert!(validate_node(&node(2, "docs/a.txt"), &Limits::default()).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_bad_paths() {
+        let limits = Limits::default();
+        for path in ["", "/abs", "a//b", "a/../b", "./a", "a\0b"] {
+            let err = validate_node(&node(1, path), &limits).unwrap_err();
+            assert!(err.to_string().contains("path"), "{}: {}", path, err);
+        }
+        let long = "a".repeat(limits.max_path_length + 1);
+        assert!(validate_node(&node(1, &long), &limits).is_err());
+    }
+
+    #[test]
+    fn test_rejects_inconsistent_op_and_future_timestamp() {
+        let limits = Limits::default();
+        let Node::Op(mut op_node) = node(1, "a") else { unreachable!() };
+        op_node.op.seq = 0;
+        assert!(validate_node(&Node::Op(op_node.clone()), &limits).is_err());
+
+        op_node.op.seq = 1;
+        op_node.op.timestamp.millis = u64::MAX / 2;
+        let err = validate_node(&Node::Op(op_node), &limits).unwrap_err();
+        assert!(err.to_string().contains("future"));
+    }
+
+    #[test]
+    fn test_rejects_symlink_not_matching_target() {
+        let Node::Op(mut op_node) = node(1, "link") else { unreachable!() };
+        let mut entry = Entry::symlink("../target", 0);
+        op_node.op.kind = OpKind::Put { path: "link".to_string(), entry: entry.clone(), lineage: Vec::new() };
+        assert!(validate_op(&op_node.op, &Limits::default()).is_ok());
+
+        entry.symlink = Some("/etc".to_string());
+        op_node.op.kind = OpKind::Put { path: "link".to_string(), entry, lineage: Vec::new() };
+        let err = validate_op(&op_node.op, &Limits::default()).unwrap_err();
+        assert!(err.to_string().contains("target"), "{}", err);
+    }
+
+    #[test]
+    fn test_rejects_state_with_bad_chunking() {
+        let Node::Op(op_node) = node(1, "a") else { unreachable!() };
+        let chunker = Chunker { min_size: 64, avg_size: 256, max_size: 1024 };
+        let bad = [
+            OpKind::SetChunker { chunker: Chunker { min_size: 0, avg_size: 0, max_size: 0 }, rules: Vec::new() },
+            OpKind::SetChunker { chunker, rules: vec![ChunkRule::new("*.log", ChunkProfile::Fixed { size: 0 })] },
+            OpKind::SetFilePolicy { policy: FilePolicy { skip: vec!["[".to_string()], ..FilePolicy::default() } },
+        ];
+        for kind in bad {
+            let mut state = State::new();
+            state.apply(&Op { kind, ..op_node.op.clone() });
+            assert!(validate_state(&state, &Limits::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_rejects_oversized_block() {
+        let limits = Limits { max_block_size: 4, ..Limits::default() };
+        let cid = IpfsCid::compute(RAW_CODE, b"hello");
+        assert!(limits.check_size(&cid, b"hello", limits.max_block_size).is_err());
+        assert!(limits.check_size(&cid, b"hell", limits.max_block_size).is_ok());
+    }
+}
@@ -0,0 +1,631 @@
+use anyhow::{anyhow, bail, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::clock::{Hlc, VersionVector};
+use super::hamt::ShardNode;
+use super::identity::ReplicaId;
+use super::op::Op;
+use super::sign::{verify, ReplicaKeypair};
+use super::state::State;
+use crate::crypto::WorkspaceKey;
+use crate::kubo_rpc::ipfs::{IpfsCid, DAG_CBOR_CODE, RAW_CODE};
+
+/// Version written into every block this crate produces. Decoders accept
+/// this version only: there are no decoders for other schemas, so blocks
+/// of an older or newer version are rejected rather than misread. Bump it
+/// whenever a released schema changes shape.
+pub const WIRE_VERSION: u32 = 1;
+
+/// Blocks that carry a wire format version.
+pub trait Versioned {
+    fn version(&self) -> u32;
+}
+
+/// A single operation plus links to the heads it was written on top of.
+/// `signer`/`signature` are the author's Ed25519 public key and its
+/// signature over the node encoded with an empty `signature`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpNode {
+    pub version: u32,
+    pub op: Op,
+    pub parents: Vec<IpfsCid>,
+    #[serde(with = "serde_bytes")]
+    pub signer: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl OpNode {
+    pub fn new(op: Op, mut parents: Vec<IpfsCid>) -> Self {
+        parents.sort();
+        OpNode { version: WIRE_VERSION, op, parents, signer: Vec::new(), signature: Vec::new() }
+    }
+}
+
+/// Compaction point in the op DAG: links the full materialized state so a
+/// new replica can start here instead of replaying every operation before
+/// it. The version vector is repeated inline so walks can stop here
+/// without fetching the state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotNode {
+    pub version: u32,
+    pub author: ReplicaId,
+    pub timestamp: Hlc,
+    pub version_vector: VersionVector,
+    /// CID of the `StateBlock` holding the state.
+    pub state: IpfsCid,
+    pub parents: Vec<IpfsCid>,
+    #[serde(with = "serde_bytes")]
+    pub signer: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl SnapshotNode {
+    pub fn new(
+        author: ReplicaId,
+        timestamp: Hlc,
+        version_vector: VersionVector,
+        state: IpfsCid,
+        mut parents: Vec<IpfsCid>,
+    ) -> Self {
+        parents.sort();
+        SnapshotNode {
+            version: WIRE_VERSION,
+            author,
+            timestamp,
+            version_vector,
+            state,
+            parents,
+            signer: Vec::new(),
+            signature: Vec::new(),
+        }
+    }
+
+    pub fn version_vector(&self) -> &VersionVector {
+        &self.version_vector
+    }
+
+    /// Checks that `state` is the state this snapshot describes.
+    pub fn check_state(&self, state: &State) -> Result<()> {
+        if state.version_vector() != &self.version_vector {
+            bail!("State block {} doesn't match the snapshot's version vector", self.state);
+        }
+        Ok(())
+    }
+}
+
+/// Materialized state referenced by snapshot nodes. The path registers
+/// live in the shard trie rooted at `entries` (see `hamt`); `state` holds
+/// the rest and no entries. Nothing in it is replica-specific, so replicas
+/// that reach the same state write byte-identical blocks with the same
+/// CIDs and the daemon stores them once.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateBlock {
+    pub version: u32,
+    pub fanout: u32,
+    pub entries: IpfsCid,
+    pub state: State,
+}
+
+impl StateBlock {
+    pub fn new(state: State, entries: IpfsCid, fanout: u32) -> Self {
+        StateBlock { version: WIRE_VERSION, fanout, entries, state }
+    }
+
+    pub fn encode(&self) -> Result<(IpfsCid, Vec<u8>)> {
+        encode_block(self)
+    }
+
+    pub fn decode(cid: &IpfsCid, bytes: &[u8]) -> Result<StateBlock> {
+        decode_block(cid, bytes)
+    }
+}
+
+impl Versioned for StateBlock {
+    fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+/// A node of the operation DAG, stored as a DAG-CBOR block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Node {
+    Op(OpNode),
+    Snapshot(SnapshotNode),
+}
+
+impl Node {
+    pub fn parents(&self) -> &[IpfsCid] {
+        match self {
+            Node::Op(node) => &node.parents,
+            Node::Snapshot(node) => &node.parents,
+        }
+    }
+
+    pub fn timestamp(&self) -> Hlc {
+        match self {
+            Node::Op(node) => node.op.timestamp,
+            Node::Snapshot(node) => node.timestamp,
+        }
+    }
+
+    pub fn author(&self) -> &ReplicaId {
+        match self {
+            Node::Op(node) => &node.op.author,
+            Node::Snapshot(node) => &node.author,
+        }
+    }
+
+    pub fn signer(&self) -> &[u8] {
+        match self {
+            Node::Op(node) => &node.signer,
+            Node::Snapshot(node) => &node.signer,
+        }
+    }
+
+    fn signature_fields(&mut self) -> (&mut Vec<u8>, &mut Vec<u8>) {
+        match self {
+            Node::Op(node) => (&mut node.signer, &mut node.signature),
+            Node::Snapshot(node) => (&mut node.signer, &mut node.signature),
+        }
+    }
+
+    /// Bytes covered by the signature: the node with an empty signature.
+    fn signing_payload(&self) -> Result<Vec<u8>> {
+        let mut unsigned = self.clone();
+        unsigned.signature_fields().1.clear();
+        Ok(encode_block(&unsigned)?.1)
+    }
+
+    /// Sets `signer` to the keypair's public key and signs the node.
+    pub fn sign(&mut self, keypair: &ReplicaKeypair) -> Result<()> {
+        {
+            let (signer, signature) = self.signature_fields();
+            *signer = keypair.public_key().to_vec();
+            signature.clear();
+        }
+        let signed = keypair.sign(&self.signing_payload()?);
+        *self.signature_fields().1 = signed.to_vec();
+        Ok(())
+    }
+
+    /// Checks the embedded signature against the embedded signer key. Who
+    /// that key belongs to is up to the caller.
+    pub fn verify_signature(&self) -> Result<()> {
+        let signature = match self {
+            Node::Op(node) => &node.signature,
+            Node::Snapshot(node) => &node.signature,
+        };
+        verify(self.signer(), &self.signing_payload()?, signature)
+    }
+
+    /// Encodes the node as DAG-CBOR and returns its CID alongside the bytes.
+    pub fn encode(&self) -> Result<(IpfsCid, Vec<u8>)> {
+        encode_block(self)
+    }
+
+    /// Decodes a block, checking that it actually hashes to `cid`.
+    pub fn decode(cid: &IpfsCid, bytes: &[u8]) -> Result<Node> {
+        decode_block(cid, bytes)
+    }
+}
+
+impl Versioned for Node {
+    fn version(&self) -> u32 {
+        match self {
+            Node::Op(node) => node.version,
+            Node::Snapshot(node) => node.version,
+        }
+    }
+}
+
+/// What a replica publishes under its IPNS key: its current DAG heads.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeadAnnouncement {
+    pub version: u32,
+    pub author: ReplicaId,
+    pub heads: Vec<IpfsCid>,
+    pub version_vector: VersionVector,
+}
+
+impl HeadAnnouncement {
+    pub fn new(author: ReplicaId, heads: Vec<IpfsCid>, version_vector: VersionVector) -> Self {
+        HeadAnnouncement { version: WIRE_VERSION, author, heads, version_vector }
+    }
+
+    pub fn encode(&self) -> Result<(IpfsCid, Vec<u8>)> {
+        encode_block(self)
+    }
+
+    pub fn decode(cid: &IpfsCid, bytes: &[u8]) -> Result<HeadAnnouncement> {
+        decode_block(cid, bytes)
+    }
+}
+
+impl Versioned for HeadAnnouncement {
+    fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+/// Every DAG node a peer is missing relative to `since`, plus the states
+/// of the snapshots among them and their shards, shipped inline in one block so it can be
+/// pushed over a single message instead of fetched node by node. Each goes
+/// with the CID its author encoded it under, which a receiver with another
+/// compression setting couldn't derive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeltaBundle {
+    pub version: u32,
+    pub author: ReplicaId,
+    pub since: VersionVector,
+    pub heads: Vec<IpfsCid>,
+    pub nodes: Vec<(IpfsCid, Node)>,
+    pub states: Vec<(IpfsCid, StateBlock)>,
+    pub shards: Vec<(IpfsCid, ShardNode)>,
+}
+
+impl DeltaBundle {
+    pub fn new(
+        author: ReplicaId,
+        since: VersionVector,
+        heads: Vec<IpfsCid>,
+        nodes: Vec<(IpfsCid, Node)>,
+        states: Vec<(IpfsCid, StateBlock)>,
+        shards: Vec<(IpfsCid, ShardNode)>,
+    ) -> Self {
+        DeltaBundle { version: WIRE_VERSION, author, since, heads, nodes, states, shards }
+    }
+
+    pub fn encode(&self) -> Result<(IpfsCid, Vec<u8>)> {
+        encode_block(self)
+    }
+
+    pub fn decode(cid: &IpfsCid, bytes: &[u8]) -> Result<DeltaBundle> {
+        let bundle: DeltaBundle = decode_block(cid, bytes)?;
+        for (_, node) in &bundle.nodes {
+            check_version(node)?;
+        }
+        for (_, state) in &bundle.states {
+            check_version(state)?;
+        }
+        for (_, shard) in &bundle.shards {
+            check_version(shard)?;
+        }
+        Ok(bundle)
+    }
+}
+
+impl Versioned for DeltaBundle {
+    fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+fn check_version<T: Versioned>(value: &T) -> Result<()> {
+    if value.version() != WIRE_VERSION {
+        bail!("Unsupported wire format version {} (this build reads only version {})", value.version(), WIRE_VERSION);
+    }
+    Ok(())
+}
+
+/// First byte of a zstd-compressed payload. DAG-CBOR blocks start with a
+/// map header, so the two can't be confused.
+pub const COMPRESSED_PAYLOAD: u8 = 0x00;
+/// Largest payload a compressed block may expand to, so a small block
+/// can't decompress into gigabytes.
+pub const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+fn compress_payload(cbor: &[u8]) -> Result<Vec<u8>> {
+    let mut payload = vec![COMPRESSED_PAYLOAD];
+    payload.extend(zstd::bulk::compress(cbor, ZSTD_LEVEL).map_err(|e| anyhow!("Failed to compress block: {}", e))?);
+    Ok(payload)
+}
+
+/// Undoes `compress_payload`; anything without the flag is returned as is.
+fn decompress_payload(cid: &IpfsCid, payload: &[u8]) -> Result<Vec<u8>> {
+    match payload.split_first() {
+        Some((&COMPRESSED_PAYLOAD, compressed)) => zstd::bulk::decompress(compressed, MAX_DECOMPRESSED_SIZE)
+            .map_err(|e| anyhow!("Failed to decompress block {}: {}", cid, e)),
+        _ => Ok(payload.to_vec()),
+    }
+}
+
+/// Encodes `value` as a DAG-CBOR block and returns its CID alongside the bytes.
+pub fn encode_block<T: Serialize>(value: &T) -> Result<(IpfsCid, Vec<u8>)> {
+    let bytes = serde_ipld_dagcbor::to_vec(value)
+        .map_err(|e| anyhow!("Failed to encode DAG-CBOR block: {}", e))?;
+    Ok((IpfsCid::compute(DAG_CBOR_CODE, &bytes), bytes))
+}
+
+/// Decodes a DAG-CBOR block, checking that it hashes to `cid` and that its
+/// wire format version is supported.
+pub fn decode_block<T: DeserializeOwned + Serialize + Versioned>(cid: &IpfsCid, bytes: &[u8]) -> Result<T> {
+    if cid.0.codec() != DAG_CBOR_CODE {
+        bail!("Block {} is not DAG-CBOR", cid);
+    }
+    if !cid.verify(bytes) {
+        bail!("Block does not match CID {}", cid);
+    }
+    decode_payload(cid, bytes)
+}
+
+/// Encodes `value` like `encode_block`, then compresses it with zstd into
+/// a raw block flagged with `COMPRESSED_PAYLOAD`. The daemon can't follow
+/// links out of it.
+pub fn compress_block<T: Serialize>(value: &T) -> Result<(IpfsCid, Vec<u8>)> {
+    let bytes = compress_payload(&encode_block(value)?.1)?;
+    Ok((IpfsCid::compute(RAW_CODE, &bytes), bytes))
+}
+
+/// Checks a block produced by `compress_block` against `cid`, decompresses
+/// it and decodes the DAG-CBOR inside.
+pub fn decompress_block<T: DeserializeOwned + Serialize + Versioned>(cid: &IpfsCid, bytes: &[u8]) -> Result<T> {
+    if cid.0.codec() != RAW_CODE || bytes.first() != Some(&COMPRESSED_PAYLOAD) {
+        bail!("Block {} is not a compressed block", cid);
+    }
+    if !cid.verify(bytes) {
+        bail!("Block does not match CID {}", cid);
+    }
+    decode_payload(cid, &decompress_payload(cid, bytes)?)
+}
+
+/// Encodes `value` like `encode_block`, then seals it with `key` into a raw
+/// block so the daemon only ever sees ciphertext.
+pub fn seal_block<T: Serialize>(value: &T, key: &WorkspaceKey) -> Result<(IpfsCid, Vec<u8>)> {
+    let (_, plaintext) = encode_block(value)?;
+    Ok(seal_payload(&plaintext, key))
+}
+
+/// `seal_block` compressing the DAG-CBOR before sealing it; ciphertext
+/// doesn't compress.
+pub fn seal_compressed_block<T: Serialize>(value: &T, key: &WorkspaceKey) -> Result<(IpfsCid, Vec<u8>)> {
+    let (_, plaintext) = encode_block(value)?;
+    Ok(seal_payload(&compress_payload(&plaintext)?, key))
+}
+
+fn seal_payload(payload: &[u8], key: &WorkspaceKey) -> (IpfsCid, Vec<u8>) {
+    let sealed = key.seal(payload);
+    (IpfsCid::compute(RAW_CODE, &sealed), sealed)
+}
+
+/// Checks a block produced by `seal_block` or `seal_compressed_block`
+/// against `cid`, decrypts it and decodes the DAG-CBOR inside.
+pub fn open_block<T: DeserializeOwned + Serialize + Versioned>(cid: &IpfsCid, bytes: &[u8], key: &WorkspaceKey) -> Result<T> {
+    if cid.0.codec() != RAW_CODE {
+        bail!("Block {} is not a sealed block", cid);
+    }
+    if !cid.verify(bytes) {
+        bail!("Block does not match CID {}", cid);
+    }
+    let plaintext = key.open(bytes).map_err(|e| anyhow!("Block {}: {}", cid, e))?;
+    decode_payload(cid, &decompress_payload(cid, &plaintext)?)
+}
+
+/// Decodes a block payload, rejecting anything that isn't exactly what
+/// `encode_block` would have written for the decoded value: the same
+/// logical block must always have one encoding and so one CID.
+fn decode_payload<T: DeserializeOwned + Serialize + Versioned>(cid: &IpfsCid, bytes: &[u8]) -> Result<T> {
+    let value: T = serde_ipld_dagcbor::from_slice(bytes)
+        .map_err(|e| anyhow!("Failed to decode block {}: {}", cid, e))?;
+    check_version(&value)?;
+    if encode_block(&value)?.1 != bytes {
+        bail!("Block {} is not canonically encoded", cid);
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod wire_test {
+    use super::*;
+    use crate::crdt::clock::Dot;
+    use crate::crdt::hamt;
+    use crate::crdt::op::{Ancestor, Entry, OpKind};
+    use crate::test_util::alice;
+    use std::str::FromStr;
+
+    fn author() -> ReplicaId {
+        alice()
+    }
+
+    fn parent() -> IpfsCid {
+        IpfsCid::from_str("QmdbWa3wBGwQ4suXjEpPkrigP3UmBMECdJNmkHfz6btqaJ").unwrap()
+    }
+
+    fn op_node() -> OpNode {
+        let mut context = VersionVector::new();
+        context.observe(&Dot { author: author(), seq: 1 });
+        let entry = Entry { content: IpfsCid::compute(RAW_CODE, b"hello"), size: 5, mode: 0o644, mtime: 1700000000, chunks: Vec::new(), symlink: None };
+        let ancestor = Ancestor {
+            dot: Dot { author: author(), seq: 1 },
+            timestamp: Hlc { millis: 1700000000000, counter: 0 },
+            content: IpfsCid::compute(RAW_CODE, b"hell"),
+        };
+        OpNode::new(
+            Op {
+                author: author(),
+                seq: 2,
+                timestamp: Hlc { millis: 1700000000000, counter: 1 },
+                context,
+                kind: OpKind::Put { path: "docs/readme.md".into(), entry, lineage: vec![ancestor] },
+                reverts: Vec::new(),
+            },
+            vec![parent()],
+        )
+    }
+
+    fn state() -> State {
+        let mut state = State::new();
+        state.apply(&op_node().op);
+        state
+    }
+
+    fn shard_node() -> ShardNode {
+        let encode = |shard: &ShardNode| Ok(encode_block(shard)?.0);
+        let (_, mut shards) = hamt::build(state().registers(), 16, &encode).unwrap();
+        shards.pop().unwrap().1
+    }
+
+    fn state_block() -> StateBlock {
+        StateBlock::new(state().without_entries(), cid_of(&shard_node()).parse().unwrap(), 16)
+    }
+
+    fn snapshot_node() -> SnapshotNode {
+        let (state_cid, _) = state_block().encode().unwrap();
+        let timestamp = Hlc { millis: 1700000000001, counter: 0 };
+        SnapshotNode::new(author(), timestamp, state().version_vector().clone(), state_cid, vec![parent()])
+    }
+
+    fn signed(mut node: Node) -> Node {
+        node.sign(&ReplicaKeypair::from_seed([1; 32])).unwrap();
+        node
+    }
+
+    fn cid_of<T: Serialize>(value: &T) -> String {
+        encode_block(value).unwrap().0.to_string()
+    }
+
+    // Golden vectors: these CIDs pin the exact bytes of each schema. If one
+    // changes, the wire format changed and WIRE_VERSION must be bumped.
+    #[test]
+    fn test_golden_op_node() {
+        assert_eq!(cid_of(&signed(Node::Op(op_node()))), "zdpuAwPo2e1Z6261FKHuMPg7G3YTE2GXccizrtpZTyN7SEXhx");
+    }
+
+    #[test]
+    fn test_golden_snapshot_node() {
+        assert_eq!(cid_of(&signed(Node::Snapshot(snapshot_node()))), "zdpuAtFzqEaSaomJHe578NhBakqbE9Qdb4koYgeq8r6ZVKiFq");
+    }
+
+    #[test]
+    fn test_golden_state_block() {
+        assert_eq!(cid_of(&state_block()), "zdpuAyuaXiSaKmymuwbkFmTKMstf1xUFvuGBUmBZ3E2myjgU2");
+    }
+
+    #[test]
+    fn test_golden_shard_node() {
+        assert_eq!(cid_of(&shard_node()), "zdpuAv8QAJpMk6qc3hXF7hzCGxDo8sScGwkyQmMMvca1WNNS2");
+    }
+
+    #[test]
+    fn test_golden_head_announcement() {
+        let mut vv = VersionVector::new();
+        vv.observe(&Dot { author: author(), seq: 2 });
+        assert_eq!(cid_of(&HeadAnnouncement::new(author(), vec![parent()], vv)), "zdpuAxb1asTDfqRVjbKKHiuzVkYb6yYEF1dwoEkkSCqwqAXog");
+    }
+
+    #[test]
+    fn test_golden_delta_bundle() {
+        let nodes: Vec<(IpfsCid, Node)> = [signed(Node::Op(op_node())), signed(Node::Snapshot(snapshot_node()))]
+            .into_iter()
+            .map(|node| (encode_block(&node).unwrap().0, node))
+            .collect();
+        let states = vec![(encode_block(&state_block()).unwrap().0, state_block())];
+        let shards = vec![(encode_block(&shard_node()).unwrap().0, shard_node())];
+        let bundle = DeltaBundle::new(author(), VersionVector::new(), vec![parent()], nodes, states, shards);
+        assert_eq!(cid_of(&bundle), "zdpuAxCP1eLxGxkJPzoDhjQWbdC4Xk4g9rNmYtzZ7ZUp4hguc");
+    }
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let node = Node::Snapshot(snapshot_node());
+        let (cid, bytes) = node.encode().unwrap();
+        assert_eq!(cid.0.codec(), DAG_CBOR_CODE);
+        assert_eq!(Node::decode(&cid, &bytes).unwrap(), node);
+    }
+
+    #[test]
+    fn test_sealed_block_roundtrip() {
+        let key = WorkspaceKey::from_bytes([3; 32]);
+        let node = signed(Node::Op(op_node()));
+        let (cid, bytes) = seal_block(&node, &key).unwrap();
+        assert_eq!(cid.0.codec(), RAW_CODE);
+        assert!(!bytes.windows(6).any(|w| w == b"readme"));
+        assert_eq!(open_block::<Node>(&cid, &bytes, &key).unwrap(), node);
+        assert!(open_block::<Node>(&cid, &bytes, &WorkspaceKey::from_bytes([4; 32])).is_err());
+        assert!(Node::decode(&cid, &bytes).is_err());
+    }
+
+    #[test]
+    fn test_compressed_block_roundtrip() {
+        let mut state = State::new();
+        let mut context = VersionVector::new();
+        for seq in 1..=200 {
+            let mut op = op_node().op;
+            op.seq = seq;
+            op.context = context.clone();
+            op.kind = OpKind::Remove { path: format!("docs/chapter-{}/notes.md", seq) };
+            state.apply(&op);
+            context.observe(&op.dot());
+        }
+        let block = StateBlock::new(state, cid_of(&shard_node()).parse().unwrap(), 16);
+        let (cid, bytes) = compress_block(&block).unwrap();
+        assert_eq!((cid.0.codec(), bytes[0]), (RAW_CODE, COMPRESSED_PAYLOAD));
+        assert!(bytes.len() * 3 < block.encode().unwrap().1.len());
+        assert_eq!(decompress_block::<StateBlock>(&cid, &bytes).unwrap(), block);
+        assert!(StateBlock::decode(&cid, &bytes).is_err());
+
+        let key = WorkspaceKey::from_bytes([3; 32]);
+        let (cid, bytes) = seal_compressed_block(&block, &key).unwrap();
+        assert_eq!(open_block::<StateBlock>(&cid, &bytes, &key).unwrap(), block);
+    }
+
+    #[test]
+    fn test_decompression_is_bounded() {
+        let huge = vec![0u8; MAX_DECOMPRESSED_SIZE + 1];
+        let bytes = compress_payload(&huge).unwrap();
+        let cid = IpfsCid::compute(RAW_CODE, &bytes);
+        assert!(bytes.len() < 4096);
+        assert!(decompress_block::<StateBlock>(&cid, &bytes).is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_non_canonical_block() {
+        // Same node with an indefinite-length parents array: decodes to the
+        // same value but would get a different CID than the canonical form.
+        let node = Node::Op(op_node());
+        let (_, bytes) = node.encode().unwrap();
+        let parents = serde_ipld_dagcbor::to_vec(&node.parents()).unwrap();
+        let at = bytes.windows(parents.len()).position(|w| w == parents).unwrap();
+        let mut relaxed = bytes[..at].to_vec();
+        relaxed.push(0x9f);
+        relaxed.extend_from_slice(&parents[1..]);
+        relaxed.push(0xff);
+        relaxed.extend_from_slice(&bytes[at + parents.len()..]);
+
+        let cid = IpfsCid::compute(DAG_CBOR_CODE, &relaxed);
+        assert!(Node::decode(&cid, &relaxed).is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_mismatched_block() {
+        let (cid, mut bytes) = Node::Op(op_node()).encode().unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(Node::decode(&cid, &bytes).is_err());
+    }
+
+    #[test]
+    fn test_signature_covers_whole_node() {
+        let node = signed(Node::Op(op_node()));
+        assert!(node.verify_signature().is_ok());
+
+        let Node::Op(mut forged) = node.clone() else { unreachable!() };
+        forged.op.seq += 1;
+        assert!(Node::Op(forged).verify_signature().is_err());
+
+        assert!(Node::Op(op_node()).verify_signature().is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_other_versions() {
+        for version in [WIRE_VERSION - 1, WIRE_VERSION + 1] {
+            let mut node = op_node();
+            node.version = version;
+            let (cid, bytes) = Node::Op(node).encode().unwrap();
+            assert!(Node::decode(&cid, &bytes).is_err());
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Extended attributes: small named values applications attach to a file,
+//! such as labels, checksums or MIME types. Each name is its own
+//! last-writer-wins register, so concurrent writes to different names both
+//! survive and neither touches the file's content or mode.
+
+use anyhow::Result;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+
+use super::op::EntryPatch;
+use super::replica::Replica;
+use super::state::State;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+impl State {
+    /// The value of the extended attribute `name` of the file at `path`.
+    pub fn xattr(&self, path: &str, name: &str) -> Option<&[u8]> {
+        self.get(path)?;
+        let value = self.register(path)?.xattrs.get(name)?.winner()?.value.as_ref()?;
+        Some(value.as_slice())
+    }
+
+    /// Every extended attribute of the file at `path`, by name.
+    pub fn xattrs(&self, path: &str) -> BTreeMap<&str, &[u8]> {
+        let Some(register) = self.register(path).filter(|_| self.get(path).is_some()) else {
+            return BTreeMap::new();
+        };
+        register
+            .xattrs
+            .iter()
+            .filter_map(|(name, xattr)| Some((name.as_str(), xattr.winner()?.value.as_ref()?.as_slice())))
+            .collect()
+    }
+}
+
+impl Replica {
+    /// Sets the extended attribute `name` of the file at `path`. Returns
+    /// `None` if there was no such file.
+    pub fn set_xattr(&mut self, path: &str, name: &str, value: &[u8]) -> Result<Option<IpfsCid>> {
+        self.write_xattr(path, name, Some(ByteBuf::from(value)))
+    }
+
+    /// Deletes the extended attribute `name` of the file at `path`.
+    /// Returns `None` if there was no such file or attribute.
+    pub fn remove_xattr(&mut self, path: &str, name: &str) -> Result<Option<IpfsCid>> {
+        if self.state().xattr(path, name).is_none() {
+            return Ok(None);
+        }
+        self.write_xattr(path, name, None)
+    }
+
+    fn write_xattr(&mut self, path: &str, name: &str, value: Option<ByteBuf>) -> Result<Option<IpfsCid>> {
+        let patch = EntryPatch { xattrs: BTreeMap::from([(name.to_string(), value)]), ..EntryPatch::default() };
+        self.patch(path, patch)
+    }
+}
+
+#[cfg(test)]
+mod xattr_test {
+    use super::*;
+    use crate::crdt::clock::VersionVector;
+    use crate::test_util::{alice, bob, entry};
+
+    #[test]
+    fn test_concurrent_xattrs_merge_per_name() {
+        let alice = alice();
+        let bob = bob();
+        let mut a = Replica::new(alice);
+        a.put("doc.txt", entry(b"text")).unwrap();
+        a.set_xattr("doc.txt", "user.label", b"draft").unwrap();
+        let mut b = Replica::new(bob);
+        b.apply_delta(&a.delta_since(&VersionVector::new())).unwrap();
+
+        a.set_xattr("doc.txt", "user.mime", b"text/plain").unwrap();
+        a.set_xattr("doc.txt", "user.label", b"review").unwrap();
+        b.set_xattr("doc.txt", "user.label", b"final").unwrap();
+        b.set_mode("doc.txt", 0o600).unwrap();
+        a.apply_delta(&b.delta_since(&VersionVector::new())).unwrap();
+        b.apply_delta(&a.delta_since(&VersionVector::new())).unwrap();
+
+        assert_eq!(a.state(), b.state());
+        assert_eq!(a.state().xattr("doc.txt", "user.mime"), Some(&b"text/plain"[..]));
+        assert_eq!(a.state().get("doc.txt").unwrap().mode, 0o600);
+        let label = &a.state().register("doc.txt").unwrap().xattrs["user.label"];
+        assert_eq!(label.versions().len(), 2);
+        assert_eq!(a.state().xattr("doc.txt", "user.label"), label.winner().unwrap().value.as_deref().map(Vec::as_slice));
+
+        assert!(a.remove_xattr("doc.txt", "user.mime").unwrap().is_some());
+        assert!(a.remove_xattr("doc.txt", "user.mime").unwrap().is_none());
+        assert_eq!(a.state().xattrs("doc.txt").into_keys().collect::<Vec<_>>(), vec!["user.label"]);
+        assert!(a.set_xattr("missing", "user.label", b"x").unwrap().is_none());
+
+        // a removed file doesn't bring its attributes back when recreated
+        a.remove("doc.txt").unwrap();
+        a.put("doc.txt", entry(b"new")).unwrap();
+        assert!(a.state().xattrs("doc.txt").is_empty());
+    }
+}
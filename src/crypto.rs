@@ -0,0 +1,247 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use cid::multibase::{self, Base};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::kubo_rpc::ipfs::{get_block, put_block, IpfsCid, RAW_CODE};
+
+pub const KEY_LENGTH: usize = 32;
+pub const NONCE_LENGTH: usize = 24;
+
+/// Version of the keyring file format.
+pub const KEYRING_VERSION: u32 = 1;
+
+/// Symmetric key shared by every member of a workspace. File chunks and DAG
+/// nodes are encrypted with XChaCha20-Poly1305 under keys derived from it
+/// before anything reaches the daemon. Filenames only travel inside sealed
+/// nodes and states, so the daemon never sees them either.
+#[derive(Clone, PartialEq, Eq)]
+pub struct WorkspaceKey {
+    key: [u8; KEY_LENGTH],
+}
+
+impl fmt::Debug for WorkspaceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the key itself
+        f.debug_struct("WorkspaceKey").field("id", &self.id()).finish()
+    }
+}
+
+impl WorkspaceKey {
+    /// Generates a fresh key from the OS random number generator.
+    pub fn generate() -> Result<Self> {
+        let mut key = [0u8; KEY_LENGTH];
+        getrandom::getrandom(&mut key).map_err(|e| anyhow!("Failed to gather randomness: {}", e))?;
+        Ok(Self::from_bytes(key))
+    }
+
+    pub fn from_bytes(key: [u8; KEY_LENGTH]) -> Self {
+        WorkspaceKey { key }
+    }
+
+    /// Short public fingerprint of the key, safe to show and to compare
+    /// out-of-band.
+    pub fn id(&self) -> String {
+        let digest = self.derive(b"id");
+        multibase::encode(Base::Base32Lower, &digest[..10])
+    }
+
+    /// Key for what is kept on this machine, derived from this one so a
+    /// local file and a network block never share a key; see `at_rest`.
+    pub fn at_rest(&self) -> WorkspaceKey {
+        WorkspaceKey::from_bytes(self.derive(b"at-rest"))
+    }
+
+    /// Encrypts `plaintext`. The nonce is derived from the plaintext, so the
+    /// same block always seals to the same bytes (and CID); only equality of
+    /// plaintexts is revealed.
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.synthetic_nonce(b"block-nonce", plaintext);
+        let cipher = XChaCha20Poly1305::new(&self.derive(b"block").into());
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .expect("XChaCha20-Poly1305 encryption doesn't fail for in-memory buffers");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    /// Decrypts bytes produced by `seal`, failing if they were tampered
+    /// with or sealed under another key.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LENGTH {
+            bail!("Sealed block is shorter than its nonce");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let cipher = XChaCha20Poly1305::new(&self.derive(b"block").into());
+        cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt block: wrong workspace key or corrupted data"))
+    }
+
+    /// Subkey for one purpose, so the block and nonce keys never coincide.
+    fn derive(&self, purpose: &[u8]) -> [u8; KEY_LENGTH] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(purpose);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn synthetic_nonce(&self, purpose: &[u8], plaintext: &[u8]) -> [u8; NONCE_LENGTH] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.derive(purpose)).expect("HMAC accepts any key length");
+        mac.update(plaintext);
+        let digest = mac.finalize().into_bytes();
+        digest[..NONCE_LENGTH].try_into().expect("SHA-256 output is longer than the nonce")
+    }
+}
+
+/// Text form used to share a key out-of-band: the raw key in multibase
+/// base32, e.g. `bxxxx...`.
+impl fmt::Display for WorkspaceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&multibase::encode(Base::Base32Lower, self.key))
+    }
+}
+
+impl FromStr for WorkspaceKey {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (_, bytes) = multibase::decode(s.trim()).map_err(|_| "Invalid workspace key encoding")?;
+        let key: [u8; KEY_LENGTH] = bytes.try_into().map_err(|_| "Workspace key must be 32 bytes")?;
+        Ok(WorkspaceKey::from_bytes(key))
+    }
+}
+
+impl Serialize for WorkspaceKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for WorkspaceKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        WorkspaceKey::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Seals `data` (a file or file chunk) with `key` and stores it as a raw
+/// block on the IPFS daemon at `base_url`. Returns the CID of the sealed
+/// block.
+pub async fn put_sealed(base_url: &str, key: &WorkspaceKey, data: &[u8]) -> Result<IpfsCid> {
+    let sealed = key.seal(data);
+    let cid = IpfsCid::compute(RAW_CODE, &sealed);
+    let stored = put_block(base_url, sealed).await?;
+    if stored != cid {
+        bail!("Daemon stored sealed block as {} but expected {}", stored, cid);
+    }
+    Ok(cid)
+}
+
+/// Fetches the sealed block `cid` from the IPFS daemon at `base_url` and
+/// decrypts it with `key`.
+pub async fn get_sealed(base_url: &str, key: &WorkspaceKey, cid: &IpfsCid) -> Result<Vec<u8>> {
+    let sealed = get_block(base_url, cid).await?;
+    if !cid.verify(&sealed) {
+        bail!("Block does not match CID {}", cid);
+    }
+    key.open(&sealed)
+}
+
+/// Workspace keys held by one user, by workspace name. Stored as JSON so
+/// it can be copied between machines or pasted into a password manager.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Keyring {
+    pub version: u32,
+    pub keys: BTreeMap<String, WorkspaceKey>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Keyring { version: KEYRING_VERSION, keys: BTreeMap::new() }
+    }
+
+    pub fn insert(&mut self, workspace: &str, key: WorkspaceKey) {
+        self.keys.insert(workspace.to_string(), key);
+    }
+
+    pub fn get(&self, workspace: &str) -> Option<&WorkspaceKey> {
+        self.keys.get(workspace)
+    }
+
+    pub fn export(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn import(s: &str) -> Result<Self> {
+        let keyring: Keyring = serde_json::from_str(s).context("Invalid keyring")?;
+        if keyring.version == 0 || keyring.version > KEYRING_VERSION {
+            bail!("Unsupported keyring version {}", keyring.version);
+        }
+        Ok(keyring)
+    }
+
+    /// Loads the keyring at `path`, or an empty one if the file doesn't
+    /// exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Keyring::new());
+        }
+        let s = std::fs::read_to_string(path).with_context(|| format!("Failed to read keyring {}", path.display()))?;
+        Keyring::import(&s)
+    }
+
+    /// Writes the keyring to `path`, readable only by the owner on Unix.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, self.export()?)
+            .with_context(|| format!("Failed to write keyring {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod crypto_test {
+    use super::*;
+
+    #[test]
+    fn test_seal_is_deterministic_and_authenticated() {
+        let key = WorkspaceKey::from_bytes([1; 32]);
+        let sealed = key.seal(b"file contents");
+        assert_eq!(sealed, key.seal(b"file contents"));
+        assert_ne!(sealed, key.seal(b"other contents"));
+        assert_eq!(key.open(&sealed).unwrap(), b"file contents");
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key.open(&tampered).is_err());
+        assert!(WorkspaceKey::from_bytes([2; 32]).open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_keyring_roundtrip() {
+        let key = WorkspaceKey::from_bytes([9; 32]);
+        assert_eq!(WorkspaceKey::from_str(&key.to_string()).unwrap(), key);
+
+        let mut keyring = Keyring::new();
+        keyring.insert("photos", key.clone());
+        let imported = Keyring::import(&keyring.export().unwrap()).unwrap();
+        assert_eq!(imported.get("photos"), Some(&key));
+        assert!(Keyring::import(r#"{"version": 99, "keys": {}}"#).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Where the crate keeps its files unless told otherwise: local stores
+//! under the platform's data directory and block caches under its cache
+//! directory, so a synced directory only holds what its users see in it.
+//! That is `$XDG_DATA_HOME` and `$XDG_CACHE_HOME` on Linux and other Unix
+//! systems, `~/Library/Application Support` and `~/Library/Caches` on
+//! macOS, and `%LOCALAPPDATA%` on Windows. Each synced directory gets a
+//! subdirectory of its own, named after it and a hash of its absolute
+//! path. `LocalStore::open`, `Scanner::with_block_cache_in` and a
+//! workspace's config override the defaults.
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+/// Name of the crate's directory in the platform's directories.
+pub const APP_DIR: &str = "crdt-dir-ipfs";
+/// Directories under the data directory.
+pub const STORES_DIR: &str = "stores";
+pub const WORKSPACES_DIR: &str = "workspaces";
+
+/// The crate's directory for data that must be kept.
+pub fn data_dir() -> Result<PathBuf> {
+    Ok(platform_dir(false)?.join(APP_DIR))
+}
+
+/// The crate's directory for data that can be fetched again.
+pub fn cache_dir() -> Result<PathBuf> {
+    Ok(platform_dir(true)?.join(APP_DIR))
+}
+
+/// The subdirectory for the synced directory at `root`.
+pub fn dir_name(root: &Path) -> Result<String> {
+    let root = std::path::absolute(root).with_context(|| format!("Failed to resolve {}", root.display()))?;
+    let name: String = root
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .take(32)
+        .collect();
+    let hash = Sha256::digest(root.to_string_lossy().as_bytes());
+    let hash: String = hash[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(if name.is_empty() || name.starts_with('.') { hash } else { format!("{}-{}", name, hash) })
+}
+
+/// Where the local store of the directory at `root` goes by default.
+pub fn default_store_dir(root: &Path) -> Result<PathBuf> {
+    Ok(data_dir()?.join(STORES_DIR).join(dir_name(root)?))
+}
+
+/// Where the block cache of the directory at `root` goes by default.
+pub fn default_cache_dir(root: &Path) -> Result<PathBuf> {
+    Ok(cache_dir()?.join(dir_name(root)?))
+}
+
+#[cfg(windows)]
+fn platform_dir(cache: bool) -> Result<PathBuf> {
+    let local = env_dir("LOCALAPPDATA").ok_or_else(|| anyhow!("LOCALAPPDATA is not set"))?;
+    // both live under the local app data, which doesn't roam
+    Ok(if cache { local.join("cache") } else { local })
+}
+
+#[cfg(target_os = "macos")]
+fn platform_dir(cache: bool) -> Result<PathBuf> {
+    let library = home()?.join("Library");
+    Ok(if cache { library.join("Caches") } else { library.join("Application Support") })
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn platform_dir(cache: bool) -> Result<PathBuf> {
+    let (var, fallback) = if cache { ("XDG_CACHE_HOME", ".cache") } else { ("XDG_DATA_HOME", ".local/share") };
+    match env_dir(var) {
+        Some(dir) => Ok(dir),
+        None => Ok(home()?.join(fallback)),
+    }
+}
+
+#[cfg(not(windows))]
+fn home() -> Result<PathBuf> {
+    env_dir("HOME").ok_or_else(|| anyhow!("HOME is not set"))
+}
+
+/// The directory in the environment variable `var`, if it is an absolute
+/// path; relative ones are ignored, as the XDG spec says.
+fn env_dir(var: &str) -> Option<PathBuf> {
+    std::env::var_os(var).map(PathBuf::from).filter(|dir| dir.is_absolute())
+}
+
+#[cfg(test)]
+mod dirs_test {
+    use super::*;
+
+    #[test]
+    fn test_each_directory_gets_its_own_name() {
+        let root = std::env::temp_dir().join("crdt dirs");
+        let name = dir_name(&root).unwrap();
+        assert!(name.starts_with("crdt_dirs-") && name.len() == "crdt_dirs-".len() + 16, "{}", name);
+        assert_eq!(dir_name(&root).unwrap(), name);
+        assert_ne!(dir_name(&root.join("sub/crdt dirs")).unwrap(), name);
+        assert!(!dir_name(&root.join(".hidden")).unwrap().starts_with('.'));
+    }
+}
@@ -0,0 +1,68 @@
+//! How hard writes try to survive a crash or power cut, traded against
+//! speed. One setting covers the files a checkout writes, the journal of
+//! local commits and the scan index.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+/// When writes are synced to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// Each write as it is made: every file a checkout writes, before it
+    /// replaces the old one, and its directory after; every journal
+    /// record; every save of the scan index.
+    Always,
+    /// At checkpoints, where progress is recorded: the files of each batch
+    /// a checkout writes, and their directories, before the index records
+    /// them; the journal before a scan records what it committed; the
+    /// index itself. A crash loses no more than the work since the last
+    /// checkpoint, which is then done again.
+    #[default]
+    Checkpoint,
+    /// Never; the OS writes back in its own time, and a power cut can
+    /// lose or tear anything written lately.
+    Never,
+}
+
+impl Durability {
+    /// Whether each write is synced as it is made.
+    pub fn syncs_writes(self) -> bool {
+        self == Durability::Always
+    }
+
+    /// Whether checkpoints are synced.
+    pub fn syncs_checkpoints(self) -> bool {
+        self != Durability::Never
+    }
+}
+
+/// Writes `data` to the file at `path`, creating its directory. Unless
+/// `durability` never syncs, it is written to a temporary file, synced and
+/// renamed into place, so a crash leaves the old file or the new; the
+/// directory is synced too if it syncs each write.
+pub(crate) fn write_durably(path: &Path, data: &[u8], durability: Durability) -> Result<()> {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+    if let Some(parent) = parent {
+        std::fs::create_dir_all(parent)?;
+    }
+    if !durability.syncs_checkpoints() {
+        return Ok(std::fs::write(path, data)?);
+    }
+    let temp = path.with_extension("tmp");
+    std::fs::File::create(&temp).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    })?;
+    std::fs::rename(&temp, path)?;
+    #[cfg(unix)]
+    if durability.syncs_writes() {
+        let dir = parent.unwrap_or(Path::new("."));
+        std::fs::File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("Failed to sync {}", dir.display()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,215 @@
+//! Moving a replica to another machine in one file, without cloning its
+//! history again. The file is a CAR rooted at a manifest naming the
+//! replica's identity, its settings and the head announcement it last
+//! wrote, followed by every block of its history, the way
+//! `Replica::to_car` has it, and optionally the content of the current
+//! files. Importing stores the blocks on the daemon and merges the
+//! announcement into a fresh replica with the same identity, which rebuilds
+//! the state, heads and version vectors; blocks not published yet are
+//! among them, and are published from the new machine. The manifest holds
+//! the replica's signing seed and the workspace key, so the file is as
+//! secret as they are.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::crdt::car::Car;
+use crate::crdt::identity::ReplicaId;
+use crate::crdt::replica::Replica;
+use crate::crdt::sign::ReplicaKeypair;
+use crate::crdt::wire::encode_block;
+use crate::crypto::WorkspaceKey;
+use crate::durability::Durability;
+use crate::kubo_rpc::ipfs::{put_block_with_codec, IpfsCid};
+use crate::workspace::{SyncMode, Workspace};
+
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Local settings of the replica and workspace, which aren't in the DAG.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportConfig {
+    pub mode: SyncMode,
+    pub durability: Durability,
+    pub scope: String,
+    pub snapshot_interval: usize,
+    pub shard_fanout: u32,
+    pub compression: Option<usize>,
+    pub upload_window: usize,
+}
+
+/// The root of an export. Not `Debug`, since it holds secrets.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportManifest {
+    pub version: u32,
+    pub author: ReplicaId,
+    /// Seed of the replica's signing keypair.
+    #[serde(with = "serde_bytes")]
+    pub seed: Vec<u8>,
+    pub workspace_key: Option<WorkspaceKey>,
+    /// Head announcement of the replica when it was exported.
+    pub announcement: IpfsCid,
+    pub config: ExportConfig,
+    /// Whether the content of the current files is included.
+    pub content: bool,
+}
+
+/// An export as read or about to be written.
+#[derive(Clone)]
+pub struct StateExport {
+    pub manifest: ExportManifest,
+    pub blocks: Vec<(IpfsCid, Vec<u8>)>,
+}
+
+impl StateExport {
+    /// The replica of `workspace` with its settings, without content.
+    pub fn of(workspace: &Workspace) -> Result<Self> {
+        let replica = workspace.replica();
+        let car = replica.to_car()?;
+        let manifest = ExportManifest {
+            version: EXPORT_VERSION,
+            author: replica.author().clone(),
+            seed: replica.keypair().seed().to_vec(),
+            workspace_key: replica.workspace_key().cloned(),
+            announcement: car.roots[0].clone(),
+            config: ExportConfig {
+                mode: workspace.mode(),
+                durability: replica.durability(),
+                scope: replica.scope().to_string(),
+                snapshot_interval: replica.snapshot_interval(),
+                shard_fanout: replica.shard_fanout(),
+                compression: replica.compression(),
+                upload_window: replica.upload_window(),
+            },
+            content: false,
+        };
+        Ok(StateExport { manifest, blocks: car.blocks })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let (root, manifest) = encode_block(&self.manifest)?;
+        let mut blocks = vec![(root.clone(), manifest)];
+        blocks.extend(self.blocks.iter().cloned());
+        Car { roots: vec![root], blocks }.encode()
+    }
+
+    /// Parses an export, checking every block against its CID.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut car = Car::decode(bytes)?;
+        let [root] = car.roots.as_slice() else {
+            bail!("Export has {} roots, expected a single manifest", car.roots.len());
+        };
+        let position = car.blocks.iter().position(|(cid, _)| cid == root).ok_or_else(|| anyhow!("Export manifest {} is missing", root))?;
+        let (_, manifest) = car.blocks.remove(position);
+        let manifest: ExportManifest = serde_ipld_dagcbor::from_slice(&manifest).map_err(|e| anyhow!("Malformed export manifest: {}", e))?;
+        if manifest.version == 0 || manifest.version > EXPORT_VERSION {
+            bail!("Unsupported export version {}", manifest.version);
+        }
+        Ok(StateExport { manifest, blocks: car.blocks })
+    }
+
+    /// Writes the export to `path`, readable only by the owner on Unix.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.encode()?).with_context(|| format!("Failed to write export {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read export {}", path.display()))?;
+        StateExport::decode(&bytes)
+    }
+
+    /// A fresh replica with the exported identity and settings, with the
+    /// exported history merged in.
+    pub async fn replica(&self) -> Result<Replica> {
+        let manifest = &self.manifest;
+        let seed: [u8; 32] = manifest.seed.as_slice().try_into().map_err(|_| anyhow!("Exported keypair seed is not 32 bytes"))?;
+        let config = &manifest.config;
+        let mut replica = Replica::new(manifest.author.clone())
+            .with_keypair(ReplicaKeypair::from_seed(seed))
+            .with_durability(config.durability)
+            .with_scope(&config.scope)
+            .with_snapshot_interval(config.snapshot_interval)
+            .with_shard_fanout(config.shard_fanout)
+            .with_upload_window(config.upload_window);
+        if let Some(key) = &manifest.workspace_key {
+            replica = replica.with_workspace_key(key.clone());
+        }
+        if let Some(min_size) = config.compression {
+            replica = replica.with_compression(min_size);
+        }
+        let blocks: HashMap<&IpfsCid, &Vec<u8>> = self.blocks.iter().map(|(cid, bytes)| (cid, bytes)).collect();
+        replica
+            .merge_announcement(&manifest.announcement, async |cid| {
+                blocks.get(&cid).map(|bytes| bytes.to_vec()).ok_or_else(|| anyhow!("Block {} is missing from the export", cid))
+            })
+            .await
+            .context("Failed to restore exported replica")?;
+        Ok(replica)
+    }
+}
+
+impl Workspace {
+    /// Exports this workspace's replica to `path`; see `StateExport`. With
+    /// `content`, the content of the current files is read from the daemon
+    /// and included, as `export_car` does.
+    pub async fn export_state(&self, path: impl AsRef<Path>, content: bool) -> Result<()> {
+        let mut export = StateExport::of(self)?;
+        if content {
+            let mut car = Car { roots: Vec::new(), blocks: export.blocks };
+            self.add_content(&mut car).await?;
+            export.blocks = car.blocks;
+            export.manifest.content = true;
+        }
+        export.write(path.as_ref())
+    }
+
+    /// Opens the workspace exported to `path` on the IPFS daemon at
+    /// `base_url`, storing every block of the export there first.
+    pub async fn import_state(base_url: &str, path: impl AsRef<Path>) -> Result<Self> {
+        let export = StateExport::read(path.as_ref())?;
+        for (cid, bytes) in &export.blocks {
+            let stored = put_block_with_codec(base_url, bytes.clone(), cid.codec_name()).await?;
+            if stored != *cid {
+                bail!("Daemon stored block as {} but expected {}", stored, cid);
+            }
+        }
+        let replica = export.replica().await?;
+        Ok(Workspace::new(base_url, replica).with_mode(export.manifest.config.mode))
+    }
+}
+
+#[cfg(test)]
+mod export_test {
+    use super::*;
+    use crate::test_util::{alice, entry};
+
+    #[tokio::test]
+    async fn test_export_moves_replica() {
+        let key = WorkspaceKey::generate().unwrap();
+        let mut replica = Replica::new(alice()).with_workspace_key(key.clone()).with_snapshot_interval(2).with_compression(64);
+        for i in 0..3 {
+            replica.put(&format!("file{}", i), entry(&[i])).unwrap();
+        }
+        let workspace = Workspace::new("http://127.0.0.1:5001", replica).with_mode(SyncMode::PushOnly);
+        let path = std::env::temp_dir().join(format!("crdt-export-{}", std::process::id()));
+        StateExport::of(&workspace).unwrap().write(&path).unwrap();
+
+        let export = StateExport::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(export.manifest.config.mode, SyncMode::PushOnly);
+        let moved = export.replica().await.unwrap();
+        let replica = workspace.replica();
+        assert_eq!(moved.state(), replica.state());
+        assert_eq!(moved.heads(), replica.heads());
+        assert_eq!(moved.keypair().public_key(), replica.keypair().public_key());
+        assert_eq!(moved.workspace_key(), Some(&key));
+        assert_eq!((moved.snapshot_interval(), moved.compression()), (2, Some(64)));
+    }
+}
@@ -1,13 +1,47 @@
-use cid::{Cid, multibase::Base};
+use cid::{Cid, multibase::Base, multihash::Multihash};
+use bytes::Bytes;
 use reqwest::Client;
 use std::fmt;
 use reqwest::multipart;
 use std::str::FromStr;
 use anyhow::{anyhow, Result};
 use std::time::Duration;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub const DAG_PB_CODE: u64 = 0x70;
+pub const RAW_CODE: u64 = 0x55;
+pub const DAG_CBOR_CODE: u64 = 0x71;
+pub const SHA2_256_CODE: u64 = 0x12;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct IpfsCid(pub Cid);
+
+impl IpfsCid {
+    /// Computes the CIDv1 (sha2-256) of `data` under `codec`, matching what
+    /// the daemon returns from `block/put` for the same bytes.
+    pub fn compute(codec: u64, data: &[u8]) -> Self {
+        let digest = Sha256::digest(data);
+        let hash = Multihash::wrap(SHA2_256_CODE, &digest)
+            .expect("sha2-256 digest fits in a multihash");
+        IpfsCid(Cid::new_v1(codec, hash))
+    }
+
+    /// Name of the CID's codec as the daemon's `cid-codec` option spells it.
+    pub fn codec_name(&self) -> &'static str {
+        match self.0.codec() {
+            DAG_PB_CODE => "dag-pb",
+            DAG_CBOR_CODE => "dag-cbor",
+            _ => "raw",
+        }
+    }
+
+    /// Returns true if `data` hashes to this CID.
+    pub fn verify(&self, data: &[u8]) -> bool {
+        self.0.hash().code() == SHA2_256_CODE
+            && IpfsCid::compute(self.0.codec(), data) == *self
+    }
+}
 impl From<IpfsCid> for Cid {
     fn from(key: IpfsCid) -> Self {
         key.0
@@ -19,7 +53,7 @@ impl std::convert::TryFrom<Cid> for IpfsCid {
 
     fn try_from(cid: Cid) -> Result<Self, Self::Error> {
         match cid.codec() {
-            0x70 | 0x55 => Ok(IpfsCid(cid)), // dag-pb or raw
+            DAG_PB_CODE | RAW_CODE | DAG_CBOR_CODE => Ok(IpfsCid(cid)),
             _ => Err("Unsupported codec for IPFS CID"),
         }
     }
@@ -44,6 +78,36 @@ impl std::fmt::Display for IpfsCid {
     }
 }
 
+// Human readable formats get the base58 string, binary formats (DAG-CBOR)
+// get a real IPLD link so the daemon can follow it.
+impl Serialize for IpfsCid {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for IpfsCid {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            IpfsCid::from_str(&s).map_err(serde::de::Error::custom)
+        } else {
+            let cid = Cid::deserialize(deserializer)?;
+            IpfsCid::try_from(cid).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
 #[cfg(test)]
 mod ipfs_cid_test {
     use super::*;
@@ -90,6 +154,18 @@ mod ipfs_cid_test {
             "Expected '{}' to fail parsing as CID", bad_str
         );
     }
+
+    #[test]
+    fn test_compute_matches_daemon_cid() {
+        // `echo -n hello | ipfs block put` on a default Kubo node
+        let cid = IpfsCid::compute(RAW_CODE, b"hello");
+        assert_eq!(
+            cid.0.to_string(),
+            "bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq"
+        );
+        assert!(cid.verify(b"hello"));
+        assert!(!cid.verify(b"hello!"));
+    }
 }
 
 
@@ -109,6 +185,28 @@ pub async fn get_block(
         .send()
         .await?;
 
+    if !response.status().is_success() {
+        return Err(anyhow!("Failed to get block {}: {}", cid, error_message(response).await));
+    }
+    let bytes = response.bytes().await?;
+    Ok(bytes.to_vec())
+}
+
+/// Reads the full contents of the file at `cid` (UnixFS or raw) from the
+/// IPFS daemon at `base_url`.
+pub async fn cat(
+    base_url: &str,
+    cid: &IpfsCid,
+) -> Result<Vec<u8>> {
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/api/v0/cat", base_url))
+        .query(&[("arg", cid.to_string())])
+        .send()
+        .await?
+        .error_for_status()?;
+
     let bytes = response.bytes().await?;
     Ok(bytes.to_vec())
 }
@@ -116,15 +214,29 @@ pub async fn get_block(
 /// Puts a block of data into IPFS daemon at `base_url`.
 pub async fn put_block(
     base_url: &str,
-    data: &[u8],
+    data: impl Into<Bytes>,
+) -> Result<IpfsCid> {
+    put_block_with_codec(base_url, data, "raw").await
+}
+
+/// Puts a block of data into IPFS daemon at `base_url`, tagging the
+/// resulting CID with `codec` (e.g. "raw", "dag-cbor"). The data is
+/// streamed into the request body as it is, without a copy.
+pub async fn put_block_with_codec(
+    base_url: &str,
+    data: impl Into<Bytes>,
+    codec: &str,
 ) -> Result<IpfsCid> {
     let client = Client::new();
 
-    let part = multipart::Part::bytes(data.to_vec()).file_name("block.data");
+    let data = data.into();
+    let len = data.len() as u64;
+    let part = multipart::Part::stream_with_length(data, len).file_name("block.data");
     let form = multipart::Form::new().part("data", part);
 
     let response = client
         .post(&format!("{}/api/v0/block/put", base_url))
+        .query(&[("cid-codec", codec)])
         .multipart(form)
         .send()
         .await?;
@@ -141,6 +253,142 @@ pub async fn put_block(
     Ok(cid)
 }
 
+/// Exports the DAG rooted at `cid` as a CAR file from the IPFS daemon at
+/// `base_url`. Only blocks the daemon holds are used; a missing one fails
+/// the export instead of being searched for on the network.
+pub async fn dag_export(
+    base_url: &str,
+    cid: &IpfsCid,
+) -> Result<Vec<u8>> {
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/api/v0/dag/export", base_url))
+        .query(&[("arg", cid.to_string().as_str()), ("offline", "true")])
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Failed to export {}: {}", cid, error_message(response).await));
+    }
+    let bytes = response.bytes().await?;
+    Ok(bytes.to_vec())
+}
+
+/// Pins `cid` and everything it links to recursively on the IPFS daemon
+/// at `base_url`.
+pub async fn pin_add(
+    base_url: &str,
+    cid: &IpfsCid,
+) -> Result<()> {
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/api/v0/pin/add", base_url))
+        .query(&[("arg", cid.to_string())])
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Failed to pin {}: {}", cid, error_message(response).await));
+    }
+    Ok(())
+}
+
+/// Removes the recursive pin on `cid` from the IPFS daemon at `base_url`.
+/// Returns false if `cid` wasn't pinned.
+pub async fn pin_rm(
+    base_url: &str,
+    cid: &IpfsCid,
+) -> Result<bool> {
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/api/v0/pin/rm", base_url))
+        .query(&[("arg", cid.to_string())])
+        .send()
+        .await?;
+
+    if response.status().is_success() {
+        return Ok(true);
+    }
+    let message = error_message(response).await;
+    if message.contains("not pinned") {
+        Ok(false)
+    } else {
+        Err(anyhow!("Failed to unpin {}: {}", cid, message))
+    }
+}
+
+/// Deletes the block `cid` from the blockstore of the IPFS daemon at
+/// `base_url`. Fails if the block is still pinned.
+pub async fn block_rm(
+    base_url: &str,
+    cid: &IpfsCid,
+) -> Result<()> {
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/api/v0/block/rm", base_url))
+        .query(&[("arg", cid.to_string())])
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Failed to remove block {}: {}", cid, error_message(response).await));
+    }
+
+    #[derive(serde::Deserialize)]
+    #[allow(non_snake_case)]
+    struct RemoveBlockResponse {
+        #[serde(default)]
+        Error: String,
+    }
+
+    let resp_json = response.json::<RemoveBlockResponse>().await?;
+    if !resp_json.Error.is_empty() {
+        return Err(anyhow!("Failed to remove block {}: {}", cid, resp_json.Error));
+    }
+    Ok(())
+}
+
+/// Whether the IPFS daemon at `base_url` holds the block `cid` itself,
+/// without asking the network for it.
+pub async fn has_block(
+    base_url: &str,
+    cid: &IpfsCid,
+) -> Result<bool> {
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/api/v0/block/stat", base_url))
+        .query(&[("arg", cid.to_string()), ("offline", "true".to_string())])
+        .send()
+        .await?;
+
+    if response.status().is_success() {
+        return Ok(true);
+    }
+    let message = error_message(response).await;
+    if message.contains("not found") {
+        Ok(false)
+    } else {
+        Err(anyhow!("Failed to look up block {}: {}", cid, message))
+    }
+}
+
+/// The `Message` of a daemon error response, or its raw body.
+pub(crate) async fn error_message(response: reqwest::Response) -> String {
+    #[derive(serde::Deserialize)]
+    #[allow(non_snake_case)]
+    struct ErrorResponse {
+        Message: String,
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    serde_json::from_str::<ErrorResponse>(&body).map(|e| e.Message).unwrap_or(body)
+}
+
 
 #[cfg(test)]
 mod api_tests {
@@ -155,7 +403,7 @@ mod api_tests {
         let data = b"hello from rust integration test";
 
         // Put the block
-        let cid = put_block(LOCAL_IPFS, data).await?;
+        let cid = put_block(LOCAL_IPFS, &data[..]).await?;
         println!("Stored CID: {}", cid);
 
         // Get it back
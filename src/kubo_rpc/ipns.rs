@@ -5,7 +5,7 @@ use serde::{Deserialize,Deserializer,Serialize,Serializer};
 use reqwest::Client;
 
 use super::keys::IpnsKey;
-use super::ipfs::IpfsCid;
+use super::ipfs::{error_message, IpfsCid};
 
 use std::str::FromStr;
 use anyhow::{anyhow, Result};
@@ -151,6 +151,33 @@ mod path_test {
         let s = "/invalid/path";
         assert!(IpfsPath::from_str(s).is_err());
     }
+
+    #[test]
+    fn ipns_record_reads_data_over_v1_fields() {
+        #[derive(Serialize)]
+        struct Data {
+            #[serde(rename = "Value")]
+            value: serde_bytes::ByteBuf,
+            #[serde(rename = "Sequence")]
+            sequence: u64,
+        }
+
+        let path = "/ipfs/QmdbWa3wBGwQ4suXjEpPkrigP3UmBMECdJNmkHfz6btqaJ";
+        let mut v1 = vec![0x0a, path.len() as u8];
+        v1.extend(path.as_bytes());
+        // sequence 300, a two-byte varint
+        v1.extend([0x28, 0xac, 0x02]);
+        let record = parse_ipns_record(&v1).unwrap();
+        assert_eq!((record.value.as_str(), record.sequence), (path.to_string(), 300));
+
+        let data = serde_ipld_dagcbor::to_vec(&Data { value: serde_bytes::ByteBuf::from(path.as_bytes()), sequence: 301 }).unwrap();
+        let mut v2 = v1.clone();
+        v2.push(0x4a);
+        v2.push(data.len() as u8);
+        v2.extend(&data);
+        assert_eq!(parse_ipns_record(&v2).unwrap().sequence, 301);
+        assert!(parse_ipns_record(&v1[..10]).is_err());
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -266,6 +293,96 @@ pub async fn name_resolve_streaming(
     Ok(parsed_stream)
 }
 
+/// What an IPNS record points at, and its sequence number, which grows
+/// with every publish under the key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpnsRecord {
+    pub value: IpfsPath,
+    pub sequence: u64,
+}
+
+/// Fetches the IPNS record of `name` through the daemon's routing system.
+pub async fn routing_get_ipns(
+    base_url: &str,
+    name: &IpnsKey,
+) -> Result<IpnsRecord> {
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/api/v0/routing/get", base_url))
+        .query(&[("arg", format!("/ipns/{}", name))])
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Failed to get IPNS record of {}: {}", name, error_message(response).await));
+    }
+    parse_ipns_record(&response.bytes().await?)
+}
+
+/// Reads a protobuf-encoded IPNS record: from its signed CBOR `data`
+/// where it has one, and from the V1 fields otherwise.
+pub fn parse_ipns_record(bytes: &[u8]) -> Result<IpnsRecord> {
+    #[derive(Deserialize)]
+    struct Data {
+        #[serde(rename = "Value")]
+        value: serde_bytes::ByteBuf,
+        #[serde(rename = "Sequence")]
+        sequence: u64,
+    }
+
+    let malformed = || anyhow!("Malformed IPNS record");
+    let (mut value, mut sequence, mut data) = (None, None, None);
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let key = read_varint(&mut rest).ok_or_else(malformed)?;
+        match key & 7 {
+            0 => {
+                let varint = read_varint(&mut rest).ok_or_else(malformed)?;
+                if key >> 3 == 5 {
+                    sequence = Some(varint);
+                }
+            }
+            2 => {
+                let len = read_varint(&mut rest).ok_or_else(malformed)? as usize;
+                let field = rest.get(..len).ok_or_else(malformed)?;
+                rest = &rest[len..];
+                match key >> 3 {
+                    1 => value = Some(field.to_vec()),
+                    9 => data = Some(field),
+                    _ => {}
+                }
+            }
+            1 => rest = rest.get(8..).ok_or_else(malformed)?,
+            5 => rest = rest.get(4..).ok_or_else(malformed)?,
+            _ => return Err(malformed()),
+        }
+    }
+    if let Some(data) = data {
+        let data: Data = serde_ipld_dagcbor::from_slice(data).map_err(|e| anyhow!("Malformed IPNS record data: {}", e))?;
+        (value, sequence) = (Some(data.value.into_vec()), Some(data.sequence));
+    }
+    let (Some(value), Some(sequence)) = (value, sequence) else {
+        return Err(anyhow!("IPNS record lacks a value or sequence number"));
+    };
+    let value = String::from_utf8(value).map_err(|_| malformed())?;
+    Ok(IpnsRecord { value: IpfsPath::from_str(&value)?, sequence })
+}
+
+/// Reads a protobuf varint off the front of `data`.
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
 #[cfg(test)]
 mod apitests {
     use super::*;
@@ -2,7 +2,7 @@ use cid::{Cid, multibase::Base};
 use std::fmt;
 use reqwest::Client;
 use anyhow::{Result, Context,bail};
-use serde::{Deserialize,Deserializer};
+use serde::{Deserialize,Deserializer,Serialize,Serializer};
 use reqwest::StatusCode;
 
 use std::str::FromStr;
@@ -10,7 +10,7 @@ use std::str::FromStr;
 const LIBP2P_KEY_CODE: u64 = 0x72;
 
 /// Newtype for IPNS keys
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct IpnsKey(pub Cid);
 impl From<IpnsKey> for Cid {
     fn from(key: IpnsKey) -> Self {
@@ -57,6 +57,14 @@ impl<'de> Deserialize<'de> for IpnsKey {
     }
 }
 
+impl Serialize for IpnsKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
 
 impl std::fmt::Display for IpnsKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -164,6 +172,25 @@ pub async fn generate_ipns_key(base_url: &str, name: &str) -> Result<IpnsKey> {
     }
 }
 
+/// Removes the IPNS key with the given name.
+pub async fn remove_ipns_key(base_url: &str, name: &str) -> Result<()> {
+    let url = format!("{}/api/v0/key/rm", base_url);
+    let client = Client::new();
+
+    let response = client
+        .post(&url)
+        .query(&[("arg", name)])
+        .send()
+        .await
+        .context("Failed to send request to /key/rm")?;
+
+    if response.status() != StatusCode::OK {
+        let message = response.text().await.unwrap_or_default();
+        bail!("IPFS key removal failed: {}", message)
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod api_tests {
     use super::*;
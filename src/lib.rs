@@ -3,3 +3,92 @@ pub mod kubo_rpc {
     pub mod ipns;
     pub mod keys;
 }
+
+pub mod crdt {
+    pub mod access;
+    pub mod audit;
+    pub mod car;
+    pub mod causal;
+    pub mod checksum;
+    pub mod chunk;
+    pub mod clock;
+    pub mod fork;
+    pub mod gc;
+    pub mod hamt;
+    pub mod history;
+    pub mod identity;
+    pub mod journal;
+    pub mod materialize;
+    pub mod membership;
+    pub mod merge3;
+    pub mod op;
+    pub mod policy;
+    pub mod preview;
+    pub mod quota;
+    pub mod replica;
+    #[cfg(any(test, feature = "sim"))]
+    pub mod sim;
+    pub mod sign;
+    pub mod sniff;
+    pub mod state;
+    pub mod transaction;
+    pub mod undo;
+    pub mod upload;
+    pub mod validate;
+    pub mod wire;
+    pub mod xattr;
+}
+
+pub mod anti_entropy;
+pub mod at_rest;
+pub mod block_store;
+pub mod crypto;
+pub mod dirs;
+pub mod durability;
+pub mod export;
+pub mod manager;
+pub mod migrate;
+pub mod missing;
+pub mod outbox;
+pub mod pins;
+pub mod progress;
+pub mod read_only;
+pub mod resolution;
+pub mod stats;
+pub mod store;
+
+pub mod sync {
+    pub mod busy;
+    pub mod cache;
+    pub mod case;
+    pub mod checkout;
+    pub mod conflicts;
+    pub mod control;
+    pub mod dedup;
+    pub mod filter;
+    pub mod hooks;
+    pub mod ignore;
+    pub mod index;
+    pub mod mmap;
+    pub mod moves;
+    pub mod portable;
+    pub mod priority;
+    pub mod scan;
+    pub mod schedule;
+    pub mod space;
+    pub mod sparse;
+    pub mod status;
+    pub mod syncer;
+    pub mod trash;
+    pub mod unicode;
+    pub mod verify;
+    pub mod watch;
+    #[cfg(all(unix, feature = "xattr"))]
+    pub mod xattr;
+}
+
+#[cfg(test)]
+mod test_util;
+pub mod throttle;
+pub mod tiered_store;
+pub mod workspace;
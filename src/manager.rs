@@ -0,0 +1,258 @@
+//! Several synced directories in one store on one daemon. Each workspace
+//! gets a subdirectory of the store holding its settings, the secret seed
+//! of its signing keypair and a `LocalStore`, and its own IPNS key on the
+//! daemon, named after it. The pin ledger is shared, so a root two
+//! workspaces hold stays pinned until both let go of it.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::crdt::identity::ReplicaId;
+use crate::crdt::replica::Replica;
+use crate::crdt::sign::ReplicaKeypair;
+use crate::dirs::{data_dir, WORKSPACES_DIR};
+use crate::durability::Durability;
+use crate::kubo_rpc::ipfs::get_block;
+use crate::kubo_rpc::keys::{generate_ipns_key, remove_ipns_key};
+use crate::store::{LocalStore, PINS_FILE};
+use crate::sync::scan::Scanner;
+use crate::workspace::{SyncMode, Workspace};
+
+/// Names of the files in a workspace's directory.
+pub const CONFIG_FILE: &str = "workspace.json";
+pub const KEYPAIR_FILE: &str = "keypair";
+/// Prefix of the names of the IPNS keys of managed workspaces.
+pub const KEY_PREFIX: &str = "crdt-";
+
+/// Settings of one managed workspace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkspaceConfig {
+    pub name: String,
+    /// The directory synced.
+    pub root: PathBuf,
+    /// Name of its IPNS key on the daemon.
+    pub key_name: String,
+    /// That key, which the replica publishes under.
+    pub author: ReplicaId,
+    #[serde(default)]
+    pub mode: SyncMode,
+    #[serde(default)]
+    pub durability: Durability,
+    /// Where its local store is kept instead of the workspace's directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store_dir: Option<PathBuf>,
+    /// Bytes its scanner caches fetched blocks up to, if it caches them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_bytes: Option<u64>,
+    /// Where they are cached instead of the default cache directory of
+    /// `root`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_dir: Option<PathBuf>,
+}
+
+/// The workspaces in a store directory, synced through the IPFS daemon at
+/// a base URL.
+#[derive(Debug, Clone)]
+pub struct WorkspaceManager {
+    dir: PathBuf,
+    base_url: String,
+}
+
+impl WorkspaceManager {
+    /// Opens the store in `dir`, creating the directory if needed.
+    pub fn open(dir: impl Into<PathBuf>, base_url: &str) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        Ok(WorkspaceManager { dir, base_url: base_url.to_string() })
+    }
+
+    /// Opens the store under the platform's data directory; see `dirs`.
+    pub fn open_default(base_url: &str) -> Result<Self> {
+        WorkspaceManager::open(data_dir()?.join(WORKSPACES_DIR), base_url)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Where the pin ledger of every workspace is kept.
+    pub fn pins_path(&self) -> PathBuf {
+        self.dir.join(PINS_FILE)
+    }
+
+    /// The settings of every workspace, by name.
+    pub fn list(&self) -> Result<Vec<WorkspaceConfig>> {
+        let mut configs = Vec::new();
+        for entry in std::fs::read_dir(&self.dir).with_context(|| format!("Failed to list {}", self.dir.display()))? {
+            let entry = entry?;
+            if entry.path().join(CONFIG_FILE).exists() {
+                configs.push(self.config(&entry.file_name().to_string_lossy())?);
+            }
+        }
+        configs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(configs)
+    }
+
+    pub fn config(&self, name: &str) -> Result<WorkspaceConfig> {
+        let path = self.workspace_dir(name)?.join(CONFIG_FILE);
+        let s = std::fs::read_to_string(&path).with_context(|| format!("No workspace named {}", name))?;
+        serde_json::from_str(&s).with_context(|| format!("Invalid workspace config {}", path.display()))
+    }
+
+    /// Replaces the settings of the workspace `config` names.
+    pub fn set_config(&self, config: &WorkspaceConfig) -> Result<()> {
+        let path = self.workspace_dir(&config.name)?.join(CONFIG_FILE);
+        let json = serde_json::to_string_pretty(config)?;
+        std::fs::write(&path, json).with_context(|| format!("Failed to write workspace config {}", path.display()))
+    }
+
+    /// Creates a workspace syncing `root` with a fresh IPNS key and
+    /// signing keypair, and opens it.
+    pub async fn create(&self, name: &str, root: impl Into<PathBuf>) -> Result<Workspace> {
+        if self.workspace_dir(name)?.exists() {
+            bail!("Workspace {} already exists", name);
+        }
+        let key_name = format!("{}{}", KEY_PREFIX, name);
+        let key = generate_ipns_key(&self.base_url, &key_name).await?;
+        self.register(name, root, &key_name, key.into())?;
+        self.open_workspace(name).await
+    }
+
+    /// Adds a workspace syncing `root` that publishes under `author`, the
+    /// existing IPNS key `key_name` on the daemon.
+    pub fn register(&self, name: &str, root: impl Into<PathBuf>, key_name: &str, author: ReplicaId) -> Result<WorkspaceConfig> {
+        let dir = self.workspace_dir(name)?;
+        if dir.exists() {
+            bail!("Workspace {} already exists", name);
+        }
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        ReplicaKeypair::generate()?.save(&dir.join(KEYPAIR_FILE))?;
+        let config = WorkspaceConfig {
+            name: name.to_string(),
+            root: root.into(),
+            key_name: key_name.to_string(),
+            author,
+            mode: SyncMode::default(),
+            durability: Durability::default(),
+            store_dir: None,
+            cache_bytes: None,
+            cache_dir: None,
+        };
+        self.set_config(&config)?;
+        Ok(config)
+    }
+
+    /// The local store of the workspace `name`, in its directory unless
+    /// its config puts it elsewhere.
+    pub fn store(&self, name: &str) -> Result<LocalStore> {
+        match self.config(name)?.store_dir {
+            Some(dir) => LocalStore::open(dir),
+            None => LocalStore::open(self.workspace_dir(name)?),
+        }
+    }
+
+    /// Opens the workspace `name`, restoring its replica from its local
+    /// store; nodes the journal needs that the store lacks are fetched
+    /// from the daemon. What it owed others is in its outbox; see
+    /// `Workspace::deliver_outbox`, and the heads it last merged in its
+    /// resolution cache; see `Workspace::merge_cached`.
+    pub async fn open_workspace(&self, name: &str) -> Result<Workspace> {
+        let config = self.config(name)?;
+        let keypair = ReplicaKeypair::load_or_generate(&self.workspace_dir(name)?.join(KEYPAIR_FILE))?;
+        let mut replica = Replica::new(config.author).with_keypair(keypair).with_durability(config.durability);
+        let store = self.store(name)?;
+        store.restore(&mut replica, async |cid| get_block(&self.base_url, &cid).await).await?;
+        Ok(Workspace::new(&self.base_url, replica)
+            .with_mode(config.mode)
+            .with_pin_ledger(self.pins_path())
+            .with_outbox(store.outbox_path())
+            .with_resolution_cache(store.resolution_cache_path()))
+    }
+
+    /// Saves the replica of `workspace`, opened as `name`, to its store.
+    pub fn save(&self, name: &str, workspace: &Workspace) -> Result<()> {
+        self.store(name)?.save(workspace.replica())
+    }
+
+    /// A scanner of the directory the workspace `name` syncs, keeping its
+    /// index in the workspace's store and a block cache if its config
+    /// asks for one.
+    pub fn scanner(&self, name: &str) -> Result<Scanner> {
+        let config = self.config(name)?;
+        let scanner = Scanner::new(config.root).with_durability(config.durability).with_index(self.store(name)?.index_path())?;
+        match (config.cache_bytes, config.cache_dir) {
+            (Some(max_bytes), Some(dir)) => scanner.with_block_cache_in(dir, max_bytes),
+            (Some(max_bytes), None) => scanner.with_block_cache(max_bytes),
+            (None, _) => Ok(scanner),
+        }
+    }
+
+    /// Removes the workspace `name`: releases its pins, deletes its IPNS
+    /// key and its directory in the store. The synced directory is left.
+    pub async fn remove(&self, name: &str) -> Result<()> {
+        let config = self.config(name)?;
+        let workspace = Workspace::new(&self.base_url, Replica::new(config.author)).with_pin_ledger(self.pins_path());
+        workspace.release_pins().await?;
+        remove_ipns_key(&self.base_url, &config.key_name).await?;
+        let dir = self.workspace_dir(name)?;
+        std::fs::remove_dir_all(&dir).with_context(|| format!("Failed to remove {}", dir.display()))
+    }
+
+    /// The directory of the workspace `name`, which must be a plain name.
+    fn workspace_dir(&self, name: &str) -> Result<PathBuf> {
+        let valid = !name.is_empty() && !name.starts_with('.') && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        if !valid {
+            bail!("Invalid workspace name {:?}", name);
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+#[cfg(test)]
+mod manager_test {
+    use super::*;
+    use crate::store::STATE_FILE;
+    use crate::test_util::{alice, bob, entry};
+
+    #[tokio::test]
+    async fn test_workspaces_keep_their_own_state() {
+        let dir = std::env::temp_dir().join(format!("crdt-manager-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let manager = WorkspaceManager::open(&dir, "http://127.0.0.1:5001").unwrap();
+        let alice = alice();
+        let bob = bob();
+        manager.register("photos", dir.join("photos-tree"), "crdt-photos", alice.clone()).unwrap();
+        manager.register("notes", dir.join("notes-tree"), "crdt-notes", bob).unwrap();
+        assert!(manager.register("notes", dir.join("other"), "crdt-other", alice.clone()).is_err());
+        assert!(manager.config("../notes").is_err());
+        let names: Vec<String> = manager.list().unwrap().into_iter().map(|config| config.name).collect();
+        assert_eq!(names, ["notes", "photos"]);
+
+        let mut photos = manager.open_workspace("photos").await.unwrap();
+        let data = b"jpeg";
+        let entry = entry(data);
+        photos.replica_mut().put("a.jpg", entry).unwrap();
+        manager.save("photos", &photos).unwrap();
+
+        let mut config = manager.config("photos").unwrap();
+        config.store_dir = Some(dir.join("photos-store"));
+        config.cache_bytes = Some(1 << 20);
+        config.cache_dir = Some(dir.join("photos-cache"));
+        manager.set_config(&config).unwrap();
+        manager.save("photos", &photos).unwrap();
+        assert!(dir.join("photos-store").join(STATE_FILE).exists());
+        assert_eq!(manager.scanner("photos").unwrap().block_cache().unwrap().dir(), dir.join("photos-cache"));
+
+        let reopened = manager.open_workspace("photos").await.unwrap();
+        assert_eq!(reopened.replica().author(), &alice);
+        assert_eq!(reopened.replica().state(), photos.replica().state());
+        assert_eq!(reopened.replica().keypair().public_key(), photos.replica().keypair().public_key());
+        assert!(manager.open_workspace("notes").await.unwrap().replica().state().get("a.jpg").is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+//! Schema versions of the local store, so a store written by an older
+//! version of this crate is upgraded in place when opened instead of
+//! being deleted and cloned again. The version is stamped in a file of its
+//! own; a store from before stamping is version 0. Each migration takes
+//! the store one version up and is stamped as soon as it is done, so an
+//! upgrade cut short picks up where it stopped. A store newer than this
+//! crate understands is refused rather than misread.
+
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+use crate::durability::{write_durably, Durability};
+use crate::store::{INDEX_FILE, JOURNAL_FILE, PINS_FILE, STATE_FILE};
+
+/// Schema version of the stores this version of the crate writes.
+pub const STORE_VERSION: u32 = 1;
+/// Name of the file in a store's directory holding its version.
+pub const VERSION_FILE: &str = "version";
+
+/// A step from one schema version to the next.
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    run: fn(&Path) -> Result<()>,
+}
+
+/// Every migration, in order; the one at index `n` goes from version `n`.
+const MIGRATIONS: [Migration; 1] = [Migration {
+    from: 0,
+    description: "stamp the schema version",
+    // the layout didn't change
+    run: |_| Ok(()),
+}];
+
+/// The schema version of the store in `dir`. A directory without a store
+/// yet is taken to be the current version.
+pub fn store_version(dir: &Path) -> Result<u32> {
+    let path = dir.join(VERSION_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(s) => s.trim().parse().with_context(|| format!("Invalid store version in {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let written = [STATE_FILE, JOURNAL_FILE, INDEX_FILE, PINS_FILE].iter().any(|file| dir.join(file).exists());
+            Ok(if written { 0 } else { STORE_VERSION })
+        }
+        Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Brings the store in `dir` up to `STORE_VERSION` and stamps it. Returns
+/// the descriptions of the migrations run, none if it was current.
+pub fn migrate(dir: &Path) -> Result<Vec<&'static str>> {
+    let mut version = store_version(dir)?;
+    if version > STORE_VERSION {
+        bail!(
+            "Store {} has schema version {}, newer than version {} this crate reads; upgrade crdt-dir-ipfs to open it",
+            dir.display(),
+            version,
+            STORE_VERSION
+        );
+    }
+    let mut ran = Vec::new();
+    for migration in &MIGRATIONS[version as usize..] {
+        (migration.run)(dir).with_context(|| format!("Failed to migrate store {} from version {}", dir.display(), migration.from))?;
+        version = migration.from + 1;
+        stamp(dir, version)?;
+        ran.push(migration.description);
+    }
+    if !dir.join(VERSION_FILE).exists() {
+        stamp(dir, version)?;
+    }
+    Ok(ran)
+}
+
+fn stamp(dir: &Path, version: u32) -> Result<()> {
+    let path = dir.join(VERSION_FILE);
+    write_durably(&path, format!("{}\n", version).as_bytes(), Durability::Always)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod migrate_test {
+    use super::*;
+    use crate::store::LocalStore;
+
+    #[test]
+    fn test_stores_are_stamped_and_newer_ones_refused() {
+        let dir = std::env::temp_dir().join(format!("crdt-migrate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        LocalStore::open(dir.join("fresh")).unwrap();
+        assert_eq!(store_version(&dir.join("fresh")).unwrap(), STORE_VERSION);
+
+        // written before stores were stamped
+        let old = dir.join("old");
+        std::fs::create_dir_all(&old).unwrap();
+        std::fs::write(old.join(INDEX_FILE), "{}").unwrap();
+        assert_eq!(store_version(&old).unwrap(), 0);
+        assert_eq!(migrate(&old).unwrap(), ["stamp the schema version"]);
+        assert_eq!(store_version(&old).unwrap(), STORE_VERSION);
+        assert!(migrate(&old).unwrap().is_empty());
+
+        std::fs::write(old.join(VERSION_FILE), format!("{}\n", STORE_VERSION + 1)).unwrap();
+        let err = LocalStore::open(&old).unwrap_err();
+        assert!(err.to_string().contains("newer than version"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+//! Negative caching of blocks the network doesn't have. A block that
+//! can't be fetched twice in a row isn't asked for again until a TTL runs
+//! out, so a merge that needs it fails straight away instead of waiting
+//! out the daemon's timeout every round, and the rest of the round goes
+//! on. The blocks given up on are listed in `SyncReport::unavailable`.
+//! Only failures that say the network lacks the block count, the daemon
+//! not finding it or timing out; a daemon that can't be reached says
+//! nothing about the block. Fetching one successfully forgets its
+//! failures, and so does the TTL running out. Clones share the cache.
+
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::kubo_rpc::ipfs::IpfsCid;
+use crate::workspace::Workspace;
+
+/// How long a missing block isn't asked for by default.
+pub const DEFAULT_MISSING_TTL: Duration = Duration::from_secs(10 * 60);
+/// Failed fetches in a row after which a block counts as missing.
+pub const MISSING_AFTER: u32 = 2;
+
+#[derive(Debug, Clone, Copy)]
+struct Misses {
+    failures: u32,
+    /// Until when the failures are remembered; the block isn't asked for
+    /// until then once it counts as missing.
+    until: Instant,
+}
+
+impl Misses {
+    fn is_missing(&self, now: Instant) -> bool {
+        self.failures >= MISSING_AFTER && now < self.until
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MissingBlocks {
+    ttl: Duration,
+    misses: Arc<Mutex<HashMap<IpfsCid, Misses>>>,
+}
+
+impl Default for MissingBlocks {
+    fn default() -> Self {
+        MissingBlocks::new(DEFAULT_MISSING_TTL)
+    }
+}
+
+impl MissingBlocks {
+    pub fn new(ttl: Duration) -> Self {
+        MissingBlocks { ttl, misses: Arc::default() }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Whether `cid` isn't asked for now.
+    pub fn is_missing(&self, cid: &IpfsCid) -> bool {
+        let misses = self.misses.lock().unwrap();
+        misses.get(cid).is_some_and(|misses| misses.is_missing(Instant::now()))
+    }
+
+    /// The blocks not asked for now.
+    pub fn unavailable(&self) -> Vec<IpfsCid> {
+        let now = Instant::now();
+        let mut misses = self.misses.lock().unwrap();
+        misses.retain(|_, misses| now < misses.until);
+        let mut unavailable: Vec<IpfsCid> =
+            misses.iter().filter(|(_, misses)| misses.is_missing(now)).map(|(cid, _)| cid.clone()).collect();
+        unavailable.sort();
+        unavailable
+    }
+
+    /// Forgets every failure, so every block is asked for again.
+    pub fn clear(&self) {
+        self.misses.lock().unwrap().clear();
+    }
+
+    /// The block `cid` from `fetch`, unless it is missing.
+    pub async fn fetch<F>(&self, cid: IpfsCid, fetch: F) -> Result<Vec<u8>>
+    where
+        F: AsyncFnOnce(IpfsCid) -> Result<Vec<u8>>,
+    {
+        if self.is_missing(&cid) {
+            bail!("Block {} is unavailable; not asking for it again for up to {:?}", cid, self.ttl);
+        }
+        match fetch(cid.clone()).await {
+            Ok(bytes) => {
+                self.misses.lock().unwrap().remove(&cid);
+                Ok(bytes)
+            }
+            Err(err) if is_miss(&err) => {
+                let now = Instant::now();
+                let mut misses = self.misses.lock().unwrap();
+                misses.retain(|_, misses| now < misses.until);
+                let entry = misses.entry(cid).or_insert(Misses { failures: 0, until: now });
+                entry.failures += 1;
+                entry.until = now + self.ttl;
+                Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Whether `err` says the network lacks a block: the daemon didn't find
+/// it, or the request timed out while the daemon looked.
+fn is_miss(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| match cause.downcast_ref::<reqwest::Error>() {
+        Some(err) => err.is_timeout(),
+        None => {
+            let message = cause.to_string();
+            ["not found", "could not find", "deadline exceeded"].iter().any(|miss| message.contains(miss))
+        }
+    })
+}
+
+impl Workspace {
+    /// Doesn't ask for a block that couldn't be fetched twice in a row
+    /// again for `ttl`; see `MissingBlocks`. Ten minutes by default.
+    pub fn with_missing_ttl(mut self, ttl: Duration) -> Self {
+        self.missing = MissingBlocks::new(ttl);
+        self
+    }
+
+    pub fn missing_blocks(&self) -> &MissingBlocks {
+        &self.missing
+    }
+}
+
+#[cfg(test)]
+mod missing_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::{get_block, RAW_CODE};
+    use anyhow::anyhow;
+    use std::cell::Cell;
+
+    #[tokio::test]
+    async fn test_block_given_up_on_after_repeated_failures() {
+        let cid = IpfsCid::compute(RAW_CODE, b"gone");
+        let asked = Cell::new(0);
+        let unreachable = async |_: IpfsCid| {
+            asked.set(asked.get() + 1);
+            Err(anyhow!("not found"))
+        };
+        let missing = MissingBlocks::new(Duration::from_secs(3600));
+        for _ in 0..3 {
+            assert!(missing.fetch(cid.clone(), unreachable).await.is_err());
+        }
+        assert_eq!(asked.get(), MISSING_AFTER);
+        assert!(missing.is_missing(&cid) && missing.unavailable().len() == 1);
+
+        // once the TTL is up it is asked for again, and found
+        let missing = MissingBlocks::new(Duration::ZERO);
+        for _ in 0..3 {
+            assert!(missing.fetch(cid.clone(), unreachable).await.is_err());
+        }
+        assert_eq!(asked.get(), MISSING_AFTER + 3);
+        assert_eq!(missing.fetch(cid.clone(), async |_| Ok(b"gone".to_vec())).await.unwrap(), b"gone");
+        assert!(missing.misses.lock().unwrap().is_empty());
+
+        // a daemon that is down doesn't make blocks missing
+        let missing = MissingBlocks::new(Duration::from_secs(3600));
+        for _ in 0..3 {
+            let err = missing.fetch(cid.clone(), async |cid| get_block("http://127.0.0.1:1", &cid).await).await.unwrap_err();
+            assert!(!is_miss(&err), "{:#}", err);
+        }
+        assert!(!missing.is_missing(&cid));
+        assert!(missing.misses.lock().unwrap().is_empty());
+    }
+}
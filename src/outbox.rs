@@ -0,0 +1,228 @@
+//! A durable outbox of what this replica still owes others: a publish of
+//! its heads that failed, and deltas composed for peers that weren't
+//! delivered. Unlike the journal, which keeps the blocks themselves, the
+//! outbox keeps the messages, so those written while offline go out after
+//! the next start instead of waiting for peers to ask. Each message is
+//! recorded before it is sent and dropped once it was, so one may be sent
+//! twice but never lost; merging a delta twice is harmless.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::crdt::clock::VersionVector;
+use crate::crdt::identity::ReplicaId;
+use crate::crdt::wire::DeltaBundle;
+use crate::durability::{write_durably, Durability};
+use crate::kubo_rpc::ipfs::IpfsCid;
+use crate::workspace::Workspace;
+
+pub const OUTBOX_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Outbox {
+    pub version: u32,
+    /// The heads a publish failed for, if one is still owed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish: Option<Vec<IpfsCid>>,
+    /// Deltas waiting for each peer, oldest first.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deltas: BTreeMap<ReplicaId, Vec<DeltaBundle>>,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Outbox::new()
+    }
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Outbox { version: OUTBOX_VERSION, publish: None, deltas: BTreeMap::new() }
+    }
+
+    /// Messages waiting, the owed publish included.
+    pub fn len(&self) -> usize {
+        self.publish.iter().count() + self.deltas.values().map(Vec::len).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Loads the outbox at `path`, or an empty one if the file doesn't
+    /// exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Outbox::new());
+        }
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read outbox {}", path.display()))?;
+        let outbox: Outbox = serde_ipld_dagcbor::from_slice(&bytes).context("Invalid outbox")?;
+        if outbox.version == 0 || outbox.version > OUTBOX_VERSION {
+            bail!("Unsupported outbox version {}", outbox.version);
+        }
+        Ok(outbox)
+    }
+
+    /// Saves the outbox to `path`, always synced, since a lost message is
+    /// one nothing will send.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let bytes = serde_ipld_dagcbor::to_vec(self).context("Failed to encode outbox")?;
+        write_durably(path, &bytes, Durability::Always).with_context(|| format!("Failed to write outbox {}", path.display()))
+    }
+}
+
+/// Outcome of `Workspace::deliver_outbox`.
+#[derive(Debug, Default)]
+pub struct DeliveryReport {
+    /// The announcement an owed publish wrote.
+    pub published: Option<IpfsCid>,
+    /// Why an owed publish failed again.
+    pub publish_failed: Option<anyhow::Error>,
+    /// Deltas delivered.
+    pub delivered: usize,
+    /// Peers a delivery failed for, whose later deltas wait too.
+    pub failed: Vec<(ReplicaId, anyhow::Error)>,
+}
+
+impl Workspace {
+    /// Keeps what this workspace owes others in the outbox at `path`; see
+    /// `LocalStore::outbox_path`. A publish that fails is then owed, and
+    /// `send_delta` queues what it sends.
+    pub fn with_outbox(mut self, path: impl Into<PathBuf>) -> Self {
+        self.outbox = Some(path.into());
+        self
+    }
+
+    pub fn outbox(&self) -> Option<&Path> {
+        self.outbox.as_deref()
+    }
+
+    /// Sends `peer`, which has seen `since`, what it is missing, through
+    /// `send`. With an outbox the delta is queued first and the peer's
+    /// queue delivered in order; returns whether all of it went out.
+    pub async fn send_delta<F>(&self, peer: &ReplicaId, since: &VersionVector, mut send: F) -> Result<bool>
+    where
+        F: AsyncFnMut(&ReplicaId, &DeltaBundle) -> Result<()>,
+    {
+        let bundle = self.replica().delta_since(since);
+        let Some(path) = &self.outbox else {
+            send(peer, &bundle).await?;
+            return Ok(true);
+        };
+        let mut outbox = Outbox::load(path)?;
+        outbox.deltas.entry(peer.clone()).or_default().push(bundle);
+        outbox.save(path)?;
+        let (_, failed) = deliver_to(path, &mut outbox, peer, &mut send).await?;
+        Ok(failed.is_none())
+    }
+
+    /// Sends everything the outbox holds, as after a start: publishes if
+    /// a publish is owed and delivers each peer's deltas in order through
+    /// `send`, stopping at a peer's first failure.
+    pub async fn deliver_outbox<F>(&mut self, mut send: F) -> Result<DeliveryReport>
+    where
+        F: AsyncFnMut(&ReplicaId, &DeltaBundle) -> Result<()>,
+    {
+        let mut report = DeliveryReport::default();
+        let Some(path) = self.outbox.clone() else {
+            return Ok(report);
+        };
+        let mut outbox = Outbox::load(&path)?;
+        if outbox.publish.is_some() {
+            match self.publish().await {
+                Ok(announcement) => report.published = Some(announcement),
+                Err(err) => report.publish_failed = Some(err),
+            }
+            outbox = Outbox::load(&path)?;
+        }
+        for peer in outbox.deltas.keys().cloned().collect::<Vec<_>>() {
+            let (delivered, failed) = deliver_to(&path, &mut outbox, &peer, &mut send).await?;
+            report.delivered += delivered;
+            report.failed.extend(failed.map(|err| (peer, err)));
+        }
+        Ok(report)
+    }
+
+    /// Records in the outbox whether a publish of the current heads is
+    /// owed.
+    pub(crate) fn owe_publish(&self, owed: bool) -> Result<()> {
+        let Some(path) = &self.outbox else {
+            return Ok(());
+        };
+        let mut outbox = Outbox::load(path)?;
+        let publish = owed.then(|| self.replica().heads().to_vec());
+        if outbox.publish != publish {
+            outbox.publish = publish;
+            outbox.save(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends the deltas queued for `peer` in order, dropping each from the
+/// outbox at `path` once sent. Returns how many were, and why the next
+/// one wasn't.
+async fn deliver_to<F>(path: &Path, outbox: &mut Outbox, peer: &ReplicaId, send: &mut F) -> Result<(usize, Option<anyhow::Error>)>
+where
+    F: AsyncFnMut(&ReplicaId, &DeltaBundle) -> Result<()>,
+{
+    let mut delivered = 0;
+    while let Some(bundle) = outbox.deltas.get(peer).and_then(|queue| queue.first()) {
+        if let Err(err) = send(peer, bundle).await {
+            return Ok((delivered, Some(err)));
+        }
+        let queue = outbox.deltas.get_mut(peer).expect("the queue was just read");
+        queue.remove(0);
+        if queue.is_empty() {
+            outbox.deltas.remove(peer);
+        }
+        outbox.save(path)?;
+        delivered += 1;
+    }
+    Ok((delivered, None))
+}
+
+#[cfg(test)]
+mod outbox_test {
+    use super::*;
+    use crate::crdt::replica::Replica;
+    use crate::test_util::{alice, bob, entry};
+    use anyhow::anyhow;
+
+    #[tokio::test]
+    async fn test_messages_written_offline_go_out_after_restart() {
+        let path = std::env::temp_dir().join(format!("crdt-outbox-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let alice = alice();
+        let bob = bob();
+        // nothing listens there
+        let offline = "http://127.0.0.1:9";
+        let mut workspace = Workspace::new(offline, Replica::new(alice.clone())).with_outbox(&path);
+        let entry = entry(b"a");
+        workspace.replica_mut().put("a", entry).unwrap();
+        assert!(workspace.publish().await.is_err());
+        let unreachable = async |_: &ReplicaId, _: &DeltaBundle| Err(anyhow!("offline"));
+        assert!(!workspace.send_delta(&bob, &VersionVector::new(), unreachable).await.unwrap());
+        let outbox = Outbox::load(&path).unwrap();
+        assert_eq!(outbox.publish.as_deref(), Some(workspace.replica().heads()));
+        assert_eq!(outbox.len(), 2);
+
+        let mut restarted = Workspace::new(offline, workspace.into_replica()).with_outbox(&path);
+        let mut bob_replica = Replica::new(bob.clone());
+        let report = restarted
+            .deliver_outbox(async |peer: &ReplicaId, bundle: &DeltaBundle| {
+                assert_eq!(peer, &bob);
+                bob_replica.apply_delta(bundle).map(|_| ())
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.delivered, 1);
+        assert!(report.publish_failed.is_some());
+        assert_eq!(bob_replica.state(), restarted.replica().state());
+        let outbox = Outbox::load(&path).unwrap();
+        assert!(outbox.deltas.is_empty() && outbox.publish.is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
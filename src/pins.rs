@@ -0,0 +1,281 @@
+//! A ledger of the pins this crate made on the daemon: for each root, who
+//! holds it, for what and how many times. The daemon only knows a root is
+//! pinned, not why or by whom, so unpinning goes through the ledger: a root
+//! is unpinned once nobody holds it any longer, and never if this crate
+//! didn't pin it. A root is recorded before it is pinned and forgotten
+//! after it is unpinned, so the ledger never misses a pin, and cleaning up
+//! removes every pin the crate ever added.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::durability::{write_durably, Durability};
+use crate::kubo_rpc::ipfs::{pin_add, pin_rm, IpfsCid};
+use crate::workspace::Workspace;
+
+pub const PIN_LEDGER_VERSION: u32 = 1;
+
+/// Why a root is pinned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PinReason {
+    /// The head announcement a workspace last published, and the history
+    /// it links to.
+    Announcement,
+    /// Pinned on request; see `Workspace::pin`.
+    Manual,
+}
+
+/// One holder of a pinned root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pin {
+    pub reason: PinReason,
+    /// The author key of the workspace's replica.
+    pub workspace: String,
+    /// How many times it was pinned for this and not released.
+    pub refs: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinLedger {
+    pub version: u32,
+    pub pins: BTreeMap<IpfsCid, Vec<Pin>>,
+}
+
+impl Default for PinLedger {
+    fn default() -> Self {
+        PinLedger::new()
+    }
+}
+
+impl PinLedger {
+    pub fn new() -> Self {
+        PinLedger { version: PIN_LEDGER_VERSION, pins: BTreeMap::new() }
+    }
+
+    /// Who holds `root`, if it is pinned.
+    pub fn get(&self, root: &IpfsCid) -> &[Pin] {
+        self.pins.get(root).map_or(&[], Vec::as_slice)
+    }
+
+    /// The roots `workspace` holds for `reason`.
+    pub fn roots_of(&self, workspace: &str, reason: PinReason) -> Vec<IpfsCid> {
+        self.pins
+            .iter()
+            .filter(|(_, pins)| pins.iter().any(|pin| pin.workspace == workspace && pin.reason == reason))
+            .map(|(root, _)| root.clone())
+            .collect()
+    }
+
+    /// Records that `workspace` holds `root` for `reason` once more.
+    /// Returns whether nobody held it before, so it still needs pinning.
+    pub fn hold(&mut self, root: &IpfsCid, reason: PinReason, workspace: &str) -> bool {
+        let pins = self.pins.entry(root.clone()).or_default();
+        let unpinned = pins.is_empty();
+        match pins.iter_mut().find(|pin| pin.workspace == workspace && pin.reason == reason) {
+            Some(pin) => pin.refs += 1,
+            None => pins.push(Pin { reason, workspace: workspace.to_string(), refs: 1 }),
+        }
+        unpinned
+    }
+
+    /// Drops one hold of `workspace` on `root` for `reason`, or every one
+    /// it has for any reason if `reason` is None. Returns whether anybody
+    /// still holds the root, so it stays pinned.
+    pub fn release(&mut self, root: &IpfsCid, reason: Option<PinReason>, workspace: &str) -> bool {
+        let Some(pins) = self.pins.get_mut(root) else {
+            return false;
+        };
+        for pin in pins.iter_mut().filter(|pin| pin.workspace == workspace) {
+            match reason {
+                Some(reason) if pin.reason == reason => pin.refs -= 1,
+                Some(_) => {}
+                None => pin.refs = 0,
+            }
+        }
+        pins.retain(|pin| pin.refs > 0);
+        true
+    }
+
+    /// Forgets `root` once nobody holds it. Call after unpinning it.
+    pub(crate) fn forget_released(&mut self, root: &IpfsCid) {
+        if self.pins.get(root).is_some_and(Vec::is_empty) {
+            self.pins.remove(root);
+        }
+    }
+
+    /// Loads the ledger at `path`, or an empty one if the file doesn't
+    /// exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(PinLedger::new());
+        }
+        let s = std::fs::read_to_string(path).with_context(|| format!("Failed to read pin ledger {}", path.display()))?;
+        let ledger: PinLedger = serde_json::from_str(&s).context("Invalid pin ledger")?;
+        if ledger.version == 0 || ledger.version > PIN_LEDGER_VERSION {
+            bail!("Unsupported pin ledger version {}", ledger.version);
+        }
+        Ok(ledger)
+    }
+
+    /// Saves the ledger to `path`, always synced, since a lost record is a
+    /// pin nothing will remove.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string(self)?;
+        write_durably(path, json.as_bytes(), Durability::Always)
+            .with_context(|| format!("Failed to write pin ledger {}", path.display()))
+    }
+
+    /// Unpins every root in the ledger at `path` on the daemon at
+    /// `base_url`, whoever holds it, and empties the ledger, as when
+    /// uninstalling. Returns the number of roots that were still pinned.
+    pub async fn unpin_all(base_url: &str, path: &Path) -> Result<usize> {
+        let mut ledger = PinLedger::load(path)?;
+        let mut unpinned = 0;
+        for root in ledger.pins.keys().cloned().collect::<Vec<_>>() {
+            if pin_rm(base_url, &root).await? {
+                unpinned += 1;
+            }
+            ledger.pins.remove(&root);
+            ledger.save(path)?;
+        }
+        Ok(unpinned)
+    }
+}
+
+impl Workspace {
+    /// Records pins this workspace makes in the ledger at `path`, which
+    /// several workspaces on one daemon may share; see `LocalStore::pins_path`.
+    /// Publishing then pins the announcement and unpins the one before,
+    /// and garbage collection only unpins what the ledger says this
+    /// workspace alone holds.
+    pub fn with_pin_ledger(mut self, path: impl Into<PathBuf>) -> Self {
+        self.pin_ledger = Some(path.into());
+        self
+    }
+
+    pub fn pin_ledger(&self) -> Option<&Path> {
+        self.pin_ledger.as_deref()
+    }
+
+    /// Pins `root` on the daemon for this workspace, recording it in the
+    /// ledger, which must be set.
+    pub async fn pin(&self, root: &IpfsCid) -> Result<()> {
+        self.hold_pin(root, PinReason::Manual).await
+    }
+
+    /// Releases a manual pin on `root`, unpinning it on the daemon unless
+    /// someone else still holds it. Returns whether it was unpinned.
+    pub async fn unpin(&self, root: &IpfsCid) -> Result<bool> {
+        self.release_pin(root, Some(PinReason::Manual)).await
+    }
+
+    /// Releases every pin this workspace holds, as when leaving it.
+    /// Returns the number of roots unpinned on the daemon.
+    pub async fn release_pins(&self) -> Result<usize> {
+        let Some(path) = &self.pin_ledger else {
+            return Ok(0);
+        };
+        let workspace = self.replica().author().to_string();
+        let ledger = PinLedger::load(path)?;
+        let mut unpinned = 0;
+        for root in ledger.pins.iter().filter(|(_, pins)| pins.iter().any(|pin| pin.workspace == workspace)).map(|(root, _)| root) {
+            if self.release_pin(root, None).await? {
+                unpinned += 1;
+            }
+        }
+        Ok(unpinned)
+    }
+
+    /// Holds `root` for `reason`, pinning it on the daemon if nobody did.
+    pub(crate) async fn hold_pin(&self, root: &IpfsCid, reason: PinReason) -> Result<()> {
+        let Some(path) = &self.pin_ledger else {
+            bail!("Workspace has no pin ledger");
+        };
+        let mut ledger = PinLedger::load(path)?;
+        let workspace = self.replica().author().to_string();
+        if !ledger.hold(root, reason, &workspace) {
+            return ledger.save(path);
+        }
+        ledger.save(path)?;
+        if let Err(err) = pin_add(self.base_url(), root).await {
+            ledger.release(root, Some(reason), &workspace);
+            ledger.forget_released(root);
+            ledger.save(path)?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Releases a hold on `root`, see `PinLedger::release`, unpinning it on
+    /// the daemon once nobody holds it. Returns whether it was unpinned;
+    /// a root the ledger doesn't have is never unpinned.
+    pub(crate) async fn release_pin(&self, root: &IpfsCid, reason: Option<PinReason>) -> Result<bool> {
+        let Some(path) = &self.pin_ledger else {
+            return Ok(false);
+        };
+        let mut ledger = PinLedger::load(path)?;
+        if !ledger.release(root, reason, &self.replica().author().to_string()) || !ledger.get(root).is_empty() {
+            ledger.save(path)?;
+            return Ok(false);
+        }
+        let unpinned = pin_rm(self.base_url(), root).await?;
+        ledger.forget_released(root);
+        ledger.save(path)?;
+        Ok(unpinned)
+    }
+
+    /// Pins `announcement`, just published, and unpins the ones this
+    /// workspace published before.
+    pub(crate) async fn pin_announcement(&self, announcement: &IpfsCid) -> Result<()> {
+        let Some(path) = &self.pin_ledger else {
+            return Ok(());
+        };
+        let previous = PinLedger::load(path)?.roots_of(&self.replica().author().to_string(), PinReason::Announcement);
+        if !previous.contains(announcement) {
+            self.hold_pin(announcement, PinReason::Announcement).await?;
+        }
+        for root in previous.iter().filter(|root| *root != announcement) {
+            self.release_pin(root, Some(PinReason::Announcement)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod pins_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+
+    #[test]
+    fn test_ledger_counts_holders() {
+        let path = std::env::temp_dir().join(format!("crdt-pins-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let root = IpfsCid::compute(RAW_CODE, b"root");
+        let other = IpfsCid::compute(RAW_CODE, b"other");
+
+        let mut ledger = PinLedger::load(&path).unwrap();
+        assert!(ledger.hold(&root, PinReason::Announcement, "alice"));
+        assert!(!ledger.hold(&root, PinReason::Manual, "alice"));
+        assert!(!ledger.hold(&root, PinReason::Manual, "alice"));
+        assert!(!ledger.hold(&root, PinReason::Manual, "bob"));
+        ledger.save(&path).unwrap();
+        let mut ledger = PinLedger::load(&path).unwrap();
+        assert_eq!(ledger.get(&root).len(), 3);
+        assert_eq!(ledger.roots_of("bob", PinReason::Manual), vec![root.clone()]);
+
+        // never pinned here, so never unpinned
+        assert!(!ledger.release(&other, None, "alice"));
+        assert!(ledger.release(&root, Some(PinReason::Manual), "alice"));
+        assert_eq!(ledger.get(&root).len(), 3);
+        ledger.release(&root, None, "alice");
+        assert_eq!(ledger.get(&root), [Pin { reason: PinReason::Manual, workspace: "bob".to_string(), refs: 1 }]);
+        ledger.release(&root, Some(PinReason::Manual), "bob");
+        assert!(ledger.get(&root).is_empty());
+        ledger.forget_released(&root);
+        assert!(ledger.pins.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
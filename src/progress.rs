@@ -0,0 +1,120 @@
+//! Progress events from scans, checkouts, merges and publishes, sent on a
+//! broadcast channel so a GUI or CLI can draw progress bars without
+//! polling. Nothing waits on a slow receiver: it misses events instead,
+//! and learns how many from the channel.
+
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::crdt::identity::ReplicaId;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// Receivers that fall this far behind miss events by default.
+pub const DEFAULT_PROGRESS_CAPACITY: usize = 1024;
+
+/// One step of a sync operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    ScanStarted,
+    /// The files a scan has to read, as the index can't vouch for them.
+    Hashing { files: usize },
+    /// A block of the file at `path` was stored: `chunks` so far, about
+    /// `bytes` of its `size`.
+    ChunkUploaded { path: String, chunks: usize, bytes: u64, size: u64 },
+    /// The file at `path` was read and its chunks stored, the `done`th of
+    /// `total`.
+    Hashed { path: String, done: usize, total: usize },
+    ScanFinished { put: usize, removed: usize },
+    /// The files and symlinks a checkout looks at.
+    CheckoutStarted { paths: usize },
+    /// `path` was brought up to date, the `done`th of `total`.
+    CheckedOut { path: String, done: usize, total: usize },
+    CheckoutFinished { written: usize, removed: usize },
+    /// `path` was in use, for the `attempts`th checkout in a row, and is
+    /// tried again in `retry_in`.
+    Deferred { path: String, attempts: u32, retry_in: Duration },
+    /// `ops` operations were merged from `member`.
+    MergeApplied { member: ReplicaId, ops: usize },
+    Published { announcement: IpfsCid },
+}
+
+/// Where progress events go; nowhere by default. Clones send to the same
+/// channel.
+#[derive(Debug, Clone, Default)]
+pub struct Reporter(Option<broadcast::Sender<Progress>>);
+
+impl Reporter {
+    pub fn new(sender: broadcast::Sender<Progress>) -> Self {
+        Reporter(Some(sender))
+    }
+
+    /// A reporter on a new channel, and a receiver of it.
+    pub fn channel() -> (Self, broadcast::Receiver<Progress>) {
+        let (sender, receiver) = broadcast::channel(DEFAULT_PROGRESS_CAPACITY);
+        (Reporter(Some(sender)), receiver)
+    }
+
+    /// Another receiver of the events, if they go anywhere.
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<Progress>> {
+        self.0.as_ref().map(broadcast::Sender::subscribe)
+    }
+
+    /// Sends the event `event` makes, if anyone is listening.
+    pub(crate) fn emit(&self, event: impl FnOnce() -> Progress) {
+        if let Some(sender) = self.0.as_ref().filter(|sender| sender.receiver_count() > 0) {
+            let _ = sender.send(event());
+        }
+    }
+}
+
+#[cfg(test)]
+mod progress_test {
+    use super::*;
+    use crate::crdt::replica::Replica;
+    use crate::sync::scan::Scanner;
+    use crate::test_util::alice;
+    use anyhow::anyhow;
+    use bytes::Bytes;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_scan_and_checkout_report_progress() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-progress-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("from")).unwrap();
+        std::fs::write(root.join("from/a.txt"), b"a").unwrap();
+        std::fs::write(root.join("from/b.txt"), b"b").unwrap();
+
+        let (reporter, mut events) = Reporter::channel();
+        let blobs = std::sync::Mutex::new(HashMap::new());
+        let store = async |cid: IpfsCid, bytes: Bytes| {
+            blobs.lock().unwrap().insert(cid, bytes.to_vec());
+            Ok(())
+        };
+        Scanner::new(root.join("from")).with_progress(reporter.clone()).scan(&mut replica, &store).await.unwrap();
+        let fetch = async |cid: IpfsCid| blobs.lock().unwrap().get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        Scanner::new(root.join("to")).with_progress(reporter).with_downloads(1).checkout(&replica, fetch).await.unwrap();
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        let hashed = received.iter().filter(|event| matches!(event, Progress::Hashed { total: 2, .. })).count();
+        assert_eq!(hashed, 2);
+        assert!(received.iter().any(|event| matches!(event, Progress::ChunkUploaded { size: 1, .. })));
+        assert_eq!(received[0], Progress::ScanStarted);
+        assert_eq!(received[1], Progress::Hashing { files: 2 });
+        let tail = &received[received.len() - 4..];
+        assert_eq!(
+            tail,
+            [
+                Progress::CheckoutStarted { paths: 2 },
+                Progress::CheckedOut { path: "a.txt".to_string(), done: 1, total: 2 },
+                Progress::CheckedOut { path: "b.txt".to_string(), done: 2, total: 2 },
+                Progress::CheckoutFinished { written: 2, removed: 0 },
+            ]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
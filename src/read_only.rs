@@ -0,0 +1,125 @@
+//! Read-only replicas for mirrors and CI consumers: they merge what the
+//! members publish and check it out, but have no way to write an op or
+//! publish a head.
+
+use anyhow::Result;
+use std::path::Path;
+
+use crate::anti_entropy::{AntiEntropy, AntiEntropyReport};
+use crate::crdt::fork::ForkResolution;
+use crate::crdt::identity::ReplicaId;
+use crate::crdt::materialize::materialize_read_only;
+use crate::crdt::preview::MergeReport;
+use crate::crdt::quota::Usage;
+use crate::crdt::replica::Replica;
+use crate::kubo_rpc::ipfs::IpfsCid;
+use crate::sync::cache::fetch_cached;
+use crate::workspace::{SyncReport, Workspace};
+
+/// A `Workspace` handle with only the methods that leave the CRDT
+/// unchanged but for merging: nothing reachable from it commits an op,
+/// writes a snapshot or publishes, and the replica is only lent out
+/// immutably. Its author key just names the replica; it needn't be a
+/// member.
+pub struct ReadOnlyWorkspace {
+    inner: Workspace,
+}
+
+impl ReadOnlyWorkspace {
+    /// Opens `replica` read-only on the IPFS daemon at `base_url`. Ops it
+    /// already wrote but never published stay unpublished.
+    pub fn new(base_url: &str, replica: Replica) -> Self {
+        ReadOnlyWorkspace { inner: Workspace::new(base_url, replica) }
+    }
+
+    pub fn replica(&self) -> &Replica {
+        self.inner.replica()
+    }
+
+    /// Gives the replica back, e.g. to open it writable again.
+    pub fn into_replica(self) -> Replica {
+        self.inner.into_replica()
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &ReplicaId> {
+        self.inner.members()
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.inner.usage()
+    }
+
+    /// See `Workspace::merge_members`. Call `merge_member` once with a
+    /// member first to pick up the membership document.
+    pub async fn merge_members(&mut self) -> Result<SyncReport> {
+        self.inner.merge_members().await
+    }
+
+    pub async fn merge_member(&mut self, member: &ReplicaId) -> Result<usize> {
+        self.inner.merge_member(member).await
+    }
+
+    pub async fn resolve_fork(&mut self, member: &ReplicaId, resolution: ForkResolution) -> Result<usize> {
+        self.inner.resolve_fork(member, resolution).await
+    }
+
+    pub async fn preview_member(&self, member: &ReplicaId) -> Result<MergeReport> {
+        self.inner.preview_member(member).await
+    }
+
+    pub async fn anti_entropy_round(&mut self, schedule: &mut AntiEntropy) -> AntiEntropyReport {
+        self.inner.anti_entropy_round(schedule).await
+    }
+
+    /// Writes the current files under `target`, read-only. `target` must
+    /// not exist or be empty.
+    pub async fn checkout(&self, target: &Path) -> Result<usize> {
+        let replica = self.inner.replica();
+        materialize_read_only(replica.state(), target, async |cid| self.fetch_content(cid).await).await
+    }
+
+    pub async fn checkout_historical(&self, at: &IpfsCid, target: &Path) -> Result<usize> {
+        self.inner.replica().checkout_historical(at, target, async |cid| self.fetch_content(cid).await).await
+    }
+
+    /// File content from the block cache, if any, or the daemon.
+    async fn fetch_content(&self, cid: IpfsCid) -> Result<Vec<u8>> {
+        let (replica, base_url) = (self.inner.replica(), self.inner.base_url());
+        fetch_cached(self.inner.block_cache(), cid, async |cid| replica.fetch_content(base_url, &cid).await).await
+    }
+
+    pub async fn export_car(&self, path: impl AsRef<Path>) -> Result<IpfsCid> {
+        self.inner.export_car(path).await
+    }
+
+    pub async fn import_car(&mut self, path: impl AsRef<Path>) -> Result<usize> {
+        self.inner.import_car(path).await
+    }
+}
+
+impl Workspace {
+    /// Drops write access to this workspace for good, keeping what was
+    /// merged.
+    pub fn into_read_only(self) -> ReadOnlyWorkspace {
+        ReadOnlyWorkspace { inner: self }
+    }
+}
+
+#[cfg(test)]
+mod read_only_test {
+    use super::*;
+    use crate::test_util::{alice, entry};
+
+    #[test]
+    fn test_read_only_keeps_merged_state() {
+        let mut replica = Replica::new(alice());
+        let entry = entry(b"x");
+        replica.put("file", entry.clone()).unwrap();
+        let heads = replica.heads().to_vec();
+
+        let mirror = Workspace::new("http://127.0.0.1:5001", replica).into_read_only();
+        assert_eq!(mirror.replica().state().get("file"), Some(entry));
+        assert_eq!(mirror.usage().files, 1);
+        assert_eq!(mirror.into_replica().heads(), heads.as_slice());
+    }
+}
@@ -0,0 +1,199 @@
+//! The heads members were last resolved to, kept across restarts: for each
+//! member, the announcement its IPNS key pointed at, the sequence number of
+//! the record and when it was seen. A replica that starts merges them
+//! right away with `merge_cached`, without waiting for IPNS, which can take
+//! a while, and a record whose sequence number is lower than one seen
+//! before, a stale copy or a rolled back key, is refused rather than
+//! merged as news.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::crdt::identity::ReplicaId;
+use crate::durability::{write_durably, Durability};
+use crate::kubo_rpc::ipfs::IpfsCid;
+use crate::kubo_rpc::ipns::{routing_get_ipns, IpfsPath};
+use crate::workspace::{resolve_announcement, SyncReport, Workspace};
+
+pub const RESOLUTION_CACHE_VERSION: u32 = 1;
+
+/// What a member's IPNS key was resolved to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResolvedHead {
+    pub announcement: IpfsCid,
+    /// Sequence number of the IPNS record, if the record itself was read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    /// Seconds since the Unix epoch.
+    pub observed_at: u64,
+}
+
+impl ResolvedHead {
+    pub fn new(announcement: IpfsCid, sequence: Option<u64>) -> Self {
+        let observed_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        ResolvedHead { announcement, sequence, observed_at }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResolutionCache {
+    pub version: u32,
+    pub heads: BTreeMap<ReplicaId, ResolvedHead>,
+}
+
+impl Default for ResolutionCache {
+    fn default() -> Self {
+        ResolutionCache::new()
+    }
+}
+
+impl ResolutionCache {
+    pub fn new() -> Self {
+        ResolutionCache { version: RESOLUTION_CACHE_VERSION, heads: BTreeMap::new() }
+    }
+
+    pub fn get(&self, member: &ReplicaId) -> Option<&ResolvedHead> {
+        self.heads.get(member)
+    }
+
+    /// Fails if `head` comes from a record older than the one `member`
+    /// was last resolved to.
+    pub fn check(&self, member: &ReplicaId, head: &ResolvedHead) -> Result<()> {
+        if let (Some(known), Some(sequence)) = (self.get(member).and_then(|known| known.sequence), head.sequence)
+            && sequence < known
+        {
+            bail!("IPNS record of {} went back from sequence {} to {}", member, known, sequence);
+        }
+        Ok(())
+    }
+
+    /// Records that `member` was resolved to `head`. A head without a
+    /// sequence number keeps the one seen before.
+    pub fn record(&mut self, member: &ReplicaId, mut head: ResolvedHead) {
+        if head.sequence.is_none() {
+            head.sequence = self.get(member).and_then(|known| known.sequence);
+        }
+        self.heads.insert(member.clone(), head);
+    }
+
+    /// Loads the cache at `path`, or an empty one if the file doesn't
+    /// exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(ResolutionCache::new());
+        }
+        let s = std::fs::read_to_string(path).with_context(|| format!("Failed to read resolution cache {}", path.display()))?;
+        let cache: ResolutionCache = serde_json::from_str(&s).context("Invalid resolution cache")?;
+        if cache.version == 0 || cache.version > RESOLUTION_CACHE_VERSION {
+            bail!("Unsupported resolution cache version {}", cache.version);
+        }
+        Ok(cache)
+    }
+
+    pub fn save(&self, path: &Path, durability: Durability) -> Result<()> {
+        let json = serde_json::to_string(self)?;
+        write_durably(path, json.as_bytes(), durability)
+            .with_context(|| format!("Failed to write resolution cache {}", path.display()))
+    }
+}
+
+impl Workspace {
+    /// Keeps the heads members are resolved to in the cache at `path`; see
+    /// `LocalStore::resolution_cache_path`. Resolving then reads members'
+    /// IPNS records, to refuse those older than the cached ones.
+    pub fn with_resolution_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.resolution_cache = Some(path.into());
+        self
+    }
+
+    pub fn resolution_cache(&self) -> Option<&Path> {
+        self.resolution_cache.as_deref()
+    }
+
+    /// Merges the cached head of every other member without resolving
+    /// their IPNS keys, as right after a start. Members without a cached
+    /// head are left to `merge_members`.
+    pub async fn merge_cached(&mut self) -> Result<SyncReport> {
+        self.mode().check_pull()?;
+        let mut report = SyncReport::default();
+        let Some(path) = &self.resolution_cache else {
+            return Ok(report);
+        };
+        let cache = ResolutionCache::load(path)?;
+        let author = self.replica().author().clone();
+        let others: Vec<ReplicaId> = self.members().filter(|id| **id != author).cloned().collect();
+        for member in others {
+            let Some(head) = cache.get(&member) else {
+                continue;
+            };
+            match self.merge_announced(&member, &head.announcement).await {
+                Ok(applied) => {
+                    report.applied += applied;
+                    report.merged.push(member);
+                }
+                Err(_) if self.replica().fork(&member).is_some() => report.forked.push(member),
+                Err(e) => report.failed.push((member, e)),
+            }
+        }
+        report.unavailable = self.missing.unavailable();
+        Ok(report)
+    }
+
+    /// Resolves the head `member` announces. With a resolution cache its
+    /// IPNS record is read, falling back to a plain resolve if it can't
+    /// be, and refused if it is older than the cached one.
+    pub(crate) async fn resolve_head(&self, member: &ReplicaId) -> Result<ResolvedHead> {
+        let resolve = async || Ok(ResolvedHead::new(resolve_announcement(self.base_url(), member.ipns_key()).await?, None));
+        let Some(path) = &self.resolution_cache else {
+            return resolve().await;
+        };
+        let head = match routing_get_ipns(self.base_url(), member.ipns_key()).await {
+            Ok(record) => match record.value {
+                IpfsPath::Ipfs(cid) => ResolvedHead::new(cid, Some(record.sequence)),
+                other => bail!("{} resolved to {} instead of an /ipfs/ path", member, other.as_str()),
+            },
+            Err(_) => resolve().await?,
+        };
+        ResolutionCache::load(path)?.check(member, &head)?;
+        Ok(head)
+    }
+
+    /// Records `head`, just merged, in the resolution cache, if any.
+    pub(crate) fn remember_head(&self, member: &ReplicaId, head: ResolvedHead) -> Result<()> {
+        let Some(path) = &self.resolution_cache else {
+            return Ok(());
+        };
+        let mut cache = ResolutionCache::load(path)?;
+        cache.record(member, head);
+        cache.save(path, self.durability())
+    }
+}
+
+#[cfg(test)]
+mod resolution_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use crate::test_util::bob;
+
+    #[test]
+    fn test_cache_refuses_older_records() {
+        let path = std::env::temp_dir().join(format!("crdt-resolution-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let bob = bob();
+        let (first, second) = (IpfsCid::compute(RAW_CODE, b"first"), IpfsCid::compute(RAW_CODE, b"second"));
+
+        let mut cache = ResolutionCache::load(&path).unwrap();
+        cache.record(&bob, ResolvedHead::new(second.clone(), Some(7)));
+        cache.save(&path, Durability::Always).unwrap();
+
+        let cache = ResolutionCache::load(&path).unwrap();
+        assert_eq!(cache.get(&bob).unwrap().announcement, second);
+        assert!(cache.check(&bob, &ResolvedHead::new(first.clone(), Some(6))).is_err());
+        assert!(cache.check(&bob, &ResolvedHead::new(first.clone(), None)).is_ok());
+        assert!(cache.check(&bob, &ResolvedHead::new(first, Some(8))).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
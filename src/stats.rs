@@ -0,0 +1,217 @@
+//! Numbers for operators: how the block cache is doing, how much the
+//! replica and its local store hold, and how much is waiting to go out, so
+//! caches can be sized and backlogs spotted. `Workspace::stats` gathers
+//! them; `Stats::metrics` lists them as gauges under stable names, and
+//! `encode_metrics` writes those in the Prometheus text format for a
+//! scrape endpoint.
+
+use anyhow::Result;
+use std::fmt::Write;
+
+use crate::crdt::replica::Replica;
+use crate::outbox::Outbox;
+use crate::store::LocalStore;
+use crate::sync::cache::{BlockCache, CacheMetrics};
+use crate::workspace::Workspace;
+
+/// Prefix of the names of every metric.
+pub const METRIC_PREFIX: &str = "crdt_";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplicaStats {
+    /// DAG nodes held.
+    pub nodes: usize,
+    /// Nodes, snapshot states and shards held.
+    pub blocks: usize,
+    pub heads: usize,
+    /// Received nodes and deltas waiting for their dependencies.
+    pub pending: usize,
+    pub quarantined: usize,
+    /// Blocks written locally and not published yet.
+    pub unpublished: usize,
+    /// Records in the journal, if the replica keeps one.
+    pub journal_records: usize,
+}
+
+impl ReplicaStats {
+    pub fn of(replica: &Replica) -> Result<Self> {
+        Ok(ReplicaStats {
+            nodes: replica.nodes().count(),
+            blocks: replica.held_blocks().count(),
+            heads: replica.heads().len(),
+            pending: replica.pending_len(),
+            quarantined: replica.quarantined().count(),
+            unpublished: replica.unpublished.len(),
+            journal_records: match &replica.journal {
+                Some(journal) => journal.records()?.len(),
+                None => 0,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub metrics: CacheMetrics,
+    /// Share of lookups answered, memory and disk together.
+    pub hit_rate: Option<f64>,
+    /// Blocks and bytes on disk, and the cap on the bytes.
+    pub blocks: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+impl CacheStats {
+    pub fn of(cache: &BlockCache) -> Self {
+        let metrics = cache.metrics();
+        CacheStats { metrics, hit_rate: metrics.hit_rate(), blocks: cache.len(), bytes: cache.size(), max_bytes: cache.max_bytes() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Bytes the files in the store take.
+    pub bytes: u64,
+    /// Blocks in the saved DAG.
+    pub blocks: usize,
+}
+
+impl StoreStats {
+    pub fn of(store: &LocalStore) -> Result<Self> {
+        Ok(StoreStats { bytes: store.size()?, blocks: store.load_state()?.blocks.len() })
+    }
+}
+
+/// What is waiting in an outbox.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutboxStats {
+    pub publish_owed: bool,
+    /// Deltas waiting, over all peers.
+    pub deltas: usize,
+    /// Peers deltas wait for.
+    pub peers: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub replica: ReplicaStats,
+    pub cache: Option<CacheStats>,
+    pub store: Option<StoreStats>,
+    pub outbox: Option<OutboxStats>,
+}
+
+/// One gauge, as `Stats::metrics` lists them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    pub name: String,
+    pub help: &'static str,
+    pub value: f64,
+}
+
+impl Stats {
+    /// Adds the numbers of `store`, the workspace's local store.
+    pub fn with_store(mut self, store: &LocalStore) -> Result<Self> {
+        self.store = Some(StoreStats::of(store)?);
+        Ok(self)
+    }
+
+    /// Every number as a gauge; those of parts the workspace doesn't have
+    /// are left out.
+    pub fn metrics(&self) -> Vec<Metric> {
+        let replica = &self.replica;
+        let mut metrics = Vec::new();
+        let mut gauge = |name: &str, help: &'static str, value: f64| {
+            metrics.push(Metric { name: format!("{}{}", METRIC_PREFIX, name), help, value });
+        };
+        gauge("dag_nodes", "DAG nodes held by the replica", replica.nodes as f64);
+        gauge("dag_blocks", "Nodes, snapshot states and shards held by the replica", replica.blocks as f64);
+        gauge("heads", "Heads of the replica's DAG", replica.heads as f64);
+        gauge("pending_nodes", "Received nodes and deltas waiting for their dependencies", replica.pending as f64);
+        gauge("quarantined_nodes", "Remote nodes rejected by validators", replica.quarantined as f64);
+        gauge("unpublished_blocks", "Blocks written locally and not published yet", replica.unpublished as f64);
+        gauge("journal_records", "Records in the write-ahead journal", replica.journal_records as f64);
+        if let Some(cache) = &self.cache {
+            gauge("cache_memory_hits", "Block cache lookups answered from memory", cache.metrics.memory_hits as f64);
+            gauge("cache_disk_hits", "Block cache lookups answered from disk", cache.metrics.disk_hits as f64);
+            gauge("cache_misses", "Block cache lookups not answered", cache.metrics.misses as f64);
+            if let Some(hit_rate) = cache.hit_rate {
+                gauge("cache_hit_rate", "Share of block cache lookups answered", hit_rate);
+            }
+            gauge("cache_blocks", "Blocks in the block cache", cache.blocks as f64);
+            gauge("cache_bytes", "Bytes of blocks in the block cache", cache.bytes as f64);
+            gauge("cache_max_bytes", "Cap on the bytes of the block cache", cache.max_bytes as f64);
+        }
+        if let Some(store) = &self.store {
+            gauge("store_bytes", "Bytes the files of the local store take", store.bytes as f64);
+            gauge("store_blocks", "Blocks in the DAG saved in the local store", store.blocks as f64);
+        }
+        if let Some(outbox) = &self.outbox {
+            gauge("publish_owed", "Whether a publish that failed is still owed", f64::from(u8::from(outbox.publish_owed)));
+            gauge("outbox_deltas", "Deltas waiting to be delivered to peers", outbox.deltas as f64);
+            gauge("outbox_peers", "Peers deltas wait to be delivered to", outbox.peers as f64);
+        }
+        metrics
+    }
+}
+
+/// `metrics` in the Prometheus text exposition format.
+pub fn encode_metrics(metrics: &[Metric]) -> String {
+    let mut text = String::new();
+    for metric in metrics {
+        writeln!(text, "# HELP {} {}", metric.name, metric.help).expect("writing to a String doesn't fail");
+        writeln!(text, "# TYPE {} gauge", metric.name).expect("writing to a String doesn't fail");
+        writeln!(text, "{} {}", metric.name, metric.value).expect("writing to a String doesn't fail");
+    }
+    text
+}
+
+impl Workspace {
+    /// The numbers of this workspace's replica, block cache and outbox;
+    /// see `Stats::with_store` for those of its local store.
+    pub fn stats(&self) -> Result<Stats> {
+        let outbox = match &self.outbox {
+            Some(path) => {
+                let outbox = Outbox::load(path)?;
+                let deltas = outbox.deltas.values().map(Vec::len).sum();
+                Some(OutboxStats { publish_owed: outbox.publish.is_some(), deltas, peers: outbox.deltas.len() })
+            }
+            None => None,
+        };
+        Ok(Stats { replica: ReplicaStats::of(self.replica())?, cache: self.block_cache().map(CacheStats::of), store: None, outbox })
+    }
+}
+
+#[cfg(test)]
+mod stats_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+    use crate::test_util::{alice, entry};
+
+    #[test]
+    fn test_stats_count_backlog_and_cache() {
+        let dir = std::env::temp_dir().join(format!("crdt-stats-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = BlockCache::open(dir.join("cache"), 1 << 20).unwrap();
+        let mut workspace = Workspace::new("http://127.0.0.1:5001", Replica::new(alice())).with_block_cache(cache.clone());
+        let data = b"hello";
+        let entry = entry(data);
+        workspace.replica_mut().put("a", entry).unwrap();
+        cache.insert(&IpfsCid::compute(RAW_CODE, data), data).unwrap();
+        assert!(cache.get(&IpfsCid::compute(RAW_CODE, data)).is_some());
+        assert!(cache.get(&IpfsCid::compute(RAW_CODE, b"other")).is_none());
+
+        let store = LocalStore::open(dir.join("store")).unwrap();
+        store.save(workspace.replica()).unwrap();
+        let stats = workspace.stats().unwrap().with_store(&store).unwrap();
+        assert_eq!((stats.replica.nodes, stats.replica.heads, stats.replica.unpublished), (1, 1, 1));
+        let cache = stats.cache.unwrap();
+        assert_eq!((cache.blocks, cache.hit_rate), (1, Some(0.5)));
+        assert!(stats.store.unwrap().blocks >= 1);
+
+        let text = encode_metrics(&stats.metrics());
+        assert!(text.contains("# TYPE crdt_unpublished_blocks gauge\ncrdt_unpublished_blocks 1\n"), "{}", text);
+        assert!(text.contains("crdt_cache_hit_rate 0.5\n"));
+        assert!(!text.contains("crdt_outbox_deltas"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,505 @@
+//! A local store of what a replica would otherwise rebuild on restart, in
+//! one directory: its DAG, from which the state, version vectors and heads
+//! are merged back without fetching them again; the journal of blocks
+//! waiting to be published; the scan index, so a scanner picks up where it
+//! left off instead of hashing the whole directory again; and the ledger
+//! of the pins made on the daemon.
+//!
+//! The DAG is kept as a base CAR plus a log of the blocks saved since, so
+//! a save writes only what is new instead of the whole history. Its blocks
+//! are immutable and only ever added, which an append-only log serves as
+//! well as an embedded key-value store would, without the dependency. The
+//! base is a plain CAR, which `ipfs dag import` reads unless sealed; the
+//! log is length-prefixed CAR segments only this crate reads back. The
+//! index and ledger grow with the directory, not its history, and are
+//! replaced whole. Files are written as durably as the replica's
+//! `Durability` says. With a key, the DAG is sealed at rest; see
+//! `at_rest`. `compact` sheds what a long-lived replica leaves behind in
+//! them. `open_default` keeps a directory's store under the platform's
+//! data directory; see `dirs`. Opening a store written by an older
+//! version of the crate migrates it; see `migrate`.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::at_rest::{open_file, seal_file};
+use crate::crdt::car::Car;
+use crate::crdt::journal::Journal;
+use crate::crdt::replica::Replica;
+use crate::crypto::WorkspaceKey;
+use crate::dirs::default_store_dir;
+use crate::durability::{write_durably, Durability};
+use crate::kubo_rpc::ipfs::IpfsCid;
+use crate::migrate::migrate;
+use crate::sync::index::ScanIndex;
+use crate::sync::scan::Scanner;
+
+/// Names of the files in a store's directory.
+pub const STATE_FILE: &str = "state.car";
+pub const STATE_LOG_FILE: &str = "state.log";
+pub const JOURNAL_FILE: &str = "journal";
+pub const INDEX_FILE: &str = "index.json";
+pub const PINS_FILE: &str = "pins.json";
+pub const OUTBOX_FILE: &str = "outbox";
+pub const RESOLUTION_CACHE_FILE: &str = "resolved.json";
+
+/// Outcome of `LocalStore::compact`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactReport {
+    /// Bytes the store's files took before and after.
+    pub before: u64,
+    pub after: u64,
+    /// Blocks of superseded snapshots left out of the saved DAG.
+    pub snapshot_blocks: usize,
+    /// Journal records of blocks already published, or written twice.
+    pub journal_records: usize,
+    /// Index rows of files no longer in the state.
+    pub index_rows: usize,
+    /// Temporary files writes that were cut short left behind.
+    pub temp_files: usize,
+}
+
+impl CompactReport {
+    /// Bytes freed; none if the DAG grew more since it was last saved.
+    pub fn reclaimed(&self) -> u64 {
+        self.before.saturating_sub(self.after)
+    }
+}
+
+/// The store in a directory.
+#[derive(Debug, Clone)]
+pub struct LocalStore {
+    dir: PathBuf,
+    /// What the DAG is sealed under, if anything.
+    key: Option<WorkspaceKey>,
+    /// What was saved, once the first save read it.
+    saved: Arc<Mutex<Option<Saved>>>,
+}
+
+/// The saved DAG, as far as a save cares.
+#[derive(Debug)]
+struct Saved {
+    /// The announcement the base is rooted at, which the log extends.
+    base: IpfsCid,
+    /// Blocks a save needn't write again.
+    blocks: HashSet<IpfsCid>,
+}
+
+impl LocalStore {
+    /// Opens the store in `dir`, creating the directory if needed and
+    /// migrating it to `STORE_VERSION`. Fails on a store written by a newer
+    /// version of the crate.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        migrate(&dir)?;
+        Ok(LocalStore { dir, key: None, saved: Arc::default() })
+    }
+
+    /// Opens the store of the directory at `root` in its default place;
+    /// see `dirs::default_store_dir`.
+    pub fn open_default(root: &Path) -> Result<Self> {
+        LocalStore::open(default_store_dir(root)?)
+    }
+
+    /// Seals the DAG under `key`, an at-rest key. A DAG saved unsealed is
+    /// still restored. Pass the key to `Scanner::with_encrypted_index`
+    /// too, for the index.
+    pub fn with_encryption(mut self, key: WorkspaceKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn encryption(&self) -> Option<&WorkspaceKey> {
+        self.key.as_ref()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Where the scan index is kept; see `Scanner::with_index`.
+    pub fn index_path(&self) -> PathBuf {
+        self.dir.join(INDEX_FILE)
+    }
+
+    /// Where the pin ledger is kept; see `Workspace::with_pin_ledger`.
+    pub fn pins_path(&self) -> PathBuf {
+        self.dir.join(PINS_FILE)
+    }
+
+    /// Where undelivered messages are kept; see `Workspace::with_outbox`.
+    pub fn outbox_path(&self) -> PathBuf {
+        self.dir.join(OUTBOX_FILE)
+    }
+
+    /// Where the heads members were resolved to are kept; see
+    /// `Workspace::with_resolution_cache`.
+    pub fn resolution_cache_path(&self) -> PathBuf {
+        self.dir.join(RESOLUTION_CACHE_FILE)
+    }
+
+    /// Where the journal is kept; `restore` opens it.
+    pub fn journal_path(&self) -> PathBuf {
+        self.dir.join(JOURNAL_FILE)
+    }
+
+    /// Saves `replica`'s DAG: a fresh head announcement and the blocks not
+    /// saved before are appended to the log. Once the log is as large as
+    /// the base, the whole DAG is written as a new base instead. Blocks
+    /// committed since are in the journal until the next save.
+    pub fn save(&self, replica: &Replica) -> Result<()> {
+        let mut guard = self.saved.lock().unwrap();
+        // put back only once written, so a failed save reads it again
+        let saved = match guard.take() {
+            Some(saved) => Some(saved),
+            None => {
+                // a log cut short is rewritten, since nothing appended after
+                // it would be read
+                let (base, log, whole) = self.read_dag()?;
+                let blocks = base.blocks.iter().chain(log.iter().flat_map(|segment| &segment.blocks)).map(|(cid, _)| cid.clone()).collect();
+                base.roots.first().filter(|_| whole).map(|root| Saved { base: root.clone(), blocks })
+            }
+        };
+        let mut saved = match saved {
+            Some(saved) if self.file_size(STATE_LOG_FILE)? < self.file_size(STATE_FILE)? => saved,
+            _ => {
+                let car = replica.to_car()?;
+                self.write_base(&car, replica.durability())?;
+                *guard = Some(Saved { base: car.roots[0].clone(), blocks: car.blocks.into_iter().map(|(cid, _)| cid).collect() });
+                return Ok(());
+            }
+        };
+
+        let (root, announcement) = replica.encode_block(&replica.announcement())?;
+        let mut cids: Vec<IpfsCid> = replica.history_blocks().into_iter().filter(|cid| !saved.blocks.contains(cid)).collect();
+        cids.sort();
+        let mut blocks = vec![(root.clone(), announcement)];
+        for cid in cids {
+            let bytes = replica.block_bytes(&cid)?.ok_or_else(|| anyhow!("Block {} to save isn't held", cid))?;
+            blocks.push((cid, bytes));
+        }
+        let segment = Car { roots: vec![root, saved.base.clone()], blocks };
+        self.append_log(&segment, replica.durability())?;
+        saved.blocks.extend(segment.blocks.into_iter().map(|(cid, _)| cid));
+        *guard = Some(saved);
+        Ok(())
+    }
+
+    /// Replaces the base with `car` and empties the log. A crash between
+    /// the two leaves a log of the old base, which `read_dag` skips.
+    fn write_base(&self, car: &Car, durability: Durability) -> Result<()> {
+        let path = self.dir.join(STATE_FILE);
+        write_durably(&path, &seal_file(self.key.as_ref(), &car.encode()?), durability)
+            .with_context(|| format!("Failed to write local state {}", path.display()))?;
+        let log = self.dir.join(STATE_LOG_FILE);
+        match std::fs::remove_file(&log) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err).with_context(|| format!("Failed to remove {}", log.display())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Appends `segment`, rooted at its announcement and the base's, to the
+    /// log, sealed and prefixed with its length.
+    fn append_log(&self, segment: &Car, durability: Durability) -> Result<()> {
+        let sealed = seal_file(self.key.as_ref(), &segment.encode()?);
+        let mut record = (sealed.len() as u64).to_le_bytes().to_vec();
+        record.extend(sealed);
+        let path = self.dir.join(STATE_LOG_FILE);
+        let written = std::fs::File::options().create(true).append(true).open(&path).and_then(|mut file| {
+            file.write_all(&record)?;
+            if durability.syncs_checkpoints() {
+                file.sync_data()?;
+            }
+            Ok(())
+        });
+        written.with_context(|| format!("Failed to write local state log {}", path.display()))
+    }
+
+    /// Bytes the store's file `name` takes, none if it doesn't exist.
+    fn file_size(&self, name: &str) -> Result<u64> {
+        let path = self.dir.join(name);
+        match std::fs::metadata(&path) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// Rewrites the store's files with only what `replica`, the one it
+    /// holds, still needs: the DAG, as a new base with an empty log,
+    /// without the state blocks of snapshots a later one supersedes, the
+    /// journal without records of blocks since published, and the index
+    /// without rows of files no longer in the state. Stray temporary files
+    /// are removed. Runs while the replica is in use; pass the scanner
+    /// keeping the index, if one is running, so it doesn't save the stale
+    /// rows back.
+    pub fn compact(&self, replica: &Replica, scanner: Option<&mut Scanner>) -> Result<CompactReport> {
+        let mut report = CompactReport { before: self.size()?, ..CompactReport::default() };
+        for entry in std::fs::read_dir(&self.dir).with_context(|| format!("Failed to list {}", self.dir.display()))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                std::fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+                report.temp_files += 1;
+            }
+        }
+
+        // the DAG first, so it holds every block the journal drops
+        let mut car = replica.to_car()?;
+        let reachable = replica.retained_blocks();
+        let nodes: HashSet<&IpfsCid> = replica.nodes().map(|(cid, _)| cid).collect();
+        let blocks = car.blocks.len();
+        car.blocks.retain(|(cid, _)| car.roots.contains(cid) || nodes.contains(cid) || reachable.contains(cid));
+        report.snapshot_blocks = blocks - car.blocks.len();
+        let mut saved = self.saved.lock().unwrap();
+        // read again by the next save, should this fail
+        *saved = None;
+        self.write_base(&car, replica.durability())?;
+        // the blocks left out aren't saved again either
+        *saved = Some(Saved { base: car.roots[0].clone(), blocks: replica.history_blocks() });
+        drop(saved);
+
+        let journal = Journal::new(self.journal_path()).with_durability(replica.durability());
+        report.journal_records = journal.rewrite(|record| replica.is_unpublished(&record.cid))?;
+
+        let in_state = |path: &str| replica.state().get(path).is_some();
+        match scanner {
+            Some(scanner) => {
+                report.index_rows = scanner.index.prune(in_state);
+                if let Some(path) = &scanner.index_path {
+                    scanner.index.save_sealed(path, scanner.durability, scanner.index_key.as_ref())?;
+                }
+            }
+            None if self.index_path().exists() => {
+                let mut index = ScanIndex::load_sealed(&self.index_path(), self.key.as_ref())?;
+                report.index_rows = index.prune(in_state);
+                index.save_sealed(&self.index_path(), replica.durability(), self.key.as_ref())?;
+            }
+            None => {}
+        }
+        report.after = self.size()?;
+        Ok(report)
+    }
+
+    /// The saved DAG, empty if none was saved yet: the blocks of the base
+    /// and the log, each once, rooted at the last announcement logged, or
+    /// the base's if none was.
+    pub(crate) fn load_state(&self) -> Result<Car> {
+        let (mut state, log, _) = self.read_dag()?;
+        for segment in log {
+            state.roots = segment.roots[..1].to_vec();
+            state.blocks.extend(segment.blocks);
+        }
+        let mut seen = HashSet::new();
+        state.blocks.retain(|(cid, _)| seen.insert(cid.clone()));
+        Ok(state)
+    }
+
+    /// The base, the segments of the log that extend it and whether the
+    /// log was read to its end. A segment a crash cut short, or that
+    /// doesn't open, ends the log.
+    fn read_dag(&self) -> Result<(Car, Vec<Car>, bool)> {
+        let base = match self.read_state_file(STATE_FILE)? {
+            Some(bytes) => self.open_segment(bytes, STATE_FILE)?,
+            None => Car::default(),
+        };
+        let log = self.read_state_file(STATE_LOG_FILE)?.unwrap_or_default();
+        let mut rest = log.as_slice();
+        let mut segments = Vec::new();
+        while let Some((len, after)) = rest.split_first_chunk::<8>()
+            && let Some((sealed, after)) = after.split_at_checked(u64::from_le_bytes(*len) as usize)
+        {
+            let Ok(segment) = self.open_segment(sealed.to_vec(), STATE_LOG_FILE) else {
+                break;
+            };
+            let [_, extends] = segment.roots.as_slice() else {
+                break;
+            };
+            if base.roots.first() == Some(extends) {
+                segments.push(segment);
+            }
+            rest = after;
+        }
+        Ok((base, segments, rest.is_empty()))
+    }
+
+    fn read_state_file(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.dir.join(name);
+        match std::fs::read(&path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to read local state {}", path.display())),
+        }
+    }
+
+    fn open_segment(&self, bytes: Vec<u8>, name: &str) -> Result<Car> {
+        let path = self.dir.join(name);
+        let bytes = open_file(self.key.as_ref(), bytes).with_context(|| format!("Failed to decrypt local state {}", path.display()))?;
+        Car::decode(&bytes).with_context(|| format!("Invalid local state {}", path.display()))
+    }
+
+    /// Bytes the files in the store take.
+    pub fn size(&self) -> Result<u64> {
+        let mut size = 0;
+        for entry in std::fs::read_dir(&self.dir).with_context(|| format!("Failed to list {}", self.dir.display()))? {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                size += metadata.len();
+            }
+        }
+        Ok(size)
+    }
+
+    /// Replays and opens the journal in `replica`, a fresh one with the
+    /// author and keypair that saved it, then merges the saved DAG. The
+    /// journal goes first: a merge stops at the newest snapshot, so nodes
+    /// journaled before it would otherwise never be held to be pushed.
+    /// Nodes the journaled ones descend from that neither holds are
+    /// fetched with `fetch`. Returns the number of operations applied.
+    pub async fn restore<F>(&self, replica: &mut Replica, mut fetch: F) -> Result<usize>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let car = self.load_state()?;
+        let blocks: HashMap<&IpfsCid, &Vec<u8>> = car.blocks.iter().map(|(cid, bytes)| (cid, bytes)).collect();
+        let mut applied = replica
+            .open_journal(self.journal_path(), async |cid| match blocks.get(&cid) {
+                Some(bytes) => Ok(bytes.to_vec()),
+                None => fetch(cid).await,
+            })
+            .await?;
+        if !car.roots.is_empty() {
+            applied += replica.merge_car(&car).await.context("Failed to restore local state")?;
+        }
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod store_test {
+    use super::*;
+    use crate::crdt::sign::ReplicaKeypair;
+    use crate::sync::index::IndexEntry;
+    use crate::test_util::{alice, entry};
+    use anyhow::anyhow;
+
+    #[tokio::test]
+    async fn test_restore_needs_no_daemon() {
+        let author = alice();
+        let keypair = ReplicaKeypair::generate().unwrap();
+        let dir = std::env::temp_dir().join(format!("crdt-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = LocalStore::open(&dir).unwrap();
+        let no_daemon = async |cid: IpfsCid| -> Result<Vec<u8>> { Err(anyhow!("missing {}", cid)) };
+
+        let mut replica = Replica::new(author.clone()).with_keypair(keypair.clone());
+        assert_eq!(store.restore(&mut replica, no_daemon).await.unwrap(), 0);
+        replica.put("a.txt", entry(b"a")).unwrap();
+        replica.put("b.txt", entry(b"b")).unwrap();
+        store.save(&replica).unwrap();
+        // after the save, so only the journal has it
+        replica.put("c.txt", entry(b"c")).unwrap();
+        let (state, heads) = (replica.state().clone(), replica.heads().to_vec());
+        drop(replica);
+
+        let mut restarted = Replica::new(author).with_keypair(keypair);
+        store.restore(&mut restarted, no_daemon).await.unwrap();
+        assert_eq!(restarted.state(), &state);
+        assert_eq!(restarted.heads(), heads);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compact_keeps_what_restore_needs() {
+        let author = alice();
+        let keypair = ReplicaKeypair::generate().unwrap();
+        let dir = std::env::temp_dir().join(format!("crdt-store-compact-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = LocalStore::open(&dir).unwrap();
+        let no_daemon = async |cid: IpfsCid| -> Result<Vec<u8>> { Err(anyhow!("missing {}", cid)) };
+
+        let mut replica = Replica::new(author.clone()).with_keypair(keypair.clone()).with_snapshot_interval(2);
+        store.restore(&mut replica, no_daemon).await.unwrap();
+        for i in 0..5 {
+            replica.put(&format!("file{}", i), entry(&[i])).unwrap();
+        }
+        store.save(&replica).unwrap();
+        replica.push(async |_, _| Ok(())).await.unwrap();
+        replica.remove("file0").unwrap();
+        let mut index = ScanIndex::new();
+        for path in ["file0", "file1"] {
+            index.files.insert(path.to_string(), IndexEntry { size: 1, mtime: 0, inode: 0, content: entry(b"x").content });
+        }
+        index.save(&store.index_path(), replica.durability()).unwrap();
+        std::fs::write(dir.join("state.tmp"), b"torn").unwrap();
+
+        let report = store.compact(&replica, None).unwrap();
+        assert!(report.snapshot_blocks > 0);
+        assert!(report.journal_records > 0);
+        assert_eq!((report.index_rows, report.temp_files), (1, 1));
+        assert!(report.reclaimed() > 0);
+        assert_eq!(ScanIndex::load(&store.index_path()).unwrap().files.len(), 1);
+        let (state, heads, unpublished) = (replica.state().clone(), replica.heads().to_vec(), replica.unpublished.clone());
+        drop(replica);
+
+        let mut restarted = Replica::new(author).with_keypair(keypair);
+        store.restore(&mut restarted, no_daemon).await.unwrap();
+        assert_eq!(restarted.state(), &state);
+        assert_eq!(restarted.heads(), heads);
+        // only the removal, and the snapshot after it, are left to push
+        assert_eq!(restarted.unpublished, unpublished);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_saves_append_only_new_blocks() {
+        let keypair = ReplicaKeypair::generate().unwrap();
+        let dir = std::env::temp_dir().join(format!("crdt-store-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = LocalStore::open(&dir).unwrap();
+        let no_daemon = async |cid: IpfsCid| -> Result<Vec<u8>> { Err(anyhow!("missing {}", cid)) };
+
+        let mut replica = Replica::new(alice()).with_keypair(keypair.clone());
+        replica.put("a.txt", entry(b"a")).unwrap();
+        store.save(&replica).unwrap();
+        let base = std::fs::read(dir.join(STATE_FILE)).unwrap();
+        replica.put("b.txt", entry(b"b")).unwrap();
+        store.save(&replica).unwrap();
+        // the base is left alone and the log holds less than the whole DAG
+        assert_eq!(std::fs::read(dir.join(STATE_FILE)).unwrap(), base);
+        let log = std::fs::read(dir.join(STATE_LOG_FILE)).unwrap();
+        assert!(log.len() < replica.to_car().unwrap().encode().unwrap().len());
+        let mut restarted = Replica::new(alice()).with_keypair(keypair.clone());
+        store.restore(&mut restarted, no_daemon).await.unwrap();
+        assert_eq!(restarted.state(), replica.state());
+
+        // a log as large as the base is folded into a new one
+        let mut i = 0u8;
+        while dir.join(STATE_LOG_FILE).exists() {
+            replica.put(&format!("f{}", i), entry(&[i])).unwrap();
+            store.save(&replica).unwrap();
+            i += 1;
+        }
+        // as a crash before the old log was removed would leave it, with a
+        // segment cut short after it
+        std::fs::write(dir.join(STATE_LOG_FILE), [log.as_slice(), &[9, 0, 0]].concat()).unwrap();
+        let (state, heads) = (replica.state().clone(), replica.heads().to_vec());
+        let reopened = LocalStore::open(&dir).unwrap();
+        let mut restarted = Replica::new(alice()).with_keypair(keypair.clone());
+        reopened.restore(&mut restarted, no_daemon).await.unwrap();
+        assert_eq!(restarted.state(), &state);
+        // nothing is appended after a segment cut short
+        reopened.save(&replica).unwrap();
+        assert!(!dir.join(STATE_LOG_FILE).exists());
+        drop(replica);
+
+        let mut restarted = Replica::new(alice()).with_keypair(keypair);
+        reopened.restore(&mut restarted, no_daemon).await.unwrap();
+        assert_eq!(restarted.state(), &state);
+        assert_eq!(restarted.heads(), heads);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
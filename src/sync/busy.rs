@@ -0,0 +1,133 @@
+//! Files in use. A checkout doesn't replace or delete a file another
+//! program has locked, or on Windows has open without sharing it: the
+//! write would fail there, or the program would carry on with a file
+//! changed under it. The file is left as it is and tried again by later
+//! checkouts, at growing intervals, each deferral sent as a progress
+//! event. Unix has no way to tell a file is merely open for writing, so
+//! there only locks count.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::checkout::CheckoutReport;
+use super::scan::Scanner;
+use crate::progress::Progress;
+
+/// Wait before the first retry of a file in use; it doubles with each
+/// retry up to `MAX_RETRY_INTERVAL`.
+pub const FIRST_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+pub const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// A file left as it was because it was in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deferral {
+    /// Checkouts that found it in use in a row.
+    pub attempts: u32,
+    pub retry_at: Instant,
+}
+
+impl Scanner {
+    /// The files checkouts left as they were because they were in use, by
+    /// path.
+    pub fn deferred(&self) -> &BTreeMap<String, Deferral> {
+        &self.deferred
+    }
+
+    /// When a checkout should next try a file it deferred, if any.
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.deferred.values().map(|deferral| deferral.retry_at).min()
+    }
+
+    /// Schedules the files `report` found in use, and forgets those it
+    /// didn't.
+    pub(crate) fn defer(&mut self, report: &CheckoutReport) {
+        let now = Instant::now();
+        let mut deferred = BTreeMap::new();
+        for path in &report.in_use {
+            let attempts = self.deferred.get(path).map_or(1, |deferral| deferral.attempts + 1);
+            let retry_in = FIRST_RETRY_INTERVAL.saturating_mul(1 << (attempts - 1).min(16)).min(MAX_RETRY_INTERVAL);
+            deferred.insert(path.clone(), Deferral { attempts, retry_at: now + retry_in });
+            self.progress.emit(|| Progress::Deferred { path: path.clone(), attempts, retry_in });
+        }
+        self.deferred = deferred;
+    }
+}
+
+/// Whether another program has the file at `path` locked, or open without
+/// sharing it.
+pub(crate) async fn in_use(path: &Path) -> bool {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || match std::fs::File::options().read(true).write(true).open(&path) {
+        Ok(file) => matches!(file.try_lock(), Err(std::fs::TryLockError::WouldBlock)),
+        Err(err) => is_sharing_violation(&err),
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Whether `err` came of a file another program has open without sharing
+/// it, as only happens on Windows.
+pub(crate) fn is_sharing_violation(err: &std::io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    cfg!(windows) && matches!(err.raw_os_error(), Some(32 | 33))
+}
+
+/// Whether writing failed as the file is in use.
+pub(crate) fn failed_in_use<T>(result: &Result<T>) -> bool {
+    result.as_ref().err().and_then(|err| err.downcast_ref::<std::io::Error>()).is_some_and(is_sharing_violation)
+}
+
+#[cfg(test)]
+mod busy_test {
+    use super::*;
+    use crate::crdt::replica::Replica;
+    use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+    use crate::test_util::{alice, entry};
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_locked_files_are_deferred() {
+        let mut replica = Replica::new(alice());
+        let blobs: HashMap<IpfsCid, Vec<u8>> =
+            [b"old".to_vec(), b"new".to_vec()].into_iter().map(|data| (IpfsCid::compute(RAW_CODE, &data), data)).collect();
+        let entry = |data: &[u8]| {
+            entry(data)
+        };
+        replica.put("doc.txt", entry(b"old")).unwrap();
+        let root = std::env::temp_dir().join(format!("crdt-busy-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let fetch = async |cid: IpfsCid| blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let mut scanner = Scanner::new(&root);
+        scanner.checkout(&replica, fetch).await.unwrap();
+
+        let (reporter, mut events) = crate::progress::Reporter::channel();
+        let mut scanner = scanner.with_progress(reporter);
+        let editor = std::fs::File::open(root.join("doc.txt")).unwrap();
+        editor.lock().unwrap();
+        replica.put("doc.txt", entry(b"new")).unwrap();
+        for attempts in 1..=2 {
+            let report = scanner.checkout(&replica, fetch).await.unwrap();
+            assert_eq!(report.in_use, vec!["doc.txt"]);
+            assert_eq!(scanner.deferred()["doc.txt"].attempts, attempts);
+        }
+        assert_eq!(std::fs::read(root.join("doc.txt")).unwrap(), b"old");
+        let deferrals: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                Progress::Deferred { retry_in, .. } => Some(retry_in),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deferrals, [FIRST_RETRY_INTERVAL, FIRST_RETRY_INTERVAL * 2]);
+
+        // once the lock is let go the retry goes through
+        editor.unlock().unwrap();
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.written, vec!["doc.txt"]);
+        assert!(scanner.next_retry().is_none());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+//! An on-disk cache of fetched blocks, file content and DAG nodes alike,
+//! so checking out again or browsing history doesn't go back to the
+//! daemon. Blocks are content-addressed, so a cached one never goes stale;
+//! each is a file named by its CID in the cache's directory, by default
+//! one under the platform's cache directory; see `dirs`. The cache keeps to
+//! a size cap by evicting the least recently used blocks; a block's mtime
+//! is when it was last read from disk, so the order mostly survives
+//! restarts. In front of the disk,
+//! a smaller LRU in memory holds the blocks used most lately, the DAG
+//! nodes a merge walks again and again among them. Content is cached
+//! decrypted, so with a key the files on disk are sealed; see `at_rest`.
+
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use super::scan::Scanner;
+use crate::at_rest::{open_file, seal_file};
+use crate::crypto::WorkspaceKey;
+use crate::dirs::default_cache_dir;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// Bytes of blocks held in memory by default.
+pub const DEFAULT_MEMORY_BYTES: u64 = 8 << 20;
+
+/// The block cache in a directory. Clones share it.
+#[derive(Debug, Clone)]
+pub struct BlockCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    lru: Arc<Mutex<Lru>>,
+    memory: Arc<Mutex<Memory>>,
+    /// What the files on disk are sealed under, if anything.
+    key: Option<WorkspaceKey>,
+}
+
+/// Counters describing how lookups in the cache went so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheMetrics {
+    /// Lookups answered from memory.
+    pub memory_hits: u64,
+    /// Lookups answered from disk.
+    pub disk_hits: u64,
+    /// Lookups the cache couldn't answer.
+    pub misses: u64,
+}
+
+impl CacheMetrics {
+    /// Share of lookups answered, from memory or disk; `None` before any.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let hits = self.memory_hits + self.disk_hits;
+        let lookups = hits + self.misses;
+        (lookups > 0).then(|| hits as f64 / lookups as f64)
+    }
+}
+
+/// The blocks held in memory.
+#[derive(Debug, Default)]
+struct Memory {
+    max_bytes: u64,
+    lru: Lru,
+    blocks: HashMap<IpfsCid, Vec<u8>>,
+    metrics: CacheMetrics,
+}
+
+impl Memory {
+    fn insert(&mut self, cid: &IpfsCid, bytes: &[u8]) {
+        if bytes.len() as u64 > self.max_bytes {
+            return;
+        }
+        self.lru.insert(cid.clone(), bytes.len() as u64);
+        self.blocks.insert(cid.clone(), bytes.to_vec());
+        while self.lru.bytes > self.max_bytes {
+            let Some(oldest) = self.lru.pop_oldest() else {
+                break;
+            };
+            self.blocks.remove(&oldest);
+        }
+    }
+}
+
+/// Cached blocks by last use.
+#[derive(Debug, Default)]
+struct Lru {
+    blocks: HashMap<IpfsCid, (u64, u64)>,
+    by_use: BTreeMap<u64, IpfsCid>,
+    bytes: u64,
+    clock: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, cid: &IpfsCid) {
+        self.clock += 1;
+        if let Some((_, used)) = self.blocks.get_mut(cid) {
+            self.by_use.remove(used);
+            *used = self.clock;
+            self.by_use.insert(self.clock, cid.clone());
+        }
+    }
+
+    fn insert(&mut self, cid: IpfsCid, size: u64) {
+        self.remove(&cid);
+        self.clock += 1;
+        self.blocks.insert(cid.clone(), (size, self.clock));
+        self.by_use.insert(self.clock, cid);
+        self.bytes += size;
+    }
+
+    fn remove(&mut self, cid: &IpfsCid) {
+        if let Some((size, used)) = self.blocks.remove(cid) {
+            self.by_use.remove(&used);
+            self.bytes -= size;
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<IpfsCid> {
+        let (_, cid) = self.by_use.pop_first()?;
+        self.remove(&cid);
+        Some(cid)
+    }
+}
+
+impl BlockCache {
+    /// Opens the cache in `dir`, creating it if needed, holding at most
+    /// `max_bytes` of blocks.
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Failed to list {}", dir.display()))? {
+            let entry = entry?;
+            let Some(cid) = entry.file_name().to_str().and_then(|name| IpfsCid::from_str(name).ok()) else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            found.push((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), cid, metadata.len()));
+        }
+        found.sort();
+        let mut lru = Lru::default();
+        for (_, cid, size) in found {
+            lru.insert(cid, size);
+        }
+        let memory = Memory { max_bytes: DEFAULT_MEMORY_BYTES, ..Memory::default() };
+        let cache = BlockCache { dir, max_bytes, lru: Arc::new(Mutex::new(lru)), memory: Arc::new(Mutex::new(memory)), key: None };
+        cache.evict()?;
+        Ok(cache)
+    }
+
+    /// Holds at most `max_bytes` of the blocks used most lately in memory
+    /// too; `DEFAULT_MEMORY_BYTES` by default, none with 0.
+    pub fn with_memory(self, max_bytes: u64) -> Self {
+        let mut memory = self.memory.lock().unwrap();
+        *memory = Memory { max_bytes, metrics: memory.metrics, ..Memory::default() };
+        drop(memory);
+        self
+    }
+
+    /// Seals the blocks written to disk under `key`, an at-rest key. Blocks
+    /// cached before are still read; ones sealed under another key are
+    /// dropped as they are read.
+    pub fn with_encryption(mut self, key: WorkspaceKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether the block `cid` is cached, without reading it.
+    pub fn contains(&self, cid: &IpfsCid) -> bool {
+        self.lru.lock().unwrap().blocks.contains_key(cid)
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        self.memory.lock().unwrap().metrics
+    }
+
+    /// Bytes of blocks held.
+    pub fn size(&self) -> u64 {
+        self.lru.lock().unwrap().bytes
+    }
+
+    /// Blocks held.
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes of blocks held at most.
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// The block `cid`, if it is cached.
+    pub fn get(&self, cid: &IpfsCid) -> Option<Vec<u8>> {
+        {
+            let mut memory = self.memory.lock().unwrap();
+            if let Some(bytes) = memory.blocks.get(cid).cloned() {
+                memory.lru.touch(cid);
+                memory.metrics.memory_hits += 1;
+                drop(memory);
+                self.lru.lock().unwrap().touch(cid);
+                return Some(bytes);
+            }
+        }
+        let path = self.dir.join(cid.to_string());
+        let Ok(bytes) = std::fs::read(&path).map_err(anyhow::Error::from).and_then(|bytes| open_file(self.key.as_ref(), bytes)) else {
+            let _ = std::fs::remove_file(&path);
+            self.lru.lock().unwrap().remove(cid);
+            self.memory.lock().unwrap().metrics.misses += 1;
+            return None;
+        };
+        self.lru.lock().unwrap().touch(cid);
+        if let Ok(file) = std::fs::File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        let mut memory = self.memory.lock().unwrap();
+        memory.metrics.disk_hits += 1;
+        memory.insert(cid, &bytes);
+        Some(bytes)
+    }
+
+    /// Caches the block `bytes` as `cid`, evicting the least recently used
+    /// blocks to make room. One bigger than the cache isn't kept.
+    pub fn insert(&self, cid: &IpfsCid, bytes: &[u8]) -> Result<()> {
+        if bytes.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+        let path = self.dir.join(cid.to_string());
+        let temp = path.with_extension("tmp");
+        let sealed = seal_file(self.key.as_ref(), bytes);
+        std::fs::write(&temp, &sealed)
+            .and_then(|_| std::fs::rename(&temp, &path))
+            .with_context(|| format!("Failed to cache block {}", cid))?;
+        self.lru.lock().unwrap().insert(cid.clone(), sealed.len() as u64);
+        self.memory.lock().unwrap().insert(cid, bytes);
+        self.evict()
+    }
+
+    /// The block `cid` from the cache, or from `fetch`, caching it.
+    pub async fn fetch<F>(&self, cid: IpfsCid, fetch: F) -> Result<Vec<u8>>
+    where
+        F: AsyncFnOnce(IpfsCid) -> Result<Vec<u8>>,
+    {
+        if let Some(bytes) = self.get(&cid) {
+            return Ok(bytes);
+        }
+        let bytes = fetch(cid.clone()).await?;
+        self.insert(&cid, &bytes)?;
+        Ok(bytes)
+    }
+
+    fn evict(&self) -> Result<()> {
+        let mut lru = self.lru.lock().unwrap();
+        while lru.bytes > self.max_bytes {
+            let Some(cid) = lru.pop_oldest() else {
+                break;
+            };
+            let mut memory = self.memory.lock().unwrap();
+            memory.lru.remove(&cid);
+            memory.blocks.remove(&cid);
+            drop(memory);
+            match std::fs::remove_file(self.dir.join(cid.to_string())) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(err).with_context(|| format!("Failed to evict block {}", cid));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The block `cid` from `cache`, if any, or from `fetch`.
+pub(crate) async fn fetch_cached<F>(cache: Option<&BlockCache>, cid: IpfsCid, fetch: F) -> Result<Vec<u8>>
+where
+    F: AsyncFnOnce(IpfsCid) -> Result<Vec<u8>>,
+{
+    match cache {
+        Some(cache) => cache.fetch(cid, fetch).await,
+        None => fetch(cid).await,
+    }
+}
+
+impl Scanner {
+    /// Caches the content checkouts fetch, and the nodes `diff_from`
+    /// fetches, holding at most `max_bytes`, in the default cache
+    /// directory of the scanned directory; see `dirs::default_cache_dir`.
+    pub fn with_block_cache(self, max_bytes: u64) -> Result<Self> {
+        let dir = default_cache_dir(&self.root)?;
+        self.with_block_cache_in(dir, max_bytes)
+    }
+
+    /// `with_block_cache` keeping the cache in `dir`.
+    pub fn with_block_cache_in(mut self, dir: impl Into<PathBuf>, max_bytes: u64) -> Result<Self> {
+        self.cache = Some(BlockCache::open(dir, max_bytes)?);
+        Ok(self)
+    }
+
+    pub fn block_cache(&self) -> Option<&BlockCache> {
+        self.cache.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod cache_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use anyhow::anyhow;
+
+    #[tokio::test]
+    async fn test_cache_evicts_least_recently_used() {
+        let dir = std::env::temp_dir().join(format!("crdt-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let blocks: Vec<(IpfsCid, Vec<u8>)> =
+            (0..3u8).map(|i| vec![i; 100]).map(|data| (IpfsCid::compute(RAW_CODE, &data), data)).collect();
+        let cache = BlockCache::open(&dir, 250).unwrap();
+        for (cid, data) in &blocks[..2] {
+            assert_eq!(cache.fetch(cid.clone(), async |_| Ok(data.clone())).await.unwrap(), *data);
+        }
+        // a hit doesn't fetch, and makes the first block the latest used
+        let offline = async |cid: IpfsCid| -> Result<Vec<u8>> { Err(anyhow!("missing {}", cid)) };
+        assert_eq!(cache.fetch(blocks[0].0.clone(), offline).await.unwrap(), blocks[0].1);
+        cache.insert(&blocks[2].0, &blocks[2].1).unwrap();
+        assert_eq!(cache.size(), 200);
+        assert!(cache.get(&blocks[1].0).is_none());
+        assert!(cache.get(&blocks[0].0).is_some());
+
+        // after a restart, the block read from disk longest ago goes first
+        let file = std::fs::File::options().write(true).open(dir.join(blocks[2].0.to_string())).unwrap();
+        file.set_modified(SystemTime::now() - std::time::Duration::from_secs(3600)).unwrap();
+        let reopened = BlockCache::open(&dir, 100).unwrap();
+        assert_eq!(reopened.size(), 100);
+        assert!(reopened.get(&blocks[0].0).is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_answers_before_disk() {
+        let dir = std::env::temp_dir().join(format!("crdt-cache-memory-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let blocks: Vec<(IpfsCid, Vec<u8>)> =
+            (0..2u8).map(|i| vec![i; 100]).map(|data| (IpfsCid::compute(RAW_CODE, &data), data)).collect();
+        let cache = BlockCache::open(&dir, 1000).unwrap().with_memory(150);
+        cache.insert(&blocks[0].0, &blocks[0].1).unwrap();
+        assert_eq!(cache.get(&blocks[0].0), Some(blocks[0].1.clone()));
+        // pushes the first block out of memory only
+        cache.insert(&blocks[1].0, &blocks[1].1).unwrap();
+        assert_eq!(cache.get(&blocks[0].0), Some(blocks[0].1.clone()));
+        assert_eq!(cache.get(&IpfsCid::compute(RAW_CODE, b"never cached")), None);
+        assert_eq!(cache.metrics(), CacheMetrics { memory_hits: 1, disk_hits: 1, misses: 1 });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
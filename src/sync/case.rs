@@ -0,0 +1,255 @@
+//! Paths that differ only in case, like `README.md` and `readme.md`, name
+//! one file on a filesystem that folds case, as on macOS and Windows, so
+//! a checkout would write one over the other. Instead the first of them in
+//! byte order keeps its name and the others are written as
+//! `readme.case1.md` and so on; the index remembers the names, so scans
+//! commit edits to those files under their workspace paths.
+
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+use super::checkout::TEMP_SUFFIX;
+use super::portable::windows_path;
+use super::scan::{Found, Scanner};
+use crate::crdt::materialize::safe_join;
+use crate::crdt::replica::Replica;
+
+/// Workspace paths that name the same file where case is folded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseCollision {
+    /// Checked out under its own name.
+    pub kept: String,
+    /// The others, with the names they were checked out under.
+    pub renamed: Vec<(String, String)>,
+}
+
+impl Scanner {
+    /// Treats the filesystem as folding case, or not, instead of finding
+    /// out on the first checkout.
+    pub fn with_case_insensitive(mut self, folds: bool) -> Self {
+        self.case_insensitive = Some(folds);
+        self
+    }
+
+    /// Works out the names the checkout writes `paths` under, into the
+    /// index: files spelled in another Unicode form on disk, and files in
+    /// directories that are, keep that spelling; paths Windows can't hold
+    /// get names it can, if the scanner keeps to those; and paths whose
+    /// names collide, where case is folded or after renaming, get numbered
+    /// ones. Returns the collisions where
+    /// case is folded and the paths renamed for Windows. A file checked
+    /// out under another name before is moved to the new one, or deleted to
+    /// be written afresh if that is taken, unless it changed on disk since;
+    /// then it stays where it is until a scan has committed it.
+    pub(crate) async fn rename_paths(
+        &mut self,
+        replica: &Replica,
+        paths: &[&str],
+    ) -> Result<(Vec<CaseCollision>, Vec<(String, String)>)> {
+        let folds = match self.case_insensitive {
+            Some(folds) => folds,
+            None => *self.case_insensitive.insert(folds_case(&self.root).await?),
+        };
+        let key = |name: &str| if folds { name.to_lowercase() } else { name.to_string() };
+        let mut renamed: BTreeMap<String, String> = BTreeMap::new();
+        let spelled: BTreeMap<&str, &str> = self
+            .index
+            .renamed
+            .iter()
+            .filter(|(path, name)| self.is_spelling(path, name))
+            .map(|(path, name)| (path.as_str(), name.as_str()))
+            .collect();
+        let mut dirs: HashMap<&str, &str> = HashMap::new();
+        for (path, name) in &spelled {
+            for ((end, _), (name_end, _)) in path.match_indices('/').zip(name.match_indices('/')) {
+                dirs.insert(&path[..end], &name[..name_end]);
+            }
+        }
+        for path in paths {
+            if let Some(name) = spelled.get(path) {
+                renamed.insert(path.to_string(), name.to_string());
+            } else if let Some((end, _)) = path.rmatch_indices('/').find(|(end, _)| dirs.contains_key(&path[..*end])) {
+                renamed.insert(path.to_string(), format!("{}{}", dirs[&path[..end]], &path[end..]));
+            }
+        }
+        if self.windows_names {
+            renamed.extend(paths.iter().filter_map(|path| Some((path.to_string(), windows_path(path)?))));
+        }
+        let mut groups: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        for path in paths {
+            groups.entry(key(renamed.get(*path).map_or(path, String::as_str))).or_default().push(path);
+        }
+        let mut taken: HashSet<String> = replica.state().iter().map(|(path, _)| key(path)).collect();
+        taken.extend(renamed.values().map(|name| key(name)));
+        let mut collisions = Vec::new();
+        for mut group in groups.into_values().filter(|group| group.len() > 1) {
+            // a path written under its own name keeps it
+            group.sort_unstable_by_key(|path| (renamed.contains_key(*path), *path));
+            let mut collision = CaseCollision { kept: group[0].to_string(), renamed: Vec::new() };
+            for path in &group[1..] {
+                let placed = renamed.get(*path).map_or(*path, String::as_str);
+                let name = (1..).map(|n| case_name(placed, n)).find(|name| taken.insert(key(name))).expect("a free name");
+                renamed.insert(path.to_string(), name.clone());
+                collision.renamed.push((path.to_string(), name));
+            }
+            if folds {
+                collisions.push(collision);
+            }
+        }
+        let windows_names: Vec<(String, String)> = renamed
+            .iter()
+            .filter(|(path, _)| self.windows_names && windows_path(path).is_some())
+            .map(|(path, name)| (path.clone(), name.clone()))
+            .collect();
+
+        let moved: Vec<(String, String)> = self
+            .index
+            .renamed
+            .iter()
+            .map(|(path, name)| (path.clone(), name.clone()))
+            .chain(
+                self.index
+                    .files
+                    .keys()
+                    .filter(|path| renamed.contains_key(*path) && !self.index.renamed.contains_key(*path))
+                    .map(|path| (path.clone(), path.clone())),
+            )
+            .filter(|(path, name)| renamed.get(path).unwrap_or(path) != name)
+            .collect();
+        for (path, name) in moved {
+            let local = safe_join(&self.root, &name)?;
+            match tokio::fs::symlink_metadata(&local).await {
+                Ok(metadata) if metadata.is_file() && self.index.get(&path).is_some_and(|indexed| indexed.matches(&metadata)) => {
+                    let dest = safe_join(&self.root, renamed.get(&path).unwrap_or(&path))?;
+                    if tokio::fs::symlink_metadata(&dest).await.is_err() {
+                        if let Some(parent) = dest.parent() {
+                            tokio::fs::create_dir_all(parent).await?;
+                        }
+                        tokio::fs::rename(&local, &dest)
+                            .await
+                            .with_context(|| format!("Failed to move {} to {}", local.display(), dest.display()))?;
+                    } else {
+                        tokio::fs::remove_file(&local)
+                            .await
+                            .with_context(|| format!("Failed to remove {}", local.display()))?;
+                        self.index.forget(&path);
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err).with_context(|| format!("Failed to stat {}", local.display())),
+                // changed since: left where it is
+                Ok(_) if name == path => {
+                    renamed.remove(&path);
+                }
+                Ok(_) => {
+                    renamed.insert(path, name);
+                }
+            }
+        }
+        self.index.renamed = renamed;
+        Ok((collisions, windows_names))
+    }
+
+    /// Gives files found under the names a checkout wrote them as their
+    /// workspace paths back.
+    pub(crate) fn restore_renamed(&self, found: &mut [Found]) {
+        if self.index.renamed.is_empty() {
+            return;
+        }
+        // the filesystem may have kept the case of a directory differently
+        let paths: HashMap<String, &String> =
+            self.index.renamed.iter().map(|(path, name)| (name.to_lowercase(), path)).collect();
+        for file in found {
+            if let Some(path) = paths.get(&file.path.to_lowercase()) {
+                file.path = path.to_string();
+            }
+        }
+    }
+}
+
+/// The `n`th name for a file at `path` whose name collides with another's.
+fn case_name(path: &str, n: usize) -> String {
+    let (dir, name) = path.rsplit_once('/').map_or(("", path), |(dir, name)| (dir, name));
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    let renamed = format!("{}.case{}{}", stem, n, ext);
+    if dir.is_empty() { renamed } else { format!("{}/{}", dir, renamed) }
+}
+
+/// Whether the filesystem at `dir` folds case, found by creating a file
+/// and looking it up in upper case.
+async fn folds_case(dir: &Path) -> Result<bool> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    let mut nonce = [0u8; 8];
+    getrandom::getrandom(&mut nonce).map_err(|e| anyhow::anyhow!("Failed to gather randomness: {}", e))?;
+    let name = format!(".case-probe.{:016x}{}", u64::from_le_bytes(nonce), TEMP_SUFFIX);
+    let probe = dir.join(&name);
+    tokio::fs::File::create_new(&probe)
+        .await
+        .with_context(|| format!("Failed to create {}", probe.display()))?;
+    let folds = tokio::fs::symlink_metadata(dir.join(name.to_uppercase())).await.is_ok();
+    tokio::fs::remove_file(&probe)
+        .await
+        .with_context(|| format!("Failed to remove {}", probe.display()))?;
+    Ok(folds)
+}
+
+#[cfg(test)]
+mod case_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+    use crate::test_util::{alice, entry};
+    use anyhow::anyhow;
+    use bytes::Bytes;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_case_names() {
+        assert_eq!(case_name("readme.md", 1), "readme.case1.md");
+        assert_eq!(case_name("docs/Makefile", 2), "docs/Makefile.case2");
+        assert_eq!(case_name(".env", 1), ".env.case1");
+    }
+
+    #[tokio::test]
+    async fn test_colliding_paths_are_renamed() {
+        let mut replica = Replica::new(alice());
+        let blobs: HashMap<IpfsCid, Vec<u8>> =
+            [b"upper".to_vec(), b"lower".to_vec()].into_iter().map(|data| (IpfsCid::compute(RAW_CODE, &data), data)).collect();
+        let entry = |data: &[u8]| {
+            entry(data)
+        };
+        replica.put("README.md", entry(b"upper")).unwrap();
+        replica.put("readme.md", entry(b"lower")).unwrap();
+
+        let root = std::env::temp_dir().join(format!("crdt-case-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        // a case-sensitive filesystem told to act as one that folds
+        let mut scanner = Scanner::new(&root).with_case_insensitive(true);
+        let fetch = async |cid: IpfsCid| blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        let renamed = vec![("readme.md".to_string(), "readme.case1.md".to_string())];
+        assert_eq!(report.case_collisions, vec![CaseCollision { kept: "README.md".to_string(), renamed }]);
+        assert_eq!(std::fs::read(root.join("readme.case1.md")).unwrap(), b"lower");
+
+        // edits land under the workspace path
+        let local = root.join("readme.case1.md");
+        std::fs::write(&local, b"edited").unwrap();
+        std::fs::File::options().write(true).open(&local).unwrap().set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!((report.put, report.removed), (vec!["readme.md".to_string()], Vec::new()));
+
+        // once the collision is gone the file takes its own name
+        replica.remove("README.md").unwrap();
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert!(report.case_collisions.is_empty());
+        assert!(!local.exists() && !root.join("README.md").exists());
+        assert_eq!(std::fs::read(root.join("readme.md")).unwrap(), b"edited");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
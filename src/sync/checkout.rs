@@ -0,0 +1,903 @@
+//! The "pull" half of syncing a working directory: write, rewrite and
+//! delete local files until the directory matches a replica's state. Only
+//! files the scan index vouches for are ever overwritten or deleted, so a
+//! change made on disk since the last scan is never lost; scan it first.
+//! Files are written to a temporary file and renamed into place, so a
+//! reader sees the old content or the new and never part of either. Their
+//! chunks are fetched straight into it, each written at its offset as it
+//! arrives, so a file never has to fit in memory.
+//! Symlinks are written as the scanner's `SymlinkPolicy` says; a copy or
+//! a gap left in place of one is remembered in the index as its stand-in,
+//! which scans don't commit. The index is saved as batches of files are
+//! written, so an interrupted checkout picks up where it left off; a file
+//! written after the last save is found to have the content wanted and
+//! kept rather than fetched again.
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::busy::{failed_in_use, in_use};
+use super::cache::fetch_cached;
+use super::case::CaseCollision;
+use super::filter::FileInfo;
+use super::hooks::ChangeSet;
+use super::index::{IndexEntry, ScanIndex};
+use super::scan::{mode_of, Scanner};
+use super::sparse::in_sparse;
+use crate::crdt::history::path_matches;
+use crate::crdt::materialize::{create_symlink, refuse_symlinked_parents, safe_join, symlinks_unavailable, system_time};
+use crate::crdt::op::Entry;
+use crate::crdt::replica::Replica;
+use crate::crdt::state::State;
+use crate::crdt::upload::same_content;
+use crate::durability::Durability;
+use crate::kubo_rpc::ipfs::IpfsCid;
+use crate::progress::Progress;
+
+/// Suffix of the temporary files a checkout writes, which scans skip.
+pub const TEMP_SUFFIX: &str = ".crdt-tmp";
+/// Files a checkout writes between saves of the scan index.
+pub const CHECKPOINT_FILES: usize = 64;
+/// Longest chain of symlinks to symlinks a dereferencing checkout follows.
+const MAX_LINK_HOPS: usize = 40;
+
+/// What a checkout writes for a symlink in the workspace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// A symlink, or nothing where this process can't create one, as on
+    /// Windows without the privilege.
+    #[default]
+    Preserve,
+    /// A copy of the file the link resolves to within the workspace, or
+    /// nothing if it resolves to something else.
+    Dereference,
+    Skip,
+}
+
+/// What a checkout did, file by file.
+#[derive(Debug, Default)]
+pub struct CheckoutReport {
+    /// Files and symlinks created or rewritten, or whose mode changed.
+    pub written: Vec<String>,
+    pub removed: Vec<String>,
+    pub unchanged: usize,
+    /// Files left alone because they changed on disk since the last scan.
+    pub local_changes: Vec<String>,
+    /// Files whose concurrent versions were left in the conflict
+    /// quarantine, see `Scanner::conflicts`.
+    pub quarantined: Vec<String>,
+    /// Symlinks left off disk, with why.
+    pub skipped_links: Vec<(String, String)>,
+    /// Paths written under other names, as the filesystem folds case.
+    pub case_collisions: Vec<CaseCollision>,
+    /// Paths Windows can't hold, with the names they were written under.
+    pub windows_names: Vec<(String, String)>,
+    /// Files left as they were because another program had them in use;
+    /// see `Scanner::deferred`.
+    pub in_use: Vec<String>,
+    /// Files left out because they didn't fit on disk, under
+    /// `SpaceCheck::Partial`.
+    pub no_space: Vec<String>,
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+/// What became of one path.
+enum Outcome {
+    Written,
+    Removed,
+    Unchanged,
+    LocalChanges,
+    InUse,
+    SkippedLink(String),
+}
+
+impl CheckoutReport {
+    fn add(&mut self, path: &str, outcome: Result<Outcome>) {
+        match outcome {
+            Ok(Outcome::Written) => self.written.push(path.to_string()),
+            Ok(Outcome::Removed) => self.removed.push(path.to_string()),
+            Ok(Outcome::Unchanged) => self.unchanged += 1,
+            Ok(Outcome::LocalChanges) => self.local_changes.push(path.to_string()),
+            Ok(Outcome::InUse) => self.in_use.push(path.to_string()),
+            Ok(Outcome::SkippedLink(why)) => self.skipped_links.push((path.to_string(), why)),
+            Err(err) => self.failed.push((path.to_string(), err)),
+        }
+    }
+}
+
+impl Scanner {
+    /// Makes the directory match `replica`'s state, fetching content with
+    /// `fetch`. Paths that the scanner's rules or the workspace's ignore
+    /// files ignore, or that its filter leaves out, are left alone, as are
+    /// symlinks the workspace no longer has; paths outside a sparse
+    /// checkout aren't written, see `Scanner::with_sparse`. Several files
+    /// are fetched at once, in the scanner's priority order. A checkout
+    /// whose content doesn't fit on disk fails before writing anything,
+    /// see `Scanner::with_space_check`. A file that can't be written is
+    /// reported and the rest carry on.
+    pub async fn checkout<F>(&mut self, replica: &Replica, mut fetch: F) -> Result<CheckoutReport>
+    where
+        F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+    {
+        self.mode.check_pull()?;
+        let mut report = CheckoutReport::default();
+        let scanned = SystemTime::now();
+        let mut rules = self.ignore.clone();
+        rules.load_from_state(replica.state(), &mut fetch).await?;
+        let kept = |path: &str, info: &FileInfo| {
+            path_matches(replica.scope(), path) && !rules.is_ignored(path, false) && self.filter.allows(info)
+        };
+        let (links, files): (Vec<_>, Vec<_>) = replica
+            .state()
+            .iter()
+            .filter(|(path, entry)| kept(path, &FileInfo::of_entry(path, entry)) && in_sparse(&self.sparse, path))
+            .map(|(path, entry)| (path.to_string(), entry))
+            .partition(|(_, entry)| entry.is_symlink());
+        // stand-ins are settled afresh: copies are vouched for as files until
+        // a link takes them back, but stay stand-ins in the saves until then
+        for (path, copy) in self.index.stand_ins.clone() {
+            if let Some(copy) = copy {
+                self.index.files.insert(path, copy);
+            }
+        }
+        // files synced before that the workspace no longer has, or that
+        // aren't selected now
+        let mut gone: Vec<String> = self
+            .index
+            .files
+            .iter()
+            .filter(|(path, _)| path_matches(replica.scope(), path) && !rules.is_ignored(path, false))
+            .filter(|(path, indexed)| match replica.state().get(path) {
+                Some(entry) => !in_sparse(&self.sparse, path) || !self.filter.allows(&FileInfo::of_entry(path, &entry)),
+                None => self.filter.allows(&FileInfo { path, size: indexed.size, symlink: false }),
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        let paths: Vec<&str> = files.iter().chain(&links).map(|(path, _)| path.as_str()).collect();
+        (report.case_collisions, report.windows_names) = self.rename_paths(replica, &paths).await?;
+        tokio::fs::create_dir_all(&self.root)
+            .await
+            .with_context(|| format!("Failed to create {}", self.root.display()))?;
+
+        let mut stale: BTreeSet<String> = self.conflicts().await?.into_iter().map(|manifest| manifest.path).collect();
+        let mut files = files;
+        self.priority.sort(&mut files, |(path, entry)| (path, entry.size, entry.mtime));
+        // hooks may hold files back, which later checkouts find again
+        let vouched = |index: &ScanIndex, path: &str, entry: &Entry| index.get(path).is_some_and(|indexed| indexed.content == entry.content);
+        let mut changes = ChangeSet {
+            put: files.iter().filter(|(path, entry)| !vouched(&self.index, path, entry)).map(|(path, _)| path.clone()).collect(),
+            patched: Vec::new(),
+            removed: gone.clone(),
+        };
+        self.pre_checkout(&mut changes).await?;
+        let (put, removed): (HashSet<&String>, HashSet<&String>) = (changes.put.iter().collect(), changes.removed.iter().collect());
+        files.retain(|(path, entry)| put.contains(path) || vouched(&self.index, path, entry));
+        gone.retain(|path| removed.contains(path));
+        report.no_space = self.check_space(&mut files, |path, entry| !vouched(&self.index, path, entry))?;
+        let total = files.len() + links.len();
+        self.progress.emit(|| Progress::CheckoutStarted { paths: total });
+        let checked_out = &Cell::new(0);
+        for batch in files.chunks(CHECKPOINT_FILES) {
+            let (scanner, fetch_one) = (&*self, &fetch);
+            let done: Vec<_> = stream::iter(batch)
+                .map(|(path, entry)| async move {
+                    let quarantined = scanner.quarantine(replica, path, &mut { fetch_one }).await;
+                    let outcome = match scanner.checkout_file(replica, path, entry, fetch_one).await {
+                        Ok((outcome @ (Outcome::Written | Outcome::Unchanged), metadata)) => scanner
+                            .restore_xattrs(replica, path)
+                            .map(|changed| (if changed { Outcome::Written } else { outcome }, metadata)),
+                        other => other,
+                    };
+                    checked_out.set(checked_out.get() + 1);
+                    scanner.progress.emit(|| Progress::CheckedOut { path: path.clone(), done: checked_out.get(), total });
+                    (path, entry, quarantined, outcome)
+                })
+                .buffered(self.downloads)
+                .collect()
+                .await;
+            let mut written = Vec::new();
+            for (path, entry, quarantined, outcome) in done {
+                match quarantined {
+                    Ok(true) => report.quarantined.push(path.clone()),
+                    Ok(false) => {}
+                    Err(err) => report.failed.push((path.clone(), err)),
+                }
+                stale.remove(path);
+                let outcome = outcome.map(|(outcome, metadata)| {
+                    if let Some(metadata) = metadata {
+                        self.index.record(path, &metadata, entry.content.clone(), scanned);
+                    }
+                    if matches!(outcome, Outcome::Written) {
+                        written.push(path.as_str());
+                    }
+                    outcome
+                });
+                report.add(path, outcome);
+            }
+            self.checkpoint(&written).await?;
+        }
+        // after the files, so none can redirect a write
+        self.index.stand_ins.clear();
+        for (path, entry) in &links {
+            let outcome = self.checkout_link(replica, path, entry, &fetch).await;
+            report.add(path, outcome);
+            checked_out.set(checked_out.get() + 1);
+            self.progress.emit(|| Progress::CheckedOut { path: path.clone(), done: checked_out.get(), total });
+        }
+        for path in &gone {
+            // deleted in the workspace, not just left out
+            let deleted = replica.state().get(path).is_none();
+            let outcome = self.remove_file(path, deleted.then_some(scanned)).await;
+            report.add(path, outcome);
+        }
+        self.expire_trash(scanned).await?;
+        self.defer(&report);
+        self.post_checkout(&report).await?;
+        // settled since, here or by another member
+        for path in stale.iter().filter(|path| !report.quarantined.contains(path)) {
+            self.clear_quarantine(path).await?;
+        }
+        self.checkpoint(&[]).await?;
+        self.progress.emit(|| Progress::CheckoutFinished { written: report.written.len(), removed: report.removed.len() });
+        Ok(report)
+    }
+
+    /// `checkout` fetching content from the IPFS daemon at `base_url`,
+    /// decrypting it if the replica has a workspace key, unless the block
+    /// cache holds it.
+    pub async fn checkout_from(&mut self, base_url: &str, replica: &Replica) -> Result<CheckoutReport> {
+        let (bandwidth, cache) = (self.bandwidth.clone(), self.cache.clone());
+        self.checkout(replica, async |cid| {
+            fetch_cached(cache.as_ref(), cid, async |cid| {
+                let data = replica.fetch_content(base_url, &cid).await?;
+                bandwidth.download(data.len()).await;
+                Ok(data)
+            })
+            .await
+        })
+        .await
+    }
+
+    /// Brings the file at `path` up to date, returning what became of it
+    /// and, for the index, the stat data it has now.
+    async fn checkout_file<F>(&self, replica: &Replica, path: &str, entry: &Entry, fetch: &F) -> Result<(Outcome, Option<Metadata>)>
+    where
+        F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let dest = self.local_path(path).await?;
+        let local = match tokio::fs::symlink_metadata(&dest).await {
+            Ok(metadata) if metadata.is_dir() => bail!("{} is a directory", dest.display()),
+            Ok(metadata) => Some(metadata),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err).with_context(|| format!("Failed to stat {}", dest.display())),
+        };
+        if let Some(metadata) = local.as_ref().filter(|metadata| metadata.is_file()) {
+            let up_to_date = match self.index.get(path).filter(|indexed| indexed.matches(metadata)) {
+                Some(indexed) => indexed.content == entry.content,
+                None => {
+                    let file = tokio::fs::File::open(&dest)
+                        .await
+                        .with_context(|| format!("Failed to read {}", dest.display()))?;
+                    let ignore = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+                    let (on_disk, _, _) = replica.store_stream(path, file, entry.mode, entry.mtime, &ignore).await?;
+                    if !same_content(&on_disk, entry) {
+                        return Ok((Outcome::LocalChanges, None));
+                    }
+                    true
+                }
+            };
+            if up_to_date {
+                let outcome = if !self.keep_modes || mode_of(metadata) == entry.mode {
+                    Outcome::Unchanged
+                } else {
+                    set_mode(&dest, entry.mode).await?;
+                    Outcome::Written
+                };
+                return Ok((outcome, Some(tokio::fs::symlink_metadata(&dest).await?)));
+            }
+        }
+
+        if local.as_ref().is_some_and(Metadata::is_file) && in_use(&dest).await {
+            return Ok((Outcome::InUse, None));
+        }
+        let written = self.fetch_file(path, &dest, entry, fetch).await;
+        if failed_in_use(&written) {
+            return Ok((Outcome::InUse, None));
+        }
+        Ok((Outcome::Written, Some(written?)))
+    }
+
+    /// Fetches the content of `entry`, the workspace's at `path`, into
+    /// `dest` by way of a temporary file, returning the stat data it has
+    /// then. Errors fetching the content are `Unfetched`.
+    pub(crate) async fn fetch_file<F>(&self, path: &str, dest: &Path, entry: &Entry, fetch: &F) -> Result<Metadata>
+    where
+        F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+    {
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // renaming replaces a symlink itself, not what it points at
+        let temp = temp_path(dest)?;
+        if let Err(err) = self.fetch_replacing(path, &temp, dest, entry, fetch).await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(err);
+        }
+        Ok(tokio::fs::symlink_metadata(dest).await?)
+    }
+
+    /// Saves the index, syncing the files at `written` and their directories
+    /// first if the scanner syncs at checkpoints rather than each write, so
+    /// the index never vouches for content a crash could still take back.
+    pub(crate) async fn checkpoint(&self, written: &[&str]) -> Result<()> {
+        if self.durability == Durability::Checkpoint {
+            let mut dirs = BTreeSet::new();
+            for path in written {
+                let local = self.local_path(path).await?;
+                let file = tokio::fs::File::open(&local)
+                    .await
+                    .with_context(|| format!("Failed to open {}", local.display()))?;
+                file.sync_all().await.with_context(|| format!("Failed to sync {}", local.display()))?;
+                dirs.insert(local.parent().unwrap_or(&self.root).to_path_buf());
+            }
+            for dir in &dirs {
+                sync_dir(dir).await?;
+            }
+        }
+        if let Some(path) = &self.index_path {
+            self.index.save_sealed(path, self.durability, self.index_key.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Fetches `entry` into `temp` and renames it over `dest`, syncing and
+    /// applying `entry`'s mode and mtime as the scanner is set to.
+    async fn fetch_replacing<F>(&self, path: &str, temp: &Path, dest: &Path, entry: &Entry, fetch: &F) -> Result<()>
+    where
+        F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let file = tokio::fs::File::create_new(temp)
+            .await
+            .with_context(|| format!("Failed to create {}", temp.display()))?;
+        let file = self
+            .fetch_into(path, file.into_std().await, entry, fetch)
+            .await
+            .with_context(|| format!("Failed to write {}", temp.display()))?;
+        let file = tokio::fs::File::from_std(file);
+        if self.durability.syncs_writes() {
+            file.sync_all().await.with_context(|| format!("Failed to sync {}", temp.display()))?;
+        }
+        if self.keep_mtimes {
+            file.into_std().await.set_modified(system_time(entry.mtime))?;
+        }
+        if self.keep_modes {
+            set_mode(temp, entry.mode).await?;
+        }
+        tokio::fs::rename(temp, dest)
+            .await
+            .with_context(|| format!("Failed to replace {}", dest.display()))?;
+        if self.durability.syncs_writes() {
+            sync_dir(dest.parent().unwrap_or(&self.root)).await?;
+        }
+        Ok(())
+    }
+
+    /// Fetches the content of `entry` into `file`, up to `chunk_downloads`
+    /// chunks at once, and writes each at its offset as it arrives.
+    async fn fetch_into<F>(&self, path: &str, file: std::fs::File, entry: &Entry, fetch: &F) -> Result<std::fs::File>
+    where
+        F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let unfetched = || Unfetched(path.to_string());
+        let file = Arc::new(file);
+        if entry.chunks.is_empty() {
+            let data = fetch(entry.content.clone()).await.context(unfetched())?;
+            if data.len() as u64 != entry.size {
+                return Err(anyhow!("Content is {} bytes, expected {}", data.len(), entry.size).context(unfetched()));
+            }
+            write_at(&file, data, 0).await?;
+        } else {
+            let size: u64 = entry.chunks.iter().map(|chunk| chunk.size).sum();
+            if size != entry.size {
+                return Err(anyhow!("Chunks make {} bytes, expected {}", size, entry.size).context(unfetched()));
+            }
+            let offsets = entry.chunks.iter().scan(0, |offset, chunk| {
+                let at = *offset;
+                *offset += chunk.size;
+                Some((chunk, at))
+            });
+            let file = &file;
+            stream::iter(offsets)
+                .map(|(chunk, at)| async move {
+                    let bytes = fetch(chunk.content.clone()).await.context(unfetched())?;
+                    if bytes.len() as u64 != chunk.size {
+                        let err = anyhow!("Chunk {} is {} bytes, expected {}", chunk.content, bytes.len(), chunk.size);
+                        return Err(err.context(unfetched()));
+                    }
+                    write_at(file, bytes, at).await
+                })
+                .buffer_unordered(self.chunk_downloads)
+                .try_collect::<()>()
+                .await?;
+        }
+        Ok(Arc::into_inner(file).expect("every write is done"))
+    }
+
+    async fn checkout_link<F>(&mut self, replica: &Replica, path: &str, entry: &Entry, fetch: &F) -> Result<Outcome>
+    where
+        F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+    {
+        match self.symlinks {
+            SymlinkPolicy::Preserve => match self.write_link(path, entry).await {
+                Err(err) if symlinks_unavailable(&err) => self.skip_link(path, format!("{:#}", err)).await,
+                outcome => outcome,
+            },
+            SymlinkPolicy::Dereference => {
+                let target = match dereference(replica.state(), path) {
+                    Ok(target) => target,
+                    Err(err) => return self.skip_link(path, format!("{:#}", err)).await,
+                };
+                let (outcome, _) = self.checkout_file(replica, path, &target, fetch).await?;
+                if !matches!(outcome, Outcome::LocalChanges) {
+                    // recorded even if racy, or a scan would commit the copy
+                    let metadata = tokio::fs::symlink_metadata(self.local_path(path).await?).await?;
+                    self.index.forget(path);
+                    self.index.stand_ins.insert(path.to_string(), Some(IndexEntry::new(&metadata, target.content)));
+                }
+                Ok(outcome)
+            }
+            SymlinkPolicy::Skip => self.skip_link(path, "skipped by the symlink policy".to_string()).await,
+        }
+    }
+
+    /// Leaves the symlink at `path` off disk, removing a copy of its target
+    /// an earlier checkout wrote in its place.
+    async fn skip_link(&mut self, path: &str, why: String) -> Result<Outcome> {
+        let dest = self.local_path(path).await?;
+        if let Ok(metadata) = tokio::fs::symlink_metadata(&dest).await
+            && metadata.is_file()
+            && self.index.get(path).is_some_and(|indexed| indexed.matches(&metadata))
+        {
+            tokio::fs::remove_file(&dest)
+                .await
+                .with_context(|| format!("Failed to remove {}", dest.display()))?;
+            self.index.forget(path);
+        }
+        self.index.stand_ins.insert(path.to_string(), None);
+        Ok(Outcome::SkippedLink(why))
+    }
+
+    async fn write_link(&mut self, path: &str, entry: &Entry) -> Result<Outcome> {
+        let target = entry.symlink.as_deref().expect("only symlinks get here");
+        let dest = self.local_path(path).await?;
+        match tokio::fs::symlink_metadata(&dest).await {
+            Ok(metadata) if metadata.is_symlink() => {
+                if tokio::fs::read_link(&dest).await?.to_str() == Some(target) {
+                    return Ok(Outcome::Unchanged);
+                }
+                tokio::fs::remove_file(&dest).await?;
+            }
+            Ok(metadata) if metadata.is_dir() => bail!("{} is a directory", dest.display()),
+            Ok(metadata) => {
+                if !self.index.get(path).is_some_and(|indexed| indexed.matches(&metadata)) {
+                    return Ok(Outcome::LocalChanges);
+                }
+                tokio::fs::remove_file(&dest).await?;
+                self.index.forget(path);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                if let Some(parent) = dest.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+            }
+            Err(err) => return Err(err).with_context(|| format!("Failed to stat {}", dest.display())),
+        }
+        create_symlink(target, &dest).await?;
+        Ok(Outcome::Written)
+    }
+
+    /// Deletes a file the workspace no longer has, and the directories
+    /// that leaves empty. A file `deleted` in the workspace at a checkout
+    /// goes to the trash instead, if the scanner keeps one.
+    async fn remove_file(&mut self, path: &str, deleted: Option<SystemTime>) -> Result<Outcome> {
+        let dest = self.local_path(path).await?;
+        let metadata = match tokio::fs::symlink_metadata(&dest).await {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                self.index.forget(path);
+                return Ok(Outcome::Unchanged);
+            }
+            Err(err) => return Err(err).with_context(|| format!("Failed to stat {}", dest.display())),
+        };
+        if !metadata.is_file() || !self.index.get(path).is_some_and(|indexed| indexed.matches(&metadata)) {
+            return Ok(Outcome::LocalChanges);
+        }
+        if in_use(&dest).await {
+            return Ok(Outcome::InUse);
+        }
+        let removed = match deleted.filter(|_| self.trash.is_some()) {
+            Some(deleted) => self.move_to_trash(&dest, deleted).await,
+            None => tokio::fs::remove_file(&dest)
+                .await
+                .with_context(|| format!("Failed to remove {}", dest.display())),
+        };
+        if failed_in_use(&removed) {
+            return Ok(Outcome::InUse);
+        }
+        removed?;
+        self.index.forget(path);
+        let mut dir = dest.parent();
+        while let Some(parent) = dir.filter(|parent| *parent != self.root) {
+            if tokio::fs::remove_dir(parent).await.is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+        Ok(Outcome::Removed)
+    }
+
+    /// Where `path` is on disk, refusing a path through a symlinked
+    /// directory, which could send a write outside the root.
+    pub(crate) async fn local_path(&self, path: &str) -> Result<PathBuf> {
+        let path = self.index.renamed.get(path).map_or(path, String::as_str);
+        let dest = safe_join(&self.root, path)?;
+        refuse_symlinked_parents(&self.root, path).await?;
+        Ok(dest)
+    }
+}
+
+/// The file the symlink at `link` resolves to within the workspace,
+/// following links to links.
+fn dereference(state: &State, link: &str) -> Result<Entry> {
+    let mut path = link.to_string();
+    for _ in 0..MAX_LINK_HOPS {
+        let entry = match state.get(&path) {
+            Some(entry) => entry,
+            None if state.iter().any(|(other, _)| path_matches(&path, other)) => bail!("{} points at a directory", link),
+            None => bail!("{} points at nothing in the workspace", link),
+        };
+        let Some(target) = &entry.symlink else {
+            return Ok(entry);
+        };
+        let Some(resolved) = resolve_link(&path, target) else {
+            bail!("{} points outside the workspace", link);
+        };
+        path = resolved;
+    }
+    bail!("{} is part of a symlink loop", link)
+}
+
+/// `target`, relative to the directory of the symlink at `path`, as a
+/// workspace path; `None` if it leads outside.
+fn resolve_link(path: &str, target: &str) -> Option<String> {
+    if target.starts_with('/') {
+        return None;
+    }
+    let mut parts: Vec<&str> = path.split('/').collect();
+    parts.pop();
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            _ => parts.push(part),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// A fresh temporary path in the directory of `dest`.
+fn temp_path(dest: &Path) -> Result<PathBuf> {
+    let mut nonce = [0u8; 8];
+    getrandom::getrandom(&mut nonce).map_err(|e| anyhow!("Failed to gather randomness: {}", e))?;
+    let name = dest.file_name().map_or_else(Default::default, |name| name.to_string_lossy());
+    Ok(dest.with_file_name(format!(".{}.{:016x}{}", name, u64::from_le_bytes(nonce), TEMP_SUFFIX)))
+}
+
+/// Marks an error fetching the content of a file, as opposed to writing
+/// it: gone from every provider, or not what the workspace says.
+#[derive(Debug)]
+pub(crate) struct Unfetched(pub(crate) String);
+
+impl fmt::Display for Unfetched {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to fetch content of {}", self.0)
+    }
+}
+
+/// Writes all of `data` to `file` at `offset`, on the blocking pool.
+async fn write_at(file: &Arc<std::fs::File>, data: Vec<u8>, offset: u64) -> Result<()> {
+    let file = file.clone();
+    tokio::task::spawn_blocking(move || write_all_at(&file, &data, offset)).await.context("Write task failed")??;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &std::fs::File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(data, offset)
+}
+
+#[cfg(not(unix))]
+fn write_all_at(file: &std::fs::File, mut data: &[u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        match file.seek_write(data, offset)? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            written => {
+                data = &data[written..];
+                offset += written as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn sync_dir(dir: &Path) -> Result<()> {
+    let file = tokio::fs::File::open(dir)
+        .await
+        .with_context(|| format!("Failed to open {}", dir.display()))?;
+    file.sync_all().await.with_context(|| format!("Failed to sync {}", dir.display()))
+}
+
+/// Directories can't be opened to sync them elsewhere.
+#[cfg(not(unix))]
+async fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .await
+        .with_context(|| format!("Failed to set the mode of {}", path.display()))
+}
+
+#[cfg(not(unix))]
+async fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    let mut permissions = tokio::fs::metadata(path).await?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    tokio::fs::set_permissions(path, permissions)
+        .await
+        .with_context(|| format!("Failed to set the mode of {}", path.display()))
+}
+
+#[cfg(test)]
+mod checkout_test {
+    use super::*;
+    use crate::crdt::chunk::Chunker;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use crate::sync::ignore::IgnoreRules;
+    use crate::sync::index::ScanIndex;
+    use crate::test_util::{alice, entry};
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_checkout_follows_the_workspace() {
+        let mut replica = Replica::new(alice());
+        let mut blobs = HashMap::new();
+        fn put(replica: &mut Replica, blobs: &mut HashMap<IpfsCid, Vec<u8>>, path: &str, data: &[u8]) {
+            let content = IpfsCid::compute(RAW_CODE, data);
+            blobs.insert(content, data.to_vec());
+            replica.put(path, entry(data)).unwrap();
+        }
+        put(&mut replica, &mut blobs, "a.txt", b"one");
+        put(&mut replica, &mut blobs, "dir/b.txt", b"two");
+        put(&mut replica, &mut blobs, "debug.log", b"noise");
+        replica.put("link", Entry::symlink("a.txt", 0)).unwrap();
+
+        let root = std::env::temp_dir().join(format!("crdt-pull-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let rules = IgnoreRules::new().with_patterns(["*.log"]).unwrap();
+        let mut scanner = Scanner::new(&root).with_ignore(rules);
+        let fetch = async |cid: IpfsCid| blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.written, vec!["a.txt", "dir/b.txt", "link"]);
+        assert_eq!(std::fs::read(root.join("dir/b.txt")).unwrap(), b"two");
+        assert!(!root.join("debug.log").exists());
+
+        // remote changes land; a file changed on disk is kept
+        put(&mut replica, &mut blobs, "a.txt", b"ONE");
+        put(&mut replica, &mut blobs, "c.txt", b"three");
+        replica.remove("dir/b.txt").unwrap();
+        std::fs::write(root.join("c.txt"), b"mine").unwrap();
+        let fetch = async |cid: IpfsCid| blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!((report.written, report.removed), (vec!["a.txt".to_string()], vec!["dir/b.txt".to_string()]));
+        assert_eq!((report.local_changes, report.unchanged), (vec!["c.txt".to_string()], 1));
+        assert_eq!(std::fs::read(root.join("link")).unwrap(), b"ONE");
+        assert_eq!(std::fs::read(root.join("c.txt")).unwrap(), b"mine");
+        assert!(!root.join("dir").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_symlink_policies() {
+        let mut replica = Replica::new(alice());
+        let content = IpfsCid::compute(RAW_CODE, b"one");
+        let fetch = async |cid: IpfsCid| (cid == content).then(|| b"one".to_vec()).ok_or_else(|| anyhow!("missing {}", cid));
+        replica.put("dir/a.txt", Entry { content: content.clone(), size: 3, mode: 0o644, mtime: 0, chunks: Vec::new(), symlink: None }).unwrap();
+        replica.put("dir/link", Entry::symlink("a.txt", 0)).unwrap();
+        replica.put("up", Entry::symlink("dir/./link", 0)).unwrap();
+        replica.put("out", Entry::symlink("../etc/passwd", 0)).unwrap();
+
+        let root = std::env::temp_dir().join(format!("crdt-links-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let mut scanner = Scanner::new(&root).with_symlinks(SymlinkPolicy::Dereference);
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.written, vec!["dir/a.txt", "dir/link", "up"]);
+        assert_eq!(report.skipped_links.len(), 1);
+        assert!(report.skipped_links[0].1.contains("outside the workspace"));
+        assert!(!std::fs::symlink_metadata(root.join("up")).unwrap().is_symlink());
+        assert_eq!(std::fs::read(root.join("up")).unwrap(), b"one");
+        // neither the copies nor the gap are committed
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert!(report.put.is_empty() && report.removed.is_empty());
+        assert!(scanner.status(&replica).await.unwrap().is_clean());
+
+        scanner = scanner.with_symlinks(SymlinkPolicy::Skip);
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.skipped_links.len(), 3);
+        assert!(!root.join("up").exists() && !root.join("dir/link").exists());
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert!(report.put.is_empty() && report.removed.is_empty());
+        assert!(replica.state().get("up").unwrap().is_symlink());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_checkout_keeps_modes_and_mtimes() {
+        use std::os::unix::fs::PermissionsExt;
+        let mut replica = Replica::new(alice());
+        let content = IpfsCid::compute(RAW_CODE, b"#!/bin/sh\n");
+        let fetch = async |cid: IpfsCid| (cid == content).then(|| b"#!/bin/sh\n".to_vec()).ok_or_else(|| anyhow!("missing {}", cid));
+        let mtime = 1_600_000_000;
+        replica.put("run.sh", Entry { content: content.clone(), size: 10, mode: 0o755, mtime, chunks: Vec::new(), symlink: None }).unwrap();
+
+        let root = std::env::temp_dir().join(format!("crdt-perms-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let mut scanner = Scanner::new(root.join("kept"));
+        scanner.checkout(&replica, fetch).await.unwrap();
+        let metadata = std::fs::metadata(root.join("kept/run.sh")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        assert_eq!(metadata.modified().unwrap(), system_time(mtime));
+
+        // as on a filesystem without mode bits: the workspace's mode stands
+        let mut scanner = Scanner::new(root.join("flat")).with_preserved_metadata(false, false);
+        scanner.checkout(&replica, fetch).await.unwrap();
+        std::fs::set_permissions(root.join("flat/run.sh"), std::fs::Permissions::from_mode(0o644)).unwrap();
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert!(report.patched.is_empty() && report.put.is_empty());
+        assert_eq!(replica.state().get("run.sh").unwrap().mode, 0o755);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_checkout_replaces_files_atomically() {
+        let mut replica = Replica::new(alice());
+        let blobs: HashMap<IpfsCid, Vec<u8>> =
+            [b"old".to_vec(), b"new".to_vec()].into_iter().map(|data| (IpfsCid::compute(RAW_CODE, &data), data)).collect();
+        let entry = |data: &[u8], mode| {
+            Entry { content: IpfsCid::compute(RAW_CODE, data), size: data.len() as u64, mode, mtime: 0, chunks: Vec::new(), symlink: None }
+        };
+        replica.put("f", entry(b"old", 0o444)).unwrap();
+
+        let root = std::env::temp_dir().join(format!("crdt-atomic-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let mut scanner = Scanner::new(root.join("tree")).with_durability(Durability::Always);
+        let fetch = async |cid: IpfsCid| blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        scanner.checkout(&replica, fetch).await.unwrap();
+        // a reader holding the old file keeps seeing all of it
+        std::fs::hard_link(root.join("tree/f"), root.join("reader")).unwrap();
+
+        replica.put("f", entry(b"new", 0o444)).unwrap();
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.written, vec!["f"]);
+        assert_eq!(std::fs::read(root.join("tree/f")).unwrap(), b"new");
+        assert_eq!(std::fs::read(root.join("reader")).unwrap(), b"old");
+        assert_eq!(std::fs::read_dir(root.join("tree")).unwrap().count(), 1);
+
+        // scans pass over a temporary file left behind
+        std::fs::write(root.join("tree/.f.0123456789abcdef.crdt-tmp"), b"partial").unwrap();
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        assert!(scanner.scan(&mut replica, &store).await.unwrap().put.is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_checkout_resumes() {
+        let mut replica = Replica::new(alice());
+        let mut blobs = HashMap::new();
+        let count = CHECKPOINT_FILES + 6;
+        for i in 0..count {
+            let data = format!("file {}", i).into_bytes();
+            let content = IpfsCid::compute(RAW_CODE, &data);
+            replica.put(&format!("f{:03}", i), entry(&data)).unwrap();
+            blobs.insert(content, data);
+        }
+        let root = std::env::temp_dir().join(format!("crdt-checkout-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let index_path = root.join("index.json");
+
+        // the pull stalls on the second file after the first save
+        let (calls, stalled) = (std::sync::Mutex::new(0), tokio::sync::Notify::new());
+        let fetch = async |cid: IpfsCid| {
+            *calls.lock().unwrap() += 1;
+            if *calls.lock().unwrap() == CHECKPOINT_FILES + 2 {
+                stalled.notify_one();
+                std::future::pending::<()>().await;
+            }
+            blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid))
+        };
+        let mut scanner = Scanner::new(root.join("tree")).with_index(&index_path).unwrap().with_downloads(1);
+        tokio::select! {
+            _ = scanner.checkout(&replica, fetch) => panic!("checkout finished"),
+            _ = stalled.notified() => {}
+        }
+        assert_eq!(ScanIndex::load(&index_path).unwrap().files.len(), CHECKPOINT_FILES);
+
+        // after a restart only the files never written are fetched
+        let fetched = std::sync::Mutex::new(0);
+        let fetch = async |cid: IpfsCid| {
+            *fetched.lock().unwrap() += 1;
+            blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid))
+        };
+        let mut scanner = Scanner::new(root.join("tree")).with_index(&index_path).unwrap();
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.written.len(), 5);
+        assert_eq!(report.unchanged, CHECKPOINT_FILES + 1);
+        assert_eq!(*fetched.lock().unwrap(), 5);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunks_are_written_as_they_arrive() {
+        let mut replica = Replica::new(alice()).with_chunker(Chunker { min_size: 64, avg_size: 256, max_size: 1024 });
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let mut blobs = HashMap::new();
+        replica
+            .put_chunked("big.bin", &data, 0o644, 0, async |cid, bytes| {
+                blobs.insert(cid, bytes.to_vec());
+                Ok(())
+            })
+            .await
+            .unwrap();
+        let chunks = replica.state().get("big.bin").unwrap().chunks;
+        assert!(chunks.len() > 4);
+
+        // the first chunk only arrives once the last one has
+        let (first, last) = (chunks[0].content.clone(), chunks[chunks.len() - 1].content.clone());
+        let arrived = tokio::sync::Notify::new();
+        let fetch = async |cid: IpfsCid| {
+            if cid == first {
+                arrived.notified().await;
+            } else if cid == last {
+                arrived.notify_one();
+            }
+            blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid))
+        };
+        let root = std::env::temp_dir().join(format!("crdt-chunk-downloads-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let mut scanner = Scanner::new(&root).with_chunk_downloads(chunks.len());
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.written, vec!["big.bin"]);
+        assert_eq!(std::fs::read(root.join("big.bin")).unwrap(), data);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
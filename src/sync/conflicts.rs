@@ -0,0 +1,273 @@
+//! Conflicts a checkout can't settle on its own. When concurrent contents
+//! at a path don't three-way merge cleanly, are binary, or have no merge
+//! base to merge against, the checkout writes the last-writer-wins pick as usual and
+//! copies every version, plus the merge with its conflict markers, under
+//! `.crdt/conflicts/<path>/` next to a manifest naming them. Resolving the
+//! conflict puts the chosen copy, edited or not, superseding every version,
+//! and clears the quarantine. Scans never look inside `.crdt`.
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::portable::windows_path;
+use super::scan::Scanner;
+use crate::crdt::chunk::read_file;
+use crate::crdt::clock::Dot;
+use crate::crdt::materialize::safe_join;
+use crate::crdt::merge3::Merged;
+use crate::crdt::replica::Replica;
+use crate::crdt::state::lww_cmp;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// Directory at the root of a synced directory that scans pass over.
+pub const STATE_DIR: &str = ".crdt";
+/// Name of the manifest in each path's quarantine directory.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// What a path's quarantine directory holds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConflictManifest {
+    pub path: String,
+    /// The concurrent versions, the last-writer-wins pick first.
+    pub versions: Vec<ConflictVersion>,
+    /// The file holding the three-way merge with conflict markers, if the
+    /// versions had a merge base.
+    pub merged: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConflictVersion {
+    /// File name in the quarantine directory.
+    pub file: String,
+    /// The write that produced this version.
+    pub dot: Dot,
+    pub content: IpfsCid,
+    pub size: u64,
+}
+
+impl ConflictManifest {
+    fn holds(&self, file: &str) -> bool {
+        self.merged.as_deref() == Some(file) || self.versions.iter().any(|version| version.file == file)
+    }
+}
+
+impl Scanner {
+    /// The conflicts quarantined in the directory, in path order.
+    pub async fn conflicts(&self) -> Result<Vec<ConflictManifest>> {
+        let mut manifests = Vec::new();
+        let mut dirs = vec![self.conflicts_root()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err).with_context(|| format!("Failed to list {}", dir.display())),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(entry.path());
+                }
+            }
+            if let Some(manifest) = read_manifest(&dir).await? {
+                manifests.push(manifest);
+            }
+        }
+        manifests.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(manifests)
+    }
+
+    /// Resolves the conflict at `path` with the quarantined copy named
+    /// `file`, as it is on disk now: puts it, storing its content with
+    /// `store`, and clears the quarantine. The next checkout writes it out.
+    pub async fn resolve_conflict<F>(&mut self, replica: &mut Replica, path: &str, file: &str, store: F) -> Result<IpfsCid>
+    where
+        F: AsyncFn(IpfsCid, Bytes) -> Result<()>,
+    {
+        self.mode.check_push()?;
+        let dir = self.conflict_dir(path)?;
+        let Some(manifest) = read_manifest(&dir).await? else {
+            bail!("No conflict is quarantined at {}", path);
+        };
+        if !manifest.holds(file) {
+            bail!("{} is not a version of {}", file, path);
+        }
+        let chosen = dir.join(file);
+        let reader = tokio::fs::File::open(&chosen)
+            .await
+            .with_context(|| format!("Failed to read {}", chosen.display()))?;
+        let mode = replica.state().get(path).map_or(0o644, |entry| entry.mode);
+        let mtime = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |since| since.as_secs() as i64);
+        let (entry, _, _) = replica.store_stream(path, reader, mode, mtime, &store).await?;
+        let node = replica.put(path, entry)?;
+        self.clear_quarantine(path).await?;
+        Ok(node)
+    }
+
+    /// Quarantines the concurrent contents at `path` unless they merge
+    /// cleanly, fetching them with `fetch`. Returns whether the path is
+    /// quarantined. One quarantined before with the same versions is left
+    /// as it is, edits and all.
+    pub(crate) async fn quarantine<F>(&self, replica: &Replica, path: &str, fetch: &mut F) -> Result<bool>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let Some(register) = replica.state().register(path) else {
+            return Ok(false);
+        };
+        let mut versions: Vec<_> = register.content.versions().iter().collect();
+        if versions.len() < 2 || versions.iter().any(|version| version.value.symlink.is_some()) {
+            return Ok(false);
+        }
+        versions.sort_by(|a, b| lww_cmp(b, a));
+        let dir = self.conflict_dir(path)?;
+        if let Some(manifest) = read_manifest(&dir).await?
+            && manifest.versions.iter().map(|version| &version.dot).eq(versions.iter().map(|version| &version.dot))
+        {
+            return Ok(true);
+        }
+        let merged = match replica.merge_content(path, &mut *fetch).await? {
+            Some(Merged::Clean(_)) => return Ok(false),
+            Some(Merged::Conflicted(bytes)) => Some(bytes),
+            None => None,
+        };
+
+        match tokio::fs::remove_dir_all(&dir).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err).with_context(|| format!("Failed to remove {}", dir.display()));
+            }
+            _ => {}
+        }
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        let extension = Path::new(path).extension().map_or_else(String::new, |ext| format!(".{}", ext.to_string_lossy()));
+        let mut manifest = ConflictManifest { path: path.to_string(), versions: Vec::new(), merged: None };
+        for version in versions {
+            let content = &version.value;
+            let data = read_file(&content.content, &content.chunks, fetch)
+                .await
+                .with_context(|| format!("Failed to fetch a version of {}", path))?;
+            let file = format!("{}-{}{}", version.dot.author, version.dot.seq, extension);
+            write_copy(&dir.join(&file), &data).await?;
+            manifest.versions.push(ConflictVersion {
+                file,
+                dot: version.dot.clone(),
+                content: content.content.clone(),
+                size: content.size,
+            });
+        }
+        if let Some(bytes) = merged {
+            let file = format!("merged{}", extension);
+            write_copy(&dir.join(&file), &bytes).await?;
+            manifest.merged = Some(file);
+        }
+        // last, so a manifest always names what is there
+        write_copy(&dir.join(MANIFEST_FILE), serde_json::to_string_pretty(&manifest)?.as_bytes()).await?;
+        Ok(true)
+    }
+
+    /// Removes the quarantine of `path`, if any, and the directories that
+    /// leaves empty.
+    pub(crate) async fn clear_quarantine(&self, path: &str) -> Result<()> {
+        let dir = self.conflict_dir(path)?;
+        match tokio::fs::remove_dir_all(&dir).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).with_context(|| format!("Failed to remove {}", dir.display())),
+        }
+        let mut parent = dir.parent();
+        while let Some(dir) = parent.filter(|dir| *dir != self.root) {
+            if tokio::fs::remove_dir(dir).await.is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+        Ok(())
+    }
+
+    fn conflicts_root(&self) -> PathBuf {
+        self.root.join(STATE_DIR).join("conflicts")
+    }
+
+    fn conflict_dir(&self, path: &str) -> Result<PathBuf> {
+        // named the same everywhere, as the index's names may change
+        safe_join(&self.conflicts_root(), windows_path(path).as_deref().unwrap_or(path))
+    }
+}
+
+async fn read_manifest(dir: &Path) -> Result<Option<ConflictManifest>> {
+    let path = dir.join(MANIFEST_FILE);
+    match tokio::fs::read_to_string(&path).await {
+        Ok(s) => Ok(Some(serde_json::from_str(&s).with_context(|| format!("Invalid conflict manifest {}", path.display()))?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+async fn write_copy(path: &Path, data: &[u8]) -> Result<()> {
+    tokio::fs::write(path, data)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod conflicts_test {
+    use super::*;
+    use crate::crdt::op::Entry;
+    use crate::crdt::upload::same_content;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use crate::test_util::{alice, bob, entry};
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_unmergeable_versions_are_quarantined_until_resolved() {
+        let alice = alice();
+        let bob = bob();
+        let mut blobs = HashMap::new();
+        let mut write = |replica: &mut Replica, data: &'static [u8]| {
+            let content = IpfsCid::compute(RAW_CODE, data);
+            blobs.insert(content, data.to_vec());
+            replica.put("notes.txt", entry(data)).unwrap();
+        };
+        let mut a = Replica::new(alice);
+        let mut b = Replica::new(bob);
+        write(&mut a, b"a\nb\nc\n");
+        b.apply_delta(&a.delta_since(b.state().version_vector())).unwrap();
+        write(&mut a, b"a\nours\nc\n");
+        write(&mut b, b"a\ntheirs\nc\n");
+        a.apply_delta(&b.delta_since(a.state().version_vector())).unwrap();
+
+        let root = std::env::temp_dir().join(format!("crdt-conflicts-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let mut scanner = Scanner::new(&root);
+        let fetch = async |cid: IpfsCid| blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let report = scanner.checkout(&a, fetch).await.unwrap();
+        assert_eq!(report.quarantined, vec!["notes.txt"]);
+        let manifests = scanner.conflicts().await.unwrap();
+        assert_eq!(manifests.len(), 1);
+        let manifest = &manifests[0];
+        let dir = root.join(".crdt/conflicts/notes.txt");
+        assert_eq!(manifest.versions.len(), 2);
+        let merged = std::fs::read_to_string(dir.join(manifest.merged.as_ref().unwrap())).unwrap();
+        assert!(merged.starts_with("a\n<<<<<<< ours\n") && merged.ends_with(">>>>>>> theirs\nc\n"));
+        for version in &manifest.versions {
+            assert_eq!(IpfsCid::compute(RAW_CODE, &std::fs::read(dir.join(&version.file)).unwrap()), version.content);
+        }
+
+        // a scan doesn't commit the quarantine
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        assert!(scanner.scan(&mut a, &store).await.unwrap().put.is_empty());
+
+        std::fs::write(dir.join("merged.txt"), b"a\nboth\nc\n").unwrap();
+        scanner.resolve_conflict(&mut a, "notes.txt", "merged.txt", &store).await.unwrap();
+        assert!(!a.state().register("notes.txt").unwrap().is_conflicted());
+        let both = Entry { content: IpfsCid::compute(RAW_CODE, b"a\nboth\nc\n"), size: 9, mode: 0o644, mtime: 0, chunks: Vec::new(), symlink: None };
+        assert!(same_content(&a.state().get("notes.txt").unwrap(), &both));
+        assert!(scanner.conflicts().await.unwrap().is_empty());
+        assert!(!root.join(".crdt").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+//! Controls for a running syncer, through a handle it hands out: forcing a
+//! round, and pausing, say before travelling on a metered network. A pause
+//! stops new rounds and cuts the scan or checkout under way short at its
+//! last checkpoint, the chunks uploaded and the files written so far kept,
+//! while a merge under way finishes. Nothing is published while paused.
+//! Changes on disk pile up meanwhile and the first round after a resume
+//! commits them, and takes the transfers up where they stopped.
+
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::sync::{watch, Notify};
+
+/// Asks a running syncer for rounds, or for none; clones control the same
+/// syncer.
+#[derive(Debug, Clone)]
+pub struct SyncControl {
+    pub(crate) wake: Arc<Notify>,
+    pub(crate) paused: Arc<watch::Sender<bool>>,
+}
+
+impl Default for SyncControl {
+    fn default() -> Self {
+        SyncControl { wake: Arc::new(Notify::new()), paused: Arc::new(watch::channel(false).0) }
+    }
+}
+
+impl SyncControl {
+    /// Starts a round that commits, merges, checks out and publishes at
+    /// once, windows and quiet hours or not. Asked during a round, it
+    /// starts another straight after; while paused, once resumed.
+    pub fn sync_now(&self) {
+        self.wake.notify_one();
+    }
+
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.paused.subscribe()
+    }
+}
+
+/// Runs `work` unless `paused` turns true first, in which case it is
+/// dropped at its next await and `None` returned.
+pub(crate) async fn unless_paused<T>(paused: &mut watch::Receiver<bool>, work: impl Future<Output = Result<T>>) -> Result<Option<T>> {
+    tokio::select! {
+        done = work => done.map(Some),
+        _ = paused.wait_for(|paused| *paused) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod control_test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_pause_cuts_work_short() {
+        let control = SyncControl::default();
+        let mut paused = control.subscribe();
+        assert_eq!(unless_paused(&mut paused, async { Ok(1) }).await.unwrap(), Some(1));
+
+        let pauser = control.clone();
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(2)
+        };
+        tokio::spawn(async move { pauser.pause() });
+        assert_eq!(unless_paused(&mut paused, slow).await.unwrap(), None);
+        assert!(control.is_paused());
+        control.resume();
+        assert!(!control.is_paused());
+    }
+}
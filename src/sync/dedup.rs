@@ -0,0 +1,151 @@
+//! The chunk index: which files of the workspace each stored block is part
+//! of, so a scan storing a file that shares content with another, a copy
+//! or a near-duplicate cut the same way around what differs, skips the
+//! chunks the workspace already has instead of storing them again. It
+//! follows the replica's state, files that were removed dropping out since
+//! their blocks may be collected, and chunks a scan stores are added as
+//! they are, so files scanned together share them too.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::crdt::chunk::blocks_of;
+use crate::crdt::state::State;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+#[derive(Debug, Clone, Default)]
+pub struct ChunkIndex {
+    /// Paths by the blocks they are made of.
+    chunks: HashMap<IpfsCid, BTreeSet<String>>,
+    /// The content of every version of each indexed file, with its blocks.
+    files: HashMap<String, (Vec<IpfsCid>, Vec<IpfsCid>)>,
+    /// Chunks stored since the last refresh, with the file they were
+    /// stored for.
+    stored: Vec<(IpfsCid, String)>,
+}
+
+impl ChunkIndex {
+    pub fn new() -> Self {
+        ChunkIndex::default()
+    }
+
+    /// Whether a file of the workspace is made of `chunk`.
+    pub fn contains(&self, chunk: &IpfsCid) -> bool {
+        self.chunks.contains_key(chunk)
+    }
+
+    /// The files made of `chunk`.
+    pub fn locations(&self, chunk: &IpfsCid) -> impl Iterator<Item = &str> {
+        self.chunks.get(chunk).into_iter().flatten().map(String::as_str)
+    }
+
+    /// How many distinct blocks are indexed.
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Catches up with `state`: indexes the files whose content changed
+    /// since the last refresh and drops those that are gone, along with
+    /// chunks stored for changes that weren't committed.
+    pub fn refresh(&mut self, state: &State) {
+        for (chunk, path) in std::mem::take(&mut self.stored) {
+            if !self.files.get(&path).is_some_and(|(_, blocks)| blocks.contains(&chunk)) {
+                self.forget(&chunk, &path);
+            }
+        }
+        let mut current = HashSet::new();
+        for (path, _) in state.iter() {
+            let versions = state.register(path).map_or(&[][..], |register| register.content.versions());
+            let contents: Vec<IpfsCid> = versions.iter().map(|version| version.value.content.clone()).collect();
+            current.insert(path);
+            if self.files.get(path).is_some_and(|(indexed, _)| *indexed == contents) {
+                continue;
+            }
+            self.unindex(path);
+            let blocks: Vec<IpfsCid> = versions.iter().flat_map(|version| blocks_of(&version.value)).collect();
+            for block in &blocks {
+                self.chunks.entry(block.clone()).or_default().insert(path.to_string());
+            }
+            self.files.insert(path.to_string(), (contents, blocks));
+        }
+        let gone: Vec<String> = self.files.keys().filter(|path| !current.contains(path.as_str())).cloned().collect();
+        for path in gone {
+            self.unindex(&path);
+        }
+    }
+
+    /// Records that `chunk` was stored for the file at `path`.
+    pub(crate) fn insert(&mut self, chunk: IpfsCid, path: &str) {
+        self.chunks.entry(chunk.clone()).or_default().insert(path.to_string());
+        self.stored.push((chunk, path.to_string()));
+    }
+
+    fn unindex(&mut self, path: &str) {
+        if let Some((_, blocks)) = self.files.remove(path) {
+            for block in blocks {
+                self.forget(&block, path);
+            }
+        }
+    }
+
+    fn forget(&mut self, chunk: &IpfsCid, path: &str) {
+        if let Some(paths) = self.chunks.get_mut(chunk) {
+            paths.remove(path);
+            if paths.is_empty() {
+                self.chunks.remove(chunk);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod dedup_test {
+    use crate::crdt::chunk::Chunker;
+    use crate::crdt::replica::Replica;
+    use crate::kubo_rpc::ipfs::IpfsCid;
+    use crate::sync::scan::Scanner;
+    use crate::test_util::alice;
+    use bytes::Bytes;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_copies_reuse_chunks_of_other_files() {
+        let mut replica = Replica::new(alice()).with_chunker(Chunker { min_size: 64, avg_size: 256, max_size: 1024 });
+        let root = std::env::temp_dir().join(format!("crdt-dedup-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let mut seed = 7u64;
+        let data: Vec<u8> = (0..8000)
+            .map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (seed >> 56) as u8
+            })
+            .collect();
+        std::fs::write(root.join("a.bin"), &data).unwrap();
+
+        let stored = Mutex::new(0);
+        let store = async |_: IpfsCid, _: Bytes| {
+            *stored.lock().unwrap() += 1;
+            Ok(())
+        };
+        let mut scanner = Scanner::new(&root);
+        scanner.scan(&mut replica, &store).await.unwrap();
+        let chunks = replica.state().get("a.bin").unwrap().chunks;
+        assert!(chunks.len() > 1);
+        assert_eq!(scanner.chunk_index().len(), chunks.len());
+
+        std::fs::write(root.join("copy.bin"), &data).unwrap();
+        *stored.lock().unwrap() = 0;
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!((report.stored, report.reused, *stored.lock().unwrap()), (0, chunks.len(), 0));
+
+        std::fs::remove_file(root.join("a.bin")).unwrap();
+        scanner.scan(&mut replica, &store).await.unwrap();
+        scanner.chunks.refresh(replica.state());
+        assert_eq!(scanner.chunk_index().locations(&chunks[0].content).collect::<Vec<_>>(), ["copy.bin"]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
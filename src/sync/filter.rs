@@ -0,0 +1,278 @@
+//! Include/exclude filters for applications that only sync part of a
+//! directory. Unlike ignore rules they live in code, not in the directory,
+//! and can look at a file's size and type as well as its path. A file a
+//! filter leaves out is neither uploaded nor removed from the workspace,
+//! though a checkout removes a copy it wrote before unless it changed since.
+//! A scan leaves out the files the workspace's file policy skips the same
+//! way, and reports them.
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+use super::scan::Walk;
+use crate::crdt::op::Entry;
+use crate::crdt::policy::{FilePolicy, SkipReason};
+
+/// What a filter is shown of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileInfo<'a> {
+    pub path: &'a str,
+    pub size: u64,
+    pub symlink: bool,
+}
+
+impl<'a> FileInfo<'a> {
+    pub fn of_entry(path: &'a str, entry: &Entry) -> Self {
+        FileInfo { path, size: entry.size, symlink: entry.is_symlink() }
+    }
+}
+
+/// One test a file can pass.
+#[derive(Clone)]
+pub enum Matcher {
+    /// A glob over the whole path; `*` stops at `/`, `**` doesn't.
+    Glob(GlobMatcher),
+    /// A regex searched for anywhere in the path.
+    Regex(Regex),
+    /// Files of at least this many bytes.
+    LargerThan(u64),
+    Symlinks,
+    Predicate(Arc<dyn Fn(&FileInfo) -> bool + Send + Sync>),
+}
+
+impl Matcher {
+    pub fn glob(pattern: &str) -> Result<Self> {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid glob {:?}", pattern))?;
+        Ok(Matcher::Glob(glob.compile_matcher()))
+    }
+
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Ok(Matcher::Regex(Regex::new(pattern).with_context(|| format!("Invalid regex {:?}", pattern))?))
+    }
+
+    pub fn predicate(f: impl Fn(&FileInfo) -> bool + Send + Sync + 'static) -> Self {
+        Matcher::Predicate(Arc::new(f))
+    }
+
+    pub fn matches(&self, file: &FileInfo) -> bool {
+        match self {
+            Matcher::Glob(glob) => glob.is_match(file.path),
+            Matcher::Regex(regex) => regex.is_match(file.path),
+            Matcher::LargerThan(size) => file.size >= *size,
+            Matcher::Symlinks => file.symlink,
+            Matcher::Predicate(f) => f(file),
+        }
+    }
+
+    /// What the matcher is made from, to save it; a predicate can't be.
+    pub fn pattern(&self) -> Option<Pattern> {
+        Some(match self {
+            Matcher::Glob(glob) => Pattern::Glob(glob.glob().glob().to_string()),
+            Matcher::Regex(regex) => Pattern::Regex(regex.as_str().to_string()),
+            Matcher::LargerThan(size) => Pattern::LargerThan(*size),
+            Matcher::Symlinks => Pattern::Symlinks,
+            Matcher::Predicate(_) => return None,
+        })
+    }
+}
+
+/// A matcher as saved, see `Matcher::pattern`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pattern {
+    Glob(String),
+    Regex(String),
+    LargerThan(u64),
+    Symlinks,
+}
+
+impl Pattern {
+    pub fn matcher(&self) -> Result<Matcher> {
+        match self {
+            Pattern::Glob(pattern) => Matcher::glob(pattern),
+            Pattern::Regex(pattern) => Matcher::regex(pattern),
+            Pattern::LargerThan(size) => Ok(Matcher::LargerThan(*size)),
+            Pattern::Symlinks => Ok(Matcher::Symlinks),
+        }
+    }
+}
+
+impl fmt::Debug for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Glob(glob) => write!(f, "Glob({})", glob.glob()),
+            Matcher::Regex(regex) => write!(f, "Regex({})", regex),
+            Matcher::LargerThan(size) => write!(f, "LargerThan({})", size),
+            Matcher::Symlinks => write!(f, "Symlinks"),
+            Matcher::Predicate(_) => write!(f, "Predicate"),
+        }
+    }
+}
+
+/// Keeps the files any include matches, all of them if there is none, and
+/// that no exclude matches.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    include: Vec<Matcher>,
+    exclude: Vec<Matcher>,
+}
+
+impl PathFilter {
+    pub fn new() -> Self {
+        PathFilter::default()
+    }
+
+    pub fn include(mut self, matcher: Matcher) -> Self {
+        self.include.push(matcher);
+        self
+    }
+
+    pub fn exclude(mut self, matcher: Matcher) -> Self {
+        self.exclude.push(matcher);
+        self
+    }
+
+    pub fn allows(&self, file: &FileInfo) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|matcher| matcher.matches(file)))
+            && !self.exclude.iter().any(|matcher| matcher.matches(file))
+    }
+
+    /// The patterns of the includes and of the excludes, leaving out
+    /// predicates.
+    pub fn patterns(&self) -> (Vec<Pattern>, Vec<Pattern>) {
+        let patterns = |matchers: &[Matcher]| matchers.iter().filter_map(Matcher::pattern).collect();
+        (patterns(&self.include), patterns(&self.exclude))
+    }
+
+    pub fn from_patterns(include: &[Pattern], exclude: &[Pattern]) -> Result<Self> {
+        Ok(PathFilter {
+            include: include.iter().map(Pattern::matcher).collect::<Result<_>>()?,
+            exclude: exclude.iter().map(Pattern::matcher).collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// A file the workspace's policy kept a scan from syncing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedFile {
+    pub path: String,
+    pub size: u64,
+    pub reason: SkipReason,
+}
+
+impl Walk<'_> {
+    /// Moves the files `policy` skips from those found to those filtered,
+    /// and returns them in path order.
+    pub(crate) fn apply_policy(&mut self, policy: &FilePolicy) -> Vec<SkippedFile> {
+        if policy.is_empty() {
+            return Vec::new();
+        }
+        let mut skipped = Vec::new();
+        self.found.retain(|file| {
+            let reason = policy.skips(&file.path, file.metadata.len()).filter(|_| file.metadata.is_file());
+            match reason {
+                Some(reason) => {
+                    skipped.push(SkippedFile { path: file.path.clone(), size: file.metadata.len(), reason });
+                    false
+                }
+                None => true,
+            }
+        });
+        self.filtered.extend(skipped.iter().map(|file| file.path.clone()));
+        skipped
+    }
+}
+
+#[cfg(test)]
+mod filter_test {
+    use super::*;
+    use crate::test_util::alice;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_include_and_exclude() {
+        let filter = PathFilter::new()
+            .include(Matcher::glob("src/**/*.rs").unwrap())
+            .include(Matcher::regex(r"^docs/.*\.md$").unwrap())
+            .exclude(Matcher::LargerThan(1024))
+            .exclude(Matcher::Symlinks)
+            .exclude(Matcher::predicate(|file| file.path.contains("generated")));
+        let file = |path, size| FileInfo { path, size, symlink: false };
+
+        assert!(filter.allows(&file("src/main.rs", 10)));
+        assert!(filter.allows(&file("src/a/b.rs", 10)));
+        assert!(filter.allows(&file("docs/guide.md", 10)));
+        assert!(!filter.allows(&file("main.rs", 10)));
+        assert!(!filter.allows(&file("src/big.rs", 4096)));
+        assert!(!filter.allows(&file("src/generated/x.rs", 10)));
+        assert!(!filter.allows(&FileInfo { path: "src/link.rs", size: 3, symlink: true }));
+        assert!(PathFilter::new().allows(&file("anything", 0)));
+    }
+
+    #[tokio::test]
+    async fn test_scanner_skips_filtered_files() {
+        use crate::crdt::replica::Replica;
+        use crate::kubo_rpc::ipfs::IpfsCid;
+        use crate::sync::scan::Scanner;
+
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-filter-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/lib.rs"), b"small").unwrap();
+        std::fs::write(root.join("src/blob.rs"), vec![0u8; 100]).unwrap();
+        std::fs::write(root.join("README"), b"readme").unwrap();
+
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let mut scanner = Scanner::new(&root);
+        scanner.scan(&mut replica, &store).await.unwrap();
+        let blob = replica.state().get("src/blob.rs").unwrap();
+        // narrowing the filter later removes nothing from the workspace
+        let filter = PathFilter::new().include(Matcher::glob("src/*.rs").unwrap()).exclude(Matcher::LargerThan(64));
+        let mut scanner = Scanner::new(&root).with_filter(filter);
+        std::fs::write(root.join("src/blob.rs"), vec![1u8; 100]).unwrap();
+        std::fs::write(root.join("src/lib.rs"), b"changed").unwrap();
+        std::fs::remove_file(root.join("README")).unwrap();
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!((report.put, report.removed), (vec!["src/lib.rs".to_string()], Vec::new()));
+        assert_eq!(replica.state().get("src/blob.rs"), Some(blob));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scanner_reports_files_the_policy_skips() {
+        use crate::crdt::replica::Replica;
+        use crate::kubo_rpc::ipfs::IpfsCid;
+        use crate::sync::scan::Scanner;
+
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-policy-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("images")).unwrap();
+        std::fs::write(root.join("images/disk.iso"), b"iso").unwrap();
+        std::fs::write(root.join("video.mp4"), vec![0u8; 100]).unwrap();
+        std::fs::write(root.join("notes.md"), b"notes").unwrap();
+        replica.set_file_policy(FilePolicy { max_size: Some(64), skip: vec!["**/*.iso".to_string()] }).unwrap();
+
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let mut scanner = Scanner::new(&root);
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!(report.put, vec!["notes.md"]);
+        assert_eq!(
+            report.policy_skipped,
+            [
+                SkippedFile { path: "images/disk.iso".to_string(), size: 3, reason: SkipReason::Skipped { glob: "**/*.iso".to_string() } },
+                SkippedFile { path: "video.mp4".to_string(), size: 100, reason: SkipReason::TooLarge { limit: 64 } },
+            ]
+        );
+        assert!(scanner.status(&replica).await.unwrap().is_clean());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
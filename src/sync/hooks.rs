@@ -0,0 +1,196 @@
+//! Hooks run around scans and checkouts, for integrations such as build
+//! triggers or notifications. The hooks before one see the changes it is
+//! about to make and can hold some back, by taking them out of the change
+//! set, or veto all of them with an error; later runs find what was held
+//! back again. The hooks after one, and those told of a conflict, only
+//! look. Hooks run in the order they were added.
+
+use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use std::fmt;
+use std::sync::Arc;
+
+use super::checkout::CheckoutReport;
+use super::conflicts::ConflictManifest;
+use super::scan::{ScanReport, Scanner};
+
+/// Paths a scan is about to commit changes to, or a checkout to write.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeSet {
+    /// Files and symlinks to create or rewrite.
+    pub put: Vec<String>,
+    /// Files whose mode alone changed; always empty for a checkout.
+    pub patched: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ChangeSet {
+    pub fn is_empty(&self) -> bool {
+        self.put.is_empty() && self.patched.is_empty() && self.removed.is_empty()
+    }
+
+    /// Holds back the changes to `path`.
+    pub fn hold_back(&mut self, path: &str) {
+        for paths in [&mut self.put, &mut self.patched, &mut self.removed] {
+            paths.retain(|changed| changed != path);
+        }
+    }
+}
+
+/// Callbacks around a scanner's scans and checkouts; each does nothing
+/// unless implemented.
+pub trait SyncHook: fmt::Debug + Send + Sync {
+    /// Before a scan commits `changes`.
+    fn pre_commit<'a>(&'a self, _changes: &'a mut ChangeSet) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// After a scan committed what `report` says.
+    fn post_commit<'a>(&'a self, _report: &'a ScanReport) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// Before a checkout writes and removes the files in `changes`. Files
+    /// are in it if the index doesn't vouch for their content; those whose
+    /// content is on disk already are then left as they are.
+    fn pre_checkout<'a>(&'a self, _changes: &'a mut ChangeSet) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// After a checkout did what `report` says.
+    fn post_checkout<'a>(&'a self, _report: &'a CheckoutReport) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// When a checkout quarantines the conflict `manifest` describes.
+    fn on_conflict<'a>(&'a self, _manifest: &'a ConflictManifest) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
+
+impl Scanner {
+    /// Runs `hook` around scans and checkouts, after the hooks added before.
+    pub fn with_hook(mut self, hook: impl SyncHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    pub(crate) async fn pre_commit(&self, changes: &mut ChangeSet) -> Result<()> {
+        for hook in &self.hooks {
+            hook.pre_commit(changes).await.context("Vetoed by a pre-commit hook")?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn post_commit(&self, report: &ScanReport) {
+        for hook in &self.hooks {
+            hook.post_commit(report).await;
+        }
+    }
+
+    pub(crate) async fn pre_checkout(&self, changes: &mut ChangeSet) -> Result<()> {
+        for hook in &self.hooks {
+            hook.pre_checkout(changes).await.context("Vetoed by a pre-checkout hook")?;
+        }
+        Ok(())
+    }
+
+    /// Runs the hooks after a checkout, telling them first of the conflicts
+    /// it quarantined.
+    pub(crate) async fn post_checkout(&self, report: &CheckoutReport) -> Result<()> {
+        if self.hooks.is_empty() {
+            return Ok(());
+        }
+        let conflicts = self.conflicts().await?;
+        for manifest in conflicts.iter().filter(|manifest| report.quarantined.contains(&manifest.path)) {
+            for hook in &self.hooks {
+                hook.on_conflict(manifest).await;
+            }
+        }
+        for hook in &self.hooks {
+            hook.post_checkout(report).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod hooks_test {
+    use super::*;
+    use crate::crdt::replica::Replica;
+    use crate::kubo_rpc::ipfs::IpfsCid;
+    use crate::test_util::alice;
+    use anyhow::{anyhow, bail};
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// Holds back secrets, vetoes checkouts that remove anything and notes
+    /// what happened.
+    #[derive(Debug, Default)]
+    struct Guard {
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl SyncHook for Guard {
+        fn pre_commit<'a>(&'a self, changes: &'a mut ChangeSet) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                changes.hold_back("secret.txt");
+                Ok(())
+            })
+        }
+
+        fn post_commit<'a>(&'a self, report: &'a ScanReport) -> BoxFuture<'a, ()> {
+            Box::pin(async move { self.seen.lock().unwrap().push(format!("committed {:?}", report.put)) })
+        }
+
+        fn pre_checkout<'a>(&'a self, changes: &'a mut ChangeSet) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                if !changes.removed.is_empty() {
+                    bail!("Refusing to remove {:?}", changes.removed);
+                }
+                Ok(())
+            })
+        }
+
+        fn post_checkout<'a>(&'a self, report: &'a CheckoutReport) -> BoxFuture<'a, ()> {
+            Box::pin(async move { self.seen.lock().unwrap().push(format!("wrote {:?}", report.written)) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks_hold_back_and_veto() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-hooks-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("from")).unwrap();
+        for (name, data) in [("notes.txt", "notes"), ("secret.txt", "secret")] {
+            std::fs::write(root.join("from").join(name), data).unwrap();
+            let file = std::fs::File::options().write(true).open(root.join("from").join(name)).unwrap();
+            file.set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(60)).unwrap();
+        }
+        let blobs = Mutex::new(HashMap::new());
+        let store = async |cid: IpfsCid, bytes: Bytes| {
+            blobs.lock().unwrap().insert(cid, bytes.to_vec());
+            Ok(())
+        };
+        let guard = Guard::default();
+        let seen = guard.seen.clone();
+        let mut scanner = Scanner::new(root.join("from")).with_hook(guard);
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!(report.put, vec!["notes.txt"]);
+        assert!(replica.state().get("secret.txt").is_none());
+
+        let fetch = async |cid: IpfsCid| blobs.lock().unwrap().get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let mut mirror = Scanner::new(root.join("to")).with_hook(Guard { seen: seen.clone() });
+        mirror.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(*seen.lock().unwrap(), [r#"committed ["notes.txt"]"#, r#"wrote ["notes.txt"]"#]);
+
+        // a veto leaves the file in place
+        replica.remove("notes.txt").unwrap();
+        let err = mirror.checkout(&replica, fetch).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("Refusing to remove"));
+        assert!(root.join("to/notes.txt").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+//! Ignore rules: `.crdtignore` files, read with gitignore semantics, and
+//! patterns supplied by the application. Ignored paths are left alone:
+//! the scanner, and so the watcher, neither uploads nor removes them and
+//! `checkout_ignoring` doesn't write them.
+
+use ::ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ::ignore::Match;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::crdt::chunk::read_entry;
+use crate::crdt::history::path_matches;
+use crate::crdt::materialize::materialize_filtered;
+use crate::crdt::replica::Replica;
+use crate::crdt::state::State;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// Name of the ignore file read in every directory.
+pub const IGNORE_FILE: &str = ".crdtignore";
+
+/// Gitignore-style rules. Like git, a directory's ignore file takes
+/// precedence over its parents', a later line over an earlier one, `!`
+/// re-includes what an earlier pattern ignored and nothing under an ignored
+/// directory can be re-included. Patterns given to `with_patterns` rank
+/// below every ignore file.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    patterns: Option<Gitignore>,
+    /// Rules of each directory's ignore file, by directory path.
+    files: BTreeMap<String, Gitignore>,
+}
+
+impl IgnoreRules {
+    pub fn new() -> Self {
+        IgnoreRules::default()
+    }
+
+    /// Adds gitignore lines that apply from the root of the directory.
+    pub fn with_patterns<S: AsRef<str>>(mut self, lines: impl IntoIterator<Item = S>) -> Result<Self> {
+        let text: Vec<String> = lines.into_iter().map(|line| line.as_ref().to_string()).collect();
+        self.patterns = Some(parse("", &text.join("\n"))?);
+        Ok(self)
+    }
+
+    /// Sets the ignore file of directory `dir`, "" being the root.
+    pub fn add_file(&mut self, dir: &str, contents: &str) -> Result<()> {
+        let dir = dir.trim_matches('/');
+        self.files.insert(dir.to_string(), parse(dir, contents)?);
+        Ok(())
+    }
+
+    /// Whether `path` or a directory it is in is ignored.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        path.match_indices('/').any(|(end, _)| self.matches(&path[..end], true)) || self.matches(path, is_dir)
+    }
+
+    /// Whether `path` is ignored itself, its parents aside.
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        // deepest ignore file first
+        for (dir, rules) in self.files.iter().rev() {
+            if dir == path || !path_matches(dir, path) {
+                continue;
+            }
+            let relative = if dir.is_empty() { path } else { &path[dir.len() + 1..] };
+            match rules.matched(relative, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        self.patterns.as_ref().is_some_and(|rules| rules.matched(path, is_dir).is_ignore())
+    }
+
+    /// Reads the ignore files `state` holds, fetching their content with
+    /// `fetch`, in addition to the rules already set.
+    pub async fn load_from_state<F>(&mut self, state: &State, mut fetch: F) -> Result<()>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        for (path, entry) in state.iter() {
+            let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+            if name != IGNORE_FILE || entry.is_symlink() {
+                continue;
+            }
+            let data = read_entry(&entry, &mut fetch).await?;
+            self.add_file(dir, &String::from_utf8_lossy(&data))?;
+        }
+        Ok(())
+    }
+}
+
+fn parse(dir: &str, contents: &str) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    for line in contents.lines() {
+        builder.add_line(None, line).with_context(|| format!("Invalid ignore pattern {:?}", line))?;
+    }
+    builder.build().context("Invalid ignore rules")
+}
+
+impl Replica {
+    /// `checkout`, leaving out what `rules` and the workspace's own ignore
+    /// files ignore.
+    pub async fn checkout_ignoring<F>(&self, target: &Path, mut rules: IgnoreRules, fetch: F) -> Result<usize>
+    where
+        F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+    {
+        rules.load_from_state(self.state(), &fetch).await?;
+        materialize_filtered(self.state(), target, |path| !rules.is_ignored(path, false), fetch).await
+    }
+}
+
+#[cfg(test)]
+mod ignore_test {
+    use super::*;
+    use crate::test_util::{alice, entry};
+    use bytes::Bytes;
+
+    #[test]
+    fn test_gitignore_semantics() {
+        let mut rules = IgnoreRules::new().with_patterns(["*.tmp"]).unwrap();
+        rules.add_file("", "target/\n*.log\n!keep.log\n/local").unwrap();
+        rules.add_file("docs", "!*.tmp\nbuild").unwrap();
+
+        assert!(rules.is_ignored("target", true));
+        assert!(rules.is_ignored("target/debug/app", false));
+        assert!(!rules.is_ignored("target", false));
+        assert!(rules.is_ignored("src/x.log", false));
+        assert!(!rules.is_ignored("src/keep.log", false));
+        assert!(rules.is_ignored("local", false) && !rules.is_ignored("src/local", false));
+        assert!(rules.is_ignored("a.tmp", false));
+        // a subdirectory's file outranks the programmatic patterns
+        assert!(!rules.is_ignored("docs/a.tmp", false));
+        assert!(rules.is_ignored("docs/build/index.html", false));
+        assert!(!rules.is_ignored("build/index.html", false));
+    }
+
+    #[tokio::test]
+    async fn test_scanner_leaves_ignored_files_alone() {
+        use crate::sync::scan::Scanner;
+
+        let mut replica = Replica::new(alice());
+        // synced before it was ignored
+        let entry = entry(b"x");
+        replica.put("sub/cache.bin", entry).unwrap();
+
+        let root = std::env::temp_dir().join(format!("crdt-ignore-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sub/build")).unwrap();
+        std::fs::write(root.join(".crdtignore"), "*.bin\n").unwrap();
+        std::fs::write(root.join("sub/.crdtignore"), "build/\n!keep.bin\n").unwrap();
+        std::fs::write(root.join("sub/build/out"), b"out").unwrap();
+        std::fs::write(root.join("sub/keep.bin"), b"keep").unwrap();
+        std::fs::write(root.join("sub/drop.bin"), b"drop").unwrap();
+        std::fs::write(root.join("notes.tmp"), b"tmp").unwrap();
+
+        let rules = IgnoreRules::new().with_patterns(["*.tmp"]).unwrap();
+        let mut scanner = Scanner::new(&root).with_ignore(rules);
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!(report.put, vec![".crdtignore", "sub/.crdtignore", "sub/keep.bin"]);
+        assert!(report.removed.is_empty());
+
+        // a partial scan of an ignored path finds nothing to do either
+        let report = scanner.scan_paths(&mut replica, &["sub/drop.bin".to_string()], &store).await.unwrap();
+        assert!(report.nodes.is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,327 @@
+//! The scan index: what each file looked like on disk when it was last
+//! hashed, so a rescan only reads the files whose stat data changed, and
+//! how far uploads that were cut short got.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::sparse::Selection;
+use crate::at_rest::{open_file, seal_file};
+use crate::crdt::upload::UploadProgress;
+use crate::crypto::WorkspaceKey;
+use crate::durability::{write_durably, Durability};
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+pub const SCAN_INDEX_VERSION: u32 = 1;
+
+/// Files modified this close to a scan may change again within the same
+/// mtime tick without it showing, so they aren't indexed until a later
+/// scan.
+const RACY_NANOS: u64 = 2_000_000_000;
+
+/// Stat data of a file and the content it had.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub mtime: u64,
+    /// Zero where there are no inode numbers.
+    pub inode: u64,
+    pub content: IpfsCid,
+}
+
+impl IndexEntry {
+    pub(crate) fn new(metadata: &Metadata, content: IpfsCid) -> Self {
+        IndexEntry { size: metadata.len(), mtime: mtime_nanos(metadata), inode: inode_of(metadata), content }
+    }
+
+    /// Whether the file still has the stat data it was hashed with.
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        (self.size, self.mtime, self.inode) == stat_of(metadata)
+    }
+}
+
+/// A file whose upload was cut short, with the stat data it had.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartialUpload {
+    pub size: u64,
+    pub mtime: u64,
+    pub inode: u64,
+    pub progress: UploadProgress,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanIndex {
+    pub version: u32,
+    pub files: BTreeMap<String, IndexEntry>,
+    /// Uploads to resume, by path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub partial: BTreeMap<String, PartialUpload>,
+    /// Symlinks a checkout didn't write as symlinks, by path, with the stat
+    /// data of the copy of the target written in place of each, if any.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub stand_ins: BTreeMap<String, Option<IndexEntry>>,
+    /// Files on disk under another name than their workspace path: where
+    /// theirs collides with another's as case is folded, where Windows
+    /// can't hold it, or where it is spelled in another Unicode form. The
+    /// name on disk by workspace path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub renamed: BTreeMap<String, String>,
+    /// What the scanner was last told to sync, see `Scanner::select`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selection: Option<Selection>,
+}
+
+impl Default for ScanIndex {
+    fn default() -> Self {
+        ScanIndex::new()
+    }
+}
+
+impl ScanIndex {
+    pub fn new() -> Self {
+        ScanIndex { version: SCAN_INDEX_VERSION, files: BTreeMap::new(), partial: BTreeMap::new(), stand_ins: BTreeMap::new(), renamed: BTreeMap::new(), selection: None }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&IndexEntry> {
+        self.files.get(path)
+    }
+
+    /// Records that the file at `path` had `content` when it was hashed at
+    /// `scanned`, unless it was modified too recently to tell.
+    pub(crate) fn record(&mut self, path: &str, metadata: &Metadata, content: IpfsCid, scanned: SystemTime) {
+        let entry = IndexEntry::new(metadata, content);
+        self.partial.remove(path);
+        if is_racy(entry.mtime, scanned) {
+            self.files.remove(path);
+        } else {
+            self.files.insert(path.to_string(), entry);
+        }
+    }
+
+    /// How far the upload of the file at `path` got, if it was cut short
+    /// and the file hasn't changed since.
+    pub fn partial(&self, path: &str, metadata: &Metadata) -> Option<&UploadProgress> {
+        let partial = self.partial.get(path)?;
+        ((partial.size, partial.mtime, partial.inode) == stat_of(metadata)).then_some(&partial.progress)
+    }
+
+    /// Records how far the upload of the file at `path` got, unless it was
+    /// modified too recently to resume safely.
+    pub(crate) fn record_partial(&mut self, path: &str, metadata: &Metadata, progress: &UploadProgress, scanned: SystemTime) {
+        let (size, mtime, inode) = stat_of(metadata);
+        if progress.chunks.is_empty() || is_racy(mtime, scanned) {
+            self.partial.remove(path);
+        } else {
+            self.partial.insert(path.to_string(), PartialUpload { size, mtime, inode, progress: progress.clone() });
+        }
+    }
+
+    /// Whether what is on disk at `path`, `metadata` or nothing, is what a
+    /// checkout left in place of a symlink.
+    pub fn stands_in(&self, path: &str, metadata: Option<&Metadata>) -> bool {
+        match (self.stand_ins.get(path), metadata) {
+            (Some(None), None) => true,
+            (Some(Some(copy)), Some(metadata)) => copy.matches(metadata),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn forget(&mut self, path: &str) {
+        self.files.remove(path);
+        self.partial.remove(path);
+    }
+
+    pub(crate) fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.files.retain(|path, _| keep(path));
+        self.partial.retain(|path, _| keep(path));
+    }
+
+    /// Drops the rows of files `keep` says are gone, keeping uploads to
+    /// resume. Returns the number of rows dropped.
+    pub(crate) fn prune(&mut self, keep: impl Fn(&str) -> bool) -> usize {
+        let before = self.files.len() + self.stand_ins.len() + self.renamed.len();
+        self.files.retain(|path, _| keep(path));
+        self.stand_ins.retain(|path, _| keep(path));
+        self.renamed.retain(|path, _| keep(path));
+        before - self.files.len() - self.stand_ins.len() - self.renamed.len()
+    }
+
+    /// Loads the index at `path`, or an empty one if the file doesn't
+    /// exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        ScanIndex::load_sealed(path, None)
+    }
+
+    /// `load` of an index sealed under `key`, an at-rest key, if it was;
+    /// see `at_rest`.
+    pub fn load_sealed(path: &Path, key: Option<&WorkspaceKey>) -> Result<Self> {
+        if !path.exists() {
+            return Ok(ScanIndex::new());
+        }
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read scan index {}", path.display()))?;
+        let bytes = open_file(key, bytes).with_context(|| format!("Failed to decrypt scan index {}", path.display()))?;
+        let index: ScanIndex = serde_json::from_slice(&bytes).context("Invalid scan index")?;
+        if index.version == 0 || index.version > SCAN_INDEX_VERSION {
+            bail!("Unsupported scan index version {}", index.version);
+        }
+        Ok(index)
+    }
+
+    /// Saves the index to `path`. Unless `durability` never syncs, a crash
+    /// leaves the old index or the new.
+    pub fn save(&self, path: &Path, durability: Durability) -> Result<()> {
+        self.save_sealed(path, durability, None)
+    }
+
+    /// `save`, sealing the index under `key` if there is one.
+    pub fn save_sealed(&self, path: &Path, durability: Durability, key: Option<&WorkspaceKey>) -> Result<()> {
+        let json = serde_json::to_string(self)?;
+        write_durably(path, &seal_file(key, json.as_bytes()), durability).with_context(|| format!("Failed to write scan index {}", path.display()))
+    }
+}
+
+/// Whether a file modified at `mtime` is too close to `scanned` to trust.
+fn is_racy(mtime: u64, scanned: SystemTime) -> bool {
+    let scanned = scanned.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+    mtime + RACY_NANOS > scanned
+}
+
+fn stat_of(metadata: &Metadata) -> (u64, u64, u64) {
+    (metadata.len(), mtime_nanos(metadata), inode_of(metadata))
+}
+
+fn mtime_nanos(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+#[cfg(unix)]
+fn inode_of(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode_of(_metadata: &Metadata) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod index_test {
+    use super::*;
+    use crate::crdt::replica::Replica;
+    use crate::sync::scan::Scanner;
+    use crate::test_util::alice;
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    fn write_old(path: &Path, data: &[u8], age: u64) {
+        std::fs::write(path, data).unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rescan_only_hashes_changed_files() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-index-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("tree")).unwrap();
+        write_old(&root.join("tree/a"), b"aaaa", 60);
+        write_old(&root.join("tree/b"), b"bbbb", 60);
+        // too fresh to trust yet
+        std::fs::write(root.join("tree/c"), b"cccc").unwrap();
+
+        let index_path = root.join("index.json");
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let mut scanner = Scanner::new(root.join("tree")).with_index(&index_path).unwrap();
+        assert_eq!(scanner.scan(&mut replica, &store).await.unwrap().hashed, 3);
+        assert_eq!(scanner.index().files.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+
+        // the saved index carries over to a new scanner
+        let mut scanner = Scanner::new(root.join("tree")).with_index(&index_path).unwrap();
+        write_old(&root.join("tree/b"), b"BBBB", 30);
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!((report.hashed, report.unchanged), (2, 2));
+        assert_eq!(report.put, vec!["b"]);
+        assert_eq!(ScanIndex::load(&index_path).unwrap(), *scanner.index());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_upload_resumes() {
+        use crate::crdt::chunk::Chunker;
+        use anyhow::bail;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let author = alice();
+        let chunker = Chunker { min_size: 64, avg_size: 256, max_size: 1024 };
+        let mut replica = Replica::new(author.clone()).with_chunker(chunker);
+        let root = std::env::temp_dir().join(format!("crdt-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("tree")).unwrap();
+        let mut state = 7u64;
+        let data: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect();
+        write_old(&root.join("tree/big"), &data, 60);
+
+        // the daemon goes away after 100 chunks
+        let index_path = root.join("index.json");
+        let calls = AtomicUsize::new(0);
+        let failing = async |_cid: IpfsCid, _bytes: Bytes| {
+            if calls.fetch_add(1, Ordering::SeqCst) >= 100 {
+                bail!("Daemon went away");
+            }
+            Ok(())
+        };
+        let mut scanner = Scanner::new(root.join("tree")).with_index(&index_path).unwrap();
+        assert!(scanner.scan(&mut replica, &failing).await.is_err());
+        let index = ScanIndex::load(&index_path).unwrap();
+        let resumed = index.partial["big"].progress.chunks.len();
+        assert!((90..=100).contains(&resumed), "{} chunks saved", resumed);
+
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let mut scanner = Scanner::new(root.join("tree")).with_index(&index_path).unwrap();
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!(report.put, vec!["big"]);
+        assert!(report.reused >= resumed && scanner.index().partial.is_empty());
+
+        // the same entry as an upload that went through in one go
+        let fresh = Replica::new(author).with_chunker(chunker);
+        let (entry, _, _) = fresh.store_stream("big", &data[..], 0o644, 0, &store).await.unwrap();
+        assert_eq!(replica.state().get("big").unwrap().content, entry.content);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_index_saves_at_every_durability() {
+        let root = std::env::temp_dir().join(format!("crdt-durability-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("tree")).unwrap();
+        write_old(&root.join("tree/a"), b"aaaa", 60);
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        for durability in [Durability::Always, Durability::Checkpoint, Durability::Never] {
+            let mut replica = Replica::new(alice()).with_durability(durability);
+            replica.open_journal(root.join(format!("{:?}.journal", durability)), async |cid| Err(anyhow::anyhow!("missing {}", cid))).await.unwrap();
+            let index_path = root.join(format!("{:?}.json", durability));
+            let mut scanner = Scanner::new(root.join("tree")).with_index(&index_path).unwrap().with_durability(durability);
+            scanner.scan(&mut replica, &store).await.unwrap();
+            assert!(ScanIndex::load(&index_path).unwrap().get("a").is_some());
+            assert!(!index_path.with_extension("tmp").exists());
+            assert!(!replica.journal.as_ref().unwrap().records().unwrap().is_empty());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+//! Memory-mapped hashing. Scans and verify passes read files through
+//! buffered reads by default, a syscall and a copy for every piece; with
+//! the `mmap` feature, large files can instead be mapped into memory and
+//! hashed from the map, which the kernel fills with readahead. Where a map
+//! can't be made, or would be served over the network or by FUSE, where a
+//! hiccup turns into a fault in the middle of hashing, the file is read as
+//! before. A file truncated by another program while it is being hashed
+//! from a map kills the process with SIGBUS, so maps are opt-in.
+
+use anyhow::{Context, Result};
+use std::io::SeekFrom;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::Poll;
+use tokio::io::{AsyncRead, AsyncSeekExt, ReadBuf};
+
+use super::scan::Scanner;
+
+/// How scans and verify passes read the files they hash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Hashing {
+    /// Buffered reads, a piece at a time.
+    #[default]
+    Buffered,
+    /// Files of at least `min_size` bytes are mapped into memory; smaller
+    /// ones, and those a map can't be made of, are read as `Buffered`.
+    #[cfg(all(unix, feature = "mmap"))]
+    Mapped { min_size: u64 },
+}
+
+impl Scanner {
+    /// How files are read to hash them; buffered by default.
+    pub fn with_hashing(mut self, hashing: Hashing) -> Self {
+        self.hashing = hashing;
+        self
+    }
+}
+
+impl Hashing {
+    /// Opens the file at `local`, `size` bytes long, to hash it from
+    /// `offset` on.
+    pub(crate) async fn open(self, local: &Path, size: u64, offset: u64) -> Result<HashReader> {
+        let mut file = tokio::fs::File::open(local)
+            .await
+            .with_context(|| format!("Failed to read {}", local.display()))?;
+        if let Some(mapped) = self.map(&file, size, offset) {
+            return Ok(mapped);
+        }
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .with_context(|| format!("Failed to read {}", local.display()))?;
+        Ok(HashReader::File(file))
+    }
+
+    #[cfg(all(unix, feature = "mmap"))]
+    fn map(self, file: &tokio::fs::File, size: u64, offset: u64) -> Option<HashReader> {
+        match self {
+            Hashing::Mapped { min_size } if size >= min_size.max(1) => {
+                let map = mapped::Map::new(file, size)?;
+                Some(HashReader::Mapped { map, pos: offset.min(size) as usize })
+            }
+            _ => None,
+        }
+    }
+
+    #[cfg(not(all(unix, feature = "mmap")))]
+    fn map(self, _file: &tokio::fs::File, _size: u64, _offset: u64) -> Option<HashReader> {
+        None
+    }
+}
+
+/// A file opened to hash, read either way.
+pub(crate) enum HashReader {
+    File(tokio::fs::File),
+    #[cfg(all(unix, feature = "mmap"))]
+    Mapped { map: mapped::Map, pos: usize },
+}
+
+impl AsyncRead for HashReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            HashReader::File(file) => Pin::new(file).poll_read(cx, buf),
+            #[cfg(all(unix, feature = "mmap"))]
+            HashReader::Mapped { map, pos } => {
+                let rest = &map.bytes()[*pos..];
+                let len = rest.len().min(buf.remaining());
+                buf.put_slice(&rest[..len]);
+                *pos += len;
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+mod mapped {
+    use rustix::mm::{madvise, mmap, munmap, Advice, MapFlags, ProtFlags};
+    use std::ffi::c_void;
+
+    /// Filesystems maps aren't made on: NFS, SMB, CIFS, SMB2, Ceph, 9P
+    /// and FUSE.
+    #[cfg(target_os = "linux")]
+    const UNMAPPED_FILESYSTEMS: [u32; 7] = [0x6969, 0x517b, 0xff53_4d42, 0xfe53_4d42, 0x00c3_6400, 0x0102_1997, 0x6573_5546];
+
+    /// A read-only map of a whole file, unmapped on drop.
+    pub(crate) struct Map {
+        ptr: *mut c_void,
+        len: usize,
+    }
+
+    // the map is never written, so it can be read from any thread
+    unsafe impl Send for Map {}
+    unsafe impl Sync for Map {}
+
+    impl Map {
+        /// Maps the first `size` bytes of `file`, unless it is on a
+        /// filesystem maps aren't made on or mapping fails.
+        pub(crate) fn new(file: &tokio::fs::File, size: u64) -> Option<Map> {
+            #[cfg(target_os = "linux")]
+            if rustix::fs::fstatfs(file).is_ok_and(|fs| UNMAPPED_FILESYSTEMS.contains(&(fs.f_type as u32))) {
+                return None;
+            }
+            let len = usize::try_from(size).ok()?;
+            // SAFETY: a fresh private read-only map, which nothing else aliases
+            let ptr = unsafe { mmap(std::ptr::null_mut(), len, ProtFlags::READ, MapFlags::PRIVATE, file, 0) }.ok()?;
+            // SAFETY: the range was just mapped; the advice is only a hint
+            let _ = unsafe { madvise(ptr, len, Advice::Sequential) };
+            Some(Map { ptr, len })
+        }
+
+        pub(crate) fn bytes(&self) -> &[u8] {
+            // SAFETY: the map is `len` readable bytes until it is dropped
+            unsafe { std::slice::from_raw_parts(self.ptr.cast(), self.len) }
+        }
+    }
+
+    impl Drop for Map {
+        fn drop(&mut self) {
+            // SAFETY: the range was mapped by `new` and isn't used past here
+            let _ = unsafe { munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod mmap_test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_hashing_reads_from_an_offset() {
+        let path = std::env::temp_dir().join(format!("crdt-mmap-{}", std::process::id()));
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        #[cfg(all(unix, feature = "mmap"))]
+        let ways = [Hashing::Buffered, Hashing::Mapped { min_size: 1 }, Hashing::Mapped { min_size: 1 << 20 }];
+        #[cfg(not(all(unix, feature = "mmap")))]
+        let ways = [Hashing::Buffered];
+        for hashing in ways {
+            let mut reader = hashing.open(&path, data.len() as u64, 1000).await.unwrap();
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).await.unwrap();
+            assert_eq!(read, data[1000..], "{:?}", hashing);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+//! Rename detection. Neither a full scan nor the watcher sees a file move,
+//! only one path gone and another new; a scan pairs them up by content and
+//! commits each pair as a move, whose put descends from the content the
+//! file had at its old path. A concurrent edit there then merges with the
+//! moved file instead of coming back as a copy. Files edited as they were
+//! moved, symlinks and empty files are left as a remove and a put.
+//!
+//! A directory moved as a whole, every file under it moved to the same
+//! path under a new directory, is committed as one transaction of its own
+//! however many files it holds, so the move is a single node of the DAG
+//! and merging it a single step.
+
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use super::scan::Pending;
+use crate::crdt::history::path_matches;
+use crate::crdt::op::Entry;
+use crate::crdt::replica::Replica;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// Fewest files a directory move is made of; a directory of one file moved
+/// is as well a move of the file.
+pub const MIN_DIR_MOVE_FILES: usize = 2;
+
+/// A directory moved as a whole.
+pub(crate) struct DirMove {
+    pub(crate) from: String,
+    pub(crate) to: String,
+    /// The files moved, by their path under the directory, with the
+    /// extended attributes that changed.
+    pub(crate) files: Vec<(String, Entry, BTreeMap<String, Option<ByteBuf>>)>,
+}
+
+/// Pairs each path `pending` removes with a new path it puts the same
+/// content at, in path order, and turns the pair into a move.
+pub(crate) fn pair_moves(replica: &Replica, pending: Vec<Pending>) -> Vec<Pending> {
+    let mut gone: HashMap<IpfsCid, VecDeque<String>> = HashMap::new();
+    for change in &pending {
+        if let Pending::Remove(path) = change
+            && let Some(entry) = replica.state().get(path).filter(|entry| !entry.is_symlink() && entry.size > 0)
+        {
+            gone.entry(entry.content).or_default().push_back(path.clone());
+        }
+    }
+    if gone.is_empty() {
+        return pending;
+    }
+
+    let mut moved = HashSet::new();
+    let mut paired: Vec<Pending> = pending
+        .into_iter()
+        .map(|change| match change {
+            Pending::Put(to, entry, xattrs) if replica.state().get(&to).is_none() && !entry.is_symlink() => {
+                match gone.get_mut(&entry.content).and_then(VecDeque::pop_front) {
+                    Some(from) => {
+                        moved.insert(from.clone());
+                        Pending::Move(from, to, entry, xattrs)
+                    }
+                    None => Pending::Put(to, entry, xattrs),
+                }
+            }
+            other => other,
+        })
+        .collect();
+    paired.retain(|change| !matches!(change, Pending::Remove(path) if moved.contains(path)));
+    paired
+}
+
+/// Takes the moves out of `pending` that together move a directory: every
+/// file the replica has under it, to the same paths under a directory that
+/// had none. Returns those directory moves and the rest of `pending`.
+pub(crate) fn coalesce_moves(replica: &Replica, pending: Vec<Pending>) -> (Vec<DirMove>, Vec<Pending>) {
+    let mut groups: BTreeMap<(&str, &str), usize> = BTreeMap::new();
+    for change in &pending {
+        if let Pending::Move(from, to, ..) = change
+            && let Some(dirs) = moved_dirs(from, to)
+        {
+            *groups.entry(dirs).or_default() += 1;
+        }
+    }
+    let files_under = |dir: &str| replica.state().iter().filter(|(path, _)| path_matches(dir, path)).count();
+    let whole: HashSet<(String, String)> = groups
+        .into_iter()
+        .filter(|&((from, to), moves)| moves >= MIN_DIR_MOVE_FILES && files_under(from) == moves && files_under(to) == 0)
+        .map(|((from, to), _)| (from.to_string(), to.to_string()))
+        .collect();
+    if whole.is_empty() {
+        return (Vec::new(), pending);
+    }
+
+    let mut dir_moves: BTreeMap<(String, String), Vec<_>> = BTreeMap::new();
+    let mut rest = Vec::new();
+    for change in pending {
+        match change {
+            Pending::Move(from, to, entry, xattrs)
+                if let Some((from_dir, to_dir)) = moved_dirs(&from, &to)
+                    && let dirs = (from_dir.to_string(), to_dir.to_string())
+                    && whole.contains(&dirs) =>
+            {
+                let relative = from[dirs.0.len() + 1..].to_string();
+                dir_moves.entry(dirs).or_default().push((relative, entry, xattrs));
+            }
+            other => rest.push(other),
+        }
+    }
+    let dir_moves = dir_moves.into_iter().map(|((from, to), files)| DirMove { from, to, files }).collect();
+    (dir_moves, rest)
+}
+
+/// The directories a move of `from` to `to` would be part of a move of:
+/// the paths left once the trailing names they share are taken off, if the
+/// file kept its name and neither directory holds the other.
+fn moved_dirs<'a>(from: &'a str, to: &'a str) -> Option<(&'a str, &'a str)> {
+    let (mut from_dir, mut to_dir) = (from, to);
+    while let (Some((from_parent, from_name)), Some((to_parent, to_name))) = (from_dir.rsplit_once('/'), to_dir.rsplit_once('/'))
+        && from_name == to_name
+    {
+        (from_dir, to_dir) = (from_parent, to_parent);
+    }
+    let nested = path_matches(from_dir, to_dir) || path_matches(to_dir, from_dir);
+    (from_dir != from && !nested).then_some((from_dir, to_dir))
+}
+
+#[cfg(test)]
+mod moves_test {
+    use crate::crdt::replica::Replica;
+    use crate::kubo_rpc::ipfs::IpfsCid;
+    use crate::sync::scan::Scanner;
+    use crate::test_util::alice;
+    use bytes::Bytes;
+    use std::time::{Duration, SystemTime};
+
+    #[tokio::test]
+    async fn test_scan_commits_renames_as_moves() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-moves-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("drafts")).unwrap();
+        for (name, data) in [("drafts/plan.md", "the plan"), ("notes.md", "notes")] {
+            std::fs::write(root.join(name), data).unwrap();
+            let file = std::fs::File::options().write(true).open(root.join(name)).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+        }
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let mut scanner = Scanner::new(&root);
+        scanner.scan(&mut replica, &store).await.unwrap();
+        let before = replica.state().get("drafts/plan.md").unwrap();
+
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::rename(root.join("drafts/plan.md"), root.join("docs/plan.md")).unwrap();
+        // edited as well as moved: a remove and a put
+        std::fs::remove_file(root.join("notes.md")).unwrap();
+        std::fs::write(root.join("docs/notes.md"), "more notes").unwrap();
+
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!(report.moved, vec![("drafts/plan.md".to_string(), "docs/plan.md".to_string())]);
+        assert_eq!(report.put, vec!["docs/notes.md"]);
+        assert_eq!(report.removed, vec!["notes.md"]);
+        assert_eq!(report.nodes.len(), 1);
+        assert!(replica.state().get("drafts/plan.md").is_none());
+        // the moved file descends from the old one
+        let lineage = replica.state().lineage("docs/plan.md");
+        assert_eq!(lineage.len(), 2);
+        assert_eq!(lineage[1].content, before.content);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scan_coalesces_directory_moves() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-dir-moves-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("src/old/nested")).unwrap();
+        for (name, data) in [("src/old/a.rs", "a"), ("src/old/nested/b.rs", "b"), ("src/old/nested/c.rs", "c"), ("src/lib.rs", "lib")] {
+            std::fs::write(root.join(name), data).unwrap();
+            let file = std::fs::File::options().write(true).open(root.join(name)).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+        }
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let mut scanner = Scanner::new(&root);
+        scanner.scan(&mut replica, &store).await.unwrap();
+
+        std::fs::rename(root.join("src/old"), root.join("src/new")).unwrap();
+        std::fs::write(root.join("src/lib.rs"), "mod new;").unwrap();
+
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!(report.moved_dirs, vec![("src/old".to_string(), "src/new".to_string())]);
+        assert_eq!(report.moved.len(), 3);
+        assert_eq!(report.put, vec!["src/lib.rs"]);
+        // the directory move and the edit
+        assert_eq!(report.nodes.len(), 2);
+        let paths: Vec<_> = replica.state().iter().map(|(path, _)| path.to_string()).collect();
+        assert_eq!(paths, ["src/lib.rs", "src/new/a.rs", "src/new/nested/b.rs", "src/new/nested/c.rs"]);
+        assert_eq!(replica.state().lineage("src/new/nested/b.rs").len(), 2);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_moved_dirs() {
+        assert_eq!(super::moved_dirs("a/x/f", "b/x/f"), Some(("a", "b")));
+        assert_eq!(super::moved_dirs("old/f", "docs/new/f"), Some(("old", "docs/new")));
+        assert_eq!(super::moved_dirs("a/f", "a/g"), None);
+        assert_eq!(super::moved_dirs("f", "a/f"), None);
+        assert_eq!(super::moved_dirs("a/f", "a/b/f"), None);
+    }
+}
@@ -0,0 +1,140 @@
+//! Names Windows can't hold: the reserved device names like `CON` and
+//! `NUL`, with any extension, names ending in a dot or a space, and names
+//! with `<>:"/\|?*` or control characters in them. A checkout writes such
+//! paths under names with `_` in place of what Windows refuses; as with
+//! case collisions, the index remembers the names, so scans commit edits
+//! under the workspace paths. Roots get the `\\?\` prefix on Windows, which
+//! lifts its limit of 260 characters to a path.
+
+use std::path::{Path, PathBuf};
+
+use super::scan::Scanner;
+
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+impl Scanner {
+    /// Writes paths Windows can't hold under names it can; on by default
+    /// on Windows only.
+    pub fn with_windows_names(mut self, on: bool) -> Self {
+        self.windows_names = on;
+        self
+    }
+}
+
+/// The name a checkout writes the workspace path `path` under where only
+/// names Windows can hold are allowed, or `None` if it can hold `path`.
+pub fn windows_path(path: &str) -> Option<String> {
+    let names: Vec<Option<String>> = path.split('/').map(windows_name).collect();
+    if names.iter().all(Option::is_none) {
+        return None;
+    }
+    let names: Vec<&str> = path.split('/').zip(&names).map(|(name, renamed)| renamed.as_deref().unwrap_or(name)).collect();
+    Some(names.join("/"))
+}
+
+/// `name` with `_` in place of what Windows refuses in it, or `None` if
+/// it takes `name` as it is.
+fn windows_name(name: &str) -> Option<String> {
+    let mut renamed: String =
+        name.chars().map(|c| if c < ' ' || "<>:\"\\|?*".contains(c) { '_' } else { c }).collect();
+    let kept = renamed.trim_end_matches(['.', ' ']).len();
+    if kept < renamed.len() {
+        let trailing = renamed.len() - kept;
+        renamed.truncate(kept);
+        renamed.extend(std::iter::repeat_n('_', trailing));
+    }
+    // `nul.tar.gz` is the device as much as `nul` is
+    let stem = renamed.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        renamed.insert(stem.len(), '_');
+    }
+    (renamed != name).then_some(renamed)
+}
+
+/// `path` made absolute with the `\\?\` prefix, so paths under it may be
+/// longer than 260 characters. Such a path is taken literally, so it must
+/// have no `.` or `..` components and only `\` separators.
+#[cfg(windows)]
+pub(crate) fn long_path(path: PathBuf) -> PathBuf {
+    let Ok(path) = std::path::absolute(&path) else {
+        return path;
+    };
+    let Some(text) = path.to_str() else {
+        return path;
+    };
+    if text.starts_with(r"\\?\") || text.starts_with(r"\\.\") {
+        path
+    } else if let Some(share) = text.strip_prefix(r"\\") {
+        PathBuf::from(format!(r"\\?\UNC\{}", share))
+    } else if text.as_bytes().get(1) == Some(&b':') {
+        PathBuf::from(format!(r"\\?\{}", text))
+    } else {
+        path
+    }
+}
+
+#[cfg(not(windows))]
+pub(crate) fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
+
+/// A symlink target read from disk, with `/` separators.
+pub(crate) fn link_target(target: &Path) -> Option<String> {
+    let target = target.to_str()?;
+    Some(if cfg!(windows) { target.replace('\\', "/") } else { target.to_string() })
+}
+
+#[cfg(test)]
+mod portable_test {
+    use super::*;
+    use crate::crdt::replica::Replica;
+    use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+    use crate::test_util::{alice, entry};
+    use anyhow::anyhow;
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_windows_paths() {
+        assert_eq!(windows_path("docs/readme.md"), None);
+        assert_eq!(windows_path("CON"), Some("CON_".to_string()));
+        assert_eq!(windows_path("logs/nul.tar.gz"), Some("logs/nul_.tar.gz".to_string()));
+        assert_eq!(windows_path("aux./Com1 .txt"), Some("aux_/Com1_ .txt".to_string()));
+        assert_eq!(windows_path("notes. "), Some("notes__".to_string()));
+        assert_eq!(windows_path("C:foo/a\\b?"), Some("C_foo/a_b_".to_string()));
+        assert_eq!(windows_path("console.log"), None);
+    }
+
+    #[tokio::test]
+    async fn test_paths_windows_refuses_are_renamed() {
+        let mut replica = Replica::new(alice());
+        let blobs: HashMap<IpfsCid, Vec<u8>> =
+            [b"device".to_vec(), b"plain".to_vec()].into_iter().map(|data| (IpfsCid::compute(RAW_CODE, &data), data)).collect();
+        let entry = |data: &[u8]| {
+            entry(data)
+        };
+        replica.put("dir./nul.txt", entry(b"device")).unwrap();
+        replica.put("dir_/nul_.txt", entry(b"plain")).unwrap();
+
+        let root = std::env::temp_dir().join(format!("crdt-portable-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let mut scanner = Scanner::new(&root).with_windows_names(true).with_case_insensitive(false);
+        let fetch = async |cid: IpfsCid| blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.windows_names, vec![("dir./nul.txt".to_string(), "dir_/nul_.case1.txt".to_string())]);
+        assert_eq!(std::fs::read(root.join("dir_/nul_.txt")).unwrap(), b"plain");
+
+        // edits land under the workspace path
+        let local = root.join("dir_/nul_.case1.txt");
+        std::fs::write(&local, b"edited").unwrap();
+        std::fs::File::options().write(true).open(&local).unwrap().set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!((report.put, report.removed), (vec!["dir./nul.txt".to_string()], Vec::new()));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+//! The order transfers start in. A checkout of a large workspace fetches
+//! several files at once and a scan uploads several; which go first
+//! decides which files a user has soonest.
+
+use super::scan::Scanner;
+use crate::crdt::history::path_matches;
+
+/// Files fetched and written at once by a checkout by default.
+pub const DEFAULT_DOWNLOADS: usize = 4;
+/// Chunks of one file fetched at once by default: one, in order.
+pub const DEFAULT_CHUNK_DOWNLOADS: usize = 1;
+
+/// Which files a scan uploads, and a checkout fetches, first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Priority {
+    /// In path order.
+    #[default]
+    Path,
+    SmallestFirst,
+    /// Most recently modified first.
+    NewestFirst,
+    /// The files under the first prefix, then those under the second and
+    /// so on, then the rest, each in path order.
+    Prefixes(Vec<String>),
+}
+
+impl Scanner {
+    /// Fetches and writes up to `files` files at once in a checkout.
+    pub fn with_downloads(mut self, files: usize) -> Self {
+        self.downloads = files.max(1);
+        self
+    }
+
+    /// Fetches up to `chunks` chunks of each file at once in a checkout,
+    /// each written at its offset as it arrives.
+    pub fn with_chunk_downloads(mut self, chunks: usize) -> Self {
+        self.chunk_downloads = chunks.max(1);
+        self
+    }
+
+    /// The order transfers start in; path order by default.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+impl Priority {
+    /// Sorts `items` into the order their transfers start in, given the
+    /// path, size and mtime of each.
+    pub(crate) fn sort<T>(&self, items: &mut [T], file: impl Fn(&T) -> (&str, u64, i64)) {
+        match self {
+            Priority::Path => items.sort_by(|a, b| file(a).0.cmp(file(b).0)),
+            Priority::SmallestFirst => items.sort_by_key(|item| {
+                let (path, size, _) = file(item);
+                (size, path.to_string())
+            }),
+            Priority::NewestFirst => items.sort_by_key(|item| {
+                let (path, _, mtime) = file(item);
+                (std::cmp::Reverse(mtime), path.to_string())
+            }),
+            Priority::Prefixes(prefixes) => items.sort_by_key(|item| {
+                let path = file(item).0;
+                let rank = prefixes.iter().position(|prefix| path_matches(prefix, path)).unwrap_or(prefixes.len());
+                (rank, path.to_string())
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod priority_test {
+    use super::*;
+
+    #[test]
+    fn test_priority_orders() {
+        let files = [("b/big", 300, 1), ("a/new", 200, 3), ("c/small", 100, 2)];
+        let order = |priority: Priority| {
+            let mut items = files.to_vec();
+            priority.sort(&mut items, |&(path, size, mtime)| (path, size, mtime));
+            items.iter().map(|(path, _, _)| *path).collect::<Vec<_>>()
+        };
+        assert_eq!(order(Priority::Path), ["a/new", "b/big", "c/small"]);
+        assert_eq!(order(Priority::SmallestFirst), ["c/small", "a/new", "b/big"]);
+        assert_eq!(order(Priority::NewestFirst), ["a/new", "c/small", "b/big"]);
+        assert_eq!(order(Priority::Prefixes(vec!["c".to_string(), "b/".to_string()])), ["c/small", "b/big", "a/new"]);
+    }
+}
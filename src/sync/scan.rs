@@ -0,0 +1,860 @@
+//! The "commit" half of syncing a working directory: walk it, hash every
+//! file, store chunks the workspace doesn't have yet and write the ops that
+//! make the workspace match what is on disk. With an index, a rescan only
+//! reads the files whose size, mtime or inode changed.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+use std::cell::{Cell, RefCell};
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::Metadata;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::busy::Deferral;
+use super::cache::BlockCache;
+use super::checkout::{SymlinkPolicy, TEMP_SUFFIX};
+use super::conflicts::STATE_DIR;
+use super::dedup::ChunkIndex;
+use super::filter::{FileInfo, PathFilter, SkippedFile};
+use super::hooks::{ChangeSet, SyncHook};
+use super::ignore::{IgnoreRules, IGNORE_FILE};
+use super::index::ScanIndex;
+use super::mmap::Hashing;
+use super::moves::{coalesce_moves, pair_moves};
+use super::portable::{link_target, long_path};
+use super::priority::{Priority, DEFAULT_CHUNK_DOWNLOADS, DEFAULT_DOWNLOADS};
+use super::space::SpaceCheck;
+use super::sparse::{in_sparse, sparse_reaches};
+use super::unicode::Normalization;
+use crate::crdt::chunk::put_raw_block;
+use crate::crdt::history::path_matches;
+use crate::crdt::materialize::safe_join;
+use crate::crdt::op::{Entry, EntryPatch};
+use crate::crdt::replica::Replica;
+use crate::crdt::transaction::Transaction;
+use crate::crdt::upload::{same_content, UploadProgress};
+use crate::crypto::WorkspaceKey;
+use crate::durability::Durability;
+use crate::kubo_rpc::ipfs::IpfsCid;
+use crate::progress::{Progress, Reporter};
+use crate::throttle::Bandwidth;
+use crate::workspace::SyncMode;
+
+/// Encoded size at which a scan commits the changes gathered so far, well
+/// under the block size peers accept.
+const MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// What a scan found and wrote.
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    /// Transactions committed, in order; a large scan takes several.
+    pub nodes: Vec<IpfsCid>,
+    /// Files and symlinks created or rewritten.
+    pub put: Vec<String>,
+    /// Files whose mode alone changed.
+    pub patched: Vec<String>,
+    pub removed: Vec<String>,
+    /// Files moved, from the first path to the second; see `pair_moves`.
+    pub moved: Vec<(String, String)>,
+    /// Directories moved as a whole, each in a transaction of its own; their
+    /// files are in `moved` as well.
+    pub moved_dirs: Vec<(String, String)>,
+    pub unchanged: usize,
+    /// Files read and hashed; the index vouched for the rest.
+    pub hashed: usize,
+    /// Chunks stored and those previous versions already had.
+    pub stored: usize,
+    pub reused: usize,
+    /// Entries that can't be synced: special files and names that aren't
+    /// UTF-8.
+    pub skipped: Vec<PathBuf>,
+    /// Files the workspace's file policy leaves out, in path order, with
+    /// the reason.
+    pub policy_skipped: Vec<SkippedFile>,
+}
+
+/// Syncs the directory at `root` into a replica. Only paths in the
+/// replica's scope are looked at, and none that `.crdtignore` files or the
+/// scanner's own rules ignore or that its filter leaves out. A file's mtime is recorded, in seconds
+/// since the Unix epoch, when its content or mode changes; it doesn't make
+/// a change on its own.
+#[derive(Debug, Clone)]
+pub struct Scanner {
+    pub(crate) root: PathBuf,
+    pub(crate) index: ScanIndex,
+    /// Where the index is saved after every scan.
+    pub(crate) index_path: Option<PathBuf>,
+    /// What the saved index is sealed under, if anything.
+    pub(crate) index_key: Option<WorkspaceKey>,
+    /// The files each stored chunk is part of, so copies share them.
+    pub(crate) chunks: ChunkIndex,
+    pub(crate) ignore: IgnoreRules,
+    pub(crate) filter: PathFilter,
+    /// Files hashed at once.
+    parallelism: usize,
+    /// How files are read to hash them.
+    pub(crate) hashing: Hashing,
+    /// When a checkout's writes and the index are synced to disk.
+    pub(crate) durability: Durability,
+    /// Whether a checkout applies the workspace's mode bits, and mtimes.
+    pub(crate) keep_modes: bool,
+    pub(crate) keep_mtimes: bool,
+    /// Whether extended attributes are synced too.
+    #[cfg(all(unix, feature = "xattr"))]
+    pub(crate) xattrs: bool,
+    /// A pull-only scanner never scans, a push-only one never checks out.
+    pub(crate) mode: SyncMode,
+    pub(crate) symlinks: SymlinkPolicy,
+    /// Whether the filesystem folds case; `None` until a checkout finds out.
+    pub(crate) case_insensitive: Option<bool>,
+    /// Whether a checkout renames paths Windows can't hold.
+    pub(crate) windows_names: bool,
+    pub(crate) normalization: Normalization,
+    /// The prefixes a sparse checkout keeps to.
+    pub(crate) sparse: Vec<String>,
+    /// Limits on the chunk transfers of `scan_to` and `checkout_from`.
+    pub(crate) bandwidth: Bandwidth,
+    /// Files a checkout fetches at once, chunks of each file it fetches at
+    /// once, and the order transfers start in.
+    pub(crate) downloads: usize,
+    pub(crate) chunk_downloads: usize,
+    pub(crate) priority: Priority,
+    pub(crate) space_check: SpaceCheck,
+    /// Where fetched blocks are cached, if anywhere.
+    pub(crate) cache: Option<BlockCache>,
+    pub(crate) progress: Reporter,
+    /// How long files deleted in the workspace are kept in the trash, if
+    /// they are moved there.
+    pub(crate) trash: Option<Duration>,
+    /// Files checkouts left as they were because they were in use.
+    pub(crate) deferred: BTreeMap<String, Deferral>,
+    pub(crate) hooks: Vec<Arc<dyn SyncHook>>,
+}
+
+/// A file or symlink found on disk, with its workspace path.
+pub(crate) struct Found {
+    pub(crate) path: String,
+    pub(crate) local: PathBuf,
+    pub(crate) metadata: Metadata,
+}
+
+impl Scanner {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let parallelism = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        Scanner {
+            root: long_path(root.into()),
+            index: ScanIndex::new(),
+            index_path: None,
+            index_key: None,
+            chunks: ChunkIndex::new(),
+            ignore: IgnoreRules::new(),
+            filter: PathFilter::new(),
+            parallelism,
+            hashing: Hashing::default(),
+            durability: Durability::default(),
+            keep_modes: true,
+            keep_mtimes: true,
+            #[cfg(all(unix, feature = "xattr"))]
+            xattrs: false,
+            mode: SyncMode::default(),
+            symlinks: SymlinkPolicy::default(),
+            case_insensitive: None,
+            windows_names: cfg!(windows),
+            normalization: Normalization::default(),
+            sparse: Vec::new(),
+            bandwidth: Bandwidth::default(),
+            downloads: DEFAULT_DOWNLOADS,
+            chunk_downloads: DEFAULT_CHUNK_DOWNLOADS,
+            priority: Priority::default(),
+            space_check: SpaceCheck::default(),
+            cache: None,
+            progress: Reporter::default(),
+            trash: None,
+            deferred: BTreeMap::new(),
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Keeps the index at `path`, loading it if it exists, so rescans
+    /// survive restarts. Without one the index only lasts as long as the
+    /// scanner. A selection saved in it, see `Scanner::select`, takes the
+    /// place of the sparse prefixes and filter given before.
+    pub fn with_index(mut self, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        self.index = ScanIndex::load_sealed(&path, self.index_key.as_ref())?;
+        self.index_path = Some(path);
+        self.restore_selection()?;
+        Ok(self)
+    }
+
+    /// `with_index`, sealing the index under `key`, an at-rest key, since
+    /// it holds every filename; see `at_rest`. An index saved unsealed is
+    /// sealed when next saved.
+    pub fn with_encrypted_index(mut self, path: impl Into<PathBuf>, key: WorkspaceKey) -> Result<Self> {
+        self.index_key = Some(key);
+        self.with_index(path)
+    }
+
+    /// Ignores what `rules` do besides what ignore files do.
+    pub fn with_ignore(mut self, rules: IgnoreRules) -> Self {
+        self.ignore = rules;
+        self
+    }
+
+    /// Only syncs the files `filter` allows.
+    pub fn with_filter(mut self, filter: PathFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Hashes and chunks up to `files` files at once, one per core by
+    /// default.
+    pub fn with_parallelism(mut self, files: usize) -> Self {
+        self.parallelism = files.max(1);
+        self
+    }
+
+    /// When the files a checkout writes, and the index, are synced to disk;
+    /// at checkpoints by default.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Whether a checkout applies the workspace's mode bits and mtimes to
+    /// the files it writes; both by default. Turn modes off where the
+    /// filesystem can't hold them: scans then keep the mode the workspace
+    /// has for a file instead of flattening it to what the disk shows.
+    pub fn with_preserved_metadata(mut self, modes: bool, mtimes: bool) -> Self {
+        self.keep_modes = modes;
+        self.keep_mtimes = mtimes;
+        self
+    }
+
+    /// Limits the rate at which `scan_to` stores chunks and
+    /// `checkout_from` fetches them.
+    pub fn with_bandwidth(mut self, bandwidth: Bandwidth) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// Sends progress events of scans and checkouts to `reporter`.
+    pub fn with_progress(mut self, reporter: Reporter) -> Self {
+        self.progress = reporter;
+        self
+    }
+
+    /// Only syncs one way; see `SyncMode`.
+    pub fn with_mode(mut self, mode: SyncMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// How a checkout writes symlinks; see `SymlinkPolicy`.
+    pub fn with_symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn index(&self) -> &ScanIndex {
+        &self.index
+    }
+
+    /// The chunk index as of the last scan.
+    pub fn chunk_index(&self) -> &ChunkIndex {
+        &self.chunks
+    }
+
+    /// Walks the directory and commits whatever differs from `replica`'s
+    /// state, passing every new chunk to `store` first.
+    pub async fn scan<F>(&mut self, replica: &mut Replica, store: F) -> Result<ScanReport>
+    where
+        F: AsyncFn(IpfsCid, Bytes) -> Result<()>,
+    {
+        self.scan_paths(replica, &[String::new()], store).await
+    }
+
+    /// `scan` limited to `paths`, relative to the root, and what lies under
+    /// them. A path that is gone removes every file under it; the empty
+    /// path is the whole directory.
+    pub async fn scan_paths<F>(&mut self, replica: &mut Replica, paths: &[String], store: F) -> Result<ScanReport>
+    where
+        F: AsyncFn(IpfsCid, Bytes) -> Result<()>,
+    {
+        self.mode.check_push()?;
+        self.progress.emit(|| Progress::ScanStarted);
+        let mut report = ScanReport::default();
+        let scanned = SystemTime::now();
+        let mut walk = self.walk_paths(replica.scope(), paths).await?;
+        report.policy_skipped = walk.apply_policy(&replica.state().file_policy());
+        let Walk { rules, found, filtered, skipped, spellings, .. } = walk;
+        report.skipped = skipped;
+        let prefixes: Vec<String> = paths.iter().map(|prefix| self.normalize(prefix.trim_matches('/')).into_owned()).collect();
+        let under = |path: &str| prefixes.iter().any(|prefix| path_matches(prefix, path));
+        let on_disk: BTreeSet<&str> =
+            found.iter().map(|file| file.path.as_str()).chain(filtered.iter().map(String::as_str)).collect();
+
+        // hash the files the index can't vouch for, several at a time
+        let mut unindexed: Vec<&Found> = found
+            .iter()
+            .filter(|file| file.metadata.is_file() && !self.vouches_for(replica, file))
+            .filter(|file| !self.index.stands_in(&file.path, Some(&file.metadata)))
+            .collect();
+        self.priority.sort(&mut unindexed, |file| (&file.path, file.metadata.len(), mtime_of(&file.metadata)));
+        report.hashed = unindexed.len();
+        self.progress.emit(|| Progress::Hashing { files: unindexed.len() });
+        self.chunks.refresh(replica.state());
+        let mut fresh = HashMap::new();
+        {
+            let shared = Uploads {
+                index: RefCell::new(&mut self.index),
+                index_path: self.index_path.as_deref().map(|path| (path, self.durability)),
+                index_key: self.index_key.as_ref(),
+                chunks: RefCell::new(&mut self.chunks),
+                scanned,
+                hashing: self.hashing,
+                reporter: &self.progress,
+            };
+            let (progress, total, done) = (&self.progress, unindexed.len(), Cell::new(0));
+            let uploads: Vec<_> = stream::iter(unindexed)
+                .map(|file| async {
+                    let uploaded = upload(replica, file, &shared, &store).await;
+                    done.set(done.get() + 1);
+                    progress.emit(|| Progress::Hashed { path: file.path.clone(), done: done.get(), total });
+                    (file.path.as_str(), uploaded)
+                })
+                .buffer_unordered(self.parallelism)
+                .collect()
+                .await;
+            for (path, result) in uploads {
+                fresh.insert(path, result?);
+            }
+        }
+
+        let mut pending = Vec::new();
+        for file in &found {
+            let current = replica.state().get(&file.path);
+            let mut xattrs = BTreeMap::new();
+            let mode = match current.as_ref().filter(|_| !self.keep_modes) {
+                Some(current) => current.mode,
+                None => mode_of(&file.metadata),
+            };
+            let mtime = mtime_of(&file.metadata);
+            let entry = if file.metadata.is_symlink() {
+                let target = tokio::fs::read_link(&file.local)
+                    .await
+                    .with_context(|| format!("Failed to read link {}", file.local.display()))?;
+                let Some(target) = link_target(&target) else {
+                    report.skipped.push(file.local.clone());
+                    continue;
+                };
+                if current.as_ref().is_some_and(|entry| entry.symlink.as_ref() == Some(&target)) {
+                    report.unchanged += 1;
+                    continue;
+                }
+                Entry::symlink(&target, mtime)
+            } else if self.index.stands_in(&file.path, Some(&file.metadata)) {
+                report.unchanged += 1;
+                continue;
+            } else {
+                // a copy standing in for a symlink that was edited is a file now
+                self.index.stand_ins.remove(&file.path);
+                // a file that wasn't hashed is one the index vouched for
+                let fresh = fresh.remove(file.path.as_str());
+                xattrs = self.xattr_changes(replica, file)?;
+                if let Some(current) = current.filter(|entry| fresh.as_ref().is_none_or(|(fresh, _, _)| same_content(entry, fresh))) {
+                    if fresh.is_some() {
+                        self.index.record(&file.path, &file.metadata, current.content.clone(), scanned);
+                    }
+                    let mut patch = EntryPatch { xattrs, ..EntryPatch::default() };
+                    if current.mode != mode {
+                        (patch.mode, patch.mtime) = (Some(mode), Some(mtime));
+                    }
+                    if patch.is_empty() {
+                        report.unchanged += 1;
+                    } else {
+                        pending.push(Pending::Patch(file.path.clone(), patch));
+                        report.patched.push(file.path.clone());
+                    }
+                    continue;
+                }
+                let (mut entry, stored, reused) = fresh.expect("only unindexed files get here");
+                entry.mode = mode;
+                self.index.record(&file.path, &file.metadata, entry.content.clone(), scanned);
+                report.stored += stored;
+                report.reused += reused;
+                entry
+            };
+            pending.push(Pending::Put(file.path.clone(), entry, xattrs));
+            report.put.push(file.path.clone());
+        }
+
+        let gone: Vec<String> = replica
+            .state()
+            .iter()
+            .filter(|(path, entry)| self.filter.allows(&FileInfo::of_entry(path, entry)))
+            .map(|(path, _)| path)
+            .filter(|path| path_matches(replica.scope(), path) && under(path) && !on_disk.contains(path))
+            .filter(|path| in_sparse(&self.sparse, path))
+            .filter(|path| !rules.is_ignored(path, false))
+            // symlinks a checkout skipped
+            .filter(|path| !self.index.stands_in(path, None))
+            .map(str::to_string)
+            .collect();
+        for path in gone {
+            pending.push(Pending::Remove(path.clone()));
+            report.removed.push(path);
+        }
+        let pending = pair_moves(replica, pending);
+
+        // hooks may hold changes back, which later scans find again
+        let mut changes = ChangeSet {
+            put: std::mem::take(&mut report.put),
+            patched: std::mem::take(&mut report.patched),
+            removed: std::mem::take(&mut report.removed),
+        };
+        self.pre_commit(&mut changes).await?;
+        let kept: [HashSet<&String>; 3] = [&changes.put, &changes.patched, &changes.removed].map(|paths| paths.iter().collect());
+        let pending = pending.into_iter().filter_map(|change| match change {
+            // a move a hook held half of back is a put or a remove
+            Pending::Move(from, to, entry, xattrs) => match (kept[0].contains(&to), kept[2].contains(&from)) {
+                (true, true) => Some(Pending::Move(from, to, entry, xattrs)),
+                (true, false) => Some(Pending::Put(to, entry, xattrs)),
+                (false, true) => Some(Pending::Remove(from)),
+                (false, false) => None,
+            },
+            other => Some(other),
+        });
+        let (dir_moves, pending) = coalesce_moves(replica, pending.collect());
+        let mut batch = Batch::default();
+        for dir_move in dir_moves {
+            let files = dir_move.files.iter().map(|(relative, entry, _)| (relative.clone(), entry.clone()));
+            report.nodes.push(replica.commit_transaction(Transaction::new().rename_dir(&dir_move.from, &dir_move.to, files))?);
+            for (relative, _, xattrs) in dir_move.files {
+                let (from, to) = (format!("{}/{}", dir_move.from, relative), format!("{}/{}", dir_move.to, relative));
+                batch.add_xattrs(replica, &to, xattrs, &mut report)?;
+                report.moved.push((from, to));
+            }
+            report.moved_dirs.push((dir_move.from, dir_move.to));
+        }
+        for change in pending {
+            match change {
+                Pending::Move(from, to, entry, xattrs) => {
+                    let size = entry_size(&entry) + from.len();
+                    batch.add(replica, |tx| tx.rename(&from, &to, entry), size, &mut report)?;
+                    batch.add_xattrs(replica, &to, xattrs, &mut report)?;
+                    report.moved.push((from, to));
+                }
+                Pending::Put(path, entry, xattrs) if kept[0].contains(&path) => {
+                    let size = entry_size(&entry);
+                    batch.add(replica, |tx| tx.put(&path, entry), size, &mut report)?;
+                    batch.add_xattrs(replica, &path, xattrs, &mut report)?;
+                }
+                Pending::Patch(path, patch) if kept[1].contains(&path) => {
+                    let size = 32 + xattrs_size(&patch.xattrs);
+                    batch.add(replica, |tx| tx.patch(&path, patch), size, &mut report)?;
+                }
+                Pending::Remove(path) if kept[2].contains(&path) => {
+                    batch.add(replica, |tx| tx.remove(&path), path.len(), &mut report)?;
+                }
+                _ => {}
+            }
+        }
+        batch.commit(replica, &mut report)?;
+        let (moved_from, moved_to): (HashSet<String>, HashSet<String>) = report.moved.iter().cloned().unzip();
+        changes.put.retain(|path| !moved_to.contains(path));
+        changes.removed.retain(|path| !moved_from.contains(path));
+        (report.put, report.patched, report.removed) = (changes.put, changes.patched, changes.removed);
+
+        let files: BTreeSet<&str> =
+            found.iter().filter(|file| file.metadata.is_file()).map(|file| file.path.as_str()).collect();
+        let filtered: BTreeSet<&str> = filtered.iter().map(String::as_str).collect();
+        let sparse = &self.sparse;
+        // what's left out is kept for a checkout to prune
+        self.index.retain(|path| !under(path) || !in_sparse(sparse, path) || files.contains(path) || filtered.contains(path));
+        self.record_spellings(spellings);
+        // the journal has what the index says was committed
+        replica.checkpoint_journal()?;
+        self.checkpoint(&[]).await?;
+        self.post_commit(&report).await;
+        self.progress.emit(|| Progress::ScanFinished { put: report.put.len(), removed: report.removed.len() });
+        Ok(report)
+    }
+
+    /// Without extended attribute support there are none to sync.
+    #[cfg(not(all(unix, feature = "xattr")))]
+    fn xattr_changes(&self, _replica: &Replica, _file: &Found) -> Result<BTreeMap<String, Option<ByteBuf>>> {
+        Ok(BTreeMap::new())
+    }
+
+    #[cfg(not(all(unix, feature = "xattr")))]
+    pub(crate) fn restore_xattrs(&self, _replica: &Replica, _path: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Whether the index vouches that `file` still has the content
+    /// `replica` has at its path.
+    fn vouches_for(&self, replica: &Replica, file: &Found) -> bool {
+        replica.state().get(&file.path).is_some_and(|entry| {
+            self.index
+                .get(&file.path)
+                .is_some_and(|indexed| indexed.matches(&file.metadata) && indexed.content == entry.content)
+        })
+    }
+
+    /// `scan` storing chunks on the IPFS daemon at `base_url`.
+    pub async fn scan_to(&mut self, base_url: &str, replica: &mut Replica) -> Result<ScanReport> {
+        let bandwidth = self.bandwidth.clone();
+        self.scan(replica, async |cid, bytes| {
+            bandwidth.upload(bytes.len()).await;
+            put_raw_block(base_url, &cid, bytes).await
+        })
+        .await
+    }
+
+    /// Walks `paths` as a scan of them does, for a replica with scope
+    /// `scope`. What was found is in path order.
+    pub(crate) async fn walk_paths(&self, scope: &str, paths: &[String]) -> Result<Walk<'_>> {
+        let mut walk = Walk {
+            scope: scope.to_string(),
+            sparse: &self.sparse,
+            rules: self.ignore.clone(),
+            filter: &self.filter,
+            dirs: Vec::new(),
+            found: Vec::new(),
+            filtered: Vec::new(),
+            skipped: Vec::new(),
+            spellings: BTreeMap::new(),
+        };
+        for path in paths {
+            self.walk(path.trim_matches('/'), &mut walk).await?;
+        }
+        self.restore_renamed(&mut walk.found);
+        self.normalize_found(&mut walk);
+        walk.found.sort_by(|a, b| a.path.cmp(&b.path));
+        walk.found.dedup_by(|a, b| a.path == b.path);
+        Ok(walk)
+    }
+
+    /// Adds every file and symlink at or under `start` that `walk` keeps,
+    /// reading the ignore files on the way. Nothing is added if `start`
+    /// doesn't exist.
+    async fn walk(&self, start: &str, walk: &mut Walk<'_>) -> Result<()> {
+        if !start.is_empty() {
+            self.read_ignore_file("", &mut walk.rules).await?;
+            for (end, _) in start.match_indices('/') {
+                self.read_ignore_file(&start[..end], &mut walk.rules).await?;
+            }
+        }
+        let local = if start.is_empty() { self.root.clone() } else { safe_join(&self.root, start)? };
+        match tokio::fs::symlink_metadata(&local).await {
+            Ok(metadata) => walk.visit(start.to_string(), local, metadata),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).with_context(|| format!("Failed to stat {}", local.display())),
+        }
+        while let Some((prefix, dir)) = walk.dirs.pop() {
+            self.read_ignore_file(&prefix, &mut walk.rules).await?;
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("Failed to list {}", dir.display()))?;
+            while let Some(item) = entries.next_entry().await? {
+                let local = item.path();
+                let Some(name) = item.file_name().to_str().map(str::to_string) else {
+                    walk.skipped.push(local);
+                    continue;
+                };
+                let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+                let metadata = tokio::fs::symlink_metadata(&local).await?;
+                walk.visit(path, local, metadata);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the ignore file of directory `dir`, if it has one, to `rules`.
+    async fn read_ignore_file(&self, dir: &str, rules: &mut IgnoreRules) -> Result<()> {
+        let local = self.root.join(dir).join(IGNORE_FILE);
+        match tokio::fs::read(&local).await {
+            Ok(data) => rules.add_file(dir, &String::from_utf8_lossy(&data)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {}", local.display())),
+        }
+    }
+}
+
+/// What a scan has walked so far.
+pub(crate) struct Walk<'a> {
+    scope: String,
+    sparse: &'a [String],
+    /// The scanner's rules and the ignore files read so far.
+    pub(crate) rules: IgnoreRules,
+    filter: &'a PathFilter,
+    /// Directories still to list.
+    dirs: Vec<(String, PathBuf)>,
+    pub(crate) found: Vec<Found>,
+    /// Files on disk the filter leaves out.
+    pub(crate) filtered: Vec<String>,
+    pub(crate) skipped: Vec<PathBuf>,
+    /// How found files are spelled on disk, see `Scanner::normalize_found`.
+    pub(crate) spellings: BTreeMap<String, Option<String>>,
+}
+
+impl Walk<'_> {
+    /// Sorts one directory entry: directories the scope and sparse checkout
+    /// can be in are queued, files and symlinks in both are kept and
+    /// ignored ones, and
+    /// the temporary files and `.crdt` directory of a checkout, are dropped.
+    fn visit(&mut self, path: String, local: PathBuf, metadata: Metadata) {
+        if !path.is_empty() && self.rules.is_ignored(&path, metadata.is_dir()) {
+            return;
+        }
+        // a checkout's file being written, or its conflict quarantine
+        if (path.ends_with(TEMP_SUFFIX) && metadata.is_file()) || (path == STATE_DIR && metadata.is_dir()) {
+            return;
+        }
+        if metadata.is_dir() {
+            // descend only where the scope can be
+            if (path_matches(&self.scope, &path) || path_matches(&path, &self.scope)) && sparse_reaches(self.sparse, &path) {
+                self.dirs.push((path, local));
+            }
+        } else if path_matches(&self.scope, &path) && in_sparse(self.sparse, &path) {
+            let file = FileInfo { path: &path, size: metadata.len(), symlink: metadata.is_symlink() };
+            if !metadata.is_file() && !metadata.is_symlink() {
+                self.skipped.push(local);
+            } else if self.filter.allows(&file) {
+                self.found.push(Found { path, local, metadata });
+            } else {
+                self.filtered.push(path);
+            }
+        }
+    }
+}
+
+/// A change a scan found, before the hooks have seen it.
+pub(crate) enum Pending {
+    /// With the extended attributes that changed too.
+    Put(String, Entry, BTreeMap<String, Option<ByteBuf>>),
+    Patch(String, EntryPatch),
+    Remove(String),
+    /// A file gone from the first path and put at the second, as `Put`.
+    Move(String, String, Entry, BTreeMap<String, Option<ByteBuf>>),
+}
+
+/// Changes gathered for the next transaction.
+#[derive(Default)]
+struct Batch {
+    tx: Transaction,
+    bytes: usize,
+}
+
+impl Batch {
+    /// Adds a change of about `size` encoded bytes, committing what was
+    /// gathered first if it would grow too large.
+    fn add(
+        &mut self,
+        replica: &mut Replica,
+        change: impl FnOnce(Transaction) -> Transaction,
+        size: usize,
+        report: &mut ScanReport,
+    ) -> Result<()> {
+        if !self.tx.is_empty() && self.bytes + size > MAX_BATCH_BYTES {
+            self.commit(replica, report)?;
+        }
+        self.tx = change(std::mem::take(&mut self.tx));
+        self.bytes += size;
+        Ok(())
+    }
+
+    /// Adds a patch of the extended attributes `xattrs` changed at `path`,
+    /// if any did.
+    fn add_xattrs(
+        &mut self,
+        replica: &mut Replica,
+        path: &str,
+        xattrs: BTreeMap<String, Option<ByteBuf>>,
+        report: &mut ScanReport,
+    ) -> Result<()> {
+        if xattrs.is_empty() {
+            return Ok(());
+        }
+        let size = xattrs_size(&xattrs);
+        let patch = EntryPatch { xattrs, ..EntryPatch::default() };
+        self.add(replica, |tx| tx.patch(path, patch), size, report)
+    }
+
+    fn commit(&mut self, replica: &mut Replica, report: &mut ScanReport) -> Result<()> {
+        if !self.tx.is_empty() {
+            report.nodes.push(replica.commit_transaction(std::mem::take(&mut self.tx))?);
+        }
+        self.bytes = 0;
+        Ok(())
+    }
+}
+
+/// What the uploads of a scan running at once share.
+struct Uploads<'a> {
+    index: RefCell<&'a mut ScanIndex>,
+    /// Where the index is saved, and how durably.
+    index_path: Option<(&'a Path, Durability)>,
+    index_key: Option<&'a WorkspaceKey>,
+    chunks: RefCell<&'a mut ChunkIndex>,
+    scanned: SystemTime,
+    hashing: Hashing,
+    reporter: &'a Reporter,
+}
+
+/// Stores the chunks of `file` no file of the workspace is made of yet,
+/// going on from where an earlier upload of it was cut short, and returns
+/// the entry to put with how many chunks were stored and reused. An upload
+/// cut short now is saved in the shared index.
+async fn upload<F>(replica: &Replica, file: &Found, shared: &Uploads<'_>, store: &F) -> Result<(Entry, usize, usize)>
+where
+    F: AsyncFn(IpfsCid, Bytes) -> Result<()>,
+{
+    let (chunks, bytes) = (Cell::new(0), Cell::new(0));
+    let store = async |cid: IpfsCid, block: Bytes| {
+        let len = block.len() as u64;
+        store(cid.clone(), block).await?;
+        shared.chunks.borrow_mut().insert(cid, &file.path);
+        chunks.set(chunks.get() + 1);
+        bytes.set(bytes.get() + len);
+        let size = file.metadata.len();
+        shared.reporter.emit(|| Progress::ChunkUploaded { path: file.path.clone(), chunks: chunks.get(), bytes: bytes.get().min(size), size });
+        Ok(())
+    };
+    let mut progress = shared.index.borrow().partial(&file.path, &file.metadata).cloned().unwrap_or_default();
+    let reader = shared.hashing.open(&file.local, file.metadata.len(), progress.offset()).await?;
+    let save = |progress: &UploadProgress| {
+        let mut index = shared.index.borrow_mut();
+        index.record_partial(&file.path, &file.metadata, progress, shared.scanned);
+        shared.index_path.map_or(Ok(()), |(path, durability)| index.save_sealed(path, durability, shared.index_key))
+    };
+    let elsewhere = |cid: &IpfsCid| shared.chunks.borrow().contains(cid);
+    match replica.stream_chunks(&file.path, reader, &mut progress, save, elsewhere, &store).await {
+        Ok((stored, reused)) => {
+            Ok((replica.streamed_entry(progress.chunks, mode_of(&file.metadata), mtime_of(&file.metadata)), stored, reused))
+        }
+        Err(err) => {
+            save(&progress)?;
+            Err(err)
+        }
+    }
+}
+
+/// Rough encoded size of a put of `entry`, lineage included.
+fn xattrs_size(xattrs: &BTreeMap<String, Option<ByteBuf>>) -> usize {
+    xattrs.iter().map(|(name, value)| name.len() + value.as_ref().map_or(0, |value| value.len())).sum()
+}
+
+fn entry_size(entry: &Entry) -> usize {
+    256 + entry.chunks.len() * 48 + entry.symlink.as_ref().map_or(0, String::len)
+}
+
+#[cfg(unix)]
+pub(crate) fn mode_of(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub(crate) fn mode_of(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+fn mtime_of(metadata: &Metadata) -> i64 {
+    match metadata.modified().map(|time| time.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(since)) => since.as_secs() as i64,
+        Ok(Err(before)) => -(before.duration().as_secs() as i64),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod scan_test {
+    use super::*;
+    use crate::crdt::chunk::{read_entry, Chunker};
+    use crate::test_util::alice;
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_scan_commits_only_differences() {
+        let mut replica = Replica::new(alice()).with_chunker(Chunker { min_size: 64, avg_size: 256, max_size: 1024 });
+        let root = std::env::temp_dir().join(format!("crdt-scan-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("src/nested")).unwrap();
+        std::fs::write(root.join("README"), b"hello").unwrap();
+        std::fs::write(root.join("src/nested/big.bin"), vec![7u8; 5000]).unwrap();
+        std::fs::write(root.join("src/empty"), b"").unwrap();
+
+        let blocks = Mutex::new(HashMap::new());
+        let store = async |cid: IpfsCid, bytes: Bytes| {
+            blocks.lock().unwrap().insert(cid, bytes.to_vec());
+            Ok(())
+        };
+        let mut scanner = Scanner::new(&root);
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!(report.put, vec!["README", "src/empty", "src/nested/big.bin"]);
+        assert_eq!(report.nodes.len(), 1);
+
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert!(report.nodes.is_empty());
+        assert_eq!(report.unchanged, 3);
+
+        std::fs::write(root.join("README"), b"hello again").unwrap();
+        std::fs::remove_file(root.join("src/empty")).unwrap();
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!((report.put, report.removed, report.unchanged), (vec!["README".to_string()], vec!["src/empty".to_string()], 1));
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let blocks = blocks.into_inner().unwrap();
+        let mut fetch = async |cid: IpfsCid| blocks.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let readme = replica.state().get("README").unwrap();
+        assert_eq!(read_entry(&readme, &mut fetch).await.unwrap(), b"hello again");
+        let big = replica.state().get("src/nested/big.bin").unwrap();
+        assert_eq!(read_entry(&big, &mut fetch).await.unwrap(), vec![7u8; 5000]);
+    }
+
+    #[tokio::test]
+    async fn test_scan_hashes_files_in_parallel() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let author = alice();
+        let chunker = Chunker { min_size: 64, avg_size: 256, max_size: 1024 };
+        let root = std::env::temp_dir().join(format!("crdt-parallel-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        for i in 0..8u8 {
+            let data: Vec<u8> = (0..20_000u32).map(|n| (n.wrapping_mul(2654435761) >> 13) as u8 ^ i).collect();
+            std::fs::write(root.join(format!("file{}", i)), data).unwrap();
+        }
+
+        let (in_flight, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let store = async |_cid: IpfsCid, _bytes: Bytes| {
+            peak.fetch_max(in_flight.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        };
+        let mut one = Replica::new(author.clone()).with_chunker(chunker).with_upload_window(1);
+        let mut many = Replica::new(author).with_chunker(chunker).with_upload_window(1);
+        Scanner::new(&root).with_parallelism(1).scan(&mut one, &store).await.unwrap();
+        assert_eq!(peak.swap(0, Ordering::SeqCst), 1);
+        let report = Scanner::new(&root).with_parallelism(4).scan(&mut many, &store).await.unwrap();
+        assert_eq!((report.put.len(), report.hashed), (8, 8));
+        assert!(peak.load(Ordering::SeqCst) > 1);
+        // the same entries, in the same order
+        assert_eq!(one.state().iter().collect::<Vec<_>>(), many.state().iter().collect::<Vec<_>>());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
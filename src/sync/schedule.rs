@@ -0,0 +1,128 @@
+//! When a syncer works. A schedule can hold local changes back for rounds
+//! at an interval instead of committing each burst as it settles, and keep
+//! heavy transfers, the uploads of scans and the downloads of checkouts,
+//! to daily windows or out of quiet hours. Merges and publishes only move
+//! DAG nodes and go on at any hour, so a checkout merely falls behind and
+//! catches up when a window opens. `SyncControl::sync_now` forces a round
+//! at once, whatever the schedule says.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// A stretch of each day, from `start` to `end` past midnight local time;
+/// one that ends before it starts runs past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub start: Duration,
+    pub end: Duration,
+}
+
+impl Window {
+    pub fn new(start: Duration, end: Duration) -> Self {
+        Window { start, end }
+    }
+
+    /// Whether the window holds the time `secs` past midnight.
+    fn contains(&self, secs: i64) -> bool {
+        let (start, end) = (self.start.as_secs() as i64 % DAY_SECS, self.end.as_secs() as i64 % DAY_SECS);
+        if start <= end { (start..end).contains(&secs) } else { secs >= start || secs < end }
+    }
+}
+
+/// When a syncer commits and moves content; by default as soon as changes
+/// settle, at any hour.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    /// Time between commits of local changes, if they wait for one.
+    pub interval: Option<Duration>,
+    /// Heavy transfers happen only inside these; at any hour if empty.
+    pub windows: Vec<Window>,
+    /// Heavy transfers never happen inside these.
+    pub quiet_hours: Vec<Window>,
+    /// Minutes local time is ahead of UTC, which the windows are in.
+    pub utc_offset: i32,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Schedule::default()
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    pub fn with_window(mut self, window: Window) -> Self {
+        self.windows.push(window);
+        self
+    }
+
+    pub fn with_quiet_hours(mut self, window: Window) -> Self {
+        self.quiet_hours.push(window);
+        self
+    }
+
+    pub fn with_utc_offset(mut self, minutes: i32) -> Self {
+        self.utc_offset = minutes;
+        self
+    }
+
+    /// Whether heavy transfers may happen at `at`.
+    pub fn allows(&self, at: SystemTime) -> bool {
+        self.allows_at(self.time_of_day(at))
+    }
+
+    /// How long from `now` until heavy transfers may happen, zero if they
+    /// may now; `None` if quiet hours cover every window.
+    pub fn opens_in(&self, now: SystemTime) -> Option<Duration> {
+        let secs = self.time_of_day(now);
+        // a day opens at its start, at a window's or at the end of quiet hours
+        let edges = self.windows.iter().map(|window| window.start).chain(self.quiet_hours.iter().map(|window| window.end));
+        std::iter::once(0)
+            .chain(edges.map(|edge| (edge.as_secs() as i64 - secs).rem_euclid(DAY_SECS)))
+            .filter(|wait| self.allows_at((secs + wait) % DAY_SECS))
+            .min()
+            .map(|wait| Duration::from_secs(wait as u64))
+    }
+
+    fn allows_at(&self, secs: i64) -> bool {
+        (self.windows.is_empty() || self.windows.iter().any(|window| window.contains(secs)))
+            && !self.quiet_hours.iter().any(|window| window.contains(secs))
+    }
+
+    /// Seconds past local midnight at `at`.
+    fn time_of_day(&self, at: SystemTime) -> i64 {
+        let since = at.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() as i64);
+        (since + self.utc_offset as i64 * 60).rem_euclid(DAY_SECS)
+    }
+}
+
+#[cfg(test)]
+mod schedule_test {
+    use super::*;
+
+    #[test]
+    fn test_windows_and_quiet_hours() {
+        let hours = |hours: u64| Duration::from_secs(hours * 3600);
+        let day = UNIX_EPOCH + Duration::from_secs(20_000 * 86_400);
+        // transfers at night, but not during the backup at 2
+        let schedule = Schedule::new()
+            .with_window(Window::new(hours(22), hours(6)))
+            .with_quiet_hours(Window::new(hours(2), hours(3)))
+            .with_utc_offset(60);
+        assert!(Schedule::new().allows(day));
+        assert_eq!(Schedule::new().opens_in(day), Some(Duration::ZERO));
+        // 23:00 local
+        assert!(schedule.allows(day + hours(22)));
+        // 02:30 local
+        assert!(!schedule.allows(day + hours(1) + Duration::from_secs(1800)));
+        assert_eq!(schedule.opens_in(day + hours(1) + Duration::from_secs(1800)), Some(Duration::from_secs(1800)));
+        // noon local
+        assert_eq!(schedule.opens_in(day + hours(11)), Some(hours(10)));
+
+        let never = Schedule::new().with_window(Window::new(hours(1), hours(2))).with_quiet_hours(Window::new(hours(0), hours(3)));
+        assert_eq!(never.opens_in(day), None);
+    }
+}
@@ -0,0 +1,164 @@
+//! Free-space preflight for checkouts. Before writing anything, a checkout
+//! adds up the content it is about to fetch and compares it with the space
+//! free on the filesystem of the directory, so a pull too big for the disk
+//! fails at once with the shortfall instead of halfway through with "no
+//! space left on device". Files are written beside their old versions and
+//! renamed over them, and deletions come last, so nothing a checkout
+//! replaces or removes is counted as freed.
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use super::scan::Scanner;
+use crate::crdt::op::Entry;
+
+/// What a checkout does when the content it would fetch doesn't fit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpaceCheck {
+    /// Fail with `InsufficientSpace` before writing anything.
+    #[default]
+    Strict,
+    /// Write files in priority order while they fit and leave the rest
+    /// out, reported in `CheckoutReport::no_space`; a later checkout
+    /// fetches them.
+    Partial,
+    /// Don't look at free space.
+    Off,
+}
+
+/// A checkout refused because its content doesn't fit on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsufficientSpace {
+    pub root: PathBuf,
+    /// Bytes the checkout would fetch.
+    pub needed: u64,
+    /// Bytes free to this process on the filesystem of `root`.
+    pub available: u64,
+}
+
+impl InsufficientSpace {
+    pub fn shortfall(&self) -> u64 {
+        self.needed - self.available
+    }
+}
+
+impl fmt::Display for InsufficientSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Checkout needs {} bytes but {} has only {} free, {} short",
+            self.needed,
+            self.root.display(),
+            self.available,
+            self.shortfall()
+        )
+    }
+}
+
+impl std::error::Error for InsufficientSpace {}
+
+impl Scanner {
+    /// What a checkout does when the content it would fetch doesn't fit
+    /// on disk; it fails before writing anything by default.
+    pub fn with_space_check(mut self, check: SpaceCheck) -> Self {
+        self.space_check = check;
+        self
+    }
+
+    /// Checks the files a checkout would write, `fetched` telling which
+    /// need their content fetched, fit on disk. Returns those left out.
+    pub(crate) fn check_space(&self, files: &mut Vec<(String, Entry)>, fetched: impl Fn(&str, &Entry) -> bool) -> Result<Vec<String>> {
+        if self.space_check == SpaceCheck::Off {
+            return Ok(Vec::new());
+        }
+        match free_space(&self.root)? {
+            Some(available) => self.space_check.fit(&self.root, files, fetched, available),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+impl SpaceCheck {
+    fn fit(self, root: &Path, files: &mut Vec<(String, Entry)>, fetched: impl Fn(&str, &Entry) -> bool, available: u64) -> Result<Vec<String>> {
+        let needed: u64 = files.iter().filter(|(path, entry)| fetched(path, entry)).map(|(_, entry)| entry.size).sum();
+        if needed <= available || self == SpaceCheck::Off {
+            return Ok(Vec::new());
+        }
+        if self == SpaceCheck::Strict {
+            return Err(InsufficientSpace { root: root.to_path_buf(), needed, available }.into());
+        }
+        let mut left = available;
+        let mut no_space = Vec::new();
+        files.retain(|(path, entry)| {
+            if !fetched(path, entry) {
+                return true;
+            }
+            if entry.size <= left {
+                left -= entry.size;
+                return true;
+            }
+            no_space.push(path.clone());
+            false
+        });
+        Ok(no_space)
+    }
+}
+
+/// Bytes free to this process on the filesystem holding `path`, or `None`
+/// where that can't be told.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Result<Option<u64>> {
+    let stat = rustix::fs::statvfs(path).with_context(|| format!("Failed to get free space of {}", path.display()))?;
+    Ok(Some(stat.f_bavail.saturating_mul(stat.f_frsize)))
+}
+
+#[cfg(windows)]
+pub fn free_space(path: &Path) -> Result<Option<u64>> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0;
+    // SAFETY: `wide` is NUL-terminated and the totals not asked for are null
+    if unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, std::ptr::null_mut(), std::ptr::null_mut()) } == 0 {
+        return Err(std::io::Error::last_os_error()).with_context(|| format!("Failed to get free space of {}", path.display()));
+    }
+    Ok(Some(available))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn free_space(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod space_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+
+    fn file(path: &str, size: usize) -> (String, Entry) {
+        let data = vec![0; size];
+        let entry = Entry { content: IpfsCid::compute(RAW_CODE, &data), size: size as u64, mode: 0o644, mtime: 0, chunks: Vec::new(), symlink: None };
+        (path.to_string(), entry)
+    }
+
+    #[test]
+    fn test_checkout_fits_free_space() {
+        let root = Path::new("/tmp/root");
+        let files = vec![file("big.bin", 600), file("kept.txt", 500), file("small.txt", 300), file("last.txt", 200)];
+        let fetched = |path: &str, _: &Entry| path != "kept.txt";
+
+        let err = SpaceCheck::Strict.fit(root, &mut files.clone(), fetched, 1000).unwrap_err();
+        let err = err.downcast_ref::<InsufficientSpace>().unwrap();
+        assert_eq!((err.needed, err.shortfall()), (1100, 100));
+
+        let mut partial = files.clone();
+        assert_eq!(SpaceCheck::Partial.fit(root, &mut partial, fetched, 1000).unwrap(), vec!["last.txt"]);
+        let kept: Vec<&str> = partial.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(kept, vec!["big.bin", "kept.txt", "small.txt"]);
+
+        assert!(SpaceCheck::Strict.fit(root, &mut files.clone(), fetched, 1100).unwrap().is_empty());
+        assert!(free_space(&std::env::temp_dir()).unwrap().is_some_and(|free| free > 0));
+    }
+}
@@ -0,0 +1,183 @@
+//! Sparse checkouts: a scanner can be told to only materialize some
+//! prefixes of a huge workspace, say `docs` and `src`. The replica still
+//! merges the whole state, but checkouts write, and fetch the content of,
+//! only the files under those prefixes, and scans neither walk nor remove
+//! anything outside them. Unlike a replica's scope, which leaves the rest
+//! of the state out altogether, the selection can change at any time;
+//! one made with `Scanner::select` is kept in the scan index, so it
+//! survives restarts.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::checkout::CheckoutReport;
+use super::filter::{PathFilter, Pattern};
+use super::scan::Scanner;
+use crate::crdt::history::path_matches;
+use crate::crdt::replica::Replica;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// What a scanner syncs, as saved in its index: the prefixes of a sparse
+/// checkout and the patterns of its filter.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Selection {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sparse: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<Pattern>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<Pattern>,
+}
+
+impl Scanner {
+    /// Only checks out and scans the files at or under `prefixes`; all of
+    /// them if there is none. A checkout removes files it wrote before that
+    /// are outside them now, unless they changed on disk since.
+    pub fn with_sparse<S: AsRef<str>>(mut self, prefixes: impl IntoIterator<Item = S>) -> Self {
+        self.sparse = prefixes.into_iter().map(|prefix| prefix.as_ref().trim_matches('/').to_string()).collect();
+        self
+    }
+
+    /// The prefixes a sparse checkout keeps to, empty for a full one.
+    pub fn sparse(&self) -> &[String] {
+        &self.sparse
+    }
+
+    /// Changes what the scanner syncs to the files at or under `sparse`
+    /// that `filter` allows, and checks out again: files newly selected are
+    /// fetched and written, and those no longer selected are removed from
+    /// disk unless they changed since. The selection is saved with the
+    /// index, but for the filter's predicates, which live in code.
+    pub async fn select<F>(&mut self, replica: &Replica, sparse: &[&str], filter: PathFilter, fetch: F) -> Result<CheckoutReport>
+    where
+        F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let (include, exclude) = filter.patterns();
+        self.sparse = sparse.iter().map(|prefix| prefix.trim_matches('/').to_string()).collect();
+        self.filter = filter;
+        self.index.selection = Some(Selection { sparse: self.sparse.clone(), include, exclude });
+        if !self.mode.pulls() {
+            self.checkpoint(&[]).await?;
+            return Ok(CheckoutReport::default());
+        }
+        self.checkout(replica, fetch).await
+    }
+
+    /// `select` fetching content from the IPFS daemon at `base_url`,
+    /// decrypting it if the replica has a workspace key.
+    pub async fn select_from(&mut self, base_url: &str, replica: &Replica, sparse: &[&str], filter: PathFilter) -> Result<CheckoutReport> {
+        let bandwidth = self.bandwidth.clone();
+        self.select(replica, sparse, filter, async |cid| {
+            let data = replica.fetch_content(base_url, &cid).await?;
+            bandwidth.download(data.len()).await;
+            Ok(data)
+        })
+        .await
+    }
+
+    /// Takes up the selection saved in the index, if any.
+    pub(crate) fn restore_selection(&mut self) -> Result<()> {
+        if let Some(selection) = &self.index.selection {
+            self.filter = PathFilter::from_patterns(&selection.include, &selection.exclude)?;
+            self.sparse = selection.sparse.clone();
+        }
+        Ok(())
+    }
+}
+
+/// Whether the file at `path` is in the sparse checkout of `prefixes`.
+pub(crate) fn in_sparse(prefixes: &[String], path: &str) -> bool {
+    prefixes.is_empty() || prefixes.iter().any(|prefix| path_matches(prefix, path))
+}
+
+/// Whether files under the directory `dir` can be in the sparse checkout
+/// of `prefixes`.
+pub(crate) fn sparse_reaches(prefixes: &[String], dir: &str) -> bool {
+    prefixes.is_empty() || prefixes.iter().any(|prefix| path_matches(prefix, dir) || path_matches(dir, prefix))
+}
+
+#[cfg(test)]
+mod sparse_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use crate::sync::filter::Matcher;
+    use crate::test_util::{alice, entry};
+    use anyhow::anyhow;
+    use bytes::Bytes;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_sparse_checkout_keeps_to_its_prefixes() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-sparse-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for path in ["from/docs/guide.md", "from/src/lib.rs", "from/assets/big.bin"] {
+            std::fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            std::fs::write(root.join(path), path.as_bytes()).unwrap();
+            let file = std::fs::File::options().write(true).open(root.join(path)).unwrap();
+            file.set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(60)).unwrap();
+        }
+        let blobs = std::sync::Mutex::new(HashMap::new());
+        let store = async |cid: IpfsCid, bytes: Bytes| {
+            blobs.lock().unwrap().insert(cid, bytes.to_vec());
+            Ok(())
+        };
+        Scanner::new(root.join("from")).scan(&mut replica, &store).await.unwrap();
+
+        // content outside the prefixes is never asked for
+        let fetched = std::sync::Mutex::new(Vec::new());
+        let fetch = async |cid: IpfsCid| {
+            fetched.lock().unwrap().push(cid.clone());
+            blobs.lock().unwrap().get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid))
+        };
+        let mut scanner = Scanner::new(root.join("to")).with_sparse(["docs", "src/"]);
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.written, vec!["docs/guide.md", "src/lib.rs"]);
+        assert_eq!(fetched.lock().unwrap().len(), 2);
+        assert!(!root.join("to/assets").exists());
+
+        // a scan doesn't take the missing files for deleted ones
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert!(report.removed.is_empty());
+        assert_eq!(replica.state().iter().count(), 3);
+
+        // narrowing the selection removes what fell out of it
+        let mut scanner = scanner.with_sparse(["docs"]);
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.removed, vec!["src/lib.rs"]);
+        assert!(!root.join("to/src").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_selection_is_saved_with_the_index() {
+        let mut replica = Replica::new(alice());
+        let blobs: HashMap<IpfsCid, Vec<u8>> =
+            [b"guide".to_vec(), b"draft".to_vec()].into_iter().map(|data| (IpfsCid::compute(RAW_CODE, &data), data)).collect();
+        let entry = |data: &[u8]| {
+            entry(data)
+        };
+        replica.put("docs/guide.md", entry(b"guide")).unwrap();
+        replica.put("docs/draft.tmp", entry(b"draft")).unwrap();
+        replica.put("src/lib.rs", entry(b"guide")).unwrap();
+        let root = std::env::temp_dir().join(format!("crdt-select-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let index_path = root.join("index.json");
+        let fetch = async |cid: IpfsCid| blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+
+        let mut scanner = Scanner::new(root.join("tree")).with_index(&index_path).unwrap();
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.written.len(), 3);
+        let filter = PathFilter::new().exclude(Matcher::glob("**/*.tmp").unwrap());
+        let report = scanner.select(&replica, &["docs"], filter, fetch).await.unwrap();
+        assert_eq!(report.removed, vec!["docs/draft.tmp", "src/lib.rs"]);
+
+        // a restart picks the selection up, and widening it fetches again
+        let mut scanner = Scanner::new(root.join("tree")).with_index(&index_path).unwrap();
+        assert_eq!(scanner.sparse(), ["docs"]);
+        assert!(scanner.checkout(&replica, fetch).await.unwrap().written.is_empty());
+        let report = scanner.select(&replica, &[], PathFilter::new(), fetch).await.unwrap();
+        assert_eq!(report.written, vec!["docs/draft.tmp", "src/lib.rs"]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+//! What a scan and a checkout would do, without doing either: each path
+//! whose disk and workspace versions differ, and on which side it changed
+//! since it was last synced. The scan index holds that last synced
+//! version; a file it doesn't know counts as new on whichever side has it.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use super::cache::fetch_cached;
+use super::filter::FileInfo;
+use super::scan::{mode_of, Found, Scanner, Walk};
+use super::sparse::in_sparse;
+use crate::crdt::history::path_matches;
+use crate::crdt::op::Entry;
+use crate::crdt::replica::Replica;
+use crate::crdt::upload::same_content;
+use crate::kubo_rpc::ipfs::{get_block, IpfsCid};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// How a path differs between disk and the workspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    /// Changed on disk; a scan would commit it.
+    Local(Change),
+    /// Changed in the workspace; a checkout would write it.
+    Remote(Change),
+    /// Changed on both sides, differently.
+    Conflict,
+}
+
+#[derive(Debug, Default)]
+pub struct Status {
+    /// Every path that differs, in path order.
+    pub files: BTreeMap<String, FileStatus>,
+    /// Entries that can't be synced: special files and names that aren't
+    /// UTF-8.
+    pub skipped: Vec<PathBuf>,
+}
+
+impl Status {
+    pub fn is_clean(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// A file as it is on disk.
+enum OnDisk {
+    /// As it was last synced, by the index.
+    Synced,
+    Hashed(Entry),
+    Link(String),
+    Missing,
+}
+
+impl Scanner {
+    /// Compares the directory with `replica`'s current state. Reads every
+    /// file the index doesn't vouch for but changes nothing, the index
+    /// included.
+    pub async fn status(&self, replica: &Replica) -> Result<Status> {
+        let mut walk = self.walk_paths(replica.scope(), &[String::new()]).await?;
+        // a scan would leave these as they are
+        let policy_skipped: BTreeSet<String> =
+            walk.apply_policy(&replica.state().file_policy()).into_iter().map(|file| file.path).collect();
+        let Walk { rules, found, skipped, .. } = walk;
+        let kept = |path: &str, info: &FileInfo| {
+            path_matches(replica.scope(), path)
+                && in_sparse(&self.sparse, path)
+                && !rules.is_ignored(path, false)
+                && self.filter.allows(info)
+                && !policy_skipped.contains(path)
+        };
+        let mut on_disk: BTreeMap<&str, &Found> = found.iter().map(|file| (file.path.as_str(), file)).collect();
+        let paths: BTreeSet<String> = replica
+            .state()
+            .iter()
+            .filter(|(path, entry)| kept(path, &FileInfo::of_entry(path, entry)))
+            .map(|(path, _)| path.to_string())
+            .chain(
+                self.index
+                    .files
+                    .iter()
+                    .filter(|(path, indexed)| kept(path, &FileInfo { path, size: indexed.size, symlink: false }))
+                    .map(|(path, _)| path.clone()),
+            )
+            .chain(on_disk.keys().map(|path| path.to_string()))
+            .collect();
+
+        let mut status = Status { skipped, ..Status::default() };
+        for path in paths {
+            let file = on_disk.remove(path.as_str());
+            // a symlink checked out as a copy, or skipped
+            if self.index.stands_in(&path, file.map(|file| &file.metadata)) {
+                continue;
+            }
+            let disk = match file {
+                None => OnDisk::Missing,
+                Some(file) => self.on_disk(replica, file).await?,
+            };
+            let base = self.index.get(&path).map(|indexed| &indexed.content);
+            if let Some(file_status) = classify(&disk, base, replica.state().get(&path).as_ref()) {
+                status.files.insert(path, file_status);
+            }
+        }
+        Ok(status)
+    }
+
+    /// `status` against the state `replica` would have after merging
+    /// `heads`, fetching nodes with `fetch`: what a pull would change and
+    /// what it would conflict with.
+    pub async fn diff<F>(&self, replica: &Replica, heads: &[IpfsCid], mut fetch: F) -> Result<Status>
+    where
+        F: AsyncFnMut(IpfsCid) -> Result<Vec<u8>>,
+    {
+        let mut merged = replica.clone();
+        for head in heads {
+            merged.merge_head(head, &mut fetch).await?;
+        }
+        self.status(&merged).await
+    }
+
+    /// `diff` fetching nodes from the IPFS daemon at `base_url`, unless
+    /// the block cache holds them.
+    pub async fn diff_from(&self, base_url: &str, replica: &Replica, heads: &[IpfsCid]) -> Result<Status> {
+        let cache = self.cache.as_ref();
+        self.diff(replica, heads, async |cid| fetch_cached(cache, cid, async |cid| get_block(base_url, &cid).await).await)
+            .await
+    }
+
+    async fn on_disk(&self, replica: &Replica, file: &Found) -> Result<OnDisk> {
+        if file.metadata.is_symlink() {
+            let target = tokio::fs::read_link(&file.local)
+                .await
+                .with_context(|| format!("Failed to read link {}", file.local.display()))?;
+            return Ok(OnDisk::Link(target.to_string_lossy().into_owned()));
+        }
+        if self.index.get(&file.path).is_some_and(|indexed| indexed.matches(&file.metadata)) {
+            return Ok(OnDisk::Synced);
+        }
+        let reader = self.hashing.open(&file.local, file.metadata.len(), 0).await?;
+        let ignore = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let (entry, _, _) = replica.store_stream(&file.path, reader, mode_of(&file.metadata), 0, &ignore).await?;
+        Ok(OnDisk::Hashed(entry))
+    }
+}
+
+/// How `disk` and `state` differ, given the content `base` last synced.
+/// Symlinks aren't indexed, so a link that differs counts as changed in
+/// the workspace, as a checkout would replace it.
+fn classify(disk: &OnDisk, base: Option<&IpfsCid>, state: Option<&Entry>) -> Option<FileStatus> {
+    let is_base = |entry: &Entry| {
+        base.is_some_and(|base| entry.content == *base || matches!(entry.chunks.as_slice(), [chunk] if chunk.content == *base))
+    };
+    if let OnDisk::Link(target) = disk {
+        return match state {
+            None => Some(FileStatus::Local(Change::Added)),
+            Some(entry) if entry.symlink.as_ref() == Some(target) => None,
+            Some(entry) if entry.is_symlink() => Some(FileStatus::Remote(Change::Modified)),
+            Some(_) => Some(FileStatus::Conflict),
+        };
+    }
+    if let Some(entry) = state.filter(|entry| entry.is_symlink()) {
+        return match disk {
+            OnDisk::Missing => Some(FileStatus::Remote(Change::Added)),
+            OnDisk::Synced | OnDisk::Hashed(_) if base.is_some() && !is_base(entry) => Some(FileStatus::Remote(Change::Modified)),
+            _ => Some(FileStatus::Conflict),
+        };
+    }
+
+    let local = match disk {
+        OnDisk::Synced => false,
+        OnDisk::Hashed(entry) => !is_base(entry),
+        OnDisk::Missing | OnDisk::Link(_) => base.is_some(),
+    };
+    let remote = match state {
+        Some(entry) => !is_base(entry),
+        None => base.is_some(),
+    };
+    let change = |from: bool, to: bool| match (from, to) {
+        (false, _) => Change::Added,
+        (true, false) => Change::Deleted,
+        (true, true) => Change::Modified,
+    };
+    match (local, remote) {
+        (false, false) => None,
+        (true, false) => Some(FileStatus::Local(change(base.is_some(), !matches!(disk, OnDisk::Missing)))),
+        (false, true) => Some(FileStatus::Remote(change(base.is_some(), state.is_some()))),
+        // the same change on both sides leaves nothing to do
+        (true, true) => match (disk, state) {
+            (OnDisk::Hashed(on_disk), Some(entry)) if same_content(on_disk, entry) => None,
+            (OnDisk::Missing, None) => None,
+            _ => Some(FileStatus::Conflict),
+        },
+    }
+}
+
+#[cfg(test)]
+mod status_test {
+    use super::*;
+    use crate::test_util::alice;
+    use std::time::{Duration, SystemTime};
+
+    #[tokio::test]
+    async fn test_status_classifies_each_side() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-status-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        for name in ["edit", "gone", "remote-edit", "remote-gone", "both", "same"] {
+            let path = root.join(name);
+            std::fs::write(&path, name).unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+        }
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let mut scanner = Scanner::new(&root);
+        scanner.scan(&mut replica, &store).await.unwrap();
+        assert!(scanner.status(&replica).await.unwrap().is_clean());
+
+        std::fs::write(root.join("edit"), b"edited").unwrap();
+        std::fs::remove_file(root.join("gone")).unwrap();
+        std::fs::write(root.join("new"), b"new").unwrap();
+        std::fs::write(root.join("both"), b"mine").unwrap();
+        std::fs::write(root.join("same"), b"agreed").unwrap();
+        for (path, data) in [("remote-edit", &b"theirs"[..]), ("both", b"theirs"), ("same", b"agreed"), ("pulled", b"x")] {
+            let (entry, _, _) = replica.store_stream(path, data, 0o644, 0, &store).await.unwrap();
+            replica.put(path, entry).unwrap();
+        }
+        replica.remove("remote-gone").unwrap();
+
+        let status = scanner.status(&replica).await.unwrap();
+        let expected = [
+            ("both", FileStatus::Conflict),
+            ("edit", FileStatus::Local(Change::Modified)),
+            ("gone", FileStatus::Local(Change::Deleted)),
+            ("new", FileStatus::Local(Change::Added)),
+            ("pulled", FileStatus::Remote(Change::Added)),
+            ("remote-edit", FileStatus::Remote(Change::Modified)),
+            ("remote-gone", FileStatus::Remote(Change::Deleted)),
+        ];
+        assert_eq!(status.files, expected.into_iter().map(|(path, status)| (path.to_string(), status)).collect());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,351 @@
+//! Continuous two-way sync between a working directory and a workspace.
+//! Each round first commits what changed on disk, so a merge sees local
+//! edits as concurrent changes instead of the checkout seeing them as
+//! clutter in its way. Only then are other members' heads merged and
+//! written out. A file edited after that commit is one the scan index no
+//! longer vouches for, which the checkout leaves alone until the next
+//! round commits it. A workspace that syncs one way only does its half:
+//! a push-only one never polls, a pull-only one never commits or
+//! publishes. A schedule decides when rounds commit and move content, see
+//! `Schedule`, and a `SyncControl` can force or pause them.
+
+use anyhow::Result;
+use bytes::Bytes;
+use std::ops::ControlFlow;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tokio::time::{sleep, sleep_until, Instant};
+
+use super::checkout::CheckoutReport;
+use super::control::{unless_paused, SyncControl};
+use super::filter::PathFilter;
+use super::scan::{ScanReport, Scanner};
+use super::schedule::Schedule;
+use super::watch::Watcher;
+use crate::crdt::chunk::put_raw_block;
+use crate::crdt::replica::Replica;
+use crate::kubo_rpc::ipfs::IpfsCid;
+use crate::workspace::{SyncReport, Workspace};
+
+/// Time between merges of other members' heads by default.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What one round did.
+#[derive(Debug, Default)]
+pub struct SyncRound {
+    /// Local changes committed.
+    pub scan: ScanReport,
+    /// Members' heads merged, in a round that polled them.
+    pub merge: Option<SyncReport>,
+    /// Files written from what was merged.
+    pub checkout: Option<CheckoutReport>,
+    /// The announcement published, if this replica's heads moved.
+    pub published: Option<IpfsCid>,
+    /// Whether a pause cut the round short.
+    pub paused: bool,
+}
+
+/// Why a round woke up, and so what it does.
+struct Wake {
+    poll: bool,
+    /// Whether the round may commit and move content.
+    heavy: bool,
+    retry_due: bool,
+}
+
+/// Keeps a watcher's directory and a workspace in step.
+pub struct Syncer {
+    workspace: Workspace,
+    watcher: Watcher,
+    poll_interval: Duration,
+    next_poll: Instant,
+    schedule: Schedule,
+    /// When local changes are next committed, if the schedule sets an
+    /// interval.
+    next_commit: Instant,
+    control: SyncControl,
+    paused: watch::Receiver<bool>,
+    /// Heads last published, so a failed publish is retried.
+    published: Vec<IpfsCid>,
+    /// Whether a poll has checked out yet.
+    checked_out: bool,
+    /// Whether merges brought changes in that no checkout wrote yet, as the
+    /// schedule held it back.
+    behind: bool,
+}
+
+impl Syncer {
+    /// The first round polls straight away. The watcher's scanner is set to
+    /// the workspace's mode, bandwidth limits, durability and progress
+    /// reporter; a workspace without a block cache shares the scanner's.
+    pub fn new(mut workspace: Workspace, mut watcher: Watcher) -> Self {
+        if workspace.block_cache().is_none()
+            && let Some(cache) = watcher.scanner_mut().cache.clone()
+        {
+            workspace = workspace.with_block_cache(cache);
+        }
+        watcher.scanner_mut().mode = workspace.mode();
+        watcher.scanner_mut().durability = workspace.durability();
+        watcher.scanner_mut().bandwidth = workspace.bandwidth().clone();
+        watcher.scanner_mut().progress = workspace.progress().clone();
+        let control = SyncControl::default();
+        Syncer {
+            workspace,
+            watcher,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            next_poll: Instant::now(),
+            schedule: Schedule::default(),
+            next_commit: Instant::now(),
+            paused: control.subscribe(),
+            control,
+            published: Vec::new(),
+            checked_out: false,
+            behind: false,
+        }
+    }
+
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub fn with_schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// A handle that forces and pauses rounds, for a control API to keep.
+    pub fn control(&self) -> SyncControl {
+        self.control.clone()
+    }
+
+    pub fn workspace(&self) -> &Workspace {
+        &self.workspace
+    }
+
+    pub fn watcher(&self) -> &Watcher {
+        &self.watcher
+    }
+
+    pub fn into_workspace(self) -> Workspace {
+        self.workspace
+    }
+
+    /// Changes what the watcher's directory syncs; see `Scanner::select`.
+    pub async fn select(&mut self, sparse: &[&str], filter: PathFilter) -> Result<CheckoutReport> {
+        let base_url = self.workspace.base_url().to_string();
+        self.watcher.scanner_mut().select_from(&base_url, self.workspace.replica(), sparse, filter).await
+    }
+
+    /// Waits for changes on disk to settle or the next commit the schedule
+    /// sets, for the next poll, for the retry of a file a checkout found in
+    /// use, for a transfer window to open or for a forced round. Then
+    /// commits local changes, merges remote ones on a poll and checks them
+    /// out, checks out again on a retry, and publishes if the heads moved;
+    /// outside the schedule's windows only merges and publishes happen.
+    /// While paused it waits for a resume first.
+    pub async fn next_round(&mut self) -> Result<SyncRound> {
+        let mode = self.workspace.mode();
+        let retry = self.watcher.scanner().next_retry().filter(|_| mode.pulls());
+        let interval = self.schedule.interval;
+        let (poll, forced) = loop {
+            let _ = self.paused.wait_for(|paused| !paused).await;
+            let opens_in = self.schedule.opens_in(SystemTime::now());
+            let open = opens_in == Some(Duration::ZERO);
+            tokio::select! {
+                settled = self.watcher.settled(), if mode.pushes() && open && interval.is_none() => {
+                    settled?;
+                    break (false, false);
+                }
+                _ = sleep_until(self.next_commit), if mode.pushes() && open && interval.is_some() => break (false, false),
+                _ = sleep_until(self.next_poll), if mode.pulls() => break (true, false),
+                _ = sleep_until(retry.unwrap_or(self.next_poll)), if retry.is_some() && open => break (false, false),
+                _ = sleep(opens_in.unwrap_or_default()), if !open && opens_in.is_some() => break (false, false),
+                _ = self.control.wake.notified() => break (mode.pulls(), true),
+                _ = self.paused.wait_for(|paused| *paused) => {}
+            }
+        };
+        let wake = Wake {
+            poll,
+            heavy: forced || self.schedule.allows(SystemTime::now()),
+            retry_due: retry.is_some_and(|at| at <= Instant::now()),
+        };
+        let base_url = self.workspace.base_url().to_string();
+        let bandwidth = self.workspace.bandwidth().clone();
+        self.round(
+            wake,
+            async |cid, bytes| {
+                bandwidth.upload(bytes.len()).await;
+                put_raw_block(&base_url, &cid, bytes).await
+            },
+            async |workspace: &mut Workspace| workspace.merge_members().await,
+            async |scanner: &mut Scanner, replica: &Replica| scanner.checkout_from(&base_url, replica).await,
+            async |workspace: &mut Workspace| workspace.publish().await,
+        )
+        .await
+    }
+
+    /// The round `next_round` woke up for, with blocks stored, members
+    /// merged, content fetched and heads published by the functions given.
+    async fn round<S, M, C, P>(&mut self, wake: Wake, store: S, mut merge: M, mut checkout: C, mut publish: P) -> Result<SyncRound>
+    where
+        S: AsyncFn(IpfsCid, Bytes) -> Result<()>,
+        M: AsyncFnMut(&mut Workspace) -> Result<SyncReport>,
+        C: AsyncFnMut(&mut Scanner, &Replica) -> Result<CheckoutReport>,
+        P: AsyncFnMut(&mut Workspace) -> Result<IpfsCid>,
+    {
+        let mode = self.workspace.mode();
+        let Wake { poll, heavy, retry_due } = wake;
+        let mut round = SyncRound::default();
+        if mode.pushes() && heavy {
+            if let Some(interval) = self.schedule.interval {
+                self.next_commit = Instant::now() + interval;
+            }
+            let commit = self.watcher.commit(self.workspace.replica_mut(), store);
+            match unless_paused(&mut self.paused, commit).await? {
+                Some(scan) => round.scan = scan,
+                None => return Ok(SyncRound { paused: true, ..round }),
+            }
+        } else if !mode.pushes() {
+            // a mirror's own edits are the checkout's to deal with
+            self.watcher.discard();
+        }
+        if poll {
+            self.next_poll = Instant::now() + self.poll_interval;
+            let merge = merge(&mut self.workspace).await?;
+            self.behind |= merge.applied > 0;
+            round.merge = Some(merge);
+        }
+        if heavy && (self.behind || (poll && !self.checked_out) || retry_due) {
+            self.behind = true;
+            let checkout = checkout(self.watcher.scanner_mut(), self.workspace.replica());
+            match unless_paused(&mut self.paused, checkout).await? {
+                Some(checkout) => round.checkout = Some(checkout),
+                None => return Ok(SyncRound { paused: true, ..round }),
+            }
+            self.checked_out = true;
+            self.behind = false;
+        }
+        if *self.paused.borrow() {
+            return Ok(SyncRound { paused: true, ..round });
+        }
+        if mode.pushes() && self.workspace.replica().heads() != self.published.as_slice() {
+            round.published = Some(publish(&mut self.workspace).await?);
+            self.published = self.workspace.replica().heads().to_vec();
+        }
+        Ok(round)
+    }
+
+    /// Runs rounds until `on_round`, shown how each went, breaks. A round
+    /// that fails is followed by a pause of one poll interval.
+    pub async fn run<R>(&mut self, mut on_round: R)
+    where
+        R: FnMut(Result<SyncRound>) -> ControlFlow<()>,
+    {
+        loop {
+            let round = self.next_round().await;
+            let failed = round.is_err();
+            if on_round(round).is_break() {
+                return;
+            }
+            if failed {
+                tokio::time::sleep(self.poll_interval).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod syncer_test {
+    use super::*;
+    use crate::crdt::clock::VersionVector;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use crate::test_util::{alice, bob, entry};
+    use crate::workspace::SyncMode;
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_one_way_modes_refuse_the_other_way() {
+        let author = alice();
+        // refused before anything reaches the daemon
+        let mut publisher = Workspace::new("http://127.0.0.1:1", Replica::new(author.clone())).with_mode(SyncMode::PushOnly);
+        assert!(publisher.merge_members().await.unwrap_err().to_string().contains("push-only"));
+        let mut mirror = Workspace::new("http://127.0.0.1:1", Replica::new(author.clone())).with_mode(SyncMode::PullOnly);
+        assert!(mirror.publish().await.unwrap_err().to_string().contains("pull-only"));
+
+        let root = std::env::temp_dir().join(format!("crdt-modes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("local.txt"), b"local").unwrap();
+        let mut replica = Replica::new(author);
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let fetch = async |_cid: IpfsCid| -> Result<Vec<u8>> { unreachable!() };
+        let mut scanner = Scanner::new(&root).with_mode(SyncMode::PullOnly);
+        assert!(scanner.scan(&mut replica, &store).await.is_err());
+        assert!(replica.state().get("local.txt").is_none());
+        let mut scanner = Scanner::new(&root).with_mode(SyncMode::PushOnly);
+        assert!(scanner.checkout(&replica, fetch).await.is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_round_commits_before_it_checks_out() {
+        let alice = alice();
+        let bob = bob();
+        let root = std::env::temp_dir().join(format!("crdt-syncer-round-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), b"v1").unwrap();
+        // canonical, as notify reports it
+        let root = root.canonicalize().unwrap();
+
+        let blocks = Mutex::new(HashMap::new());
+        let store = async |cid: IpfsCid, bytes: Bytes| {
+            blocks.lock().unwrap().insert(cid, bytes.to_vec());
+            Ok(())
+        };
+        let checkout = async |scanner: &mut Scanner, replica: &Replica| {
+            scanner.checkout(replica, async |cid: IpfsCid| blocks.lock().unwrap().get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid))).await
+        };
+        let publish = async |workspace: &mut Workspace| Ok(workspace.replica().heads()[0].clone());
+        let watcher = Watcher::new(Scanner::new(&root)).unwrap().with_debounce(Duration::ZERO, Duration::ZERO);
+        let mut syncer = Syncer::new(Workspace::new("http://127.0.0.1:1", Replica::new(alice)), watcher);
+
+        syncer.watcher.settled().await.unwrap();
+        let wake = Wake { poll: false, heavy: true, retry_due: false };
+        let first = syncer.round(wake, &store, async |_: &mut Workspace| unreachable!(), &checkout, &publish).await.unwrap();
+        assert_eq!(first.scan.put, vec!["a.txt"]);
+
+        let mut remote = Replica::new(bob);
+        remote.apply_delta(&syncer.workspace().replica().delta_since(&VersionVector::new())).unwrap();
+        for (path, data) in [("a.txt", &b"remote"[..]), ("c.txt", b"three")] {
+            let content = IpfsCid::compute(RAW_CODE, data);
+            blocks.lock().unwrap().insert(content, data.to_vec());
+            remote.put(path, entry(data)).unwrap();
+        }
+
+        // a.txt is edited after this round's commit, as the merge runs
+        let merge = async |workspace: &mut Workspace| {
+            let since = workspace.replica().state().version_vector().clone();
+            let applied = workspace.replica_mut().apply_delta(&remote.delta_since(&since))?;
+            std::fs::write(root.join("a.txt"), b"local edit").unwrap();
+            Ok(SyncReport { applied, ..SyncReport::default() })
+        };
+        let wake = Wake { poll: true, heavy: true, retry_due: false };
+        let second = syncer.round(wake, &store, merge, &checkout, &publish).await.unwrap();
+        let checked_out = second.checkout.unwrap();
+        assert_eq!((checked_out.written, checked_out.local_changes), (vec!["c.txt".to_string()], vec!["a.txt".to_string()]));
+        assert_eq!(std::fs::read(root.join("a.txt")).unwrap(), b"local edit");
+        assert_eq!(std::fs::read(root.join("c.txt")).unwrap(), b"three");
+
+        // the next round commits the edit over the remote one
+        tokio::time::timeout(Duration::from_secs(10), syncer.watcher.settled()).await.unwrap().unwrap();
+        let wake = Wake { poll: false, heavy: true, retry_due: false };
+        let third = syncer.round(wake, &store, async |_: &mut Workspace| unreachable!(), &checkout, &publish).await.unwrap();
+        assert!(third.scan.put.contains(&"a.txt".to_string()));
+        assert_eq!(syncer.workspace().replica().state().get("a.txt").unwrap().size, 10);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+//! The local trash: a checkout that keeps one moves files deleted in the
+//! workspace to `.crdt/trash/<timestamp>/<path>` instead of deleting them,
+//! so a collaborator's slip doesn't take the only copy with it. Each
+//! checkout's files share a directory, named for when it started in
+//! seconds since the Unix epoch, and directories older than the retention
+//! time are emptied by later checkouts.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::conflicts::STATE_DIR;
+use super::scan::Scanner;
+
+impl Scanner {
+    /// Moves files deleted in the workspace to the trash instead of
+    /// deleting them, for `retention` before they go for good. Files that
+    /// a sparse checkout or filter leaves out are still deleted, as the
+    /// workspace has them.
+    pub fn with_trash(mut self, retention: Duration) -> Self {
+        self.trash = Some(retention);
+        self
+    }
+
+    /// The directory the trash is kept in.
+    pub fn trash_root(&self) -> PathBuf {
+        self.root.join(STATE_DIR).join("trash")
+    }
+
+    /// Moves the file at `dest` into the trash directory of a checkout
+    /// started at `deleted`.
+    pub(crate) async fn move_to_trash(&self, dest: &Path, deleted: SystemTime) -> Result<()> {
+        let stamp = deleted.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let relative = dest.strip_prefix(&self.root).context("Trashed file outside the root")?;
+        let trashed = self.trash_root().join(stamp.to_string()).join(relative);
+        if let Some(parent) = trashed.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        tokio::fs::rename(dest, &trashed)
+            .await
+            .with_context(|| format!("Failed to move {} to the trash", dest.display()))
+    }
+
+    /// Empties the trash directories older than the retention time at
+    /// `now`, if the scanner keeps a trash.
+    pub(crate) async fn expire_trash(&self, now: SystemTime) -> Result<()> {
+        let Some(retention) = self.trash else {
+            return Ok(());
+        };
+        let root = self.trash_root();
+        let mut dirs = match tokio::fs::read_dir(&root).await {
+            Ok(dirs) => dirs,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).with_context(|| format!("Failed to list {}", root.display())),
+        };
+        while let Some(dir) = dirs.next_entry().await? {
+            let Some(stamp) = dir.file_name().to_str().and_then(|name| name.parse().ok()) else {
+                continue;
+            };
+            let trashed = UNIX_EPOCH + Duration::from_secs(stamp);
+            if now.duration_since(trashed).is_ok_and(|age| age > retention) {
+                tokio::fs::remove_dir_all(dir.path())
+                    .await
+                    .with_context(|| format!("Failed to empty {}", dir.path().display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod trash_test {
+    use super::*;
+    use crate::crdt::replica::Replica;
+    use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+    use crate::test_util::{alice, entry};
+    use anyhow::anyhow;
+    use bytes::Bytes;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_remote_deletes_go_to_the_trash() {
+        let mut replica = Replica::new(alice());
+        let blobs: HashMap<IpfsCid, Vec<u8>> =
+            [b"one".to_vec(), b"two".to_vec()].into_iter().map(|data| (IpfsCid::compute(RAW_CODE, &data), data)).collect();
+        let entry = |data: &[u8]| {
+            entry(data)
+        };
+        replica.put("docs/a.txt", entry(b"one")).unwrap();
+        replica.put("b.txt", entry(b"two")).unwrap();
+        let root = std::env::temp_dir().join(format!("crdt-trash-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let fetch = async |cid: IpfsCid| blobs.get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let mut scanner = Scanner::new(&root).with_trash(Duration::from_secs(86400));
+        scanner.checkout(&replica, fetch).await.unwrap();
+        // trashed by a checkout long ago
+        std::fs::create_dir_all(scanner.trash_root().join("1000")).unwrap();
+        std::fs::write(scanner.trash_root().join("1000/old.txt"), b"old").unwrap();
+
+        replica.remove("docs/a.txt").unwrap();
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.removed, vec!["docs/a.txt"]);
+        assert!(!root.join("docs").exists());
+        let stamps: Vec<_> = std::fs::read_dir(scanner.trash_root()).unwrap().map(|dir| dir.unwrap().path()).collect();
+        assert_eq!(stamps.len(), 1);
+        assert_eq!(std::fs::read(stamps[0].join("docs/a.txt")).unwrap(), b"one");
+
+        // scans don't take the trash for files
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        assert!(scanner.scan(&mut replica, &store).await.unwrap().put.is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+//! One name can be spelled in several Unicode forms: macOS reports `é` as
+//! `e` and a combining accent (NFD) where Linux keeps whatever was written,
+//! usually the single code point (NFC). So that members don't see one file
+//! as two, scans normalize the paths they commit, and the index remembers
+//! how a file is spelled on disk, so checkouts write it there rather than
+//! next to it.
+
+use icu_normalizer::ComposingNormalizerBorrowed;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+
+use super::scan::{Scanner, Walk};
+
+/// The form scans commit paths in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Normalization {
+    /// Unicode normalization form C, as most systems write names.
+    #[default]
+    Nfc,
+    /// Paths as the filesystem spells them.
+    Keep,
+}
+
+impl Scanner {
+    /// The form scans commit paths in; NFC by default. Every member of a
+    /// workspace should use the same.
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// `path` in the form scans commit paths in.
+    pub(crate) fn normalize<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        match self.normalization {
+            Normalization::Nfc => ComposingNormalizerBorrowed::new_nfc().normalize(path),
+            Normalization::Keep => Cow::Borrowed(path),
+        }
+    }
+
+    /// Gives what `walk` found its normalized paths, noting in
+    /// `walk.spellings` how each file is spelled on disk: `Some` name where
+    /// that differs, `None` where it doesn't. Of files on disk whose paths
+    /// normalize alike, the one spelled normalized is kept and the others
+    /// are skipped.
+    pub(crate) fn normalize_found(&self, walk: &mut Walk<'_>) {
+        let spelled: HashSet<String> = walk.found.iter().map(|file| file.path.clone()).collect();
+        let mut kept = HashSet::new();
+        let mut found = Vec::with_capacity(walk.found.len());
+        for mut file in std::mem::take(&mut walk.found) {
+            let path = self.normalize(&file.path).into_owned();
+            if (path != file.path && spelled.contains(&path)) || !kept.insert(path.clone()) {
+                walk.skipped.push(file.local);
+                continue;
+            }
+            let on_disk = file.local.strip_prefix(&self.root).ok().and_then(|relative| {
+                relative.components().map(|part| part.as_os_str().to_str()).collect::<Option<Vec<_>>>()
+            });
+            match on_disk.map(|names| names.join("/")) {
+                Some(name) if name != path && self.normalize(&name) == path => {
+                    walk.spellings.insert(path.clone(), Some(name));
+                }
+                Some(name) if name == path => {
+                    walk.spellings.insert(path.clone(), None);
+                }
+                _ => {}
+            }
+            file.path = path;
+            found.push(file);
+        }
+        walk.found = found;
+        for path in &mut walk.filtered {
+            if let Cow::Owned(normalized) = self.normalize(path) {
+                *path = normalized;
+            }
+        }
+    }
+
+    /// Takes the spellings a walk noted into the index: the names on disk
+    /// of files spelled otherwise than their paths.
+    pub(crate) fn record_spellings(&mut self, spellings: BTreeMap<String, Option<String>>) {
+        for (path, name) in spellings {
+            match name {
+                Some(name) => {
+                    self.index.renamed.insert(path, name);
+                }
+                None => {
+                    if self.index.renamed.get(&path).is_some_and(|name| self.is_spelling(&path, name)) {
+                        self.index.renamed.remove(&path);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether `name` on disk is `path` spelled in another form.
+    pub(crate) fn is_spelling(&self, path: &str, name: &str) -> bool {
+        name != path && self.normalize(name) == path
+    }
+}
+
+#[cfg(test)]
+mod unicode_test {
+    use super::*;
+    use crate::crdt::replica::Replica;
+    use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+    use crate::test_util::{alice, entry};
+    use anyhow::anyhow;
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    #[tokio::test]
+    async fn test_decomposed_names_are_committed_composed() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-unicode-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("cafe\u{301}")).unwrap();
+        let local = root.join("cafe\u{301}/re\u{301}sume\u{301}.txt");
+        std::fs::write(&local, b"decomposed").unwrap();
+        std::fs::File::options().write(true).open(&local).unwrap().set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+
+        let blobs = std::sync::Mutex::new(HashMap::new());
+        let store = async |cid: IpfsCid, bytes: Bytes| {
+            blobs.lock().unwrap().insert(cid, bytes.to_vec());
+            Ok(())
+        };
+        let mut scanner = Scanner::new(&root).with_case_insensitive(false);
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!(report.put, vec!["caf\u{e9}/r\u{e9}sum\u{e9}.txt"]);
+
+        // another member's edits are written where the files are spelled
+        let data = b"composed".to_vec();
+        let entry = entry(&data);
+        replica.put("caf\u{e9}/r\u{e9}sum\u{e9}.txt", entry.clone()).unwrap();
+        replica.put("caf\u{e9}/new.txt", entry).unwrap();
+        blobs.lock().unwrap().insert(IpfsCid::compute(RAW_CODE, &data), data.clone());
+        let fetch = async |cid: IpfsCid| blobs.lock().unwrap().get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let report = scanner.checkout(&replica, fetch).await.unwrap();
+        assert_eq!(report.written, vec!["caf\u{e9}/new.txt", "caf\u{e9}/r\u{e9}sum\u{e9}.txt"]);
+        assert_eq!(std::fs::read(&local).unwrap(), data);
+        assert_eq!(std::fs::read(root.join("cafe\u{301}/new.txt")).unwrap(), data);
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert!(report.put.is_empty() && report.removed.is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
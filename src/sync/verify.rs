@@ -0,0 +1,263 @@
+//! Verification: reading every file the scan index vouches for again and
+//! checking it still has the content recorded when it was synced. Scans
+//! trust stat data, so they miss bit rot and edits that kept a file's size
+//! and mtime; a verify pass catches both, and edits made while nothing was
+//! watching. A repair fetches the damaged and missing files again.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+use std::time::SystemTime;
+
+use super::checkout::Unfetched;
+use super::scan::{mode_of, Scanner};
+use super::sparse::in_sparse;
+use crate::crdt::history::path_matches;
+use crate::crdt::replica::Replica;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// How a file differs from what was synced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Damage {
+    /// The content changed but the stat data didn't: bit rot, or an edit
+    /// that put the mtime back.
+    Corrupted,
+    /// Changed on disk since it was synced.
+    Modified,
+    Missing,
+}
+
+/// A file whose content isn't what was synced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub path: String,
+    pub damage: Damage,
+    /// The content recorded when it was synced.
+    pub expected: IpfsCid,
+    /// The content on disk, if there is a file.
+    pub found: Option<IpfsCid>,
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Files that still have the content synced.
+    pub verified: usize,
+    /// Files that don't, in path order.
+    pub mismatched: Vec<Mismatch>,
+    /// Files that couldn't be read.
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+impl VerifyReport {
+    pub fn is_intact(&self) -> bool {
+        self.mismatched.is_empty() && self.failed.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Files fetched and written again.
+    pub repaired: Vec<String>,
+    /// Files changed on disk since they were synced, left for a scan.
+    pub modified: Vec<String>,
+    /// Files whose content couldn't be fetched, as no provider has it.
+    pub irrecoverable: Vec<(String, anyhow::Error)>,
+    /// Files that couldn't be read or written.
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+impl Scanner {
+    /// Hashes every file the index vouches for, ignoring its stat data,
+    /// and reports those whose content no longer matches. Changes nothing:
+    /// a scan would commit a modified file, but scans and checkouts take a
+    /// corrupted one for intact, so restore it from the workspace.
+    pub async fn verify(&self, replica: &Replica) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let synced = self
+            .index
+            .files
+            .iter()
+            .filter(|(path, _)| path_matches(replica.scope(), path) && in_sparse(&self.sparse, path));
+        for (path, indexed) in synced {
+            let hashed = async {
+                let local = self.local_path(path).await?;
+                let metadata = match tokio::fs::symlink_metadata(&local).await {
+                    Ok(metadata) if metadata.is_file() => metadata,
+                    Ok(_) => return Ok(None),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(err) => return Err(err).with_context(|| format!("Failed to stat {}", local.display())),
+                };
+                let reader = self.hashing.open(&local, metadata.len(), 0).await?;
+                let ignore = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+                let (entry, _, _) = replica.store_stream(path, reader, mode_of(&metadata), 0, &ignore).await?;
+                Ok(Some((metadata, entry)))
+            };
+            let (damage, found) = match hashed.await {
+                Err(err) => {
+                    report.failed.push((path.clone(), err));
+                    continue;
+                }
+                Ok(None) => (Damage::Missing, None),
+                Ok(Some((metadata, entry))) => {
+                    let single = matches!(entry.chunks.as_slice(), [chunk] if chunk.content == indexed.content);
+                    if entry.content == indexed.content || single {
+                        report.verified += 1;
+                        continue;
+                    }
+                    let damage = if indexed.matches(&metadata) { Damage::Corrupted } else { Damage::Modified };
+                    (damage, Some(entry.content))
+                }
+            };
+            report.mismatched.push(Mismatch { path: path.clone(), damage, expected: indexed.content.clone(), found });
+        }
+        Ok(report)
+    }
+
+    /// Verifies the directory and writes the workspace's version of each
+    /// corrupted or missing file again, fetching content with `fetch`,
+    /// several files at once. A missing file is restored even if it was
+    /// deleted on purpose, so repair in place of a scan that would commit
+    /// that. Modified files are left alone, and files the workspace no
+    /// longer has left for a checkout to remove.
+    pub async fn repair<F>(&mut self, replica: &Replica, fetch: F) -> Result<RepairReport>
+    where
+        F: AsyncFn(IpfsCid) -> Result<Vec<u8>>,
+    {
+        self.mode.check_pull()?;
+        let verified = self.verify(replica).await?;
+        let mut report = RepairReport { failed: verified.failed, ..RepairReport::default() };
+        let mut damaged = Vec::new();
+        for mismatch in verified.mismatched {
+            match replica.state().get(&mismatch.path) {
+                _ if mismatch.damage == Damage::Modified => report.modified.push(mismatch.path),
+                Some(entry) if !entry.is_symlink() => damaged.push((mismatch.path, entry)),
+                _ => {}
+            }
+        }
+
+        let (scanner, fetch) = (&*self, &fetch);
+        let written: Vec<_> = stream::iter(&damaged)
+            .map(|(path, entry)| async move {
+                let written: Result<_> = async {
+                    let metadata = scanner.fetch_file(path, &scanner.local_path(path).await?, entry, fetch).await?;
+                    scanner.restore_xattrs(replica, path)?;
+                    Ok(metadata)
+                }
+                .await;
+                (path, entry, written)
+            })
+            .buffered(self.downloads)
+            .collect()
+            .await;
+        let repaired = SystemTime::now();
+        for (path, entry, written) in written {
+            match written {
+                Ok(metadata) => {
+                    self.index.record(path, &metadata, entry.content.clone(), repaired);
+                    report.repaired.push(path.clone());
+                }
+                Err(err) if err.is::<Unfetched>() => report.irrecoverable.push((path.clone(), err)),
+                Err(err) => report.failed.push((path.clone(), err)),
+            }
+        }
+        let written: Vec<&str> = report.repaired.iter().map(String::as_str).collect();
+        self.checkpoint(&written).await?;
+        Ok(report)
+    }
+
+    /// `repair` fetching content from the IPFS daemon at `base_url`,
+    /// decrypting it if the replica has a workspace key.
+    pub async fn repair_from(&mut self, base_url: &str, replica: &Replica) -> Result<RepairReport> {
+        let bandwidth = self.bandwidth.clone();
+        self.repair(replica, async |cid| {
+            let data = replica.fetch_content(base_url, &cid).await?;
+            bandwidth.download(data.len()).await;
+            Ok(data)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod verify_test {
+    use super::*;
+    use crate::test_util::alice;
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    #[tokio::test]
+    async fn test_verify_finds_damaged_files() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-verify-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        for name in ["intact", "rotted", "edited", "gone"] {
+            std::fs::write(root.join(name), name).unwrap();
+            let file = std::fs::File::options().write(true).open(root.join(name)).unwrap();
+            file.set_modified(an_hour_ago).unwrap();
+        }
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let mut scanner = Scanner::new(&root);
+        scanner.scan(&mut replica, &store).await.unwrap();
+        assert!(scanner.verify(&replica).await.unwrap().is_intact());
+
+        // same size and mtime, different bytes
+        std::fs::write(root.join("rotted"), b"rott3d").unwrap();
+        let file = std::fs::File::options().write(true).open(root.join("rotted")).unwrap();
+        file.set_modified(an_hour_ago).unwrap();
+        std::fs::write(root.join("edited"), b"edited on disk").unwrap();
+        std::fs::remove_file(root.join("gone")).unwrap();
+
+        let report = scanner.verify(&replica).await.unwrap();
+        assert_eq!(report.verified, 1);
+        let damage: Vec<_> = report.mismatched.iter().map(|mismatch| (mismatch.path.as_str(), mismatch.damage)).collect();
+        assert_eq!(damage, [("edited", Damage::Modified), ("gone", Damage::Missing), ("rotted", Damage::Corrupted)]);
+        assert_eq!(report.mismatched[0].expected, replica.state().get("edited").unwrap().content);
+        assert!(report.mismatched[1].found.is_none());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_repair_fetches_damaged_files_again() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-repair-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let an_hour_ago = SystemTime::now() - std::time::Duration::from_secs(3600);
+        for name in ["rotted", "edited", "gone", "lost"] {
+            std::fs::write(root.join(name), name).unwrap();
+            let file = std::fs::File::options().write(true).open(root.join(name)).unwrap();
+            file.set_modified(an_hour_ago).unwrap();
+        }
+        let blobs = std::sync::Mutex::new(HashMap::new());
+        let store = async |cid: IpfsCid, bytes: Bytes| {
+            blobs.lock().unwrap().insert(cid, bytes.to_vec());
+            Ok(())
+        };
+        let mut scanner = Scanner::new(&root);
+        scanner.scan(&mut replica, &store).await.unwrap();
+
+        std::fs::write(root.join("rotted"), b"r0tted").unwrap();
+        let file = std::fs::File::options().write(true).open(root.join("rotted")).unwrap();
+        file.set_modified(an_hour_ago).unwrap();
+        std::fs::write(root.join("edited"), b"edited on disk").unwrap();
+        std::fs::remove_file(root.join("gone")).unwrap();
+        // no provider has the content of a file lost too
+        std::fs::remove_file(root.join("lost")).unwrap();
+        let lost = replica.state().get("lost").unwrap();
+        for cid in lost.chunks.iter().map(|chunk| &chunk.content).chain([&lost.content]) {
+            blobs.lock().unwrap().remove(cid);
+        }
+
+        let fetch = async |cid: IpfsCid| blobs.lock().unwrap().get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let report = scanner.repair(&replica, fetch).await.unwrap();
+        assert_eq!(report.repaired, vec!["gone", "rotted"]);
+        assert_eq!(report.modified, vec!["edited"]);
+        assert_eq!(report.irrecoverable.iter().map(|(path, _)| path.as_str()).collect::<Vec<_>>(), ["lost"]);
+        assert_eq!(std::fs::read(root.join("rotted")).unwrap(), b"rotted");
+        assert_eq!(std::fs::read(root.join("edited")).unwrap(), b"edited on disk");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
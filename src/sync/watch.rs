@@ -0,0 +1,295 @@
+//! Live change detection: filesystem notifications name the paths that
+//! changed, and only those are rescanned and committed. Notifications can
+//! be dropped (a full kernel queue, a watch limit), so a full scan still
+//! runs on a fixed interval to catch whatever they missed. Bursts of
+//! notifications, like an editor saving through a temp file, are let settle
+//! and committed together.
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use notify::{Event, EventKind, RecursiveMode, Watcher as _};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+
+use super::ignore::IGNORE_FILE;
+use super::portable::long_path;
+use super::scan::{ScanReport, Scanner};
+use crate::crdt::replica::Replica;
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// Time between full scans by default.
+pub const DEFAULT_FULL_SCAN_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Quiet time that ends a burst of changes by default.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(500);
+/// Longest a burst is held back by default.
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Watches a scanner's directory and commits changes as they happen.
+pub struct Watcher {
+    scanner: Scanner,
+    // kept alive for as long as events are wanted
+    _watcher: notify::RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<notify::Result<Event>>,
+    full_scan_interval: Duration,
+    next_full_scan: Instant,
+    debounce: Duration,
+    max_delay: Duration,
+    /// Root-relative paths changed and not yet committed; the empty path
+    /// for a full scan.
+    pending: BTreeSet<String>,
+    /// When the burst being gathered is committed at the latest.
+    burst_deadline: Option<Instant>,
+}
+
+impl Watcher {
+    /// Starts watching `scanner`'s root recursively. The first `next`
+    /// is a full scan.
+    pub fn new(scanner: Scanner) -> Result<Self> {
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })
+        .context("Failed to create filesystem watcher")?;
+        watcher
+            .watch(scanner.root(), RecursiveMode::Recursive)
+            .with_context(|| format!("Failed to watch {}", scanner.root().display()))?;
+        Ok(Watcher {
+            scanner,
+            _watcher: watcher,
+            events,
+            full_scan_interval: DEFAULT_FULL_SCAN_INTERVAL,
+            next_full_scan: Instant::now(),
+            debounce: DEFAULT_DEBOUNCE,
+            max_delay: DEFAULT_MAX_DELAY,
+            pending: BTreeSet::new(),
+            burst_deadline: None,
+        })
+    }
+
+    pub fn with_full_scan_interval(mut self, interval: Duration) -> Self {
+        self.full_scan_interval = interval;
+        self
+    }
+
+    /// Commits changes once there has been none for `quiet`, or `max_delay`
+    /// after the first of them if they keep coming. A zero `quiet` commits
+    /// every notification as it comes.
+    pub fn with_debounce(mut self, quiet: Duration, max_delay: Duration) -> Self {
+        self.debounce = quiet;
+        self.max_delay = max_delay.max(quiet);
+        self
+    }
+
+    pub fn scanner(&self) -> &Scanner {
+        &self.scanner
+    }
+
+    pub fn scanner_mut(&mut self) -> &mut Scanner {
+        &mut self.scanner
+    }
+
+    /// Waits for changes on disk to settle, or for the next full scan to be
+    /// due, and commits them in one go.
+    pub async fn next<F>(&mut self, replica: &mut Replica, store: F) -> Result<ScanReport>
+    where
+        F: AsyncFn(IpfsCid, Bytes) -> Result<()>,
+    {
+        self.settled().await?;
+        self.commit(replica, store).await
+    }
+
+    /// Waits for changes on disk to settle, or for the next full scan to be
+    /// due. Changes seen by a call that is cancelled are kept for the next.
+    pub async fn settled(&mut self) -> Result<()> {
+        while self.pending.is_empty() {
+            tokio::select! {
+                _ = sleep_until(self.next_full_scan) => {
+                    self.pending.insert(String::new());
+                }
+                event = self.events.recv() => {
+                    let Some(event) = event else {
+                        bail!("Filesystem watcher stopped");
+                    };
+                    self.collect(event);
+                }
+            }
+        }
+        let deadline = *self.burst_deadline.get_or_insert_with(|| Instant::now() + self.max_delay);
+        while !self.pending.contains("") {
+            let quiet = (Instant::now() + self.debounce).min(deadline);
+            tokio::select! {
+                biased;
+                event = self.events.recv() => match event {
+                    Some(event) => self.collect(event),
+                    None => break,
+                },
+                _ = sleep_until(quiet) => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Commits the changes notified so far, settled or not; an empty report
+    /// if there are none. If that fails, or is dropped, they are kept for
+    /// the next commit.
+    pub async fn commit<F>(&mut self, replica: &mut Replica, store: F) -> Result<ScanReport>
+    where
+        F: AsyncFn(IpfsCid, Bytes) -> Result<()>,
+    {
+        while let Ok(event) = self.events.try_recv() {
+            self.collect(event);
+        }
+        self.burst_deadline = None;
+        // kept until committed, should the commit be dropped halfway
+        let paths = self.pending.clone();
+        let result = if paths.contains("") {
+            self.next_full_scan = Instant::now() + self.full_scan_interval;
+            self.scanner.scan(replica, store).await
+        } else if paths.is_empty() {
+            Ok(ScanReport::default())
+        } else {
+            let list: Vec<String> = paths.iter().cloned().collect();
+            self.scanner.scan_paths(replica, &list, store).await
+        };
+        if result.is_ok() {
+            self.pending.retain(|path| !paths.contains(path));
+        }
+        result
+    }
+
+    /// Drops the changes notified so far without committing them.
+    pub fn discard(&mut self) {
+        while self.events.try_recv().is_ok() {}
+        self.pending.clear();
+        self.burst_deadline = None;
+    }
+
+    /// Adds the root-relative paths `event` touched to the pending ones; the
+    /// empty path if a full scan is needed.
+    fn collect(&mut self, event: notify::Result<Event>) {
+        let event = match event {
+            Ok(event) if !event.need_rescan() => event,
+            // events were lost
+            _ => {
+                self.pending.insert(String::new());
+                return;
+            }
+        };
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+        for path in &event.paths {
+            // paths outside the root or not UTF-8 are left to the full scan
+            if let Some(path) = relative_path(self.scanner.root(), path) {
+                // new ignore rules can take in files nothing changed
+                if path.rsplit('/').next() == Some(IGNORE_FILE) {
+                    self.pending.insert(String::new());
+                }
+                self.pending.insert(path);
+            }
+        }
+    }
+}
+
+/// `path` relative to `root`, with '/' separators.
+fn relative_path(root: &Path, path: &Path) -> Option<String> {
+    // the root may have the long-path prefix the event's path lacks
+    let long = long_path(path.to_path_buf());
+    let relative = path.strip_prefix(root).or_else(|_| long.strip_prefix(root)).ok()?;
+    let parts: Option<Vec<&str>> = relative.components().map(|part| part.as_os_str().to_str()).collect();
+    Some(parts?.join("/"))
+}
+
+#[cfg(test)]
+mod watch_test {
+    use super::*;
+    use crate::test_util::alice;
+
+    #[tokio::test]
+    async fn test_watcher_commits_live_changes() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("old.txt"), b"old").unwrap();
+        // canonical, as notify reports it
+        let root = root.canonicalize().unwrap();
+
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let mut watcher = Watcher::new(Scanner::new(&root)).unwrap().with_debounce(Duration::ZERO, Duration::ZERO);
+        assert_eq!(watcher.next(&mut replica, &store).await.unwrap().put, vec!["old.txt"]);
+
+        std::fs::rename(root.join("old.txt"), root.join("new.txt")).unwrap();
+        let mut removed = Vec::new();
+        let mut put = Vec::new();
+        let mut moved = Vec::new();
+        // the rename can arrive as one event or two; seen at once, it is a
+        // move
+        while moved.is_empty() && (removed.is_empty() || put.is_empty()) {
+            let next = tokio::time::timeout(Duration::from_secs(10), watcher.next(&mut replica, &store));
+            let report = next.await.expect("no change detected").unwrap();
+            removed.extend(report.removed);
+            put.extend(report.put);
+            moved.extend(report.moved);
+        }
+        if moved.is_empty() {
+            assert_eq!((removed, put), (vec!["old.txt".to_string()], vec!["new.txt".to_string()]));
+        } else {
+            assert_eq!(moved, vec![("old.txt".to_string(), "new.txt".to_string())]);
+        }
+        assert!(replica.state().get("new.txt").is_some());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_burst_of_saves_is_one_commit() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-debounce-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let mut watcher =
+            Watcher::new(Scanner::new(&root)).unwrap().with_debounce(Duration::from_millis(300), Duration::from_secs(10));
+        assert!(watcher.next(&mut replica, &store).await.unwrap().nodes.is_empty());
+
+        let saves = async {
+            for i in 0..5 {
+                // save through a temp file, the way editors do
+                std::fs::write(root.join(".doc.txt.swp"), format!("version {}", i)).unwrap();
+                std::fs::rename(root.join(".doc.txt.swp"), root.join("doc.txt")).unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        let (report, ()) = tokio::join!(watcher.next(&mut replica, &store), saves);
+        let report = report.unwrap();
+        assert_eq!(report.nodes.len(), 1);
+        assert_eq!(report.put, vec!["doc.txt"]);
+        assert_eq!(replica.state().get("doc.txt").unwrap().size, 9);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_wait_keeps_changes() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-cancel-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        let store = async |_cid: IpfsCid, _bytes: Bytes| Ok(());
+        let mut watcher =
+            Watcher::new(Scanner::new(&root)).unwrap().with_debounce(Duration::from_secs(10), Duration::from_secs(10));
+        watcher.next(&mut replica, &store).await.unwrap();
+        std::fs::write(root.join("a.txt"), b"a").unwrap();
+        // still settling when given up on
+        assert!(tokio::time::timeout(Duration::from_millis(500), watcher.settled()).await.is_err());
+        assert_eq!(watcher.commit(&mut replica, &store).await.unwrap().put, vec!["a.txt"]);
+        assert!(watcher.commit(&mut replica, &store).await.unwrap().nodes.is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+//! Extended attributes on disk: scans capture them into the workspace's
+//! per-name registers (see `crdt::xattr`) and checkouts restore them. Only
+//! regular files carry them. On Linux only the `user.` namespace is synced;
+//! the others hold security labels and ACLs, which aren't the workspace's
+//! to share and take privileges to write.
+
+use anyhow::{Context, Result};
+use rustix::io::Errno;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use super::scan::{Found, Scanner};
+use crate::crdt::materialize::safe_join;
+use crate::crdt::replica::Replica;
+
+impl Scanner {
+    /// Captures the extended attributes of files in scans and restores them
+    /// on checkout; off by default.
+    pub fn with_xattrs(mut self, on: bool) -> Self {
+        self.xattrs = on;
+        self
+    }
+
+    /// The changes that bring the workspace's extended attributes of `file`
+    /// in line with those on disk: values to set, and `None` for names to
+    /// delete.
+    pub(crate) fn xattr_changes(&self, replica: &Replica, file: &Found) -> Result<BTreeMap<String, Option<ByteBuf>>> {
+        if !self.xattrs {
+            return Ok(BTreeMap::new());
+        }
+        let mut on_disk = read_xattrs(&file.local)?;
+        let mut changes = BTreeMap::new();
+        for (name, value) in replica.state().xattrs(&file.path) {
+            match on_disk.remove(name) {
+                Some(local) if local == value => {}
+                Some(local) => {
+                    changes.insert(name.to_string(), Some(ByteBuf::from(local)));
+                }
+                None => {
+                    changes.insert(name.to_string(), None);
+                }
+            }
+        }
+        changes.extend(on_disk.into_iter().map(|(name, value)| (name, Some(ByteBuf::from(value)))));
+        Ok(changes)
+    }
+
+    /// Gives the file at `path` on disk the extended attributes the
+    /// workspace has for it. Returns whether any changed.
+    pub(crate) fn restore_xattrs(&self, replica: &Replica, path: &str) -> Result<bool> {
+        if !self.xattrs {
+            return Ok(false);
+        }
+        let dest = safe_join(&self.root, path)?;
+        let mut on_disk = read_xattrs(&dest)?;
+        let mut changed = false;
+        for (name, value) in replica.state().xattrs(path) {
+            if on_disk.remove(name).is_some_and(|local| local == value) {
+                continue;
+            }
+            rustix::fs::setxattr(&dest, name, value, rustix::fs::XattrFlags::empty())
+                .with_context(|| format!("Failed to set {} on {}", name, dest.display()))?;
+            changed = true;
+        }
+        for name in on_disk.keys() {
+            rustix::fs::removexattr(&dest, name.as_str())
+                .with_context(|| format!("Failed to remove {} from {}", name, dest.display()))?;
+            changed = true;
+        }
+        Ok(changed)
+    }
+}
+
+/// Whether the extended attribute `name` is synced.
+fn synced(name: &str) -> bool {
+    !cfg!(any(target_os = "linux", target_os = "android")) || name.starts_with("user.")
+}
+
+/// The synced extended attributes of the file at `path`; none on a
+/// filesystem without them.
+fn read_xattrs(path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    let names = match read_sized(|buf| rustix::fs::listxattr(path, buf)) {
+        Ok(names) => names,
+        Err(Errno::NOTSUP) => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err).with_context(|| format!("Failed to list the attributes of {}", path.display())),
+    };
+    let mut xattrs = BTreeMap::new();
+    for name in names.split(|&b| b == 0).filter_map(|name| std::str::from_utf8(name).ok()) {
+        if name.is_empty() || !synced(name) {
+            continue;
+        }
+        let value = read_sized(|buf| rustix::fs::getxattr(path, name, buf))
+            .with_context(|| format!("Failed to read {} of {}", name, path.display()))?;
+        xattrs.insert(name.to_string(), value);
+    }
+    Ok(xattrs)
+}
+
+/// Calls `read` once to size the buffer and again to fill it, starting
+/// over if what it reads grew in between.
+fn read_sized(read: impl Fn(&mut [u8]) -> Result<usize, Errno>) -> Result<Vec<u8>, Errno> {
+    loop {
+        let mut buf = vec![0; read(&mut [])?];
+        match read(&mut buf) {
+            Ok(len) => {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+            Err(Errno::RANGE) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod xattr_test {
+    use super::*;
+    use crate::kubo_rpc::ipfs::IpfsCid;
+    use crate::test_util::alice;
+    use anyhow::anyhow;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_xattrs_round_trip() {
+        let mut replica = Replica::new(alice());
+        let root = std::env::temp_dir().join(format!("crdt-xattr-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("from")).unwrap();
+        let file = root.join("from/tagged.txt");
+        std::fs::write(&file, b"tagged").unwrap();
+        rustix::fs::setxattr(&file, "user.tag", b"red", rustix::fs::XattrFlags::empty()).unwrap();
+        rustix::fs::setxattr(&file, "user.note", b"x", rustix::fs::XattrFlags::empty()).unwrap();
+
+        let blobs = std::sync::Mutex::new(std::collections::HashMap::new());
+        let store = async |cid: IpfsCid, bytes: Bytes| {
+            blobs.lock().unwrap().insert(cid, bytes.to_vec());
+            Ok(())
+        };
+        let mut scanner = Scanner::new(root.join("from")).with_xattrs(true);
+        scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!(replica.state().xattr("tagged.txt", "user.tag"), Some(&b"red"[..]));
+
+        // a change to the attributes alone is a patch
+        rustix::fs::removexattr(&file, "user.note").unwrap();
+        let report = scanner.scan(&mut replica, &store).await.unwrap();
+        assert_eq!(report.patched, vec!["tagged.txt"]);
+        replica.set_xattr("tagged.txt", "user.tag", b"blue").unwrap();
+
+        let fetch = async |cid: IpfsCid| blobs.lock().unwrap().get(&cid).cloned().ok_or_else(|| anyhow!("missing {}", cid));
+        let mut mirror = Scanner::new(root.join("to")).with_xattrs(true);
+        mirror.checkout(&replica, fetch).await.unwrap();
+        let restored = read_xattrs(&root.join("to/tagged.txt")).unwrap();
+        assert_eq!(restored, BTreeMap::from([("user.tag".to_string(), b"blue".to_vec())]));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,24 @@
+//! Fixtures shared by the unit tests.
+
+use std::str::FromStr;
+
+use crate::crdt::identity::ReplicaId;
+use crate::crdt::op::Entry;
+use crate::kubo_rpc::ipfs::{IpfsCid, RAW_CODE};
+
+pub(crate) fn alice() -> ReplicaId {
+    ReplicaId::from_str("k51qzi5uqu5dgndmfpeorlwuar7u66p9g9l0dolwy2v7sm6dt5sorjityev4ib").unwrap()
+}
+
+pub(crate) fn bob() -> ReplicaId {
+    ReplicaId::from_str("k51qzi5uqu5diyjoiyz6khv249l3puwbir19wiw1e3lehe4uw6g28pmtslcgqn").unwrap()
+}
+
+pub(crate) fn carol() -> ReplicaId {
+    ReplicaId::from_str("k51qzi5uqu5di2x0w2h1fhbhurkxt39id6nujigr2h34daqg4lppne6btgnfg5").unwrap()
+}
+
+/// A regular file holding `data` as a single raw block.
+pub(crate) fn entry(data: &[u8]) -> Entry {
+    Entry { content: IpfsCid::compute(RAW_CODE, data), size: data.len() as u64, mode: 0o644, mtime: 0, chunks: Vec::new(), symlink: None }
+}
@@ -0,0 +1,99 @@
+//! Bandwidth limits for chunk transfers, so background sync leaves room
+//! on a home connection. Uploads wait before they send, downloads after
+//! they arrive, as only then is their size known; either way a burst of
+//! transfers runs up a debt that later ones wait off.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Caps the bytes per second of every transfer it is shared by. Clones
+/// share the budget.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    bytes_per_sec: u64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that can go without waiting; negative when in debt.
+    available: f64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    /// Up to a second's worth of bytes can go at once.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        let bucket = Bucket { available: bytes_per_sec as f64, updated: Instant::now() };
+        RateLimit { bytes_per_sec, bucket: Arc::new(Mutex::new(bucket)) }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Takes `bytes` from the budget, waiting until it has been paid back.
+    pub async fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let rate = self.bytes_per_sec as f64;
+            let refilled = bucket.available + now.duration_since(bucket.updated).as_secs_f64() * rate;
+            bucket.available = refilled.min(rate) - bytes as f64;
+            bucket.updated = now;
+            Duration::from_secs_f64((-bucket.available).max(0.0) / rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Separate limits on uploads and downloads; none by default.
+#[derive(Debug, Clone, Default)]
+pub struct Bandwidth {
+    pub upload: Option<RateLimit>,
+    pub download: Option<RateLimit>,
+}
+
+impl Bandwidth {
+    /// Limits in bytes per second, `None` for no limit.
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> Self {
+        Bandwidth { upload: upload.map(RateLimit::new), download: download.map(RateLimit::new) }
+    }
+
+    /// Waits until `bytes` may be uploaded.
+    pub async fn upload(&self, bytes: usize) {
+        if let Some(limit) = &self.upload {
+            limit.take(bytes).await;
+        }
+    }
+
+    /// Waits off `bytes` just downloaded.
+    pub async fn download(&self, bytes: usize) {
+        if let Some(limit) = &self.download {
+            limit.take(bytes).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod throttle_test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_transfers_are_held_to_the_rate() {
+        let bandwidth = Bandwidth::new(Some(10_000), None);
+        let start = Instant::now();
+        // the first second's worth goes at once
+        bandwidth.upload(10_000).await;
+        bandwidth.download(1_000_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // clones share the budget
+        let shared = bandwidth.clone();
+        bandwidth.upload(2_000).await;
+        shared.upload(2_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(350));
+    }
+}
@@ -0,0 +1,190 @@
+//! Block stores layered local-first. Reads try the local store, then the
+//! daemon, then a gateway if one is set, keeping what comes from further
+//! out locally. Writes land in the local store at once and are written
+//! through to the daemon in the background, retried until it takes them,
+//! so committing never waits on the daemon or fails because it is down.
+//! Blocks not written through yet are held in memory as well, so the local
+//! store may evict them, as a `BlockCache` does, without losing them.
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+use crate::block_store::{BlockStore, GatewayStore, KuboStore};
+use crate::kubo_rpc::ipfs::IpfsCid;
+
+/// First wait before a failed write-through is retried; it doubles with
+/// every failure up to `MAX_WRITE_THROUGH_RETRY`.
+pub const WRITE_THROUGH_RETRY: Duration = Duration::from_millis(500);
+pub const MAX_WRITE_THROUGH_RETRY: Duration = Duration::from_secs(60);
+
+/// A local store in front of a remote one, the daemon by default. Clones
+/// share the blocks waiting to be written through.
+#[derive(Debug, Clone)]
+pub struct TieredStore<L, R = KuboStore> {
+    local: L,
+    remote: R,
+    gateway: Option<GatewayStore>,
+    /// Blocks written locally but not through yet, and how many.
+    unsent: Arc<Mutex<HashMap<IpfsCid, Bytes>>>,
+    unsent_len: Arc<watch::Sender<usize>>,
+    queue: mpsc::UnboundedSender<IpfsCid>,
+}
+
+impl<L, R> TieredStore<L, R>
+where
+    L: BlockStore + Sync,
+    R: BlockStore + Clone + Send + Sync + 'static,
+{
+    /// Layers `local` in front of `remote`. Starts the write-through task,
+    /// so it must be called within a Tokio runtime; the task ends once
+    /// every clone is dropped and what they queued is written through.
+    pub fn new(local: L, remote: R) -> Self {
+        let unsent = Arc::new(Mutex::new(HashMap::new()));
+        let unsent_len = Arc::new(watch::channel(0).0);
+        let (queue, queued) = mpsc::unbounded_channel();
+        tokio::spawn(write_through(remote.clone(), queued, unsent.clone(), unsent_len.clone()));
+        TieredStore { local, remote, gateway: None, unsent, unsent_len, queue }
+    }
+
+    /// Reads blocks neither store has from `gateway`.
+    pub fn with_gateway(mut self, gateway: GatewayStore) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    pub fn local(&self) -> &L {
+        &self.local
+    }
+
+    /// Number of blocks not written through yet.
+    pub fn unsent(&self) -> usize {
+        *self.unsent_len.borrow()
+    }
+
+    /// Waits until every block written so far is written through.
+    pub async fn flush(&self) {
+        let mut unsent_len = self.unsent_len.subscribe();
+        let _ = unsent_len.wait_for(|len| *len == 0).await;
+    }
+}
+
+impl<L, R> BlockStore for TieredStore<L, R>
+where
+    L: BlockStore + Sync,
+    R: BlockStore + Sync,
+{
+    async fn get(&self, cid: &IpfsCid) -> Result<Vec<u8>> {
+        if let Some(bytes) = self.unsent.lock().unwrap().get(cid) {
+            return Ok(bytes.to_vec());
+        }
+        if let Ok(bytes) = self.local.get(cid).await {
+            return Ok(bytes);
+        }
+        let bytes = match (self.remote.get(cid).await, &self.gateway) {
+            (Ok(bytes), _) => bytes,
+            (Err(_), Some(gateway)) => gateway.get(cid).await?,
+            (Err(err), None) => return Err(err),
+        };
+        // only a copy; the block is read either way
+        let _ = self.local.put(cid, Bytes::from(bytes.clone())).await;
+        Ok(bytes)
+    }
+
+    async fn put(&self, cid: &IpfsCid, bytes: Bytes) -> Result<()> {
+        self.local.put(cid, bytes.clone()).await?;
+        let mut unsent = self.unsent.lock().unwrap();
+        if unsent.insert(cid.clone(), bytes).is_none() && self.queue.send(cid.clone()).is_err() {
+            unsent.remove(cid);
+            bail!("Write-through of block {} stopped", cid);
+        }
+        self.unsent_len.send_replace(unsent.len());
+        Ok(())
+    }
+
+    async fn has(&self, cid: &IpfsCid) -> Result<bool> {
+        if self.unsent.lock().unwrap().contains_key(cid) || self.local.has(cid).await? {
+            return Ok(true);
+        }
+        self.remote.has(cid).await
+    }
+}
+
+/// Writes the blocks queued to `remote`, in order, retrying each until it
+/// is stored.
+async fn write_through<R: BlockStore>(
+    remote: R,
+    mut queued: mpsc::UnboundedReceiver<IpfsCid>,
+    unsent: Arc<Mutex<HashMap<IpfsCid, Bytes>>>,
+    unsent_len: Arc<watch::Sender<usize>>,
+) {
+    while let Some(cid) = queued.recv().await {
+        let Some(bytes) = unsent.lock().unwrap().get(&cid).cloned() else {
+            continue;
+        };
+        let mut retry = WRITE_THROUGH_RETRY;
+        while remote.put(&cid, bytes.clone()).await.is_err() {
+            tokio::time::sleep(retry).await;
+            retry = (retry * 2).min(MAX_WRITE_THROUGH_RETRY);
+        }
+        let mut unsent = unsent.lock().unwrap();
+        unsent.remove(&cid);
+        unsent_len.send_replace(unsent.len());
+    }
+}
+
+#[cfg(test)]
+mod tiered_store_test {
+    use super::*;
+    use crate::block_store::MemoryStore;
+    use crate::kubo_rpc::ipfs::RAW_CODE;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A remote that refuses writes while it is down.
+    #[derive(Clone)]
+    struct Remote {
+        blocks: MemoryStore,
+        down: Arc<AtomicBool>,
+    }
+
+    impl BlockStore for Remote {
+        async fn get(&self, cid: &IpfsCid) -> Result<Vec<u8>> {
+            self.blocks.get(cid).await
+        }
+
+        async fn put(&self, cid: &IpfsCid, bytes: Bytes) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                bail!("Daemon is down");
+            }
+            self.blocks.put(cid, bytes).await
+        }
+
+        async fn has(&self, cid: &IpfsCid) -> Result<bool> {
+            self.blocks.has(cid).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_writes_go_through_once_the_daemon_is_back() {
+        let remote = Remote { blocks: MemoryStore::new(), down: Arc::new(AtomicBool::new(true)) };
+        let store = TieredStore::new(MemoryStore::new(), remote.clone());
+        let cid = IpfsCid::compute(RAW_CODE, b"written");
+        store.put(&cid, Bytes::from_static(b"written")).await.unwrap();
+        assert_eq!(store.get(&cid).await.unwrap(), b"written");
+        assert_eq!(store.unsent(), 1);
+        assert!(!remote.blocks.has(&cid).await.unwrap());
+
+        remote.down.store(false, Ordering::SeqCst);
+        tokio::time::timeout(Duration::from_secs(10), store.flush()).await.unwrap();
+        assert!(remote.blocks.has(&cid).await.unwrap());
+
+        // read through from the remote, and kept locally
+        let far = IpfsCid::compute(RAW_CODE, b"far");
+        remote.blocks.put(&far, Bytes::from_static(b"far")).await.unwrap();
+        assert_eq!(store.get(&far).await.unwrap(), b"far");
+        assert!(store.local().has(&far).await.unwrap());
+    }
+}
@@ -0,0 +1,525 @@
+use anyhow::{anyhow, bail, Result};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::crdt::car::Car;
+use crate::crdt::chunk::{ChunkRule, Chunker};
+use crate::crdt::fork::ForkResolution;
+use crate::crdt::identity::ReplicaId;
+use crate::crdt::op::{Access, ConflictPolicy};
+use crate::crdt::policy::FilePolicy;
+use crate::crdt::preview::MergeReport;
+use crate::crdt::quota::Usage;
+use crate::crdt::replica::Replica;
+use crate::durability::Durability;
+use crate::kubo_rpc::ipfs::{block_rm, dag_export, get_block, pin_rm, put_block_with_codec, IpfsCid};
+use crate::kubo_rpc::ipns::{name_publish, name_resolve_streaming, IpfsPath};
+use crate::kubo_rpc::keys::IpnsKey;
+use crate::missing::MissingBlocks;
+use crate::progress::{Progress, Reporter};
+use crate::sync::cache::{fetch_cached, BlockCache};
+use crate::throttle::Bandwidth;
+
+/// Outcome of one round of merging member heads.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Operations applied from remote members.
+    pub applied: usize,
+    /// Members whose heads were merged.
+    pub merged: Vec<ReplicaId>,
+    /// Members that couldn't be resolved or fetched this round.
+    pub failed: Vec<(ReplicaId, anyhow::Error)>,
+    /// Members whose announcement rolled back to a branch that doesn't
+    /// descend from the one merged before; see `resolve_fork`.
+    pub forked: Vec<ReplicaId>,
+    /// Blocks given up on for now, which merges needing them wait for;
+    /// see `MissingBlocks`.
+    pub unavailable: Vec<IpfsCid>,
+}
+
+/// Outcome of a garbage collection pass.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// Blocks the replica dropped because its heads no longer reach them.
+    pub pruned: Vec<IpfsCid>,
+    /// How many of those were pinned on the daemon.
+    pub unpinned: usize,
+    /// How many were deleted from the daemon's blockstore.
+    pub removed: usize,
+}
+
+/// Which way a deployment syncs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    #[default]
+    Bidirectional,
+    /// Publishes its own changes and never merges anyone else's, like a CI
+    /// artifact publisher.
+    PushOnly,
+    /// Merges and checks out the members' changes and never publishes or
+    /// commits its own, like a read mirror.
+    PullOnly,
+}
+
+impl SyncMode {
+    pub fn pushes(self) -> bool {
+        self != SyncMode::PullOnly
+    }
+
+    pub fn pulls(self) -> bool {
+        self != SyncMode::PushOnly
+    }
+
+    /// Fails unless this mode pushes.
+    pub(crate) fn check_push(self) -> Result<()> {
+        if !self.pushes() {
+            bail!("Refusing to push in pull-only mode");
+        }
+        Ok(())
+    }
+
+    /// Fails unless this mode pulls.
+    pub(crate) fn check_pull(self) -> Result<()> {
+        if !self.pulls() {
+            bail!("Refusing to pull in push-only mode");
+        }
+        Ok(())
+    }
+}
+
+/// A shared directory with several writers. Every member publishes its own
+/// head announcement under its own IPNS key; merging resolves those of
+/// every member listed in the replica's membership document.
+pub struct Workspace {
+    base_url: String,
+    replica: Replica,
+    mode: SyncMode,
+    bandwidth: Bandwidth,
+    progress: Reporter,
+    /// Where fetched nodes are cached, if anywhere.
+    cache: Option<BlockCache>,
+    /// Where the pins this workspace makes are recorded; see `PinLedger`.
+    pub(crate) pin_ledger: Option<PathBuf>,
+    /// Where what this workspace owes others is kept; see `Outbox`.
+    pub(crate) outbox: Option<PathBuf>,
+    /// Where the heads members were last resolved to are kept; see
+    /// `ResolutionCache`.
+    pub(crate) resolution_cache: Option<PathBuf>,
+    /// Blocks the network didn't have, not asked for again for a while.
+    pub(crate) missing: MissingBlocks,
+}
+
+impl Workspace {
+    /// Opens a workspace on the IPFS daemon at `base_url`. The replica's
+    /// author key is the key this replica publishes under.
+    pub fn new(base_url: &str, replica: Replica) -> Self {
+        Workspace {
+            base_url: base_url.to_string(),
+            replica,
+            mode: SyncMode::default(),
+            bandwidth: Bandwidth::default(),
+            progress: Reporter::default(),
+            cache: None,
+            pin_ledger: None,
+            outbox: None,
+            resolution_cache: None,
+            missing: MissingBlocks::default(),
+        }
+    }
+
+    /// Only syncs one way; see `SyncMode`.
+    pub fn with_mode(mut self, mode: SyncMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn mode(&self) -> SyncMode {
+        self.mode
+    }
+
+    /// Limits the rate of the chunk transfers of syncing the workspace's
+    /// directory; see `Syncer`.
+    pub fn with_bandwidth(mut self, bandwidth: Bandwidth) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
+
+    /// Sends progress events of merges and publishes, and of syncing the
+    /// workspace's directory, to `reporter`.
+    pub fn with_progress(mut self, reporter: Reporter) -> Self {
+        self.progress = reporter;
+        self
+    }
+
+    pub fn progress(&self) -> &Reporter {
+        &self.progress
+    }
+
+    /// When the journal, and the files and index of syncing the workspace's
+    /// directory, are synced to disk; see `Durability`.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.replica = self.replica.with_durability(durability);
+        self
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.replica.durability()
+    }
+
+    /// Caches the DAG nodes merges fetch, and the content read-only
+    /// checkouts fetch, in `cache`.
+    pub fn with_block_cache(mut self, cache: BlockCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn block_cache(&self) -> Option<&BlockCache> {
+        self.cache.as_ref()
+    }
+
+    pub fn replica(&self) -> &Replica {
+        &self.replica
+    }
+
+    pub fn replica_mut(&mut self) -> &mut Replica {
+        &mut self.replica
+    }
+
+    pub fn into_replica(self) -> Replica {
+        self.replica
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// File count and size of the current files against the quota.
+    pub fn usage(&self) -> Usage {
+        self.replica.usage()
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &ReplicaId> {
+        self.replica.members()
+    }
+
+    /// Admits `member`, whose nodes must be signed by `signer`.
+    pub fn add_member(&mut self, member: ReplicaId, signer: &[u8]) -> Result<IpfsCid> {
+        self.replica.add_member(member, signer)
+    }
+
+    pub fn remove_member(&mut self, member: &ReplicaId) -> Result<Option<IpfsCid>> {
+        self.replica.remove_member(member)
+    }
+
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) -> Result<IpfsCid> {
+        self.replica.set_conflict_policy(policy)
+    }
+
+    pub fn set_chunker(&mut self, chunker: Chunker) -> Result<IpfsCid> {
+        self.replica.set_chunker(chunker)
+    }
+
+    pub fn set_chunk_rules(&mut self, rules: Vec<ChunkRule>) -> Result<IpfsCid> {
+        self.replica.set_chunk_rules(rules)
+    }
+
+    pub fn set_file_policy(&mut self, policy: FilePolicy) -> Result<IpfsCid> {
+        self.replica.set_file_policy(policy)
+    }
+
+    /// Grants or revokes `member`'s write permission; see `Access`.
+    pub fn set_access(&mut self, member: ReplicaId, access: Access) -> Result<IpfsCid> {
+        self.replica.set_access(member, access)
+    }
+
+    /// Replays the journal at `path` and keeps journaling there; see
+    /// `Replica::open_journal`. Publishing empties it.
+    pub async fn open_journal(&mut self, path: impl Into<PathBuf>) -> Result<usize> {
+        let (base_url, cache, missing) = (self.base_url.clone(), self.cache.clone(), self.missing.clone());
+        self.replica.open_journal(path, async |cid| get_node(&base_url, cache.as_ref(), &missing, cid).await).await
+    }
+
+    /// Stores new DAG nodes and a head announcement on the daemon, then
+    /// points this replica's IPNS key at the announcement; the journal, if
+    /// any, is emptied once it does. With an outbox, a publish that fails
+    /// is owed until one succeeds.
+    pub async fn publish(&mut self) -> Result<IpfsCid> {
+        self.mode.check_push()?;
+        let published = self.publish_heads().await;
+        self.owe_publish(published.is_err())?;
+        published
+    }
+
+    async fn publish_heads(&mut self) -> Result<IpfsCid> {
+        self.replica.push_to(&self.base_url).await?;
+
+        let (cid, bytes) = self.replica.encode_block(&self.replica.announcement())?;
+        let stored = put_block_with_codec(&self.base_url, bytes, cid.codec_name()).await?;
+        if stored != cid {
+            bail!("Daemon stored announcement as {} but expected {}", stored, cid);
+        }
+
+        let path = IpfsPath::Ipfs(cid.clone());
+        name_publish(&self.base_url, &path, self.replica.author().ipns_key(), None, None).await?;
+        self.pin_announcement(&cid).await?;
+        self.replica.acknowledge_published()?;
+        self.progress.emit(|| Progress::Published { announcement: cid.clone() });
+        Ok(cid)
+    }
+
+    /// Resolves every other member's IPNS key and merges their heads.
+    /// A member that can't be reached doesn't stop the others.
+    pub async fn merge_members(&mut self) -> Result<SyncReport> {
+        self.mode.check_pull()?;
+        let mut report = SyncReport::default();
+        let others: Vec<ReplicaId> = self.members().filter(|id| *id != self.replica.author()).cloned().collect();
+
+        for member in others {
+            match self.merge_member(&member).await {
+                Ok(applied) => {
+                    report.applied += applied;
+                    report.merged.push(member);
+                }
+                Err(_) if self.replica.fork(&member).is_some() => report.forked.push(member),
+                Err(e) => report.failed.push((member, e)),
+            }
+        }
+        report.unavailable = self.missing.unavailable();
+        Ok(report)
+    }
+
+    /// Merges the heads `member` announces. A new replica calls this once
+    /// with whoever invited it to pick up the membership document.
+    /// With a resolution cache, the head it resolved to is remembered once
+    /// merged.
+    pub async fn merge_member(&mut self, member: &ReplicaId) -> Result<usize> {
+        self.mode.check_pull()?;
+        let head = self.resolve_head(member).await?;
+        let ops = self.merge_announced(member, &head.announcement).await?;
+        self.remember_head(member, head)?;
+        Ok(ops)
+    }
+
+    /// Merges `announcement`, which `member` announced.
+    pub(crate) async fn merge_announced(&mut self, member: &ReplicaId, announcement: &IpfsCid) -> Result<usize> {
+        let (base_url, cache, missing) = (self.base_url.clone(), self.cache.clone(), self.missing.clone());
+        let ops = self
+            .replica
+            .merge_announcement(announcement, async |cid| get_node(&base_url, cache.as_ref(), &missing, cid).await)
+            .await?;
+        self.progress.emit(|| Progress::MergeApplied { member: member.clone(), ops });
+        Ok(ops)
+    }
+
+    /// Settles a fork `merge_members` reported for `member`.
+    pub async fn resolve_fork(&mut self, member: &ReplicaId, resolution: ForkResolution) -> Result<usize> {
+        self.mode.check_pull()?;
+        let (base_url, cache, missing) = (self.base_url.clone(), self.cache.clone(), self.missing.clone());
+        self.replica
+            .resolve_fork(member, resolution, async |cid| get_node(&base_url, cache.as_ref(), &missing, cid).await)
+            .await
+    }
+
+    /// Reports what `merge_member` would change, without merging.
+    pub async fn preview_member(&self, member: &ReplicaId) -> Result<MergeReport> {
+        let announcement = resolve_announcement(&self.base_url, member.ipns_key()).await?;
+        let mut merged = self.replica.clone();
+        let operations = merged
+            .merge_announcement(&announcement, async |cid| get_node(&self.base_url, self.cache.as_ref(), &self.missing, cid).await)
+            .await?;
+        Ok(MergeReport { operations, ..MergeReport::between(self.replica.state(), merged.state()) })
+    }
+
+    /// Drops history blocks the current heads no longer reach (see
+    /// `Replica::prune_unreachable`) and unpins them on the daemon; with a
+    /// pin ledger, only those it records and nobody else holds. With
+    /// `remove_blocks` they are also deleted from its blockstore, which
+    /// fails for blocks something else still pins.
+    pub async fn collect_garbage(&mut self, remove_blocks: bool) -> Result<GcReport> {
+        let mut report = GcReport::default();
+        for cid in self.replica.unreachable_blocks() {
+            let unpinned = match self.pin_ledger {
+                Some(_) => self.release_pin(&cid, None).await?,
+                None => pin_rm(&self.base_url, &cid).await?,
+            };
+            if unpinned {
+                report.unpinned += 1;
+            }
+            if remove_blocks {
+                block_rm(&self.base_url, &cid).await?;
+                report.removed += 1;
+            }
+        }
+        // only forget blocks once the daemon let go of them, so a failed
+        // pass can be retried
+        report.pruned = self.replica.prune_unreachable();
+        Ok(report)
+    }
+
+    /// `Replica::export_car` plus the content of every current file, read
+    /// from the daemon, so the backup restores the files too. Content only
+    /// older versions point to is left out, as are symlinks, which carry
+    /// their target inline.
+    pub async fn export_car(&self, path: impl AsRef<Path>) -> Result<IpfsCid> {
+        let mut car = self.replica.to_car()?;
+        self.add_content(&mut car).await?;
+        car.write(path).await?;
+        Ok(car.roots[0].clone())
+    }
+
+    /// Adds the blocks of the content of every current file to `car`,
+    /// read from the daemon.
+    pub(crate) async fn add_content(&self, car: &mut Car) -> Result<()> {
+        let mut included: HashSet<IpfsCid> = car.blocks.iter().map(|(cid, _)| cid.clone()).collect();
+        let mut roots = Vec::new();
+        for (_, entry) in self.replica.state().iter() {
+            if entry.is_symlink() {
+                continue;
+            }
+            if entry.chunks.is_empty() {
+                roots.push(entry.content);
+            } else {
+                roots.extend(entry.chunks.into_iter().map(|chunk| chunk.content));
+            }
+        }
+        for root in roots {
+            if included.contains(&root) {
+                continue;
+            }
+            let content = Car::decode(&dag_export(&self.base_url, &root).await?)?;
+            for (cid, bytes) in content.blocks {
+                if included.insert(cid.clone()) {
+                    car.blocks.push((cid, bytes));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores every block of the CAR file at `path` on the daemon, then
+    /// merges the announcement it is rooted at (see `Replica::merge_car`).
+    /// Onboards a collaborator from a backup or a copy handed over
+    /// offline.
+    pub async fn import_car(&mut self, path: impl AsRef<Path>) -> Result<usize> {
+        self.mode.check_pull()?;
+        let car = Car::read(path).await?;
+        for (cid, bytes) in &car.blocks {
+            let stored = put_block_with_codec(&self.base_url, bytes.clone(), cid.codec_name()).await?;
+            if stored != *cid {
+                bail!("Daemon stored block as {} but expected {}", stored, cid);
+            }
+        }
+        self.replica.merge_car(&car).await
+    }
+
+    /// One round of the merge loop: merge everyone else, then publish.
+    /// A workspace that syncs one way only does its half.
+    pub async fn sync_once(&mut self) -> Result<SyncReport> {
+        let report = if self.mode.pulls() { self.merge_members().await? } else { SyncReport::default() };
+        if self.mode.pushes() {
+            self.publish().await?;
+        }
+        Ok(report)
+    }
+}
+
+/// Resolves `key` to the head announcement it currently points at.
+pub async fn resolve_announcement(base_url: &str, key: &IpnsKey) -> Result<IpfsCid> {
+    let mut resolved = name_resolve_streaming(base_url, key, false, Some(true), None, None, None).await?;
+    match resolved.next().await {
+        Some(Ok(IpfsPath::Ipfs(cid))) => Ok(cid),
+        Some(Ok(other)) => Err(anyhow!("{} resolved to {} instead of an /ipfs/ path", key, other.as_str())),
+        Some(Err(e)) => Err(e),
+        None => Err(anyhow!("No IPNS record found for {}", key)),
+    }
+}
+
+/// The block `cid` from `cache`, if any, or the daemon at `base_url`
+/// unless `missing` gave up on it.
+async fn get_node(base_url: &str, cache: Option<&BlockCache>, missing: &MissingBlocks, cid: IpfsCid) -> Result<Vec<u8>> {
+    fetch_cached(cache, cid, async |cid| missing.fetch(cid, async |cid| get_block(base_url, &cid).await).await).await
+}
+
+#[cfg(test)]
+mod workspace_test {
+    use super::*;
+    use crate::test_util::{alice, bob, carol, entry};
+    use std::collections::HashMap;
+
+    async fn announce(replica: &mut Replica, store: &mut HashMap<IpfsCid, Vec<u8>>) -> IpfsCid {
+        replica
+            .push(async |cid, bytes| {
+                store.insert(cid, bytes);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        let (cid, bytes) = replica.encode_block(&replica.announcement()).unwrap();
+        store.insert(cid.clone(), bytes);
+        cid
+    }
+
+    #[test]
+    fn test_own_key_is_always_a_member() {
+        let own = alice();
+        let other = bob();
+        let mut workspace = Workspace::new("http://127.0.0.1:5001", Replica::new(own.clone()));
+        workspace.add_member(other.clone(), &[2; 32]).unwrap();
+        assert!(workspace.remove_member(&own).is_err());
+        assert_eq!(workspace.members().count(), 2);
+        workspace.remove_member(&other).unwrap();
+        assert_eq!(workspace.members().collect::<Vec<_>>(), vec![&own]);
+    }
+
+    #[tokio::test]
+    async fn test_one_way_sync_does_its_half() {
+        let own = alice();
+        let other = bob();
+        // nothing listens there, so the half that is done fails to connect
+        let mut mirror = Workspace::new("http://127.0.0.1:1", Replica::new(own.clone())).with_mode(SyncMode::PullOnly);
+        mirror.add_member(other.clone(), &[2; 32]).unwrap();
+        let report = mirror.sync_once().await.unwrap();
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, other);
+
+        let mut publisher = Workspace::new("http://127.0.0.1:1", Replica::new(own)).with_mode(SyncMode::PushOnly);
+        publisher.add_member(other, &[2; 32]).unwrap();
+        let err = publisher.sync_once().await.unwrap_err().to_string();
+        assert!(!err.contains("Refusing"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_three_writers_merge_all_announcements() {
+        let keys = [
+            alice(),
+            bob(),
+            carol(),
+        ];
+        let mut store = HashMap::new();
+        let mut replicas: Vec<Replica> = keys.iter().map(|k| Replica::new(k.clone())).collect();
+        let mut announcements = Vec::new();
+        for (i, replica) in replicas.iter_mut().enumerate() {
+            replica.put(&format!("from-{}", i), entry(&[i as u8])).unwrap();
+            announcements.push(announce(replica, &mut store).await);
+        }
+
+        for replica in replicas.iter_mut() {
+            for cid in &announcements {
+                replica
+                    .merge_announcement(cid, async |cid| store.get(&cid).cloned().ok_or_else(|| anyhow!("missing")))
+                    .await
+                    .unwrap();
+            }
+        }
+
+        assert!(replicas.iter().all(|r| r.state() == replicas[0].state()));
+        assert_eq!(replicas[0].state().iter().count(), 3);
+    }
+}